@@ -0,0 +1,224 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module handles access control for the Telegram bot.
+//!
+//! Telegram users are given a role through the `bot.admins`, `bot.operators`
+//! and `bot.viewers` settings, and `bot.default_role` applies to everyone
+//! else. The bot can also be restricted to the chats listed in
+//! `bot.allowed_chats`. Rejected attempts are appended to an audit log.
+
+use crate::config::Config;
+use crate::profile;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Represents the role of a bot user. Each role includes the permissions of
+/// the roles before it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can read sessions, sources and activities.
+    Viewer,
+    /// Can also create sessions, send messages and approve plans.
+    Operator,
+    /// Can also delete sessions and merge pull requests.
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role '{}'. Use viewer, operator or admin.", s)),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Operator => write!(f, "operator"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Decides which users may use the bot, and in which chats.
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    admins: Vec<u64>,
+    operators: Vec<u64>,
+    viewers: Vec<u64>,
+    default_role: Option<Role>,
+    allowed_chats: Vec<i64>,
+}
+
+impl AccessPolicy {
+    /// Builds the access policy from the `bot.*` settings.
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            admins: config.bot_users("bot.admins"),
+            operators: config.bot_users("bot.operators"),
+            viewers: config.bot_users("bot.viewers"),
+            default_role: config.bot_default_role().and_then(|r| r.parse().ok()),
+            allowed_chats: config.bot_allowed_chats(),
+        }
+    }
+
+    /// Returns the role of a user, or `None` if the user may not use the bot.
+    pub fn role(&self, user_id: u64) -> Option<Role> {
+        if self.admins.contains(&user_id) {
+            Some(Role::Admin)
+        } else if self.operators.contains(&user_id) {
+            Some(Role::Operator)
+        } else if self.viewers.contains(&user_id) {
+            Some(Role::Viewer)
+        } else {
+            self.default_role
+        }
+    }
+
+    /// Returns whether the bot may be used in a chat.
+    pub fn chat_allowed(&self, chat_id: i64) -> bool {
+        self.allowed_chats.is_empty() || self.allowed_chats.contains(&chat_id)
+    }
+
+    /// Checks that a user may do something requiring the given role in a chat.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the user's role, or the reason the attempt is
+    /// rejected.
+    pub fn check(&self, user_id: Option<u64>, chat_id: i64, required: Role) -> Result<Role, String> {
+        if !self.chat_allowed(chat_id) {
+            return Err("chat not allowed".to_string());
+        }
+        let user_id = user_id.ok_or("unknown user")?;
+        match self.role(user_id) {
+            Some(role) if role >= required => Ok(role),
+            Some(role) => Err(format!("requires {}, user is {}", required, role)),
+            None => Err("user not allowed".to_string()),
+        }
+    }
+}
+
+/// Represents an entry of the audit log.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    /// The time of the attempt, in seconds since the Unix epoch.
+    pub time: u64,
+    /// The Telegram user id, if known.
+    pub user_id: Option<u64>,
+    /// The Telegram username, if any.
+    pub username: Option<String>,
+    /// The Telegram chat id.
+    pub chat_id: i64,
+    /// The attempted action, e.g. `/delete`. Arguments are not recorded.
+    pub action: String,
+    /// Why the attempt was rejected.
+    pub reason: String,
+}
+
+/// Manages the audit log of rejected bot requests.
+///
+/// The log is stored as JSON lines in `audit.log` in the profile's
+/// configuration directory.
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// Creates a new `AuditLog` instance for the current profile.
+    pub fn new() -> Result<Self, String> {
+        Ok(Self {
+            path: profile::config_dir()?.join("audit.log"),
+        })
+    }
+
+    /// Records a rejected attempt.
+    pub fn record(
+        &self,
+        user_id: Option<u64>,
+        username: Option<String>,
+        chat_id: i64,
+        action: &str,
+        reason: &str,
+    ) -> Result<(), String> {
+        let entry = AuditEntry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            user_id,
+            username,
+            chat_id,
+            action: action.to_string(),
+            reason: reason.to_string(),
+        };
+        let line = serde_json::to_string(&entry)
+            .map_err(|e| format!("Could not serialize audit entry: {}", e))?;
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Could not create config directory: {}", e))?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("Could not open audit log: {}", e))?;
+        writeln!(file, "{}", line).map_err(|e| format!("Could not write audit log: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles_and_chats() {
+        let policy = AccessPolicy {
+            admins: vec![1],
+            operators: vec![2],
+            viewers: vec![3],
+            default_role: None,
+            allowed_chats: vec![100],
+        };
+        assert_eq!(policy.check(Some(1), 100, Role::Admin), Ok(Role::Admin));
+        assert_eq!(policy.check(Some(2), 100, Role::Viewer), Ok(Role::Operator));
+        assert!(policy.check(Some(2), 100, Role::Admin).is_err());
+        assert!(policy.check(Some(3), 100, Role::Operator).is_err());
+        assert!(policy.check(Some(4), 100, Role::Viewer).is_err());
+        assert!(policy.check(None, 100, Role::Viewer).is_err());
+        assert!(policy.check(Some(1), 200, Role::Viewer).is_err());
+
+        let open = AccessPolicy {
+            default_role: Some(Role::Viewer),
+            ..AccessPolicy::default()
+        };
+        assert_eq!(open.check(Some(4), 200, Role::Viewer), Ok(Role::Viewer));
+        assert!(open.check(Some(4), 200, Role::Operator).is_err());
+    }
+}
@@ -1,21 +1,44 @@
 // src/bot.rs
 
-use teloxide::{prelude::*, types::ParseMode, utils::command::BotCommands};
+use teloxide::{
+    dispatching::dialogue::InMemStorage,
+    prelude::*,
+    RequestError,
+    types::{ForceReply, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, MessageId, ParseMode, User, UserId},
+    update_listeners::{webhooks, UpdateListener},
+    utils::command::BotCommands,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
 use dotenv::dotenv;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError};
 use tokio::sync::Mutex;
-use tokio::time::{self, Duration};
-use julezz::api::JulesClient;
-use julezz::cache::{Cache, CachedSession};
-use julezz::resolve::resolve_session_identifier;
+use tokio::time::{self, Duration, Instant};
+use julezz::access::{AccessPolicy, AuditLog, Role};
+use julezz::api::{Activity, JulesClient, Session};
+use chrono::{DateTime, Local, Utc};
+use julezz::cache::{Cache, CachedSession, ChatState, DaemonState, Downtime};
+use julezz::config::Config;
+use julezz::credentials;
+use julezz::diff::{collect_patches, diff_stats};
+use julezz::digest::{stale_after, Digest, DigestSchedule, DEFAULT_STALE_HOURS};
+use julezz::events::{cursor_before, cursor_since, detect, sort_activities, Event, EventKind};
+use julezz::hooks::Hooks;
+use julezz::metrics;
+use julezz::poll::{fetch_all, is_terminal, PollStats, Poller};
+use julezz::preferences::{mute_until, parse_duration, parse_events, Mute, QuietHours};
+use julezz::profile;
+use julezz::resolve::resolve_session_identifier_with_aliases;
+use julezz::trash::Trash;
 
 fn escape_markdown_v2(text: &str) -> String {
     let mut escaped = String::new();
     for ch in text.chars() {
         match ch {
-            '_' | '*' | '[' | ']' | '(' | ')' | '~' | '`' | '>' | '#' | '+' | '-' | '=' | '|' | '{' | '}' | '.' | '!' => {
+            '\\' | '_' | '*' | '[' | ']' | '(' | ')' | '~' | '`' | '>' | '#' | '+' | '-' | '=' | '|' | '{' | '}' | '.' | '!' => {
                 escaped.push('\\');
                 escaped.push(ch);
             }
@@ -27,6 +50,489 @@ fn escape_markdown_v2(text: &str) -> String {
     escaped
 }
 
+/// Escapes text for a MarkdownV2 code block, where only backticks and
+/// backslashes must be escaped.
+fn escape_code_v2(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('`', "\\`")
+}
+
+/// Formats text as a MarkdownV2 code block.
+fn code_block(text: &str, language: &str) -> String {
+    format!("```{}\n{}\n```", language, escape_code_v2(text.trim_end_matches('\n')))
+}
+
+/// The maximum length of a Telegram message, in UTF-16 code units.
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// The number of chunks of a long message sent at once. The rest is sent when
+/// the "Show more" button is pressed.
+const MAX_CHUNKS_AT_ONCE: usize = 2;
+
+/// Bash outputs and patches longer than this are sent as attachments rather
+/// than inline.
+const MAX_INLINE_OUTPUT: usize = 1500;
+
+/// The number of long messages whose remaining chunks are kept for "Show more".
+const MAX_PENDING_MESSAGES: usize = 100;
+
+/// Splits a message into chunks of at most `max` UTF-16 code units.
+///
+/// Messages are split on line boundaries, and only lines that are too long on
+/// their own are split within. A code block split across chunks is closed at
+/// the end of one chunk and reopened at the start of the next, so that every
+/// chunk is valid MarkdownV2.
+fn split_message(text: &str, max: usize) -> Vec<String> {
+    const FENCE: &str = "```";
+    let len = |s: &str| s.encode_utf16().count();
+    // Leave room for closing and reopening a code block.
+    let max_line = max.saturating_sub(32).max(1);
+
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut open_fence: Option<String> = None;
+    for line in text.split('\n').flat_map(|line| split_line(line, max_line)) {
+        let closing = if open_fence.is_some() { FENCE.len() + 1 } else { 0 };
+        if !chunk.is_empty() && len(&chunk) + 1 + len(&line) + closing > max {
+            if open_fence.is_some() {
+                chunk.push('\n');
+                chunk.push_str(FENCE);
+            }
+            chunks.push(std::mem::take(&mut chunk));
+            if let Some(fence) = &open_fence {
+                chunk.push_str(fence);
+            }
+        }
+        if !chunk.is_empty() {
+            chunk.push('\n');
+        }
+        chunk.push_str(&line);
+        if line.starts_with(FENCE) {
+            open_fence = match open_fence {
+                Some(_) => None,
+                None => Some(line.clone()),
+            };
+        }
+    }
+    if !chunk.trim().is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Splits a line into pieces of at most `max` UTF-16 code units, without
+/// separating a MarkdownV2 escape from the character it escapes.
+fn split_line(line: &str, max: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    let mut piece_len = 0;
+    let mut chars = line.chars().peekable();
+    while let Some(ch) = chars.next() {
+        let mut unit = ch.to_string();
+        if ch == '\\' {
+            if let Some(next) = chars.next() {
+                unit.push(next);
+            }
+        }
+        let unit_len = unit.encode_utf16().count();
+        if piece_len + unit_len > max && !piece.is_empty() {
+            pieces.push(std::mem::take(&mut piece));
+            piece_len = 0;
+        }
+        piece.push_str(&unit);
+        piece_len += unit_len;
+    }
+    pieces.push(piece);
+    pieces
+}
+
+/// A file sent along with a message.
+struct Attachment {
+    file_name: String,
+    contents: String,
+}
+
+/// A message that may be too long to send at once.
+struct LongMessage {
+    /// The chunks not sent yet.
+    chunks: VecDeque<String>,
+    parse_mode: Option<ParseMode>,
+    /// The keyboard of the last chunk.
+    keyboard: Option<InlineKeyboardMarkup>,
+}
+
+impl LongMessage {
+    fn new(text: &str, parse_mode: Option<ParseMode>, keyboard: Option<InlineKeyboardMarkup>) -> Self {
+        Self {
+            chunks: split_message(text, MAX_MESSAGE_LENGTH).into(),
+            parse_mode,
+            keyboard,
+        }
+    }
+}
+
+/// Sends a message that may be longer than Telegram allows.
+///
+/// The first chunks are sent right away. If more remain, the last chunk sent
+/// gets a "Show more" button and the rest is kept until it is pressed.
+///
+/// # Returns
+///
+/// The messages sent.
+async fn send_long_message(
+    bot: &Bot,
+    state: &BotState,
+    chat_id: ChatId,
+    mut message: LongMessage,
+) -> ResponseResult<Vec<Message>> {
+    let mut sent = Vec::new();
+    for i in 0..MAX_CHUNKS_AT_ONCE {
+        let chunk = match message.chunks.pop_front() {
+            Some(chunk) => chunk,
+            None => break,
+        };
+        let mut request = bot.send_message(chat_id, chunk);
+        if let Some(parse_mode) = message.parse_mode {
+            request = request.parse_mode(parse_mode);
+        }
+        if message.chunks.is_empty() {
+            if let Some(keyboard) = message.keyboard.take() {
+                request = request.reply_markup(keyboard);
+            }
+        } else if i + 1 == MAX_CHUNKS_AT_ONCE {
+            let button = InlineKeyboardButton::callback(
+                format!("Show more ({} left)", message.chunks.len()),
+                "more:",
+            );
+            request = request.reply_markup(InlineKeyboardMarkup::new(vec![vec![button]]));
+        }
+        sent.push(request.await?);
+    }
+    if !message.chunks.is_empty() {
+        if let Some(last) = sent.last() {
+            state.keep_pending(chat_id, last.id, message).await;
+        }
+    }
+    Ok(sent)
+}
+
+/// Sends attachments as documents.
+async fn send_attachments(bot: &Bot, chat_id: ChatId, attachments: Vec<Attachment>) -> ResponseResult<()> {
+    for attachment in attachments {
+        let file = InputFile::memory(attachment.contents.into_bytes()).file_name(attachment.file_name);
+        bot.send_document(chat_id, file).await?;
+    }
+    Ok(())
+}
+
+/// Shared state of the bot.
+///
+/// Each chat authenticates with its own API key, so the bot keeps one API
+/// client per chat. Clients are created lazily from the API key stored in the
+/// chat's state.
+struct BotState {
+    cache: Cache,
+    clients: Mutex<HashMap<ChatId, Arc<JulesClient>>>,
+    policy: AccessPolicy,
+    audit_log: AuditLog,
+    /// The chats checked for new activities since the bot started.
+    checked_chats: Mutex<HashSet<ChatId>>,
+    /// The activity poller of each chat.
+    pollers: Mutex<HashMap<ChatId, Poller>>,
+    /// The remaining chunks of long messages, by the message with the "Show
+    /// more" button.
+    pending: Mutex<VecDeque<(ChatId, MessageId, LongMessage)>>,
+    /// The last activity hooks ran for, by session, whichever chats follow
+    /// the session.
+    hook_cursors: Mutex<DaemonState>,
+    /// The branch and pull request choice new sessions start with, from
+    /// `sessions.branch` and `sessions.automation_mode`.
+    new_session: NewSessionDraft,
+    /// Serializes the updates of each chat's state.
+    chat_locks: std::sync::Mutex<HashMap<ChatId, Arc<std::sync::Mutex<()>>>>,
+}
+
+impl BotState {
+    fn new(cache: Cache, policy: AccessPolicy, audit_log: AuditLog, config: &Config) -> Self {
+        Self {
+            new_session: NewSessionDraft {
+                branch: config.branch(),
+                auto_pr: config.auto_pr(),
+                ..NewSessionDraft::default()
+            },
+            clients: Mutex::new(HashMap::new()),
+            policy,
+            audit_log,
+            checked_chats: Mutex::new(HashSet::new()),
+            pollers: Mutex::new(HashMap::new()),
+            pending: Mutex::new(VecDeque::new()),
+            chat_locks: std::sync::Mutex::new(HashMap::new()),
+            hook_cursors: Mutex::new(cache.read_daemon_state(HOOKS_STATE).unwrap_or_else(|e| {
+                tracing::error!(error = %e, "Failed to read hook cursors");
+                DaemonState::default()
+            })),
+            cache,
+        }
+    }
+
+    /// Returns the events of a session that hooks have not run for, and moves
+    /// the hooks' cursor past them.
+    ///
+    /// Hooks have their own cursors, so each event runs its hook once however
+    /// many chats follow the session, and resetting a chat's cursors does not
+    /// run old hooks again. The activities must be sorted.
+    async fn hook_events(
+        &self,
+        session: &Session,
+        activities: &mut [Activity],
+        last_checked: Option<DateTime<Utc>>,
+        checked_at: DateTime<Utc>,
+    ) -> Vec<Event> {
+        let mut state = self.hook_cursors.lock().await;
+        let changes = detect(session, activities, state.cursors.get(&session.id), last_checked, checked_at);
+        if changes.cursor.is_some() && state.cursors.get(&session.id) != changes.cursor.as_ref() {
+            state.cursors.extend(changes.cursor.map(|cursor| (session.id.clone(), cursor)));
+            if let Err(e) = self.cache.write_daemon_state(HOOKS_STATE, &state) {
+                tracing::error!(error = %e, "Failed to save hook cursors");
+            }
+        }
+        changes.events
+    }
+
+    /// Keeps the remaining chunks of a long message until "Show more" is
+    /// pressed on the given message. Only the most recent messages are kept.
+    async fn keep_pending(&self, chat_id: ChatId, message_id: MessageId, message: LongMessage) {
+        let mut pending = self.pending.lock().await;
+        pending.push_back((chat_id, message_id, message));
+        while pending.len() > MAX_PENDING_MESSAGES {
+            pending.pop_front();
+        }
+    }
+
+    /// Takes the remaining chunks of a long message.
+    async fn take_pending(&self, chat_id: ChatId, message_id: MessageId) -> Option<LongMessage> {
+        let mut pending = self.pending.lock().await;
+        let index = pending
+            .iter()
+            .position(|(chat, message, _)| *chat == chat_id && *message == message_id)?;
+        pending.remove(index).map(|(_, _, message)| message)
+    }
+
+    /// Checks that a user has the required role in a chat.
+    ///
+    /// Rejected attempts are logged and recorded in the audit log.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` that is an error with the reason if the attempt is rejected.
+    fn permit(&self, user: Option<&User>, chat_id: ChatId, action: &str, required: Role) -> Result<(), String> {
+        let user_id = user.map(|u| u.id.0);
+        if let Err(reason) = self.policy.check(user_id, chat_id.0, required) {
+            tracing::warn!(action, user_id, chat_id = chat_id.0, %reason, "Rejected an action");
+            if let Err(e) = self.audit_log.record(
+                user_id,
+                user.and_then(|u| u.username.clone()),
+                chat_id.0,
+                action,
+                &reason,
+            ) {
+                tracing::error!(error = %e, "Failed to write audit log");
+            }
+            return Err(reason);
+        }
+        Ok(())
+    }
+
+    /// Checks that the sender of a message has the required role.
+    ///
+    /// Rejected attempts are answered and recorded in the audit log.
+    ///
+    /// # Returns
+    ///
+    /// Whether the message may be handled.
+    async fn authorize(&self, bot: &Bot, msg: &Message, required: Role) -> ResponseResult<bool> {
+        // Only the command name is recorded, as arguments may hold secrets
+        // such as API keys.
+        let action = match msg.text() {
+            Some(text) if text.starts_with('/') => text.split_whitespace().next().unwrap_or_default().to_string(),
+            Some(_) => "message".to_string(),
+            None => "update".to_string(),
+        };
+        if self.permit(msg.from(), msg.chat.id, &action, required).is_ok() {
+            return Ok(true);
+        }
+        let response = match msg.from() {
+            Some(user) => format!("You are not allowed to do this. Your Telegram user ID is {}.", user.id.0),
+            None => "You are not allowed to do this.".to_string(),
+        };
+        bot.send_message(msg.chat.id, response).await?;
+        Ok(false)
+    }
+
+    /// Reads the state of a chat, or the default state if it has none.
+    fn chat(&self, chat_id: ChatId) -> Result<ChatState, String> {
+        Ok(self.cache.read_chat_state(chat_id.0)?.unwrap_or_default())
+    }
+
+    /// Reads, updates and saves the state of a chat.
+    ///
+    /// Handlers and the poll loop update chats concurrently, so the updates of
+    /// a chat run one at a time, each on the state the previous one saved.
+    ///
+    /// # Returns
+    ///
+    /// The result of `update`.
+    fn update_chat<T>(&self, chat_id: ChatId, update: impl FnOnce(&mut ChatState) -> T) -> Result<T, String> {
+        let lock = self
+            .chat_locks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(chat_id)
+            .or_default()
+            .clone();
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut chat = self.chat(chat_id)?;
+        let result = update(&mut chat);
+        self.cache.write_chat_state(chat_id.0, &chat)?;
+        Ok(result)
+    }
+
+    /// Returns the API client of a chat, or `None` if it is not authenticated.
+    async fn client(&self, chat_id: ChatId) -> Option<Arc<JulesClient>> {
+        if let Some(client) = self.clients.lock().await.get(&chat_id) {
+            return Some(client.clone());
+        }
+        let api_key = match self.chat(chat_id) {
+            Ok(chat) => chat.api_key?,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to read chat state");
+                return None;
+            }
+        };
+        let client = match create_client(api_key) {
+            Ok(client) => Arc::new(client),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to create JulesClient");
+                return None;
+            }
+        };
+        self.clients.lock().await.insert(chat_id, client.clone());
+        Some(client)
+    }
+
+    /// Forgets the API client of a chat, e.g. after its key has changed.
+    async fn forget_client(&self, chat_id: ChatId) {
+        self.clients.lock().await.remove(&chat_id);
+    }
+
+    /// Remembers the session a message sent to a chat belongs to, so that
+    /// replies to it are routed to that session.
+    fn remember_message(&self, chat_id: ChatId, message: &Message, session_id: &str) {
+        let saved = self.update_chat(chat_id, |chat| chat.remember_notification(message.id.0, session_id));
+        if let Err(e) = saved {
+            tracing::error!(error = %e, "Failed to remember notification message");
+        }
+    }
+
+    /// Moves a deleted session and its aliases from a chat to the chat's trash.
+    fn trash_session(&self, chat_id: ChatId, session_id: &str) -> Result<(), String> {
+        let trash = Trash::for_chat(chat_id.0)?;
+        self.update_chat(chat_id, |chat| {
+            trash.trash(&mut chat.sessions, &mut chat.aliases, session_id)?;
+            chat.preferences.sessions.remove(session_id);
+            if chat.current_session.as_deref() == Some(session_id) {
+                chat.current_session = None;
+            }
+            Ok(())
+        })?
+    }
+
+    /// Records a merged pull request for the chat's next digest, if it
+    /// receives digests.
+    fn record_merge(&self, chat_id: ChatId, session_id: &str, url: &str) {
+        let recorded = self.update_chat(chat_id, |chat| {
+            if let Some(digest) = &mut chat.digest {
+                digest.record_pull_request(session_id, url, true, Utc::now());
+            }
+        });
+        if let Err(e) = recorded {
+            tracing::error!(error = %e, "Failed to record merged pull request");
+        }
+    }
+
+    /// Resolves a session identifier using the chat's aliases.
+    fn resolve(&self, chat_id: ChatId, identifier: &str, sessions: &[Session]) -> Result<String, String> {
+        let chat = self.chat(chat_id)?;
+        resolve_session_identifier_with_aliases(identifier, sessions, &chat.aliases).map(|(id, _)| id)
+    }
+
+    /// Resolves a session identifier, or falls back to the chat's current
+    /// session when it is empty.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the session ID, or `None` if no identifier was
+    /// given and no current session is set.
+    async fn session_or_current(
+        &self,
+        chat_id: ChatId,
+        client: &JulesClient,
+        identifier: &str,
+    ) -> Result<Option<String>, String> {
+        if identifier.is_empty() {
+            self.chat(chat_id).map(|chat| chat.current_session)
+        } else {
+            let sessions = client.list_sessions().await.map_err(|e| e.to_string())?;
+            self.resolve(chat_id, identifier, &sessions).map(Some)
+        }
+    }
+}
+
+/// Creates an API client for a chat's API key, honouring the base URL of the
+/// current profile.
+fn create_client(api_key: String) -> Result<JulesClient, String> {
+    let profile = profile::load_current()?;
+    JulesClient::for_profile(Some(api_key), &profile).map_err(|e| e.to_string())
+}
+
+/// Seeds the state of the chat that owned the bot before chats had their own
+/// state, so that upgrading does not log the owner out.
+///
+/// The owner chat gets the server's API key, the current session, and the
+/// aliases and sessions of the cache.
+fn migrate_owner_chat(cache: &Cache) -> Result<(), String> {
+    let chat_id: i64 = match cache.read_chat_id()? {
+        Some(chat_id) => chat_id
+            .trim()
+            .parse()
+            .map_err(|e| format!("Could not parse chat ID '{}': {}", chat_id, e))?,
+        None => return Ok(()),
+    };
+    if cache.read_chat_state(chat_id)?.is_some() {
+        return Ok(());
+    }
+
+    let api_key = match env::var("JULES_API_KEY") {
+        Ok(api_key) => Some(api_key),
+        Err(_) => credentials::resolve_api_key()?.map(|(api_key, _)| api_key),
+    };
+    let chat = ChatState {
+        api_key,
+        current_session: cache.read_current_session()?,
+        aliases: cache.read_aliases()?,
+        sessions: cache.read_sessions()?,
+        ..ChatState::default()
+    };
+    cache.write_chat_state(chat_id, &chat)?;
+    tracing::info!(chat_id, "Migrated the owner chat to per-chat state");
+    Ok(())
+}
+
+/// Returns the command of a message, e.g. `/list`, without its arguments,
+/// which may hold secrets.
+fn command_word(msg: &Message) -> &str {
+    msg.text().and_then(|text| text.split_whitespace().next()).unwrap_or_default()
+}
+
 #[derive(BotCommands, Clone)]
 #[command(rename_rule = "lowercase", description = "These commands are supported:")]
 enum Command {
@@ -38,36 +544,117 @@ enum Command {
     Send(String),
     #[command(description = "authenticate with your Jules API key.")]
     Auth(String),
+    #[command(description = "forget the API key of this chat.")]
+    Logout,
+    #[command(description = "turn activity notifications on or off. Usage: /notifications [on|off]")]
+    Notifications(String),
+    #[command(description = "watch a session, or list watched sessions. Usage: /watch [session_id_or_alias]")]
+    Watch(String),
+    #[command(description = "stop watching a session. Usage: /unwatch <session_id_or_alias>")]
+    Unwatch(String),
+    #[command(description = "mute notifications. Usage: /mute [session_id_or_alias] [duration, e.g. 2h]")]
+    Mute(String),
+    #[command(description = "unmute notifications. Usage: /unmute [session_id_or_alias]")]
+    Unmute(String),
+    #[command(description = "choose notified events. Usage: /notify [session_id_or_alias] [events|default]")]
+    Notify(String),
+    #[command(description = "set quiet hours. Usage: /quiet [HH:MM-HH:MM|off]")]
+    Quiet(String),
+    #[command(description = "get a daily digest of your sessions. Usage: /digest [HH:MM [stale_hours]|now|off]")]
+    Digest(String),
+    #[command(description = "summarise what happened while the bot was down.")]
+    Catchup,
+    #[command(description = "show activity polling statistics.")]
+    Stats,
     #[command(description = "switch current session.")]
     S(String),
     #[command(description = "approve a plan. Usage: /ok [session_id_or_alias]")]
     Ok(String),
+    #[command(description = "summarise the changes of a session and attach its patch. Usage: /diff [session_id_or_alias]")]
+    Diff(String),
+    #[command(description = "show the state of all active sessions.")]
+    Status,
+    #[command(description = "show the latest plan of a session. Usage: /plan [session_id_or_alias]")]
+    Plan(String),
+    #[command(description = "give feedback on a plan step. Usage: /revise [session_id_or_alias] <step> <feedback>")]
+    Revise(String),
     #[command(description = "create or list aliases. Usage: /alias [@<alias_name> <session_id_or_alias>]")]
     Alias(String),
     #[command(description = "delete an alias. Usage: /unalias @<alias_name>")]
     Unalias(String),
     #[command(description = "delete a session. Usage: /delete <session_id_or_alias>")]
     Delete(String),
+    #[command(description = "restore a deleted session's aliases, or list deleted sessions. Usage: /restore [session_id]")]
+    Restore(String),
     #[command(description = "list activities for a session. Usage: /activities <session_id_or_alias>")]
     Activities(String),
     #[command(description = "list available sources.")]
     Src,
-    #[command(description = "create a new session. Usage: /new --source <source> --branch <branch> <title>")]
+    #[command(description = "create a new session step by step, or directly with /new --source <source> --branch <branch> <title>")]
     New(String),
+    #[command(description = "cancel the /new wizard.")]
+    Cancel,
     #[command(description = "get a session by identifier. Usage: /get <session_id_or_alias>")]
     Get(String),
     #[command(description = "merge the pull request for a session. Usage: /merge <session_id_or_alias>")]
     Merge(String),
 }
 
+/// Returns the role required to run a command.
+fn required_role(cmd: &Command) -> Role {
+    match cmd {
+        Command::Help
+        | Command::Notifications(_)
+        | Command::Watch(_)
+        | Command::Unwatch(_)
+        | Command::Mute(_)
+        | Command::Unmute(_)
+        | Command::Notify(_)
+        | Command::Quiet(_)
+        | Command::Digest(_)
+        | Command::Catchup
+        | Command::Stats
+        | Command::List
+        | Command::S(_)
+        | Command::Activities(_)
+        | Command::Src
+        | Command::Get(_)
+        | Command::Plan(_)
+        | Command::Diff(_)
+        | Command::Status => Role::Viewer,
+        Command::Send(_)
+        | Command::Ok(_)
+        | Command::Revise(_)
+        | Command::New(_)
+        | Command::Cancel
+        | Command::Alias(_)
+        | Command::Unalias(_) => Role::Operator,
+        // The API key is shared by everyone in the chat.
+        Command::Auth(_) | Command::Logout | Command::Delete(_) | Command::Restore(_) | Command::Merge(_) => {
+            Role::Admin
+        }
+    }
+}
+
+#[tracing::instrument(name = "command", skip_all, fields(chat_id = msg.chat.id.0, command = command_word(&msg)))]
 async fn answer(
     bot: Bot,
     msg: Message,
     cmd: Command,
-    client: Arc<Mutex<Option<JulesClient>>>,
-    cache: Arc<Cache>,
-    server_api_key: Arc<String>,
+    state: Arc<BotState>,
+    dialogue: NewSessionDialogue,
 ) -> ResponseResult<()> {
+    // The key should not linger in the chat history, even when the sender is
+    // not allowed to use the command.
+    if matches!(cmd, Command::Auth(_)) {
+        if let Err(e) = bot.delete_message(msg.chat.id, msg.id).await {
+            tracing::warn!(error = %e, "Failed to delete /auth message");
+        }
+    }
+    if !state.authorize(&bot, &msg, required_role(&cmd)).await? {
+        return Ok(());
+    }
+
     match cmd {
         Command::Help => {
             let help_text = format!(
@@ -77,62 +664,168 @@ async fn answer(
             bot.send_message(msg.chat.id, help_text).await?;
         }
         Command::Auth(api_key) => {
-            if api_key == *server_api_key {
-                let jules_client = match JulesClient::new(Some(api_key)) {
-                    Ok(client) => client,
-                    Err(e) => {
-                        log::error!("Failed to create JulesClient: {:?}", e);
-                        bot.send_message(msg.chat.id, "Authentication failed: Could not create API client.").await?;
-                        return Ok(());
-                    }
-                };
+            let api_key = api_key.trim().to_string();
+            if api_key.is_empty() {
+                bot.send_message(msg.chat.id, "Usage: /auth <api_key>").await?;
+                return Ok(());
+            }
 
-                let mut client_guard = client.lock().await;
-                *client_guard = Some(jules_client);
+            let jules_client = match create_client(api_key.clone()) {
+                Ok(client) => client,
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to create JulesClient");
+                    bot.send_message(msg.chat.id, "Authentication failed: Could not create API client.").await?;
+                    return Ok(());
+                }
+            };
+
+            if let Err(e) = jules_client.list_sessions().await {
+                tracing::error!(error = %e, "Failed to validate API key");
+                bot.send_message(msg.chat.id, "Authentication failed: Invalid API key.").await?;
+                return Ok(());
+            }
 
-                match cache.read_chat_id() {
-                    Ok(Some(_)) => {
-                        bot.send_message(msg.chat.id, "Authentication successful! You are already the owner.").await?;
+            if let Err(e) = state.update_chat(msg.chat.id, |chat| chat.api_key = Some(api_key)) {
+                tracing::error!(error = %e, "Failed to save chat state");
+                bot.send_message(msg.chat.id, "Authentication failed: Could not save your API key.").await?;
+                return Ok(());
+            }
+            state.clients.lock().await.insert(msg.chat.id, Arc::new(jules_client));
+            bot.send_message(msg.chat.id, "Authentication successful! This chat will now use your API key and receive notifications for your sessions.").await?;
+        }
+        Command::Logout => {
+            match state.update_chat(msg.chat.id, |chat| chat.api_key.take().is_some()) {
+                Ok(true) => {
+                    state.forget_client(msg.chat.id).await;
+                    bot.send_message(msg.chat.id, "Your API key has been forgotten.").await?;
+                }
+                Ok(false) => {
+                    bot.send_message(msg.chat.id, "You are not authenticated.").await?;
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to save chat state");
+                    bot.send_message(msg.chat.id, "Sorry, something went wrong while forgetting your API key.").await?;
+                }
+            }
+        }
+        Command::Notifications(setting) => {
+            let notifications = match setting.trim() {
+                "" => {
+                    match state.chat(msg.chat.id) {
+                        Ok(chat) => {
+                            let status = if chat.notifications { "on" } else { "off" };
+                            bot.send_message(msg.chat.id, format!("Notifications are {}.", status)).await?;
+                        }
+                        Err(e) => {
+                            tracing::error!(error = %e, "Failed to read chat state");
+                            bot.send_message(msg.chat.id, "Sorry, something went wrong while reading the state of this chat.").await?;
+                        }
                     }
-                    Ok(None) => {
-                        if let Err(e) = cache.write_chat_id(&msg.chat.id.to_string()) {
-                            log::error!("Failed to write chat ID: {:?}", e);
-                            bot.send_message(msg.chat.id, "Authentication successful, but failed to save your chat ID as the owner.").await?;
-                        } else {
-                            bot.send_message(msg.chat.id, "Authentication successful! Your chat ID has been saved as the owner.").await?;
+                    return Ok(());
+                }
+                "on" => true,
+                "off" => false,
+                _ => {
+                    bot.send_message(msg.chat.id, "Invalid format. Use: /notifications [on|off]").await?;
+                    return Ok(());
+                }
+            };
+            if let Err(e) = state.update_chat(msg.chat.id, |chat| chat.notifications = notifications) {
+                tracing::error!(error = %e, "Failed to save chat state");
+                bot.send_message(msg.chat.id, "Sorry, something went wrong while saving your notification setting.").await?;
+            } else {
+                let status = if notifications { "on" } else { "off" };
+                bot.send_message(msg.chat.id, format!("Notifications turned {}.", status)).await?;
+            }
+        }
+        Command::Watch(_)
+        | Command::Unwatch(_)
+        | Command::Mute(_)
+        | Command::Unmute(_)
+        | Command::Notify(_)
+        | Command::Quiet(_) => {
+            if let Some(client) = state.client(msg.chat.id).await {
+                match update_preferences(&state, &client, msg.chat.id, cmd).await {
+                    Ok(response) => {
+                        bot.send_message(msg.chat.id, response).await?;
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, format!("Error: {}", e)).await?;
+                    }
+                }
+            } else {
+                bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
+            }
+        }
+        Command::Digest(value) => {
+            if state.client(msg.chat.id).await.is_none() {
+                bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
+            } else if value.trim() == "now" {
+                match state.chat(msg.chat.id) {
+                    Ok(chat) => send_digest(&bot, &state, msg.chat.id, &chat, false).await?,
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to read chat state");
+                        bot.send_message(msg.chat.id, "Sorry, something went wrong while building the digest.").await?;
+                    }
+                }
+            } else {
+                match update_digest(&state, msg.chat.id, value.trim()) {
+                    Ok(response) => {
+                        bot.send_message(msg.chat.id, response).await?;
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, format!("Error: {}", e)).await?;
+                    }
+                }
+            }
+        }
+        Command::Stats => {
+            let response = match state.pollers.lock().await.get(&msg.chat.id) {
+                Some(poller) => format_stats(poller.stats()),
+                None => "No activity checks have run for this chat yet.".to_string(),
+            };
+            bot.send_message(msg.chat.id, response).await?;
+        }
+        Command::Catchup => {
+            if let Some(client) = state.client(msg.chat.id).await {
+                match state.chat(msg.chat.id) {
+                    Ok(chat) => {
+                        match format_catchup(&client, &chat).await {
+                            Ok(response) => {
+                                send_long_message(&bot, &state, msg.chat.id, LongMessage::new(&response, None, None)).await?;
+                            }
+                            Err(e) => {
+                                tracing::error!(error = %e, "Failed to summarise downtime");
+                                bot.send_message(msg.chat.id, "Sorry, something went wrong while summarising what happened.").await?;
+                            }
                         }
                     }
                     Err(e) => {
-                        log::error!("Failed to read chat ID: {:?}", e);
-                        bot.send_message(msg.chat.id, "Authentication successful, but failed to check your owner status due to a server error.").await?;
+                        tracing::error!(error = %e, "Failed to read chat state");
+                        bot.send_message(msg.chat.id, "Sorry, something went wrong while reading the state of this chat.").await?;
                     }
                 }
             } else {
-                bot.send_message(msg.chat.id, "Authentication failed: Invalid API key.").await?;
+                bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
             }
         }
         Command::List => {
-            if let Some(client) = &*client.lock().await {
+            if let Some(client) = state.client(msg.chat.id).await {
                 match client.list_sessions().await {
                     Ok(sessions_list) => {
                         let cached_sessions: Vec<CachedSession> = sessions_list
                             .iter()
-                            .map(|session| CachedSession {
-                                id: session.id.clone(),
-                                title: session.title.clone(),
-                                source_context: session.source_context.clone(),
-                                pull_request_url: session.pull_request_url.clone(),
-                            })
+                            .map(CachedSession::from)
                             .collect();
 
-                        if let Err(e) = cache.write_sessions(&cached_sessions) {
-                            log::error!("Failed to write sessions to cache: {:?}", e);
-                        }
-
-                        let aliases = match cache.read_aliases() {
+                        let saved = state.update_chat(msg.chat.id, |chat| {
+                            chat.sessions = cached_sessions.clone();
+                            chat.aliases.clone()
+                        });
+                        let aliases = match saved {
                             Ok(aliases) => aliases,
                             Err(e) => {
-                                log::error!("Failed to read aliases: {:?}", e);
+                                tracing::error!(error = %e, "Failed to write sessions to chat state");
                                 std::collections::HashMap::new()
                             }
                         };
@@ -151,10 +844,10 @@ async fn answer(
                             };
                             response.push_str(&format!("{}: {}{}: {}\n", i + 1, session.id, alias_str, session.title));
                         }
-                        bot.send_message(msg.chat.id, response).await?;
+                        send_long_message(&bot, &state, msg.chat.id, LongMessage::new(&response, None, None)).await?;
                     }
                     Err(e) => {
-                        log::error!("Failed to list sessions: {:?}", e);
+                        tracing::error!(error = %e, "Failed to list sessions");
                         bot.send_message(msg.chat.id, "Sorry, something went wrong while listing the sessions.").await?;
                     }
                 }
@@ -163,10 +856,10 @@ async fn answer(
             }
         }
         Command::Get(identifier) => {
-            if let Some(client) = &*client.lock().await {
+            if let Some(client) = state.client(msg.chat.id).await {
                 match client.list_sessions().await {
                     Ok(sessions) => {
-                        match resolve_session_identifier(&identifier, &sessions) {
+                        match state.resolve(msg.chat.id, &identifier, &sessions) {
                             Ok(session_id) => {
                                 match client.get_session(&session_id).await {
                                     Ok(session) => {
@@ -174,7 +867,7 @@ async fn answer(
                                         bot.send_message(msg.chat.id, response).await?;
                                     }
                                     Err(e) => {
-                                        log::error!("Failed to get session: {:?}", e);
+                                        tracing::error!(error = %e, "Failed to get session");
                                         bot.send_message(msg.chat.id, "Sorry, something went wrong while getting the session.").await?;
                                     }
                                 }
@@ -185,7 +878,7 @@ async fn answer(
                         }
                     }
                     Err(e) => {
-                        log::error!("Failed to list sessions: {:?}", e);
+                        tracing::error!(error = %e, "Failed to list sessions");
                         bot.send_message(msg.chat.id, "Sorry, something went wrong while listing the sessions.").await?;
                     }
                 }
@@ -193,10 +886,30 @@ async fn answer(
                 bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
             }
         }
+        Command::New(text) if text.trim().is_empty() => {
+            if let Some(client) = state.client(msg.chat.id).await {
+                start_new_session_wizard(&bot, &msg, &state, &client, &dialogue).await?;
+            } else {
+                bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
+            }
+        }
+        Command::Cancel => {
+            match dialogue.get().await {
+                Ok(Some(wizard)) if wizard.is_run_by(msg.from()) => {
+                    if let Err(e) = dialogue.exit().await {
+                        tracing::error!(error = %e, "Failed to reset the /new wizard");
+                    }
+                    bot.send_message(msg.chat.id, "Cancelled.").await?;
+                }
+                _ => {
+                    bot.send_message(msg.chat.id, "Nothing to cancel.").await?;
+                }
+            }
+        }
         Command::New(text) => {
-            if let Some(client) = &*client.lock().await {
+            if let Some(client) = state.client(msg.chat.id).await {
                 let mut source = None;
-                let mut branch = "main".to_string();
+                let mut branch = state.new_session.branch.clone();
                 let mut title = None;
 
                 let parts: Vec<&str> = text.split_whitespace().collect();
@@ -229,24 +942,24 @@ async fn answer(
                 }
 
                 if let (Some(source), Some(title)) = (source, title) {
-                    match client.create_session(&source, &title, true, &branch).await {
+                    match client.create_session(&source, &title, state.new_session.auto_pr, &branch).await {
                         Ok(session) => {
                             bot.send_message(msg.chat.id, format!("Session created: {} ({})", session.id, session.title)).await?;
                         }
                         Err(e) => {
-                            log::error!("Failed to create session: {:?}", e);
+                            tracing::error!(error = %e, "Failed to create session");
                             bot.send_message(msg.chat.id, "Sorry, something went wrong while creating the session.").await?;
                         }
                     }
                 } else {
-                    bot.send_message(msg.chat.id, "Invalid format. Usage: /create --source <source> --branch <branch> <title>").await?;
+                    bot.send_message(msg.chat.id, "Invalid format. Usage: /new --source <source> --branch <branch> <title>, or /new alone to be guided").await?;
                 }
             } else {
                 bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
             }
         }
         Command::Src => {
-            if let Some(client) = &*client.lock().await {
+            if let Some(client) = state.client(msg.chat.id).await {
                 match client.list_sources().await {
                     Ok(sources) => {
                         let mut response = String::from("Available sources:\n");
@@ -256,7 +969,7 @@ async fn answer(
                         bot.send_message(msg.chat.id, response).await?;
                     }
                     Err(e) => {
-                        log::error!("Failed to list sources: {:?}", e);
+                        tracing::error!(error = %e, "Failed to list sources");
                         bot.send_message(msg.chat.id, "Sorry, something went wrong while listing the sources.").await?;
                     }
                 }
@@ -265,18 +978,20 @@ async fn answer(
             }
         }
         Command::Activities(identifier) => {
-            if let Some(client) = &*client.lock().await {
+            if let Some(client) = state.client(msg.chat.id).await {
                 match client.list_sessions().await {
                     Ok(sessions) => {
-                        match resolve_session_identifier(&identifier, &sessions) {
+                        match state.resolve(msg.chat.id, &identifier, &sessions) {
                             Ok(session_id) => {
                                 match client.fetch_activities(&session_id).await {
                                     Ok(activities) => {
-                                        let response = format_activities(&activities, 5, &session_id);
-                                        bot.send_message(msg.chat.id, response).await?;
+                                        let (response, attachments) = format_activities(&activities, 5, &session_id);
+                                        let message = LongMessage::new(&response, Some(ParseMode::MarkdownV2), None);
+                                        send_long_message(&bot, &state, msg.chat.id, message).await?;
+                                        send_attachments(&bot, msg.chat.id, attachments).await?;
                                     }
                                     Err(e) => {
-                                        log::error!("Failed to fetch activities: {:?}", e);
+                                        tracing::error!(error = %e, "Failed to fetch activities");
                                         bot.send_message(msg.chat.id, "Sorry, something went wrong while fetching activities.").await?;
                                     }
                                 }
@@ -287,7 +1002,7 @@ async fn answer(
                         }
                     }
                     Err(e) => {
-                        log::error!("Failed to list sessions: {:?}", e);
+                        tracing::error!(error = %e, "Failed to list sessions");
                         bot.send_message(msg.chat.id, "Sorry, something went wrong while listing the sessions.").await?;
                     }
                 }
@@ -296,26 +1011,26 @@ async fn answer(
             }
         }
         Command::Delete(identifier) => {
-            if let Some(client) = &*client.lock().await {
+            if let Some(client) = state.client(msg.chat.id).await {
                 match client.list_sessions().await {
                     Ok(sessions) => {
-                        match resolve_session_identifier(&identifier, &sessions) {
+                        match state.resolve(msg.chat.id, &identifier, &sessions) {
                             Ok(session_id) => {
                                 match client.delete_session(&session_id).await {
                                     Ok(_) => {
-                                        // Update cache and aliases
-                                        let mut cached_sessions = cache.read_sessions().unwrap_or_default();
-                                        cached_sessions.retain(|s| s.id != session_id);
-                                        cache.write_sessions(&cached_sessions).unwrap_or_default();
-
-                                        let mut aliases = cache.read_aliases().unwrap_or_default();
-                                        aliases.retain(|_, s_id| *s_id != session_id);
-                                        cache.write_aliases(&aliases).unwrap_or_default();
-
-                                        bot.send_message(msg.chat.id, format!("Session {} deleted.", session_id)).await?;
+                                        // Move the local metadata and activities to the chat's trash
+                                        match state.trash_session(msg.chat.id, &session_id) {
+                                            Ok(_) => {
+                                                bot.send_message(msg.chat.id, format!("Session {} deleted. Its aliases were moved to the trash; use /restore {} to get them back.", session_id, session_id)).await?;
+                                            }
+                                            Err(e) => {
+                                                tracing::error!(error = %e, "Failed to move session to trash");
+                                                bot.send_message(msg.chat.id, format!("Session {} deleted, but its local metadata could not be moved to the trash.", session_id)).await?;
+                                            }
+                                        }
                                     }
                                     Err(e) => {
-                                        log::error!("Failed to delete session: {:?}", e);
+                                        tracing::error!(error = %e, "Failed to delete session");
                                         bot.send_message(msg.chat.id, "Sorry, something went wrong while deleting the session.").await?;
                                     }
                                 }
@@ -326,7 +1041,7 @@ async fn answer(
                         }
                     }
                     Err(e) => {
-                        log::error!("Failed to list sessions: {:?}", e);
+                        tracing::error!(error = %e, "Failed to list sessions");
                         bot.send_message(msg.chat.id, "Sorry, something went wrong while listing the sessions.").await?;
                     }
                 }
@@ -334,12 +1049,56 @@ async fn answer(
                 bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
             }
         }
+        Command::Restore(session_id) => {
+            let session_id = session_id.trim();
+            let trash = match Trash::for_chat(msg.chat.id.0) {
+                Ok(trash) => trash,
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to open trash");
+                    bot.send_message(msg.chat.id, "Sorry, something went wrong while opening the trash.").await?;
+                    return Ok(());
+                }
+            };
+            if session_id.is_empty() {
+                let reply = match trash.list() {
+                    Ok(entries) if entries.is_empty() => "No deleted sessions.".to_string(),
+                    Ok(entries) => {
+                        let mut reply = "Deleted sessions:\n".to_string();
+                        for entry in entries {
+                            reply.push_str(&format!("- {}: {} ({} days ago)\n", entry.session.id, entry.session.title, entry.age_days()));
+                        }
+                        reply
+                    }
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to list trash");
+                        "Sorry, something went wrong while listing the trash.".to_string()
+                    }
+                };
+                bot.send_message(msg.chat.id, reply).await?;
+                return Ok(());
+            }
+            let restored = state
+                .update_chat(msg.chat.id, |chat| trash.restore_into(&mut chat.sessions, &mut chat.aliases, session_id))
+                .and_then(|restored| restored);
+            match restored {
+                Ok((entry, conflicts)) => {
+                    let mut reply = format!("Restored the aliases of session {}.", entry.session.id);
+                    if !conflicts.is_empty() {
+                        reply.push_str(&format!(" These aliases are now used by other sessions: {}", conflicts.join(", ")));
+                    }
+                    bot.send_message(msg.chat.id, reply).await?;
+                }
+                Err(e) => {
+                    bot.send_message(msg.chat.id, format!("Error: {}", e)).await?;
+                }
+            }
+        }
         Command::Alias(text) => {
-            if client.lock().await.is_some() {
+            if let Some(client) = state.client(msg.chat.id).await {
                 if text.is_empty() {
                     // List aliases
-                    match cache.read_aliases() {
-                        Ok(aliases) => {
+                    match state.chat(msg.chat.id) {
+                        Ok(ChatState { aliases, .. }) => {
                             if aliases.is_empty() {
                                 bot.send_message(msg.chat.id, "No aliases found.").await?;
                             } else {
@@ -351,7 +1110,7 @@ async fn answer(
                             }
                         }
                         Err(e) => {
-                            log::error!("Failed to read aliases: {:?}", e);
+                            tracing::error!(error = %e, "Failed to read aliases");
                             bot.send_message(msg.chat.id, "Sorry, something went wrong while reading your aliases.").await?;
                         }
                     }
@@ -366,24 +1125,15 @@ async fn answer(
                             return Ok(());
                         }
 
-                        let client_guard = client.lock().await;
-                        let client = client_guard.as_ref().unwrap();
-
                         match client.list_sessions().await {
                             Ok(sessions) => {
-                                match resolve_session_identifier(identifier, &sessions) {
+                                match state.resolve(msg.chat.id, identifier, &sessions) {
                                     Ok(session_id) => {
-                                        let mut aliases = match cache.read_aliases() {
-                                            Ok(aliases) => aliases,
-                                            Err(e) => {
-                                                log::error!("Failed to read aliases: {:?}", e);
-                                                bot.send_message(msg.chat.id, "Sorry, something went wrong while reading your aliases.").await?;
-                                                return Ok(());
-                                            }
-                                        };
-                                        aliases.insert(alias_name.to_string(), session_id.clone());
-                                        if let Err(e) = cache.write_aliases(&aliases) {
-                                            log::error!("Failed to write aliases: {:?}", e);
+                                        let saved = state.update_chat(msg.chat.id, |chat| {
+                                            chat.aliases.insert(alias_name.to_string(), session_id.clone());
+                                        });
+                                        if let Err(e) = saved {
+                                            tracing::error!(error = %e, "Failed to write aliases");
                                             bot.send_message(msg.chat.id, "Sorry, something went wrong while saving your alias.").await?;
                                         } else {
                                             bot.send_message(msg.chat.id, format!("Alias '{}' created for session {}", alias_name, session_id)).await?;
@@ -395,7 +1145,7 @@ async fn answer(
                                 }
                             }
                             Err(e) => {
-                                log::error!("Failed to list sessions: {:?}", e);
+                                tracing::error!(error = %e, "Failed to list sessions");
                                 bot.send_message(msg.chat.id, "Sorry, something went wrong while listing the sessions.").await?;
                             }
                         }
@@ -408,51 +1158,44 @@ async fn answer(
             }
         }
         Command::Unalias(alias_name) => {
-            if client.lock().await.is_some() {
+            if state.client(msg.chat.id).await.is_some() {
                 if !alias_name.starts_with('@') {
                     bot.send_message(msg.chat.id, "Alias must start with '@'").await?;
                     return Ok(());
                 }
 
-                let mut aliases = match cache.read_aliases() {
-                    Ok(aliases) => aliases,
-                    Err(e) => {
-                        log::error!("Failed to read aliases: {:?}", e);
-                        bot.send_message(msg.chat.id, "Sorry, something went wrong while reading your aliases.").await?;
-                        return Ok(());
+                match state.update_chat(msg.chat.id, |chat| chat.aliases.remove(&alias_name).is_some()) {
+                    Ok(true) => {
+                        bot.send_message(msg.chat.id, format!("Alias '{}' deleted.", alias_name)).await?;
                     }
-                };
-
-                if aliases.remove(&alias_name).is_some() {
-                    if let Err(e) = cache.write_aliases(&aliases) {
-                        log::error!("Failed to write aliases: {:?}", e);
+                    Ok(false) => {
+                        bot.send_message(msg.chat.id, format!("Alias '{}' not found.", alias_name)).await?;
+                    }
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to write aliases");
                         bot.send_message(msg.chat.id, "Sorry, something went wrong while deleting your alias.").await?;
-                    } else {
-                        bot.send_message(msg.chat.id, format!("Alias '{}' deleted.", alias_name)).await?;
                     }
-                } else {
-                    bot.send_message(msg.chat.id, format!("Alias '{}' not found.", alias_name)).await?;
                 }
             } else {
                 bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
             }
         }
         Command::Send(text) => {
-            if let Some(client) = &*client.lock().await {
+            if let Some(client) = state.client(msg.chat.id).await {
                 let parts: Vec<&str> = text.splitn(2, ' ').collect();
                 if parts.len() == 2 {
                     let identifier = parts[0];
                     let prompt = parts[1];
                     match client.list_sessions().await {
                         Ok(sessions) => {
-                            match resolve_session_identifier(identifier, &sessions) {
+                            match state.resolve(msg.chat.id, identifier, &sessions) {
                                 Ok(session_id) => {
                                     match client.send_message(&session_id, prompt).await {
                                         Ok(_) => {
                                             bot.send_message(msg.chat.id, "Message sent successfully!").await?;
                                         }
                                         Err(e) => {
-                                            log::error!("Failed to send message: {:?}", e);
+                                            tracing::error!(error = %e, "Failed to send message");
                                             bot.send_message(msg.chat.id, "Sorry, something went wrong while sending your message.").await?;
                                         }
                                     }
@@ -463,7 +1206,7 @@ async fn answer(
                             }
                         }
                         Err(e) => {
-                            log::error!("Failed to list sessions: {:?}", e);
+                            tracing::error!(error = %e, "Failed to list sessions");
                             bot.send_message(msg.chat.id, "Sorry, something went wrong while listing the sessions.").await?;
                         }
                     }
@@ -475,13 +1218,16 @@ async fn answer(
             }
         }
         Command::S(identifier) => {
-            if let Some(client) = &*client.lock().await {
+            if let Some(client) = state.client(msg.chat.id).await {
                 match client.list_sessions().await {
                     Ok(sessions) => {
-                        match resolve_session_identifier(&identifier, &sessions) {
+                        match state.resolve(msg.chat.id, &identifier, &sessions) {
                             Ok(session_id) => {
-                                if let Err(e) = cache.write_current_session(&session_id) {
-                                    log::error!("Failed to write current session: {:?}", e);
+                                let saved = state.update_chat(msg.chat.id, |chat| {
+                                    chat.current_session = Some(session_id.clone());
+                                });
+                                if let Err(e) = saved {
+                                    tracing::error!(error = %e, "Failed to write current session");
                                     bot.send_message(msg.chat.id, "Sorry, something went wrong while setting the current session.").await?;
                                 } else {
                                     bot.send_message(msg.chat.id, format!("Current session set to {}", session_id)).await?;
@@ -493,7 +1239,7 @@ async fn answer(
                         }
                     }
                     Err(e) => {
-                        log::error!("Failed to list sessions: {:?}", e);
+                        tracing::error!(error = %e, "Failed to list sessions");
                         bot.send_message(msg.chat.id, "Sorry, something went wrong while listing the sessions.").await?;
                     }
                 }
@@ -503,12 +1249,12 @@ async fn answer(
         }
 
         Command::Ok(identifier) => {
-            if let Some(client) = &*client.lock().await {
+            if let Some(client) = state.client(msg.chat.id).await {
                 let session_id_result = if identifier.is_empty() {
-                    cache.read_current_session()
+                    state.chat(msg.chat.id).map(|chat| chat.current_session)
                 } else {
                     match client.list_sessions().await {
-                        Ok(sessions) => resolve_session_identifier(&identifier, &sessions).map(Some),
+                        Ok(sessions) => state.resolve(msg.chat.id, &identifier, &sessions).map(Some),
                         Err(e) => Err(e.to_string()),
                     }
                 };
@@ -520,7 +1266,7 @@ async fn answer(
                                 bot.send_message(msg.chat.id, "Plan approved successfully!").await?;
                             }
                             Err(e) => {
-                                log::error!("Failed to approve plan: {:?}", e);
+                                tracing::error!(error = %e, "Failed to approve plan");
                                 bot.send_message(msg.chat.id, "Sorry, something went wrong while approving the plan.").await?;
                             }
                         }
@@ -537,35 +1283,42 @@ async fn answer(
                 bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
             }
         }
-        Command::Merge(identifier) => {
-            if let Some(client) = &*client.lock().await {
-                match client.list_sessions().await {
-                    Ok(sessions) => {
-                        match resolve_session_identifier(&identifier, &sessions) {
-                            Ok(session_id) => {
-                                let session = sessions.iter().find(|s| s.id == session_id);
-                                if let Some(session) = session {
-                                    if let Some(pull_request_url) = &session.pull_request_url {
-                                        if let Err(e) = client.merge_pull_request(pull_request_url).await {
-                                            log::error!("Failed to merge pull request: {:?}", e);
-                                            bot.send_message(msg.chat.id, "Sorry, something went wrong while merging the pull request.").await?;
-                                        } else {
-                                            bot.send_message(msg.chat.id, "Pull request merged successfully!").await?;
-                                        }
-                                    } else {
-                                        bot.send_message(msg.chat.id, "No pull request URL found for this session.").await?;
-                                    }
-                                } else {
-                                    bot.send_message(msg.chat.id, "Session not found.").await?;
-                                }
+        Command::Diff(identifier) => {
+            if let Some(client) = state.client(msg.chat.id).await {
+                match state.session_or_current(msg.chat.id, &client, identifier.trim()).await {
+                    Ok(Some(session_id)) => {
+                        // Only the pages after the cached ones are fetched.
+                        match client.fetch_activities(&session_id).await {
+                            Ok(activities) => {
+                                send_diff(&bot, &state, msg.chat.id, &session_id, &activities).await?;
                             }
                             Err(e) => {
-                                bot.send_message(msg.chat.id, format!("Error: {}", e)).await?;
+                                tracing::error!(error = %e, "Failed to fetch activities");
+                                bot.send_message(msg.chat.id, "Sorry, something went wrong while fetching the diff.").await?;
                             }
                         }
                     }
+                    Ok(None) => {
+                        bot.send_message(msg.chat.id, "No current session is set. Use /s <session_id_or_alias> to set one, or provide an identifier.").await?;
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, format!("Error: {}", e)).await?;
+                    }
+                }
+            } else {
+                bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
+            }
+        }
+        Command::Status => {
+            if let Some(client) = state.client(msg.chat.id).await {
+                match client.list_sessions().await {
+                    Ok(sessions) => {
+                        let aliases = state.chat(msg.chat.id).map(|chat| chat.aliases).unwrap_or_default();
+                        let response = format_status(&client, &sessions, &aliases, Utc::now());
+                        send_long_message(&bot, &state, msg.chat.id, LongMessage::new(&response, None, None)).await?;
+                    }
                     Err(e) => {
-                        log::error!("Failed to list sessions: {:?}", e);
+                        tracing::error!(error = %e, "Failed to list sessions");
                         bot.send_message(msg.chat.id, "Sorry, something went wrong while listing the sessions.").await?;
                     }
                 }
@@ -573,31 +1326,429 @@ async fn answer(
                 bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
             }
         }
-    };
+        Command::Plan(identifier) => {
+            if let Some(client) = state.client(msg.chat.id).await {
+                match state.session_or_current(msg.chat.id, &client, identifier.trim()).await {
+                    Ok(Some(session_id)) => {
+                        match client.fetch_activities(&session_id).await {
+                            Ok(activities) => {
+                                let keyboard = latest_plan(&activities)
+                                    .filter(|(_, approved)| !approved)
+                                    .map(|_| plan_keyboard(&session_id));
+                                let response = format_plan(&activities, &session_id)
+                                    .unwrap_or_else(|| format!("No plan found for session {}.", session_id));
+                                send_long_message(&bot, &state, msg.chat.id, LongMessage::new(&response, None, keyboard)).await?;
+                            }
+                            Err(e) => {
+                                tracing::error!(error = %e, "Failed to fetch activities");
+                                bot.send_message(msg.chat.id, "Sorry, something went wrong while fetching the plan.").await?;
+                            }
+                        }
+                    }
+                    Ok(None) => {
+                        bot.send_message(msg.chat.id, "No current session is set. Use /s <session_id_or_alias> to set one, or provide an identifier.").await?;
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, format!("Error: {}", e)).await?;
+                    }
+                }
+            } else {
+                bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
+            }
+        }
+        Command::Revise(text) => {
+            if let Some(client) = state.client(msg.chat.id).await {
+                let (identifier, step, feedback) = match parse_revise(&text) {
+                    Some(args) => args,
+                    None => {
+                        bot.send_message(msg.chat.id, "Invalid format. Use: /revise [session_id_or_alias] <step> <feedback>").await?;
+                        return Ok(());
+                    }
+                };
+
+                match state.session_or_current(msg.chat.id, &client, identifier).await {
+                    Ok(Some(session_id)) => {
+                        match client.fetch_activities(&session_id).await {
+                            Ok(activities) => {
+                                match latest_plan(&activities).ok_or_else(|| format!("No plan found for session {}.", session_id))
+                                    .and_then(|(plan, _)| format_plan_feedback(plan, step, &feedback))
+                                {
+                                    Ok(message) => {
+                                        match client.send_message(&session_id, &message).await {
+                                            Ok(_) => {
+                                                bot.send_message(msg.chat.id, format!("Feedback on step {} sent to session {}.", step, session_id)).await?;
+                                            }
+                                            Err(e) => {
+                                                tracing::error!(error = %e, "Failed to send plan feedback");
+                                                bot.send_message(msg.chat.id, "Sorry, something went wrong while sending your feedback.").await?;
+                                            }
+                                        }
+                                    }
+                                    Err(e) => {
+                                        bot.send_message(msg.chat.id, format!("Error: {}", e)).await?;
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                tracing::error!(error = %e, "Failed to fetch activities");
+                                bot.send_message(msg.chat.id, "Sorry, something went wrong while fetching the plan.").await?;
+                            }
+                        }
+                    }
+                    Ok(None) => {
+                        bot.send_message(msg.chat.id, "No current session is set. Use /s <session_id_or_alias> to set one, or provide an identifier.").await?;
+                    }
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, format!("Error: {}", e)).await?;
+                    }
+                }
+            } else {
+                bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
+            }
+        }
+        Command::Merge(identifier) => {
+            if let Some(client) = state.client(msg.chat.id).await {
+                match client.list_sessions().await {
+                    Ok(sessions) => {
+                        match state.resolve(msg.chat.id, &identifier, &sessions) {
+                            Ok(session_id) => {
+                                let session = sessions.iter().find(|s| s.id == session_id);
+                                if let Some(session) = session {
+                                    if let Some(pull_request_url) = &session.pull_request_url {
+                                        if let Err(e) = client.merge_pull_request(pull_request_url).await {
+                                            tracing::error!(error = %e, "Failed to merge pull request");
+                                            bot.send_message(msg.chat.id, "Sorry, something went wrong while merging the pull request.").await?;
+                                        } else {
+                                            state.record_merge(msg.chat.id, &session.id, pull_request_url);
+                                            bot.send_message(msg.chat.id, "Pull request merged successfully!").await?;
+                                        }
+                                    } else {
+                                        bot.send_message(msg.chat.id, "No pull request URL found for this session.").await?;
+                                    }
+                                } else {
+                                    bot.send_message(msg.chat.id, "Session not found.").await?;
+                                }
+                            }
+                            Err(e) => {
+                                bot.send_message(msg.chat.id, format!("Error: {}", e)).await?;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to list sessions");
+                        bot.send_message(msg.chat.id, "Sorry, something went wrong while listing the sessions.").await?;
+                    }
+                }
+            } else {
+                bot.send_message(msg.chat.id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
+            }
+        }
+    };
 
     Ok(())
 }
 
 
-fn format_activities(activities: &[julezz::api::Activity], n: usize, session_id: &str) -> String {
-    let mut response = format!("Activities for session {}:\n\n", session_id);
+/// Handles the commands managing notification preferences.
+///
+/// # Returns
+///
+/// A `Result` containing the response to send, or an error string.
+async fn update_preferences(
+    state: &BotState,
+    client: &JulesClient,
+    chat_id: ChatId,
+    cmd: Command,
+) -> Result<String, String> {
+    let resolve = |identifier: String| async move {
+        let sessions = client.list_sessions().await.map_err(|e| e.to_string())?;
+        state.resolve(chat_id, &identifier, &sessions)
+    };
+    let mut chat = state.chat(chat_id)?;
+    let prefs = &mut chat.preferences;
+
+    let response = match cmd {
+        Command::Watch(identifier) if identifier.trim().is_empty() => {
+            let watched = prefs.watched();
+            if watched.is_empty() {
+                return Ok("No sessions are watched; you are notified about all sessions.".to_string());
+            }
+            return Ok(format!("Watched sessions:\n{}", watched.iter().map(|id| format!("- {}\n", id)).collect::<String>()));
+        }
+        Command::Watch(identifier) => {
+            let session_id = resolve(identifier.trim().to_string()).await?;
+            prefs.session_mut(&session_id).watched = true;
+            format!("Watching session {}. Only watched sessions are notified.", session_id)
+        }
+        Command::Unwatch(identifier) => {
+            let session_id = resolve(identifier.trim().to_string()).await?;
+            prefs.session_mut(&session_id).watched = false;
+            format!("Stopped watching session {}.", session_id)
+        }
+        Command::Mute(args) => {
+            let mut identifier = None;
+            let mut duration = None;
+            for arg in args.split_whitespace() {
+                match parse_duration(arg) {
+                    Ok(d) => duration = Some(d),
+                    Err(_) if identifier.is_none() => identifier = Some(arg.to_string()),
+                    Err(e) => return Err(e),
+                }
+            }
+            let until = duration.map(|d| mute_until(Utc::now(), d)).transpose()?;
+            let mute = Some(Mute { until: until.map(|u| u.to_rfc3339()) });
+            let target = match identifier {
+                Some(identifier) => {
+                    let session_id = resolve(identifier).await?;
+                    prefs.session_mut(&session_id).mute = mute;
+                    format!("Session {}", session_id)
+                }
+                None => {
+                    prefs.mute = mute;
+                    "Notifications".to_string()
+                }
+            };
+            match until {
+                Some(until) => format!("{} muted until {}.", target, until.with_timezone(&Local).format("%Y-%m-%d %H:%M")),
+                None => format!("{} muted until you /unmute.", target),
+            }
+        }
+        Command::Unmute(identifier) if identifier.trim().is_empty() => {
+            prefs.mute = None;
+            "Notifications unmuted.".to_string()
+        }
+        Command::Unmute(identifier) => {
+            let session_id = resolve(identifier.trim().to_string()).await?;
+            prefs.session_mut(&session_id).mute = None;
+            format!("Session {} unmuted.", session_id)
+        }
+        Command::Notify(args) => {
+            let args: Vec<&str> = args.split_whitespace().collect();
+            let (identifier, events) = match args.as_slice() {
+                [] => {
+                    let events = prefs
+                        .events
+                        .clone()
+                        .map(|events| events.join(", "))
+                        .unwrap_or_else(|| "default".to_string());
+                    return Ok(format!("Notified events: {}", events));
+                }
+                [events] => (None, *events),
+                [identifier, events] => (Some(identifier.to_string()), *events),
+                _ => return Err("Invalid format. Use: /notify [session_id_or_alias] [events|default]".to_string()),
+            };
+            let events = match events {
+                "default" => None,
+                events => Some(parse_events(events)?),
+            };
+            let description = events
+                .as_ref()
+                .map(|events| events.join(", "))
+                .unwrap_or_else(|| "default".to_string());
+            match identifier {
+                Some(identifier) => {
+                    let session_id = resolve(identifier).await?;
+                    prefs.session_mut(&session_id).events = events;
+                    format!("Notified events for session {}: {}", session_id, description)
+                }
+                None => {
+                    prefs.events = events;
+                    format!("Notified events: {}", description)
+                }
+            }
+        }
+        Command::Quiet(value) => match value.trim() {
+            "" => {
+                return Ok(match &prefs.quiet_hours {
+                    Some(quiet) => format!("Quiet hours: {}-{}", quiet.start, quiet.end),
+                    None => "No quiet hours are set.".to_string(),
+                });
+            }
+            "off" => {
+                prefs.quiet_hours = None;
+                "Quiet hours turned off.".to_string()
+            }
+            value => {
+                let quiet = QuietHours::parse(value)?;
+                let response = format!(
+                    "Quiet hours set to {}-{}. Only questions and plans are notified during them.",
+                    quiet.start, quiet.end
+                );
+                prefs.quiet_hours = Some(quiet);
+                response
+            }
+        },
+        _ => return Err("Not a preferences command".to_string()),
+    };
+
+    state.update_chat(chat_id, |fresh| fresh.preferences = chat.preferences)?;
+    Ok(response)
+}
+
+/// Handles the `/digest` settings of a chat.
+///
+/// # Returns
+///
+/// A `Result` containing the response to send, or an error string.
+fn update_digest(state: &BotState, chat_id: ChatId, value: &str) -> Result<String, String> {
+    let chat = state.chat(chat_id)?;
+    let mut args = value.split_whitespace();
+    let response = match args.next() {
+        None => {
+            return Ok(match &chat.digest {
+                Some(digest) => format!(
+                    "A digest is sent every day at {}. Sessions without activity for {} hours are reported as stale.",
+                    digest.time, digest.stale_hours
+                ),
+                None => "Digests are off. Use /digest HH:MM [stale_hours] to get one every day.".to_string(),
+            });
+        }
+        Some("off") => {
+            state.update_chat(chat_id, |chat| chat.digest = None)?;
+            return Ok("Digests turned off.".to_string());
+        }
+        Some(time) => {
+            let stale_hours = match args.next() {
+                Some(hours) => hours
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid number of hours '{}'.", hours))?,
+                None => DEFAULT_STALE_HOURS,
+            };
+            let mut digest = DigestSchedule::new(time, stale_hours)?;
+            // A new digest starts now; an updated one keeps its period.
+            match &chat.digest {
+                Some(current) => {
+                    digest.last_sent = current.last_sent.clone();
+                    digest.pull_requests = current.pull_requests.clone();
+                }
+                None => digest.last_sent = Some(Utc::now().to_rfc3339()),
+            }
+            let response = format!(
+                "A digest will be sent every day at {}. Sessions without activity for {} hours are reported as stale.",
+                digest.time, digest.stale_hours
+            );
+            state.update_chat(chat_id, |chat| chat.digest = Some(digest))?;
+            response
+        }
+    };
+    Ok(response)
+}
+
+/// Sends a chat the digest of the period since its last digest, or of the
+/// last day.
+///
+/// The activity poller skips chats with notifications off, so the chat's
+/// sessions and their activities are refreshed first, falling back to the
+/// cached ones when the API cannot be reached. Scheduled digests record when
+/// they were sent and forget the pull request events they covered; digests
+/// requested with `/digest now` leave the schedule as is.
+async fn send_digest(
+    bot: &Bot,
+    state: &BotState,
+    chat_id: ChatId,
+    chat: &ChatState,
+    scheduled: bool,
+) -> ResponseResult<()> {
+    let client = match state.client(chat_id).await {
+        Some(client) => client,
+        None => return Ok(()),
+    };
+    let to = Utc::now();
+    let from = chat
+        .digest
+        .as_ref()
+        .and_then(|digest| digest.last_sent.as_deref())
+        .and_then(parse_time)
+        .filter(|from| *from < to)
+        .unwrap_or_else(|| to - chrono::Duration::days(1));
+    let stale_hours = chat.digest.as_ref().map_or(DEFAULT_STALE_HOURS, |digest| digest.stale_hours);
+    let pull_requests = chat.digest.as_ref().map_or(&[][..], |digest| &digest.pull_requests[..]);
+
+    let sessions = match client.list_sessions().await {
+        Ok(sessions) => {
+            let sessions: Vec<CachedSession> = sessions.iter().map(CachedSession::from).collect();
+            if let Err(e) = state.update_chat(chat_id, |chat| chat.sessions = sessions.clone()) {
+                tracing::error!(chat_id = chat_id.0, error = %e, "Failed to write sessions to chat state");
+            }
+            sessions
+        }
+        Err(e) => {
+            tracing::error!(chat_id = chat_id.0, error = %e, "Failed to list sessions for digest");
+            chat.sessions.clone()
+        }
+    };
+    let mut activities = HashMap::new();
+    for session in &sessions {
+        // Only the pages after the cached ones are fetched.
+        let fetched = match client.fetch_activities(&session.id).await {
+            Ok(session_activities) => Ok(session_activities),
+            Err(e) => {
+                tracing::warn!(session_id = %session.id, error = %e, "Failed to fetch activities for digest");
+                client.list_cached_activities(&session.id)
+            }
+        };
+        match fetched {
+            Ok(session_activities) => {
+                activities.insert(session.id.clone(), session_activities);
+            }
+            Err(e) => tracing::error!(session_id = %session.id, error = %e, "Failed to read cached activities"),
+        }
+    }
+    let stale_after = match stale_after(stale_hours) {
+        Ok(stale_after) => stale_after,
+        Err(e) => {
+            bot.send_message(chat_id, format!("Error: {} Use /digest HH:MM [stale_hours] to fix the digest.", e)).await?;
+            return Ok(());
+        }
+    };
+    let digest = Digest::build(&sessions, &activities, pull_requests, from, to, stale_after);
+    send_long_message(bot, state, chat_id, LongMessage::new(&digest.format(from, to), None, None)).await?;
+
+    if scheduled {
+        let saved = state.update_chat(chat_id, |chat| {
+            if let Some(digest) = &mut chat.digest {
+                digest.last_sent = Some(to.to_rfc3339());
+                digest.pull_requests.retain(|event| parse_time(&event.time).is_some_and(|time| time > to));
+            }
+        });
+        if let Err(e) = saved {
+            tracing::error!(chat_id = chat_id.0, error = %e, "Failed to save digest schedule");
+        }
+    }
+    Ok(())
+}
+
+/// Formats the most recent activities of a session as MarkdownV2.
+///
+/// Bash outputs and patches are shown in code blocks, or returned as
+/// attachments when they are too long to be shown inline.
+fn format_activities(activities: &[julezz::api::Activity], n: usize, session_id: &str) -> (String, Vec<Attachment>) {
+    let mut response = format!("Activities for session {}:\n\n", escape_markdown_v2(session_id));
+    let mut attachments = Vec::new();
     let mut activities = activities.to_vec();
     activities.sort_by(|a, b| a.create_time.cmp(&b.create_time));
     let activities_to_show = activities.iter().rev().take(n).rev();
 
     for activity in activities_to_show {
-        response.push_str(&format!("[{}] {}\n", activity.create_time, activity.originator));
+        response.push_str(&format!(
+            "\\[{}\\] {}\n",
+            escape_markdown_v2(&activity.create_time),
+            escape_markdown_v2(&activity.originator)
+        ));
 
         if let Some(agent_messaged) = &activity.agent_messaged {
             if !agent_messaged.agent_message.is_empty() {
-                response.push_str(&format!("  {}\n", agent_messaged.agent_message));
+                response.push_str(&format!("  {}\n", escape_markdown_v2(&agent_messaged.agent_message)));
             }
         } else if let Some(user_messaged) = &activity.user_messaged {
-            response.push_str(&format!("  {}\n", user_messaged.user_message));
+            response.push_str(&format!("  {}\n", escape_markdown_v2(&user_messaged.user_message)));
         } else if let Some(plan_generated) = &activity.plan_generated {
             response.push_str("  Plan Generated\n");
             for step in &plan_generated.plan.steps {
-                response.push_str(&format!("    - {}\n", step.title));
+                response.push_str(&format!("    \\- {}\n", escape_markdown_v2(&step.title)));
+                if let Some(description) = &step.description {
+                    response.push_str(&format!("      {}\n", escape_markdown_v2(description)));
+                }
             }
         } else if activity.plan_approved.is_some() {
             response.push_str("  Plan Approved\n");
@@ -605,38 +1756,55 @@ fn format_activities(activities: &[julezz::api::Activity], n: usize, session_id:
             response.push_str("  Session Completed\n");
         } else if let Some(progress) = &activity.progress_updated {
             if let Some(title) = &progress.title {
-                response.push_str(&format!("  {}\n", title));
+                response.push_str(&format!("  {}\n", escape_markdown_v2(title)));
             }
             if let Some(description) = &progress.description {
-                response.push_str(&format!("    {}\n", description));
+                response.push_str(&format!("    {}\n", escape_markdown_v2(description)));
             }
         } else if let Some(artifacts) = &activity.artifacts {
-            for artifact in artifacts {
+            for (i, artifact) in artifacts.iter().enumerate() {
                 if let Some(bash_output) = &artifact.bash_output {
-                    response.push_str(&format!("  $ {}\n", bash_output.command));
-                    response.push_str(&format!("    {}\n", bash_output.output));
+                    response.push_str(&format!("  $ {}\n", escape_markdown_v2(&bash_output.command)));
+                    if bash_output.output.len() > MAX_INLINE_OUTPUT {
+                        let file_name = format!("{}-{}-{}.txt", session_id, activity.id, i);
+                        response.push_str(&format!("  Output attached as {}\n", escape_markdown_v2(&file_name)));
+                        attachments.push(Attachment {
+                            file_name,
+                            contents: format!("$ {}\n{}", bash_output.command, bash_output.output),
+                        });
+                    } else if !bash_output.output.trim().is_empty() {
+                        response.push_str(&format!("{}\n", code_block(&bash_output.output, "")));
+                    }
                 }
                 if let Some(change_set) = &artifact.change_set {
                     response.push_str("  Code Change\n");
                     if let Some(patch) = &change_set.git_patch.unidiff_patch {
-                        response.push_str(&format!("{}\n", patch));
+                        if patch.len() > MAX_INLINE_OUTPUT {
+                            let file_name = format!("{}-{}-{}.diff", session_id, activity.id, i);
+                            response.push_str(&format!("  Patch attached as {}\n", escape_markdown_v2(&file_name)));
+                            attachments.push(Attachment {
+                                file_name,
+                                contents: patch.clone(),
+                            });
+                        } else {
+                            response.push_str(&format!("{}\n", code_block(patch, "diff")));
+                        }
                     }
                 }
             }
         } else if let Some(title) = &activity.title {
-            response.push_str(&format!("  {}\n", title));
+            response.push_str(&format!("  {}\n", escape_markdown_v2(title)));
         }
 
         response.push('\n');
     }
-    response
+    (response, attachments)
 }
 
 async fn default_message_handler(
     bot: Bot,
     msg: Message,
-    client: Arc<Mutex<Option<JulesClient>>>,
-    cache: Arc<Cache>,
+    state: Arc<BotState>,
 ) -> ResponseResult<()> {
     if let Some(text) = msg.text() {
         if text.starts_with('/') {
@@ -648,11 +1816,23 @@ async fn default_message_handler(
             return Ok(());
         }
 
-        if let Some(client) = &*client.lock().await {
-            match cache.read_current_session() {
+        // Free-text messages are sent to the current session.
+        if !state.authorize(&bot, &msg, Role::Operator).await? {
+            return Ok(());
+        }
+
+        if let Some(client) = state.client(msg.chat.id).await {
+            // A reply to a notification goes to the session the notification
+            // is about, whatever the current session is.
+            let session_id = state.chat(msg.chat.id).map(|chat| {
+                msg.reply_to_message()
+                    .and_then(|reply| chat.notification_messages.get(&reply.id.0).cloned())
+                    .or(chat.current_session)
+            });
+            match session_id {
                 Ok(Some(session_id)) => {
                     if let Err(e) = client.send_message(&session_id, text).await {
-                        log::error!("Failed to send message: {:?}", e);
+                        tracing::error!(error = %e, "Failed to send message");
                         bot.send_message(
                             msg.chat.id,
                             "Sorry, something went wrong while sending your message.",
@@ -668,7 +1848,7 @@ async fn default_message_handler(
                     .await?;
                 }
                 Err(e) => {
-                    log::error!("Failed to read current session: {:?}", e);
+                    tracing::error!(error = %e, "Failed to read current session");
                     bot.send_message(
                         msg.chat.id,
                         "Sorry, something went wrong while reading the current session.",
@@ -687,143 +1867,1564 @@ async fn default_message_handler(
     Ok(())
 }
 
-pub async fn start_bot() {
-    dotenv().ok();
-    pretty_env_logger::init();
-    log::info!("Starting command bot...");
+/// How long the `/new` wizard waits for an answer before giving up.
+const NEW_SESSION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
 
-    let server_api_key = env::var("JULES_API_KEY").expect("JULES_API_KEY must be set");
-    let client: Arc<Mutex<Option<JulesClient>>> = Arc::new(Mutex::new(None));
+/// The number of sources offered as buttons by the `/new` wizard. Other
+/// sources can be typed.
+const MAX_SOURCE_BUTTONS: usize = 20;
 
-    let bot = Bot::from_env();
+/// The state of the `/new` wizard of a chat.
+#[derive(Clone, Debug, Default, PartialEq)]
+enum NewSessionState {
+    /// No wizard is running.
+    #[default]
+    Idle,
+    /// A wizard is running.
+    Running(Wizard),
+}
 
-    let last_activities = Arc::new(Mutex::new(HashMap::<String, String>::new()));
+impl NewSessionState {
+    /// Returns whether a wizard run by the given user is running.
+    fn is_run_by(&self, user: Option<&User>) -> bool {
+        matches!((self, user), (Self::Running(wizard), Some(user)) if wizard.user == user.id)
+    }
+}
 
-    let cache = Arc::new(Cache::new().expect("Failed to create cache"));
-    let bot_for_task = bot.clone();
-    let client_for_task = client.clone();
-    let last_activities_for_task = last_activities.clone();
-    let cache_for_task = cache.clone();
+/// A `/new` wizard, answered by the user who started it.
+#[derive(Clone, Debug, PartialEq)]
+struct Wizard {
+    /// Identifies the wizard among those started by the bot.
+    id: u64,
+    /// The user who started the wizard.
+    user: UserId,
+    /// The step waiting for an answer.
+    step: WizardStep,
+    /// When the wizard gives up if the step is not answered.
+    deadline: Instant,
+}
 
-    let poll_interval_seconds = env::var("JULEZZ_POLL_INTERVAL_SECONDS")
-        .unwrap_or_else(|_| "30".to_string())
-        .parse()
-        .expect("JULEZZ_POLL_INTERVAL_SECONDS must be an integer");
+/// A step of the `/new` wizard.
+#[derive(Clone, Debug, PartialEq)]
+enum WizardStep {
+    /// Waiting for a source, among the listed ones. The other answers
+    /// start from the configured defaults.
+    Source { sources: Vec<String>, defaults: NewSessionDraft },
+    /// Waiting for the starting branch.
+    Branch { draft: NewSessionDraft },
+    /// Collecting the prompt, which may span several messages.
+    Prompt { draft: NewSessionDraft },
+    /// Waiting for the auto-PR choice.
+    AutoPr { draft: NewSessionDraft },
+    /// Waiting for an optional alias.
+    Alias { draft: NewSessionDraft },
+    /// Every question is answered; the session can be created.
+    Done { draft: NewSessionDraft, alias: Option<String> },
+}
 
-    tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(poll_interval_seconds));
-        loop {
-            interval.tick().await;
+impl WizardStep {
+    /// Answers the step with a message.
+    ///
+    /// Returns the next step, or the reply explaining why the answer was
+    /// rejected.
+    fn answer(self, text: &str) -> Result<Self, String> {
+        match self {
+            Self::Source { sources, defaults } => {
+                let source = sources
+                    .iter()
+                    .find(|name| name.as_str() == text || name.trim_start_matches("sources/") == text)
+                    .ok_or_else(|| format!("Unknown source '{}'. Pick one of the buttons, or type the name of a source listed by /src.", text))?;
+                Ok(Self::Branch {
+                    draft: NewSessionDraft {
+                        source: source.clone(),
+                        ..defaults
+                    },
+                })
+            }
+            Self::Branch { mut draft } => {
+                if text.is_empty() || text.contains(char::is_whitespace) {
+                    return Err("Please send a branch name without spaces.".to_string());
+                }
+                draft.branch = text.to_string();
+                Ok(Self::Prompt { draft })
+            }
+            Self::Prompt { mut draft } => {
+                if !draft.prompt.is_empty() {
+                    draft.prompt.push('\n');
+                }
+                draft.prompt.push_str(text);
+                Ok(Self::Prompt { draft })
+            }
+            Self::Alias { draft } => {
+                if !text.starts_with('@') || text.len() < 2 || text.contains(char::is_whitespace) {
+                    return Err("An alias must start with '@' and contain no spaces, e.g. @fix-login.".to_string());
+                }
+                Ok(Self::Done { draft, alias: Some(text.to_string()) })
+            }
+            Self::AutoPr { .. } | Self::Done { .. } => Err("Please use the buttons above, or send /cancel.".to_string()),
+        }
+    }
 
-            if let Some(client) = &*client_for_task.lock().await {
-                match cache_for_task.read_chat_id() {
-                    Ok(Some(chat_id_str)) => {
-                        let chat_id = match chat_id_str.parse() {
-                            Ok(id) => ChatId(id),
-                            Err(e) => {
-                                log::error!("Failed to parse chat ID '{}': {:?}", chat_id_str, e);
-                                continue;
-                            }
-                        };
-                        log::info!("Checking for new activities...");
-                        let sessions = match client.list_sessions().await {
-                            Ok(sessions) => sessions,
-                    Err(e) => {
-                        log::error!("Failed to list sessions for activity check: {:?}", e);
-                        continue;
-                    }
-                };
+    /// Presses a button of the step, whose payload is `new:<button>`.
+    ///
+    /// Returns the next step, or `None` if the button does not belong to the
+    /// step.
+    fn press(self, button: &str) -> Option<Self> {
+        match (self, button) {
+            (Self::Source { sources, defaults }, button) => {
+                let source = button
+                    .strip_prefix("source:")?
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|i| sources.get(i).cloned())?;
+                Some(Self::Branch { draft: NewSessionDraft { source, ..defaults } })
+            }
+            // The button keeps the default branch.
+            (Self::Branch { draft }, "branch") => Some(Self::Prompt { draft }),
+            (Self::Prompt { draft }, "done") if !draft.prompt.is_empty() => Some(Self::AutoPr { draft }),
+            (Self::AutoPr { mut draft }, "autopr") => {
+                draft.auto_pr = !draft.auto_pr;
+                Some(Self::AutoPr { draft })
+            }
+            (Self::AutoPr { draft }, "continue") => Some(Self::Alias { draft }),
+            (Self::Alias { draft }, "skip") => Some(Self::Done { draft, alias: None }),
+            _ => None,
+        }
+    }
+}
 
-                let aliases = match cache_for_task.read_aliases() {
-                    Ok(aliases) => aliases,
-                    Err(e) => {
-                        log::error!("Failed to read aliases for notification task: {:?}", e);
-                        // Continue with an empty alias map
-                        HashMap::new()
-                    }
-                };
+/// The answers given so far to the `/new` wizard.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct NewSessionDraft {
+    source: String,
+    branch: String,
+    prompt: String,
+    auto_pr: bool,
+}
 
-                let mut session_aliases: HashMap<String, Vec<String>> = HashMap::new();
-                for (alias, session_id) in aliases {
-                    session_aliases.entry(session_id).or_default().push(alias);
-                }
+type NewSessionDialogue = Dialogue<NewSessionState, InMemStorage<NewSessionState>>;
 
-                for session in sessions {
-                    let activities = match client.fetch_activities(&session.id).await {
-                        Ok(activities) => activities,
-                        Err(e) => {
-                            log::error!("Failed to fetch activities for session {}: {:?}", session.id, e);
-                            continue;
-                        }
-                    };
+/// Builds the buttons of a wizard step, followed by a "Cancel" button.
+fn wizard_keyboard(mut rows: Vec<Vec<InlineKeyboardButton>>) -> InlineKeyboardMarkup {
+    rows.push(vec![InlineKeyboardButton::callback("Cancel", "new:cancel")]);
+    InlineKeyboardMarkup::new(rows)
+}
 
-                    if let Some(last_activity) = activities.iter().filter(|a| a.originator == "agent").last() {
-                        let mut last_activities = last_activities_for_task.lock().await;
-                        let last_seen_activity_id = last_activities.get(&session.id).cloned();
+/// Builds the buttons of the auto-PR step.
+fn auto_pr_keyboard(auto_pr: bool) -> InlineKeyboardMarkup {
+    let label = if auto_pr { "Auto-PR: on" } else { "Auto-PR: off" };
+    wizard_keyboard(vec![vec![
+        InlineKeyboardButton::callback(label, "new:autopr"),
+        InlineKeyboardButton::callback("Continue", "new:continue"),
+    ]])
+}
 
-                        if last_seen_activity_id.as_deref() != Some(&last_activity.id) {
-                            let session_display = if let Some(aliases) = session_aliases.get(&session.id) {
-                                let formatted_aliases = aliases.iter().map(|a| escape_markdown_v2(a)).collect::<Vec<_>>().join(", ");
-                                format!("[{}]", formatted_aliases)
-                            } else {
-                                format!("*{}*", escape_markdown_v2(&session.title))
-                            };
+/// Moves the `/new` wizard to a new step, giving the user another
+/// `NEW_SESSION_TIMEOUT` to answer it.
+async fn advance_wizard(dialogue: &NewSessionDialogue, wizard: Wizard, step: WizardStep) {
+    let wizard = Wizard {
+        step,
+        deadline: Instant::now() + NEW_SESSION_TIMEOUT,
+        ..wizard
+    };
+    if let Err(e) = dialogue.update(NewSessionState::Running(wizard)).await {
+        tracing::error!(error = %e, "Failed to update the /new wizard");
+    }
+}
 
-                            let notification_message = if let Some(agent_messaged) = &last_activity.agent_messaged {
-                                Some(format!(
-                                    "New message in session {}:\n{}",
-                                    session_display,
-                                    escape_markdown_v2(&agent_messaged.agent_message)
-                                ))
-                            } else if last_activity.plan_generated.is_some() {
-                                Some(format!(
-                                    "Plan generated for session {}\\.",
-                                    session_display
-                                ))
-                            } else if let Some(progress) = &last_activity.progress_updated {
-                                Some(format!(
-                                    "Progress update for session {}:\n{}",
-                                    session_display,
-                                    escape_markdown_v2(progress.title.as_deref().unwrap_or("No title"))
-                                ))
-                            } else if last_activity.artifacts.is_some() {
-                                Some(format!(
-                                    "New artifacts generated for session {}\\.",
-                                    session_display
-                                ))
-                            } else {
-                                None
-                            };
+/// Cancels a wizard once its step has not been answered before its
+/// deadline. Stops when the wizard ends.
+fn watch_wizard_timeout(bot: Bot, dialogue: NewSessionDialogue, id: u64) {
+    tokio::spawn(async move {
+        loop {
+            let deadline = match dialogue.get().await {
+                Ok(Some(NewSessionState::Running(wizard))) if wizard.id == id => wizard.deadline,
+                _ => return,
+            };
+            if Instant::now() < deadline {
+                time::sleep_until(deadline).await;
+                continue;
+            }
+            if let Err(e) = dialogue.exit().await {
+                tracing::error!(error = %e, "Failed to reset the /new wizard");
+            }
+            if let Err(e) = bot
+                .send_message(dialogue.chat_id(), "The /new wizard timed out. Send /new to start again.")
+                .await
+            {
+                tracing::warn!(error = %e, "Failed to report the /new wizard timeout");
+            }
+            return;
+        }
+    });
+}
 
-                            if let Some(message) = notification_message {
-                                if let Err(e) = bot_for_task.send_message(chat_id, &message).parse_mode(ParseMode::MarkdownV2).await {
-                                    log::error!("Failed to send notification: {:?}", e);
-                                }
-                            }
-                            last_activities.insert(session.id.clone(), last_activity.id.clone());
-                        }
-                    }
-                }
-                    }
+/// Starts the `/new` wizard by asking for a source.
+async fn start_new_session_wizard(
+    bot: &Bot,
+    msg: &Message,
+    state: &BotState,
+    client: &JulesClient,
+    dialogue: &NewSessionDialogue,
+) -> ResponseResult<()> {
+    static NEXT_WIZARD: AtomicU64 = AtomicU64::new(0);
+
+    let chat_id = msg.chat.id;
+    let user = match msg.from() {
+        Some(user) => user.id,
+        None => return Ok(()),
+    };
+    if let Ok(Some(NewSessionState::Running(wizard))) = dialogue.get().await {
+        if wizard.user != user {
+            bot.send_message(chat_id, "Someone else is creating a session in this chat. Try again when they are done.").await?;
+            return Ok(());
+        }
+    }
+    let sources: Vec<String> = match client.list_sources().await {
+        Ok(sources) => sources.into_iter().map(|source| source.name).collect(),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to list sources");
+            bot.send_message(chat_id, "Sorry, something went wrong while listing the sources.").await?;
+            return Ok(());
+        }
+    };
+    if sources.is_empty() {
+        bot.send_message(chat_id, "No sources found. Connect a repository to Jules first.").await?;
+        return Ok(());
+    }
+    let step = WizardStep::Source { sources, defaults: state.new_session.clone() };
+    ask_step(bot, chat_id, &step).await?;
+    let wizard = Wizard {
+        id: NEXT_WIZARD.fetch_add(1, Ordering::Relaxed),
+        user,
+        step: step.clone(),
+        deadline: Instant::now(),
+    };
+    let id = wizard.id;
+    advance_wizard(dialogue, wizard, step).await;
+    watch_wizard_timeout(bot.clone(), dialogue.clone(), id);
+    Ok(())
+}
+
+/// Asks the question of a wizard step.
+async fn ask_step(bot: &Bot, chat_id: ChatId, step: &WizardStep) -> ResponseResult<()> {
+    match step {
+        WizardStep::Source { sources, .. } => {
+            let rows = sources
+                .iter()
+                .take(MAX_SOURCE_BUTTONS)
+                .enumerate()
+                .map(|(i, name)| {
+                    vec![InlineKeyboardButton::callback(
+                        name.trim_start_matches("sources/"),
+                        format!("new:source:{}", i),
+                    )]
+                })
+                .collect();
+            bot.send_message(chat_id, "New session (1/5): pick the source, or type its name.")
+                .reply_markup(wizard_keyboard(rows))
+                .await?;
+        }
+        WizardStep::Branch { draft } => {
+            bot.send_message(chat_id, format!("Source: {}\n\nNew session (2/5): pick the starting branch, or type its name.", draft.source))
+                .reply_markup(wizard_keyboard(vec![vec![InlineKeyboardButton::callback(draft.branch.clone(), "new:branch")]]))
+                .await?;
+        }
+        WizardStep::Prompt { draft } if draft.prompt.is_empty() => {
+            bot.send_message(chat_id, format!(
+                "Branch: {}\n\nNew session (3/5): send the prompt. It can span several messages; press Done when it is complete.",
+                draft.branch
+            ))
+            .reply_markup(wizard_keyboard(Vec::new()))
+            .await?;
+        }
+        WizardStep::Prompt { .. } => {
+            bot.send_message(chat_id, "Got it. Send more, or press Done.")
+                .reply_markup(wizard_keyboard(vec![vec![InlineKeyboardButton::callback("Done", "new:done")]]))
+                .await?;
+        }
+        WizardStep::AutoPr { draft } => {
+            bot.send_message(chat_id, "New session (4/5): create a pull request automatically when the session completes?")
+                .reply_markup(auto_pr_keyboard(draft.auto_pr))
+                .await?;
+        }
+        WizardStep::Alias { .. } => {
+            bot.send_message(chat_id, "New session (5/5): send an alias for the session, like @fix-login, or press Skip.")
+                .reply_markup(wizard_keyboard(vec![vec![InlineKeyboardButton::callback("Skip", "new:skip")]]))
+                .await?;
+        }
+        WizardStep::Done { .. } => {}
+    }
+    Ok(())
+}
+
+/// Moves the `/new` wizard to its next step: asks its question, or creates
+/// the session once every question is answered.
+async fn continue_wizard(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &BotState,
+    dialogue: &NewSessionDialogue,
+    wizard: Wizard,
+    step: WizardStep,
+) -> ResponseResult<()> {
+    if let WizardStep::Done { draft, alias } = step {
+        return finish_new_session_wizard(bot, chat_id, state, dialogue, draft, alias).await;
+    }
+    ask_step(bot, chat_id, &step).await?;
+    advance_wizard(dialogue, wizard, step).await;
+    Ok(())
+}
+
+/// Creates the session described by the `/new` wizard and ends it.
+async fn finish_new_session_wizard(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &BotState,
+    dialogue: &NewSessionDialogue,
+    draft: NewSessionDraft,
+    alias: Option<String>,
+) -> ResponseResult<()> {
+    if let Err(e) = dialogue.exit().await {
+        tracing::error!(error = %e, "Failed to reset the /new wizard");
+    }
+    let client = match state.client(chat_id).await {
+        Some(client) => client,
+        None => {
+            bot.send_message(chat_id, "You are not authenticated. Please use the `/auth` command to provide your API key.").await?;
+            return Ok(());
+        }
+    };
+    match client.create_session(&draft.source, &draft.prompt, draft.auto_pr, &draft.branch).await {
+        Ok(session) => {
+            let mut response = format!("Session created: {} ({})", session.id, session.title);
+            if let Some(alias) = alias {
+                match state.update_chat(chat_id, |chat| {
+                    chat.aliases.insert(alias.clone(), session.id.clone());
+                }) {
+                    Ok(()) => response.push_str(&format!("\nAlias '{}' created.", alias)),
                     Err(e) => {
-                        log::error!("Failed to read chat ID: {:?}", e);
-                    }
-                    Ok(None) => {
-                        // No owner chat ID set yet, do nothing.
+                        tracing::error!(error = %e, "Failed to write aliases");
+                        response.push_str("\nSorry, something went wrong while saving your alias.");
                     }
                 }
             }
+            bot.send_message(chat_id, response).await?;
         }
-    });
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to create session");
+            bot.send_message(chat_id, "Sorry, something went wrong while creating the session.").await?;
+        }
+    }
+    Ok(())
+}
 
-    let handler = Update::filter_message()
-        .branch(dptree::entry().filter_command::<Command>().endpoint(answer))
-        .branch(dptree::endpoint(default_message_handler));
+/// Handles the messages answering the `/new` wizard, sent by the user who
+/// started it.
+async fn new_session_message(
+    bot: Bot,
+    msg: Message,
+    state: Arc<BotState>,
+    dialogue: NewSessionDialogue,
+    wizard: NewSessionState,
+) -> ResponseResult<()> {
+    if !state.authorize(&bot, &msg, Role::Operator).await? {
+        return Ok(());
+    }
+    let wizard = match wizard {
+        NewSessionState::Running(wizard) => wizard,
+        NewSessionState::Idle => return Ok(()),
+    };
+    let text = match msg.text() {
+        Some(text) if !text.starts_with('/') => text.trim(),
+        _ => {
+            bot.send_message(msg.chat.id, "Please answer with text, or send /cancel to stop creating the session.").await?;
+            return Ok(());
+        }
+    };
 
-    Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![client, cache, Arc::new(server_api_key)])
+    match wizard.step.clone().answer(text) {
+        Ok(step) => continue_wizard(&bot, msg.chat.id, &state, &dialogue, wizard, step).await?,
+        Err(reply) => {
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Handles the buttons of the `/new` wizard.
+///
+/// Their payload is `new:<button>`. A button from a step the wizard has left
+/// is reported as expired, and only the user who started the wizard can
+/// press them.
+async fn new_session_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    state: Arc<BotState>,
+    dialogue: NewSessionDialogue,
+    wizard: NewSessionState,
+) -> ResponseResult<()> {
+    let (chat_id, message_id) = match &q.message {
+        Some(message) => (message.chat.id, message.id),
+        None => {
+            bot.answer_callback_query(q.id).await?;
+            return Ok(());
+        }
+    };
+    if state.permit(Some(&q.from), chat_id, "button new", Role::Operator).is_err() {
+        bot.answer_callback_query(q.id)
+            .text("You are not allowed to do this.")
+            .show_alert(true)
+            .await?;
+        return Ok(());
+    }
+    let wizard = match wizard {
+        NewSessionState::Running(wizard) => wizard,
+        NewSessionState::Idle => {
+            bot.answer_callback_query(q.id).text("This wizard has ended. Send /new to start again.").await?;
+            bot.edit_message_reply_markup(chat_id, message_id).await?;
+            return Ok(());
+        }
+    };
+    if wizard.user != q.from.id {
+        bot.answer_callback_query(q.id).text("Someone else is creating this session.").await?;
+        return Ok(());
+    }
+    let button = q.data.as_deref().unwrap_or_default().trim_start_matches("new:");
+
+    if button == "cancel" {
+        bot.answer_callback_query(q.id).await?;
+        if let Err(e) = dialogue.exit().await {
+            tracing::error!(error = %e, "Failed to reset the /new wizard");
+        }
+        bot.edit_message_reply_markup(chat_id, message_id).await?;
+        bot.send_message(chat_id, "Cancelled.").await?;
+        return Ok(());
+    }
+    match wizard.step.clone().press(button) {
+        // Toggling auto-PR updates the buttons in place.
+        Some(WizardStep::AutoPr { draft }) if matches!(wizard.step, WizardStep::AutoPr { .. }) => {
+            bot.answer_callback_query(q.id).await?;
+            bot.edit_message_reply_markup(chat_id, message_id)
+                .reply_markup(auto_pr_keyboard(draft.auto_pr))
+                .await?;
+            advance_wizard(&dialogue, wizard, WizardStep::AutoPr { draft }).await;
+        }
+        Some(step) => {
+            bot.answer_callback_query(q.id).await?;
+            bot.edit_message_reply_markup(chat_id, message_id).await?;
+            continue_wizard(&bot, chat_id, &state, &dialogue, wizard, step).await?;
+        }
+        None => {
+            bot.answer_callback_query(q.id).text("This button has expired.").await?;
+        }
+    }
+    Ok(())
+}
+
+/// Parses the arguments of `/revise` into an optional session identifier, a
+/// step number and the feedback.
+///
+/// Session ids are numbers too, so when the first two words are numbers, the
+/// first one is the identifier.
+fn parse_revise(text: &str) -> Option<(&str, usize, String)> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let number = |part: &str| part.parse::<usize>().ok();
+    let (identifier, step, feedback) = match parts.as_slice() {
+        [identifier, step, feedback @ ..] if number(step).is_some() => (*identifier, number(step)?, feedback),
+        [step, feedback @ ..] => ("", number(step)?, feedback),
+        [] => return None,
+    };
+    if feedback.is_empty() {
+        return None;
+    }
+    Some((identifier, step, feedback.join(" ")))
+}
+
+/// Builds the buttons attached to "Plan generated" notifications.
+fn plan_keyboard(session_id: &str) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("Approve", format!("approve:{}", session_id)),
+        InlineKeyboardButton::callback("Show plan", format!("plan:{}", session_id)),
+        InlineKeyboardButton::callback("Reply", format!("reply:{}", session_id)),
+    ]])
+}
+
+/// Builds the buttons attached to "Session completed" notifications.
+fn completed_keyboard(session_id: &str) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("View diff", format!("diff:{}", session_id)),
+        InlineKeyboardButton::callback("Merge PR", format!("merge:{}", session_id)),
+        InlineKeyboardButton::callback("Delete", format!("delete:{}", session_id)),
+    ]])
+}
+
+/// Returns the role required for a button action, or `None` if the action is
+/// unknown.
+fn callback_role(action: &str) -> Option<Role> {
+    match action {
+        "plan" | "diff" | "cancel" => Some(Role::Viewer),
+        "more" => Some(Role::Viewer),
+        "approve" | "reply" => Some(Role::Operator),
+        "merge" | "delete" | "confirm-delete" => Some(Role::Admin),
+        _ => None,
+    }
+}
+
+/// Finds the latest plan of a session.
+///
+/// # Returns
+///
+/// The plan and whether it has been approved, i.e. whether a plan approval
+/// for it follows it.
+fn latest_plan(activities: &[julezz::api::Activity]) -> Option<(&julezz::api::Plan, bool)> {
+    let mut activities = activities.iter().collect::<Vec<_>>();
+    activities.sort_by_key(|a| a.created_at());
+    let index = activities.iter().rposition(|a| a.plan_generated.is_some())?;
+    let plan = &activities[index].plan_generated.as_ref()?.plan;
+    let approved = activities[index + 1..].iter().any(|a| {
+        a.plan_approved
+            .as_ref()
+            .is_some_and(|approval| approval.plan_id.as_deref().is_none_or(|id| id == plan.id))
+    });
+    Some((plan, approved))
+}
+
+/// Formats the steps of the latest plan of a session, with whether it has
+/// been approved.
+fn format_plan(activities: &[julezz::api::Activity], session_id: &str) -> Option<String> {
+    let (plan, approved) = latest_plan(activities)?;
+    let status = if approved { "approved" } else { "awaiting approval" };
+    let mut response = format!("Plan for session {} ({}):\n\n", session_id, status);
+    for (i, step) in plan.steps.iter().enumerate() {
+        response.push_str(&format!("{}. {}\n", i + 1, step.title));
+        if let Some(description) = &step.description {
+            response.push_str(&format!("   {}\n", description));
+        }
+    }
+    Some(response)
+}
+
+/// Formats feedback on a step of a plan as a message to the agent.
+///
+/// # Arguments
+///
+/// * `step` - The 1-based number of the step, as shown by `/plan`.
+fn format_plan_feedback(plan: &julezz::api::Plan, step: usize, feedback: &str) -> Result<String, String> {
+    let plan_step = step
+        .checked_sub(1)
+        .and_then(|i| plan.steps.get(i))
+        .ok_or_else(|| format!("The plan has no step {}; it has {} steps.", step, plan.steps.len()))?;
+    Ok(format!(
+        "Feedback on step {} of the plan (\"{}\"):\n\n{}\n\nPlease revise the plan accordingly.",
+        step, plan_step.title, feedback
+    ))
+}
+
+/// The session states in which the agent waits for the user.
+const AWAITING_STATES: &[&str] = &["AWAITING_PLAN_APPROVAL", "AWAITING_USER_FEEDBACK"];
+
+/// Summarises the code changes of a session.
+///
+/// # Returns
+///
+/// The summary with a files-changed list and line stats, or `None` if the
+/// session has no code changes.
+fn format_diff(activities: &[julezz::api::Activity], session_id: &str) -> Option<String> {
+    let patch = collect_patches(activities);
+    if patch.is_empty() {
+        return None;
+    }
+    let stats = diff_stats(&patch);
+    let added: usize = stats.iter().map(|stat| stat.added).sum();
+    let removed: usize = stats.iter().map(|stat| stat.removed).sum();
+    let mut response = format!(
+        "Changes in session {}: {} file(s), +{} -{}\n\n",
+        session_id,
+        stats.len(),
+        added,
+        removed
+    );
+    for stat in &stats {
+        response.push_str(&format!("+{} -{}  {}\n", stat.added, stat.removed, stat.path));
+    }
+    let commit_message = activities
+        .iter()
+        .rev()
+        .filter_map(|a| a.artifacts.as_ref())
+        .flatten()
+        .find_map(|artifact| artifact.change_set.as_ref()?.suggested_commit_message.clone());
+    if let Some(commit_message) = commit_message {
+        response.push_str(&format!("\nSuggested commit message:\n{}\n", commit_message));
+    }
+    Some(response)
+}
+
+/// Sends the summary of a session's code changes, with the full patch
+/// attached.
+async fn send_diff(
+    bot: &Bot,
+    state: &BotState,
+    chat_id: ChatId,
+    session_id: &str,
+    activities: &[julezz::api::Activity],
+) -> ResponseResult<()> {
+    match format_diff(activities, session_id) {
+        Some(summary) => {
+            send_long_message(bot, state, chat_id, LongMessage::new(&summary, None, None)).await?;
+            let patch = Attachment {
+                file_name: format!("{}.diff", session_id),
+                contents: collect_patches(activities),
+            };
+            send_attachments(bot, chat_id, vec![patch]).await?;
+        }
+        None => {
+            bot.send_message(chat_id, format!("No code changes found for session {}.", session_id)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Formats how long ago something happened, e.g. `5m ago`.
+fn format_age(age: chrono::Duration) -> String {
+    if age.num_days() > 0 {
+        format!("{}d ago", age.num_days())
+    } else if age.num_hours() > 0 {
+        format!("{}h ago", age.num_hours())
+    } else if age.num_minutes() > 0 {
+        format!("{}m ago", age.num_minutes())
+    } else {
+        "just now".to_string()
+    }
+}
+
+/// Formats a dashboard of the sessions that are not in a terminal state.
+///
+/// Only the cached activities are read, so building the dashboard does not
+/// fetch activities.
+fn format_status(
+    client: &JulesClient,
+    sessions: &[Session],
+    aliases: &julezz::cache::Aliases,
+    now: DateTime<Utc>,
+) -> String {
+    let active: Vec<&Session> = sessions.iter().filter(|session| !is_terminal(session)).collect();
+    if active.is_empty() {
+        return "No active sessions.".to_string();
+    }
+
+    let mut response = format!("Active sessions ({}):\n", active.len());
+    for session in active {
+        let mut activities = client.list_cached_activities(&session.id).unwrap_or_default();
+        sort_activities(&mut activities);
+        let last = activities.last();
+
+        let state = session.state.as_deref().unwrap_or("UNKNOWN");
+        let needs_input = AWAITING_STATES.contains(&state)
+            || last.and_then(EventKind::of).is_some_and(|kind| matches!(kind, EventKind::Question | EventKind::Plan));
+        let last_activity = last
+            .and_then(|activity| activity.created_at())
+            .or_else(|| session.update_time.as_deref().and_then(parse_time))
+            .map(|time| format_age(now - time))
+            .unwrap_or_else(|| "no activity yet".to_string());
+        let mut session_aliases: Vec<&str> = aliases
+            .iter()
+            .filter(|(_, id)| **id == session.id)
+            .map(|(alias, _)| alias.as_str())
+            .collect();
+        session_aliases.sort();
+
+        response.push_str(&format!("\n{}", session.id));
+        if !session_aliases.is_empty() {
+            response.push_str(&format!(" ({})", session_aliases.join(", ")));
+        }
+        response.push_str(&format!(": {}\n  {}, last activity {}\n", session.title, state, last_activity));
+        if needs_input {
+            response.push_str("  Needs your input\n");
+        }
+        if let Some(url) = &session.pull_request_url {
+            response.push_str(&format!("  PR: {}\n", url));
+        }
+    }
+    response
+}
+
+/// Handles the buttons of notifications.
+///
+/// The payload of a button is `<action>:<session_id>`. Before acting, the
+/// user's role is checked and the session is looked up with the chat's own
+/// API key, so a forged payload cannot reach another account's sessions.
+#[tracing::instrument(name = "callback", skip_all, fields(data = q.data.as_deref()))]
+async fn callback_handler(bot: Bot, q: CallbackQuery, state: Arc<BotState>) -> ResponseResult<()> {
+    let (chat_id, message_id) = match &q.message {
+        Some(message) => (message.chat.id, message.id),
+        None => {
+            bot.answer_callback_query(q.id).await?;
+            return Ok(());
+        }
+    };
+    let (action, session_id) = match q.data.as_deref().and_then(|data| data.split_once(':')) {
+        Some((action, session_id)) => (action.to_string(), session_id.to_string()),
+        None => {
+            bot.answer_callback_query(q.id).await?;
+            return Ok(());
+        }
+    };
+    let required = match callback_role(&action) {
+        Some(role) => role,
+        None => {
+            tracing::warn!(action, "Ignoring unknown button action");
+            bot.answer_callback_query(q.id).await?;
+            return Ok(());
+        }
+    };
+
+    if state.permit(Some(&q.from), chat_id, &format!("button {}", action), required).is_err() {
+        bot.answer_callback_query(q.id)
+            .text("You are not allowed to do this.")
+            .show_alert(true)
+            .await?;
+        return Ok(());
+    }
+
+    // "Show more" is not about a session: it sends the rest of a long message.
+    if action == "more" {
+        bot.answer_callback_query(q.id).await?;
+        match state.take_pending(chat_id, message_id).await {
+            Some(message) => {
+                bot.edit_message_reply_markup(chat_id, message_id).await?;
+                send_long_message(&bot, &state, chat_id, message).await?;
+            }
+            None => {
+                bot.send_message(chat_id, "The rest of this message is no longer available.").await?;
+            }
+        }
+        return Ok(());
+    }
+
+    let client = match state.client(chat_id).await {
+        Some(client) => client,
+        None => {
+            bot.answer_callback_query(q.id)
+                .text("You are not authenticated. Please use the /auth command to provide your API key.")
+                .show_alert(true)
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let session = match client.get_session(&session_id).await {
+        Ok(session) => session,
+        Err(e) => {
+            tracing::error!(session_id, error = %e, "Failed to get session for button");
+            bot.answer_callback_query(q.id)
+                .text("Session not found.")
+                .show_alert(true)
+                .await?;
+            return Ok(());
+        }
+    };
+    bot.answer_callback_query(q.id).await?;
+
+    match action.as_str() {
+        "approve" => {
+            match client.approve_plan(&session.id).await {
+                Ok(_) => {
+                    bot.send_message(chat_id, format!("Plan approved for session {}.", session.id)).await?;
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to approve plan");
+                    bot.send_message(chat_id, "Sorry, something went wrong while approving the plan.").await?;
+                }
+            }
+        }
+        "plan" => {
+            match client.fetch_activities(&session.id).await {
+                Ok(activities) => {
+                    let response = format_plan(&activities, &session.id)
+                        .unwrap_or_else(|| format!("No plan found for session {}.", session.id));
+                    send_long_message(&bot, &state, chat_id, LongMessage::new(&response, None, None)).await?;
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to fetch activities");
+                    bot.send_message(chat_id, "Sorry, something went wrong while fetching the plan.").await?;
+                }
+            }
+        }
+        "reply" => {
+            let saved = state.update_chat(chat_id, |chat| chat.current_session = Some(session.id.clone()));
+            if let Err(e) = saved {
+                tracing::error!(error = %e, "Failed to write current session");
+                bot.send_message(chat_id, "Sorry, something went wrong while setting the current session.").await?;
+            } else {
+                let prompt = bot.send_message(chat_id, format!("Current session set to {}. Send your reply.", session.id))
+                    .reply_markup(ForceReply::new())
+                    .await?;
+                state.remember_message(chat_id, &prompt, &session.id);
+            }
+        }
+        "diff" => {
+            match client.fetch_activities(&session.id).await {
+                Ok(activities) => {
+                    send_diff(&bot, &state, chat_id, &session.id, &activities).await?;
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to fetch activities");
+                    bot.send_message(chat_id, "Sorry, something went wrong while fetching the diff.").await?;
+                }
+            }
+        }
+        "merge" => {
+            if let Some(pull_request_url) = &session.pull_request_url {
+                if let Err(e) = client.merge_pull_request(pull_request_url).await {
+                    tracing::error!(error = %e, "Failed to merge pull request");
+                    bot.send_message(chat_id, "Sorry, something went wrong while merging the pull request.").await?;
+                } else {
+                    state.record_merge(chat_id, &session.id, pull_request_url);
+                    bot.send_message(chat_id, "Pull request merged successfully!").await?;
+                }
+            } else {
+                bot.send_message(chat_id, "No pull request URL found for this session.").await?;
+            }
+        }
+        "delete" => {
+            let keyboard = InlineKeyboardMarkup::new(vec![vec![
+                InlineKeyboardButton::callback("Delete", format!("confirm-delete:{}", session.id)),
+                InlineKeyboardButton::callback("Cancel", format!("cancel:{}", session.id)),
+            ]]);
+            bot.send_message(chat_id, format!("Delete session {} ({})?", session.id, session.title))
+                .reply_markup(keyboard)
+                .await?;
+        }
+        "confirm-delete" => {
+            match client.delete_session(&session.id).await {
+                Ok(_) => {
+                    if let Err(e) = state.trash_session(chat_id, &session.id) {
+                        tracing::error!(error = %e, "Failed to move session to trash");
+                    }
+                    bot.edit_message_text(chat_id, message_id, format!("Session {} deleted.", session.id)).await?;
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to delete session");
+                    bot.send_message(chat_id, "Sorry, something went wrong while deleting the session.").await?;
+                }
+            }
+        }
+        "cancel" => {
+            bot.edit_message_text(chat_id, message_id, "Cancelled.").await?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Builds the notification for an event, as MarkdownV2 text with optional
+/// buttons.
+fn notification_for(
+    event: &Event,
+    session_display: &str,
+) -> (&'static str, String, Option<InlineKeyboardMarkup>) {
+    let detail = event.detail.as_deref();
+    let (message, keyboard) = match event.kind {
+        EventKind::Question => (format!(
+            "New message in session {}:\n{}",
+            session_display,
+            escape_markdown_v2(detail.unwrap_or_default())
+        ), None),
+        EventKind::Plan => (format!(
+            "Plan generated for session {}\\.",
+            session_display
+        ), Some(plan_keyboard(&event.session_id))),
+        EventKind::Completed => (format!(
+            "Session {} completed\\.",
+            session_display
+        ), Some(completed_keyboard(&event.session_id))),
+        EventKind::Failed => (format!(
+            "Session {} failed:\n{}",
+            session_display,
+            escape_markdown_v2(detail.unwrap_or("No reason given"))
+        ), None),
+        EventKind::PullRequest => (format!(
+            "Pull request opened for session {}:\n{}",
+            session_display,
+            escape_markdown_v2(detail.unwrap_or_default())
+        ), Some(completed_keyboard(&event.session_id))),
+        EventKind::Progress => (format!(
+            "Progress update for session {}:\n{}",
+            session_display,
+            escape_markdown_v2(detail.unwrap_or("No title"))
+        ), None),
+        EventKind::Artifact => (format!(
+            "New artifacts generated for session {}\\.",
+            session_display
+        ), None),
+    };
+    (event.kind.name(), message, keyboard)
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+/// The name of the state holding the hooks' cursors.
+const HOOKS_STATE: &str = "bot_hooks";
+
+/// The settings of the activity poller.
+struct PollSettings {
+    interval: Duration,
+    max_interval: Duration,
+    concurrency: usize,
+    events: Vec<String>,
+    hooks: Hooks,
+}
+
+/// Notifies a chat about the new activities of each of its sessions.
+///
+/// Every new activity is announced in order, and the last one delivered is
+/// persisted in the chat's cursors, so that neither restarting the bot nor a
+/// failure of Telegram loses notifications, and none is repeated. Only the sessions the chat's `Poller` considers
+/// due are fetched, in parallel.
+///
+/// Returns the chat's sessions, or nothing if they could not be listed.
+async fn check_chat_activities(
+    bot: &Bot,
+    state: &BotState,
+    chat_id: ChatId,
+    chat: &ChatState,
+    settings: &PollSettings,
+) -> Vec<Session> {
+    let client = match state.client(chat_id).await {
+        Some(client) => client,
+        None => return Vec::new(),
+    };
+
+    let started = Instant::now();
+    let sessions = match client.list_sessions().await {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            tracing::error!(chat_id = chat_id.0, error = %e, "Failed to list sessions for activity check");
+            return Vec::new();
+        }
+    };
+    let checked_at = Utc::now();
+    let last_checked = chat.last_checked.as_deref().and_then(parse_time);
+
+    let mut session_aliases: HashMap<String, Vec<String>> = HashMap::new();
+    for (alias, session_id) in &chat.aliases {
+        session_aliases.entry(session_id.clone()).or_default().push(alias.clone());
+    }
+
+    let mut poller = state
+        .pollers
+        .lock()
+        .await
+        .remove(&chat_id)
+        .unwrap_or_else(|| Poller::new(settings.interval, settings.max_interval));
+    let due = poller.due(&sessions);
+
+    // Sessions that are not fetched keep their cursor.
+    let mut cursors = chat.cursors.clone();
+    cursors.retain(|id, _| sessions.iter().any(|session| &session.id == id));
+    let mut opened_pull_requests = Vec::new();
+
+    let due_ids = due.iter().map(|session| session.id.clone()).collect();
+    for (session_id, result) in fetch_all(client.clone(), due_ids, settings.concurrency).await {
+        let session = match sessions.iter().find(|session| session.id == session_id) {
+            Some(session) => session,
+            None => continue,
+        };
+        let mut activities = match result {
+            Ok(activities) => activities,
+            Err(e) => {
+                tracing::error!(session_id = %session.id, error = %e, "Failed to fetch activities");
+                poller.record_error();
+                continue;
+            }
+        };
+
+        let session_display = if let Some(aliases) = session_aliases.get(&session.id) {
+            let formatted_aliases = aliases.iter().map(|a| escape_markdown_v2(a)).collect::<Vec<_>>().join(", ");
+            format!("[{}]", formatted_aliases)
+        } else {
+            format!("*{}*", escape_markdown_v2(&session.title))
+        };
+
+        let previous = chat.cursors.get(&session.id);
+        let changes = detect(session, &mut activities, previous, last_checked, checked_at);
+        let mut cursor = changes.cursor.clone();
+        for event in &changes.events {
+            let notification = notification_for(event, &session_display);
+            if !send_notification(bot, state, chat_id, chat, settings, &session.id, notification).await {
+                // The undelivered events are announced by the next check.
+                cursor = cursor_before(session, &activities, event, previous, last_checked);
+                break;
+            }
+            if let (EventKind::PullRequest, Some(url)) = (event.kind, &event.detail) {
+                opened_pull_requests.push((session.id.clone(), url.clone()));
+            }
+        }
+        poller.record(session, changes.new_activities);
+
+        // Hooks run for every event, whatever the chat's preferences, in the
+        // background so that a slow hook does not hold up notifications.
+        if !settings.hooks.is_empty() {
+            let events = state.hook_events(session, &mut activities, last_checked, checked_at).await;
+            let hooks = settings.hooks.clone();
+            tokio::spawn(async move {
+                for event in &events {
+                    if let Err(e) = hooks.run(event).await {
+                        tracing::error!(hook = event.kind.name(), session_id = %event.session_id, error = %e, "Failed to run hook");
+                    }
+                }
+            });
+        }
+
+        if let Some(cursor) = cursor {
+            cursors.insert(session.id.clone(), cursor);
+        }
+    }
+    // Sessions left without a cursor, e.g. because their activities could not
+    // be fetched, are detected since the previous check next time.
+    for session in &sessions {
+        if !cursors.contains_key(&session.id) {
+            cursors.extend(cursor_since(session, last_checked).map(|cursor| (session.id.clone(), cursor)));
+        }
+    }
+
+    poller.finish_tick(started.elapsed());
+    let stats = poller.stats();
+    tracing::info!(
+        chat_id = chat_id.0,
+        sessions = stats.sessions,
+        fetched = stats.fetched,
+        idle = stats.skipped_idle,
+        terminal = stats.skipped_terminal,
+        errors = stats.errors,
+        "Checked chat"
+    );
+    state.pollers.lock().await.insert(chat_id, poller);
+
+    let saved = state.update_chat(chat_id, |chat| {
+        chat.cursors = cursors;
+        chat.last_checked = Some(checked_at.to_rfc3339());
+        // The digest is computed from the cached sessions.
+        chat.sessions = sessions.iter().map(CachedSession::from).collect();
+        if let Some(digest) = &mut chat.digest {
+            for (session_id, url) in &opened_pull_requests {
+                digest.record_pull_request(session_id, url, false, checked_at);
+            }
+        }
+    });
+    if let Err(e) = saved {
+        tracing::error!(chat_id = chat_id.0, error = %e, "Failed to save notification cursors");
+    }
+    sessions
+}
+
+/// Sends a notification to a chat, unless its preferences filter it out.
+///
+/// Returns whether the notification was sent or filtered out, i.e. whether
+/// it is done with.
+async fn send_notification(
+    bot: &Bot,
+    state: &BotState,
+    chat_id: ChatId,
+    chat: &ChatState,
+    settings: &PollSettings,
+    session_id: &str,
+    (event, message, keyboard): (&'static str, String, Option<InlineKeyboardMarkup>),
+) -> bool {
+    if !chat.preferences.allows(session_id, event, &settings.events, Local::now()) {
+        return true;
+    }
+    let message = LongMessage::new(&message, Some(ParseMode::MarkdownV2), keyboard);
+    match send_long_message(bot, state, chat_id, message).await {
+        Ok(sent) => {
+            metrics::record_notification("telegram", true);
+            for sent in &sent {
+                state.remember_message(chat_id, sent, session_id);
+            }
+            true
+        }
+        Err(e) => {
+            metrics::record_notification("telegram", false);
+            metrics::record_telegram_error(telegram_error_kind(&e));
+            tracing::error!(chat_id = chat_id.0, session_id, error = %e, "Failed to send notification");
+            false
+        }
+    }
+}
+
+/// Names the kind of a Telegram Bot API error, for the metrics.
+fn telegram_error_kind(error: &RequestError) -> &'static str {
+    match error {
+        RequestError::Api(_) => "api",
+        RequestError::MigrateToChatId(_) => "migrate",
+        RequestError::RetryAfter(_) => "retry_after",
+        RequestError::Network(_) => "network",
+        RequestError::InvalidJson { .. } => "invalid_json",
+        RequestError::Io(_) => "io",
+    }
+}
+
+/// Formats the polling statistics of a chat.
+fn format_stats(stats: &PollStats) -> String {
+    format!(
+        "Polling statistics:\n- Ticks: {}\n- Sessions: {}\n- Fetches: {}\n- Skipped idle: {}\n- Skipped terminal: {}\n- Errors: {}\n- New activities: {}\n- Last tick: {} ms",
+        stats.ticks,
+        stats.sessions,
+        stats.fetched,
+        stats.skipped_idle,
+        stats.skipped_terminal,
+        stats.errors,
+        stats.new_activities,
+        stats.last_tick_millis
+    )
+}
+
+/// Summarises the activities of a chat's sessions during the bot's downtime.
+async fn format_catchup(client: &JulesClient, chat: &ChatState) -> Result<String, String> {
+    let downtime = match &chat.downtime {
+        Some(downtime) => downtime,
+        None => return Ok("The bot has not been down since this chat started receiving notifications.".to_string()),
+    };
+    let from = parse_time(&downtime.from);
+    let to = parse_time(&downtime.to);
+
+    let mut response = format!("While the bot was down, from {} to {}:\n", downtime.from, downtime.to);
+    let mut quiet = true;
+    for session in client.list_sessions().await.map_err(|e| e.to_string())? {
+        let mut activities = client.fetch_activities(&session.id).await.map_err(|e| e.to_string())?;
+        sort_activities(&mut activities);
+
+        let mut counts: Vec<(&str, usize)> = Vec::new();
+        for activity in activities.iter().filter(|a| a.created_at() > from && a.created_at() <= to) {
+            if let Some(event) = EventKind::of(activity).map(|kind| kind.name()) {
+                match counts.iter_mut().find(|(e, _)| *e == event) {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((event, 1)),
+                }
+            }
+        }
+        if counts.is_empty() {
+            continue;
+        }
+        quiet = false;
+        let summary = counts
+            .iter()
+            .map(|(event, count)| format!("{} {}", count, event))
+            .collect::<Vec<_>>()
+            .join(", ");
+        response.push_str(&format!("- {} ({}): {}\n", session.id, session.title, summary));
+    }
+    if quiet {
+        response.push_str("Nothing happened in your sessions.\n");
+    }
+    Ok(response)
+}
+
+/// The webhook settings of the bot.
+struct WebhookSettings {
+    /// The public URL Telegram sends updates to. Its path is also the path
+    /// the listener serves.
+    url: reqwest::Url,
+    /// The local address to listen on.
+    listen: SocketAddr,
+    secret: Option<String>,
+    /// Whether to register the webhook with Telegram.
+    register: bool,
+}
+
+impl WebhookSettings {
+    /// Reads the webhook settings from the config.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the settings, or `None` if no webhook URL is set.
+    fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        let url = match config.webhook_url() {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let url = reqwest::Url::parse(&url).map_err(|e| format!("Invalid webhook URL: {}", e))?;
+        let listen = config
+            .webhook_listen()
+            .parse()
+            .map_err(|e| format!("Invalid webhook listen address: {}", e))?;
+        Ok(Some(Self {
+            url,
+            listen,
+            secret: config.webhook_secret(),
+            register: config.webhook_register(),
+        }))
+    }
+}
+
+/// Starts the webhook listener.
+///
+/// The listener serves plain HTTP, so that it can sit behind a proxy
+/// terminating TLS. The port is bound before the webhook is registered, so a
+/// failure leaves no webhook pointing at nothing.
+async fn start_webhook(bot: &Bot, settings: WebhookSettings) -> Result<impl UpdateListener<Err = Infallible>, String> {
+    let tcp = std::net::TcpListener::bind(settings.listen)
+        .map_err(|e| format!("Could not listen on {}: {}", settings.listen, e))?;
+    let mut options = webhooks::Options::new(settings.listen, settings.url.clone());
+    options.secret_token = settings.secret;
+    if settings.register {
+        let secret = options.get_or_gen_secret_token().to_string();
+        bot.set_webhook(settings.url)
+            .secret_token(secret)
+            .await
+            .map_err(|e| format!("Could not register the webhook: {}", e))?;
+        tracing::info!("Registered the webhook with Telegram.");
+    }
+    tracing::info!(listen = %settings.listen, "Listening for webhook updates");
+    serve_webhook(tcp, options)
+}
+
+/// Serves the webhook on a bound socket.
+///
+/// Besides the webhook path, the listener answers `GET /healthz` for health
+/// checks.
+fn serve_webhook(
+    tcp: std::net::TcpListener,
+    options: webhooks::Options,
+) -> Result<impl UpdateListener<Err = Infallible>, String> {
+    let (mut listener, stop_flag, router) = webhooks::axum_no_setup(options);
+    let router = router.route("/healthz", axum::routing::get(|| async { "ok" }));
+    tcp.set_nonblocking(true)
+        .map_err(|e| format!("Could not configure the webhook socket: {}", e))?;
+    let server = axum::Server::from_tcp(tcp)
+        .map_err(|e| format!("Could not start the webhook server: {}", e))?
+        .serve(router.into_make_service())
+        .with_graceful_shutdown(stop_flag);
+    let stop_token = listener.stop_token();
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::error!(error = %e, "Webhook server failed");
+            stop_token.stop();
+        }
+    });
+    Ok(listener)
+}
+
+pub async fn start_bot() {
+    dotenv().ok();
+    tracing::info!("Starting command bot...");
+
+    let bot = Bot::from_env();
+
+    let cache = Cache::new().expect("Failed to create cache");
+    // Before chats had their own state, the first chat to authenticate became
+    // the owner of the bot, using the server's API key.
+    if let Err(e) = migrate_owner_chat(&cache) {
+        tracing::error!(error = %e, "Failed to migrate the owner chat");
+    }
+    let config = Config::load().expect("Failed to load config");
+    let policy = AccessPolicy::from_config(&config);
+    let audit_log = AuditLog::new().expect("Failed to open audit log");
+    let state = Arc::new(BotState::new(cache, policy, audit_log, &config));
+
+    let bot_for_task = bot.clone();
+    let state_for_task = state.clone();
+
+    let settings = PollSettings {
+        interval: Duration::from_secs(config.poll_interval_seconds()),
+        max_interval: Duration::from_secs(config.max_poll_interval_seconds()),
+        concurrency: config.poll_concurrency(),
+        events: config.notification_events(),
+        hooks: Hooks::from_config(&config),
+    };
+
+    metrics::spawn_server(&config);
+
+    tokio::spawn(async move {
+        let mut interval = time::interval(settings.interval);
+        loop {
+            interval.tick().await;
+            let started = Instant::now();
+
+            let chat_ids = match state_for_task.cache.list_chat_ids() {
+                Ok(chat_ids) => chat_ids,
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to list chats");
+                    continue;
+                }
+            };
+
+            tracing::info!("Checking for new activities...");
+            // Chats sharing an API key see the same sessions.
+            let mut session_states: HashMap<String, Option<String>> = HashMap::new();
+            for chat_id in chat_ids {
+                let chat_id = ChatId(chat_id);
+                let chat = match state_for_task.chat(chat_id) {
+                    Ok(chat) => chat,
+                    Err(e) => {
+                        tracing::error!(chat_id = chat_id.0, error = %e, "Failed to read chat state");
+                        continue;
+                    }
+                };
+                if chat.api_key.is_none() || !state_for_task.policy.chat_allowed(chat_id.0) {
+                    continue;
+                }
+                if chat.digest.as_ref().is_some_and(|digest| digest.is_due(Local::now())) {
+                    if let Err(e) = send_digest(&bot_for_task, &state_for_task, chat_id, &chat, true).await {
+                        tracing::error!(chat_id = chat_id.0, error = %e, "Failed to send digest");
+                    }
+                }
+                if !chat.notifications {
+                    continue;
+                }
+                // The first check after a restart records how long the bot
+                // was down, for `/catchup`.
+                if state_for_task.checked_chats.lock().await.insert(chat_id) {
+                    if let Some(from) = chat.last_checked.clone() {
+                        let downtime = Downtime { from, to: Utc::now().to_rfc3339() };
+                        if let Err(e) = state_for_task.update_chat(chat_id, |chat| chat.downtime = Some(downtime)) {
+                            tracing::error!(chat_id = chat_id.0, error = %e, "Failed to record downtime");
+                        }
+                    }
+                }
+                let sessions = check_chat_activities(
+                    &bot_for_task,
+                    &state_for_task,
+                    chat_id,
+                    &chat,
+                    &settings,
+                )
+                .await;
+                session_states.extend(sessions.into_iter().map(|session| (session.id, session.state)));
+            }
+            metrics::record_poll(started.elapsed());
+            metrics::set_sessions(session_states.values().map(Option::as_deref));
+        }
+    });
+
+    let handler = dptree::entry()
+        .branch(
+            Update::filter_message()
+                .enter_dialogue::<Message, InMemStorage<NewSessionState>, NewSessionState>()
+                .branch(dptree::entry().filter_command::<Command>().endpoint(answer))
+                .branch(
+                    // Other users of the chat keep using the bot as usual.
+                    dptree::filter(|wizard: NewSessionState, msg: Message| wizard.is_run_by(msg.from()))
+                        .endpoint(new_session_message),
+                )
+                .branch(dptree::endpoint(default_message_handler)),
+        )
+        .branch(
+            Update::filter_callback_query()
+                .enter_dialogue::<CallbackQuery, InMemStorage<NewSessionState>, NewSessionState>()
+                .branch(
+                    dptree::filter(|q: CallbackQuery| q.data.as_deref().is_some_and(|data| data.starts_with("new:")))
+                        .endpoint(new_session_callback),
+                )
+                .endpoint(callback_handler),
+        );
+
+    let webhook = match WebhookSettings::from_config(&config) {
+        Ok(Some(settings)) => match start_webhook(&bot, settings).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                tracing::warn!("{}; falling back to long polling.", e);
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(e) => {
+            tracing::warn!("{}; falling back to long polling.", e);
+            None
+        }
+    };
+
+    let mut dispatcher = Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![state, InMemStorage::<NewSessionState>::new()])
+        .error_handler(Arc::new(|error: RequestError| async move {
+            metrics::record_telegram_error(telegram_error_kind(&error));
+            tracing::error!(error = %error, "Error from an update handler");
+        }))
         .enable_ctrlc_handler()
-        .build()
-        .dispatch()
-        .await;
+        .build();
+    match webhook {
+        Some(listener) => {
+            dispatcher
+                .dispatch_with_listener(listener, LoggingErrorHandler::with_custom_text("An error from the webhook listener"))
+                .await
+        }
+        // Long polling removes any webhook left registered.
+        None => dispatcher.dispatch().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest_plan_and_feedback() {
+        let plan = |id: &str, plan_id: &str, time: &str| -> julezz::api::Activity {
+            serde_json::from_value(serde_json::json!({
+                "name": format!("sessions/1/activities/{}", id),
+                "id": id,
+                "createTime": time,
+                "originator": "agent",
+                "planGenerated": {"plan": {"id": plan_id, "steps": [
+                    {"id": "s1", "title": "Write the parser"},
+                    {"id": "s2", "title": "Add tests", "description": "Cover errors"},
+                ]}},
+            }))
+            .unwrap()
+        };
+        let approval: julezz::api::Activity = serde_json::from_value(serde_json::json!({
+            "name": "sessions/1/activities/approve",
+            "id": "approve",
+            "createTime": "2024-05-01T10:00:01Z",
+            "originator": "user",
+            "planApproved": {"planId": "p1"},
+        }))
+        .unwrap();
+
+        let mut activities = vec![plan("a", "p1", "2024-05-01T10:00:00Z"), approval];
+        assert_eq!(latest_plan(&activities).map(|(p, approved)| (p.id.as_str(), approved)), Some(("p1", true)));
+        activities.push(plan("b", "p2", "2024-05-01T10:00:02Z"));
+        assert_eq!(latest_plan(&activities).map(|(p, approved)| (p.id.as_str(), approved)), Some(("p2", false)));
+
+        let (plan, _) = latest_plan(&activities).unwrap();
+        let feedback = format_plan_feedback(plan, 2, "Use property tests").unwrap();
+        assert!(feedback.contains("step 2") && feedback.contains("Add tests") && feedback.contains("Use property tests"));
+        assert!(format_plan_feedback(plan, 3, "x").is_err());
+        assert!(format_plan_feedback(plan, 0, "x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_webhook_accepts_recorded_updates_with_the_secret() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        let url = reqwest::Url::parse(&format!("http://{}/telegram", address)).unwrap();
+        let options = webhooks::Options::new(address, url.clone()).secret_token("s3cret".to_string());
+        let _listener = serve_webhook(tcp, options).unwrap();
+
+        let update = serde_json::json!({
+            "update_id": 1,
+            "message": {
+                "message_id": 1,
+                "date": 1714557600,
+                "chat": {"id": 42, "type": "private", "first_name": "Test"},
+                "from": {"id": 42, "is_bot": false, "first_name": "Test"},
+                "text": "/help",
+            },
+        });
+        let client = reqwest::Client::new();
+        let post = |secret: &'static str| {
+            client
+                .post(url.clone())
+                .header("X-Telegram-Bot-Api-Secret-Token", secret)
+                .json(&update)
+                .send()
+        };
+        assert_eq!(post("s3cret").await.unwrap().status(), 200);
+        assert_eq!(post("wrong").await.unwrap().status(), 401);
+
+        let health = client.get(format!("http://{}/healthz", address)).send().await.unwrap();
+        assert_eq!(health.status(), 200);
+    }
+
+    #[test]
+    fn test_split_message_keeps_lines_and_code_blocks() {
+        assert_eq!(split_message("short", 4096), ["short"]);
+
+        let text = format!("intro\n{}", code_block(&"line\n".repeat(10), "diff"));
+        let chunks = split_message(&text, 40);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.encode_utf16().count() <= 40, "{:?}", chunk);
+            assert_eq!(chunk.matches("```").count() % 2, 0, "{:?}", chunk);
+        }
+        assert!(chunks[1].starts_with("```diff\n"));
+        assert_eq!(chunks.join("\n").matches("line").count(), 10);
+
+        // Long lines are split without breaking escapes.
+        let pieces = split_line(&escape_markdown_v2(&".".repeat(9)), 5);
+        assert!(pieces.iter().all(|piece| piece.len() <= 5 && !piece.ends_with('\\')));
+        assert_eq!(pieces.concat(), escape_markdown_v2(&".".repeat(9)));
+    }
+    #[test]
+    fn test_command_roles() {
+        assert_eq!(required_role(&Command::List), Role::Viewer);
+        assert_eq!(required_role(&Command::Alias("@fix 1".to_string())), Role::Operator);
+        assert_eq!(required_role(&Command::Unalias("@fix".to_string())), Role::Operator);
+        assert_eq!(required_role(&Command::Auth("key".to_string())), Role::Admin);
+        assert_eq!(required_role(&Command::Logout), Role::Admin);
+        assert_eq!(callback_role("reply"), Some(Role::Operator));
+        assert_eq!(callback_role("bogus"), None);
+    }
+
+    #[test]
+    fn test_parse_revise() {
+        assert_eq!(parse_revise("2 use a map"), Some(("", 2, "use a map".to_string())));
+        assert_eq!(parse_revise("@fix 2 use a map"), Some(("@fix", 2, "use a map".to_string())));
+        assert_eq!(parse_revise("3 2 fix it"), Some(("3", 2, "fix it".to_string())));
+        assert_eq!(parse_revise("123456 4 fix"), Some(("123456", 4, "fix".to_string())));
+        assert_eq!(parse_revise("@fix use a map"), None);
+        assert_eq!(parse_revise("2"), None);
+        assert_eq!(parse_revise("@fix 2"), None);
+        assert_eq!(parse_revise(""), None);
+    }
+
+    #[test]
+    fn test_new_session_wizard_transitions() {
+        let sources = vec!["sources/github/o/a".to_string(), "sources/github/o/b".to_string()];
+        // The configured defaults, e.g. `sessions.automation_mode = "none"`.
+        let defaults = NewSessionDraft { branch: "trunk".to_string(), auto_pr: false, ..NewSessionDraft::default() };
+        let step = WizardStep::Source { sources, defaults };
+        assert!(step.clone().answer("github/o/c").is_err());
+        assert_eq!(step.clone().press("source:7"), None);
+        assert_eq!(step.clone().press("source:1"), step.clone().answer("github/o/b").ok());
+
+        let step = step.answer("github/o/a").unwrap();
+        assert!(step.clone().answer("two words").is_err());
+        assert_eq!(step.clone().press("branch"), step.clone().answer("trunk").ok());
+        let step = step.answer("develop").unwrap();
+
+        // The prompt needs at least one message before Done.
+        assert_eq!(step.clone().press("done"), None);
+        let step = step.answer("Fix the login").unwrap().answer("and add a test").unwrap();
+        let step = step.press("done").unwrap();
+        assert!(step.clone().answer("yes").is_err());
+        let step = step.press("autopr").unwrap().press("continue").unwrap();
+        assert_eq!(step.clone().press("continue"), None);
+        assert!(step.clone().answer("fix-login").is_err());
+
+        let draft = NewSessionDraft {
+            source: "sources/github/o/a".to_string(),
+            branch: "develop".to_string(),
+            prompt: "Fix the login\nand add a test".to_string(),
+            auto_pr: true,
+        };
+        assert_eq!(step.clone().press("skip"), Some(WizardStep::Done { draft: draft.clone(), alias: None }));
+        assert_eq!(
+            step.answer("@fix-login"),
+            Ok(WizardStep::Done { draft, alias: Some("@fix-login".to_string()) })
+        );
+    }
+
+    #[test]
+    fn test_new_session_wizard_belongs_to_its_user() {
+        let user = |id: u64| -> User {
+            serde_json::from_value(serde_json::json!({"id": id, "is_bot": false, "first_name": "A"})).unwrap()
+        };
+        let state = NewSessionState::Running(Wizard {
+            id: 0,
+            user: UserId(1),
+            step: WizardStep::Source { sources: Vec::new(), defaults: NewSessionDraft::default() },
+            deadline: Instant::now(),
+        });
+        assert!(state.is_run_by(Some(&user(1))));
+        assert!(!state.is_run_by(Some(&user(2))));
+        assert!(!state.is_run_by(None));
+        assert!(!NewSessionState::Idle.is_run_by(Some(&user(1))));
+    }
 }
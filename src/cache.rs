@@ -1,9 +1,3 @@
-use crate::api;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs;
-use std::path::PathBuf;
-
 // Copyright 2024 Google LLC
 //
 // Licensed under the Apache License, Version 2.0 (the "License");
@@ -18,11 +12,23 @@ use std::path::PathBuf;
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-/// This module handles the local file-based cache for sessions and aliases.
-///
-/// The cache is responsible for storing and retrieving session information and
-/// alias mappings to and from the user's configuration directory. This allows
-/// for persistent state between application runs.
+//! This module handles the local file-based cache for sessions and aliases.
+//!
+//! The cache is responsible for storing and retrieving session information and
+//! alias mappings to and from the user's configuration directory. This allows
+//! for persistent state between application runs.
+
+use crate::api;
+use crate::credentials::SecretBox;
+use crate::digest::DigestSchedule;
+use crate::events::Event;
+use crate::preferences::NotificationPreferences;
+use crate::profile;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 /// Represents a session that is stored in the local cache.
 ///
@@ -40,6 +46,29 @@ pub struct CachedSession {
     /// The URL of the pull request.
     #[serde(rename = "pullRequestUrl")]
     pub pull_request_url: Option<String>,
+    /// The state of the session when it was cached.
+    #[serde(default)]
+    pub state: Option<String>,
+    /// The creation time of the session, in RFC 3339 format.
+    #[serde(rename = "createTime", default)]
+    pub create_time: Option<String>,
+    /// The last update time of the session, in RFC 3339 format.
+    #[serde(rename = "updateTime", default)]
+    pub update_time: Option<String>,
+}
+
+impl From<&api::Session> for CachedSession {
+    fn from(session: &api::Session) -> Self {
+        Self {
+            id: session.id.clone(),
+            title: session.title.clone(),
+            source_context: session.source_context.clone(),
+            pull_request_url: session.pull_request_url.clone(),
+            state: session.state.clone(),
+            create_time: session.create_time.clone(),
+            update_time: session.update_time.clone(),
+        }
+    }
 }
 
 /// A type alias for a map of aliases to their corresponding session IDs.
@@ -48,6 +77,178 @@ pub struct CachedSession {
 /// reference to sessions in the command-line interface.
 pub type Aliases = HashMap<String, String>;
 
+/// Returns the directory holding the cached activities of a session.
+///
+/// Activities are stored outside of the configuration directory, under the
+/// current profile's cache directory (e.g. `~/.cache/julezz/<session_id>`).
+pub fn session_activities_dir(session_id: &str) -> Result<PathBuf, String> {
+    Ok(profile::cache_dir()?.join(session_id))
+}
+
+/// Represents the state of a Telegram chat using the bot.
+///
+/// Each chat authenticates with its own API key and keeps its own current
+/// session, aliases and session list, so that several people can share a
+/// bot deployment.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatState {
+    /// The API key the chat authenticated with. It is encrypted on disk.
+    pub api_key: Option<String>,
+    /// The session free-text messages are sent to.
+    pub current_session: Option<String>,
+    /// The chat's aliases.
+    #[serde(default)]
+    pub aliases: Aliases,
+    /// The sessions last listed in the chat.
+    #[serde(default)]
+    pub sessions: Vec<CachedSession>,
+    /// Whether the chat receives activity notifications.
+    #[serde(default = "default_true")]
+    pub notifications: bool,
+    /// The session each recent notification message belongs to, by message
+    /// id, so that replies can be routed to it.
+    #[serde(default)]
+    pub notification_messages: BTreeMap<i32, String>,
+    /// The last activity notified for each session, by session id.
+    #[serde(default)]
+    pub cursors: HashMap<String, NotificationCursor>,
+    /// When the bot last checked the chat's sessions, in RFC 3339 format.
+    pub last_checked: Option<String>,
+    /// The period the bot was down for before its last restart.
+    pub downtime: Option<Downtime>,
+    /// The chat's notification preferences.
+    #[serde(default)]
+    pub preferences: NotificationPreferences,
+    /// The chat's digest schedule, if it receives digests.
+    #[serde(default)]
+    pub digest: Option<DigestSchedule>,
+}
+
+/// Represents the last activity the bot notified a chat about for a session.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationCursor {
+    /// The ID of the activity.
+    pub activity_id: String,
+    /// The creation time of the activity, in RFC 3339 format.
+    pub create_time: String,
+    /// The pull request URL of the session when it was last checked.
+    #[serde(default)]
+    pub pull_request_url: Option<String>,
+}
+
+/// Represents the state of a daemon reporting events, e.g. `julezz notify`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DaemonState {
+    /// The last activity notified for each session, by session id.
+    #[serde(default)]
+    pub cursors: HashMap<String, NotificationCursor>,
+    /// When the daemon last checked the sessions, in RFC 3339 format.
+    pub last_checked: Option<String>,
+    /// The events waiting to be delivered again.
+    #[serde(default)]
+    pub pending: Vec<PendingEvent>,
+}
+
+/// Represents an event a daemon failed to deliver to one of its sinks.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingEvent {
+    /// The name of the sink, e.g. `slack`.
+    pub sink: String,
+    /// The event.
+    pub event: Event,
+    /// The number of attempts made so far.
+    pub attempts: u32,
+}
+
+/// Represents a period during which the bot was not running.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Downtime {
+    /// When the bot last checked the chat's sessions before stopping.
+    pub from: String,
+    /// When the bot checked the chat's sessions again after restarting.
+    pub to: String,
+}
+
+impl ChatState {
+    /// The number of notification messages remembered per chat.
+    pub const MAX_NOTIFICATION_MESSAGES: usize = 500;
+
+    /// Remembers the session a notification message belongs to, forgetting
+    /// the oldest messages beyond `MAX_NOTIFICATION_MESSAGES`.
+    pub fn remember_notification(&mut self, message_id: i32, session_id: &str) {
+        self.notification_messages.insert(message_id, session_id.to_string());
+        while self.notification_messages.len() > Self::MAX_NOTIFICATION_MESSAGES {
+            self.notification_messages.pop_first();
+        }
+    }
+}
+
+impl fmt::Debug for ChatState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChatState")
+            .field("api_key", &self.api_key.as_ref().map(|_| "[redacted]"))
+            .field("current_session", &self.current_session)
+            .field("aliases", &self.aliases)
+            .field("sessions", &self.sessions)
+            .field("notifications", &self.notifications)
+            .field("notification_messages", &self.notification_messages)
+            .field("cursors", &self.cursors)
+            .field("last_checked", &self.last_checked)
+            .field("downtime", &self.downtime)
+            .field("preferences", &self.preferences)
+            .field("digest", &self.digest)
+            .finish()
+    }
+}
+
+impl Default for ChatState {
+    fn default() -> Self {
+        Self {
+            api_key: None,
+            current_session: None,
+            aliases: Aliases::new(),
+            sessions: Vec::new(),
+            notifications: true,
+            notification_messages: BTreeMap::new(),
+            cursors: HashMap::new(),
+            last_checked: None,
+            downtime: None,
+            preferences: NotificationPreferences::default(),
+            digest: None,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Writes a file that only the current user can read. An existing file is
+/// made private too.
+pub(crate) fn write_private(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)
+}
+
 /// Manages the local cache for sessions and aliases.
 ///
 /// This struct provides a centralized way to interact with the local cache,
@@ -62,31 +263,44 @@ pub struct Cache {
     chat_id_file: PathBuf,
     /// The path to the current session cache file.
     current_session_file: PathBuf,
+    /// The path to the directory holding the state of each bot chat.
+    chats_dir: PathBuf,
+    /// The path to the key file encrypting the API keys of the bot chats.
+    secrets_key_file: PathBuf,
+    /// The path to the directory holding the state files of the daemons.
+    daemons_dir: PathBuf,
 }
 
 impl Cache {
     /// Creates a new `Cache` instance.
     ///
     /// This function initializes the cache by determining the paths to the
-    /// session and alias cache files within the user's configuration directory.
-    /// It also ensures that the cache directory exists, creating it if necessary.
+    /// session and alias cache files within the current profile's
+    /// configuration directory. It also ensures that the cache directory
+    /// exists, creating it if necessary.
     ///
     /// # Returns
     ///
     /// A `Result` containing the new `Cache` instance, or an error string if
     /// the configuration directory cannot be found or created.
     pub fn new() -> Result<Self, String> {
-        let config_dir = dirs::config_dir().ok_or("Could not find config directory")?;
-        let julezz_dir = config_dir.join("julezz");
+        let julezz_dir = profile::config_dir()?;
         fs::create_dir_all(&julezz_dir)
             .map_err(|e| format!("Could not create config directory: {}", e))?;
+        Ok(Self::in_dir(&julezz_dir))
+    }
 
-        Ok(Self {
-            sessions_file: julezz_dir.join("sessions.json"),
-            aliases_file: julezz_dir.join("aliases.json"),
-            chat_id_file: julezz_dir.join("chat_id.txt"),
-            current_session_file: julezz_dir.join("current_session.txt"),
-        })
+    /// Creates a `Cache` whose files are in the given directory.
+    pub(crate) fn in_dir(dir: &Path) -> Self {
+        Self {
+            sessions_file: dir.join("sessions.json"),
+            aliases_file: dir.join("aliases.json"),
+            chat_id_file: dir.join("chat_id.txt"),
+            current_session_file: dir.join("current_session.txt"),
+            chats_dir: dir.join("chats"),
+            secrets_key_file: dir.join("secrets.key"),
+            daemons_dir: dir.to_path_buf(),
+        }
     }
 
     /// Reads the cached sessions from disk.
@@ -199,4 +413,100 @@ impl Cache {
         fs::write(&self.current_session_file, session_id)
             .map_err(|e| format!("Could not write current session file: {}", e))
     }
+
+    /// Reads the state of a bot chat.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the chat state, or `None` if the chat has no
+    /// saved state.
+    pub fn read_chat_state(&self, chat_id: i64) -> Result<Option<ChatState>, String> {
+        let path = self.chats_dir.join(format!("{}.json", chat_id));
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read chat state file: {}", e))?;
+        let mut state: ChatState = serde_json::from_str(&data)
+            .map_err(|e| format!("Could not parse chat state file: {}", e))?;
+        if let Some(api_key) = &state.api_key {
+            state.api_key = Some(SecretBox::load_or_create(&self.secrets_key_file)?.open(api_key)?);
+        }
+        Ok(Some(state))
+    }
+
+    /// Writes the state of a bot chat.
+    ///
+    /// The chat's API key is encrypted, and the file is only readable by the
+    /// current user.
+    pub fn write_chat_state(&self, chat_id: i64, state: &ChatState) -> Result<(), String> {
+        fs::create_dir_all(&self.chats_dir)
+            .map_err(|e| format!("Could not create chats directory: {}", e))?;
+        let mut state = state.clone();
+        if let Some(api_key) = &state.api_key {
+            state.api_key = Some(SecretBox::load_or_create(&self.secrets_key_file)?.seal(api_key)?);
+        }
+        let json = serde_json::to_string(&state)
+            .map_err(|e| format!("Could not serialize chat state: {}", e))?;
+        write_private(&self.chats_dir.join(format!("{}.json", chat_id)), json.as_bytes())
+            .map_err(|e| format!("Could not write chat state file: {}", e))
+    }
+
+    /// Lists the IDs of the chats with saved state.
+    pub fn list_chat_ids(&self) -> Result<Vec<i64>, String> {
+        if !self.chats_dir.exists() {
+            return Ok(Vec::new());
+        }
+        let entries = fs::read_dir(&self.chats_dir)
+            .map_err(|e| format!("Could not read chats directory: {}", e))?;
+        let mut chat_ids = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Could not read chats directory: {}", e))?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(chat_id) = name.strip_suffix(".json").and_then(|id| id.parse().ok()) {
+                chat_ids.push(chat_id);
+            }
+        }
+        chat_ids.sort();
+        Ok(chat_ids)
+    }
+
+    /// Reads the state of a daemon, e.g. `notifier`.
+    pub fn read_daemon_state(&self, daemon: &str) -> Result<DaemonState, String> {
+        let path = self.daemons_dir.join(format!("{}.json", daemon));
+        if !path.exists() {
+            return Ok(DaemonState::default());
+        }
+        let data = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read {} state file: {}", daemon, e))?;
+        serde_json::from_str(&data).map_err(|e| format!("Could not parse {} state file: {}", daemon, e))
+    }
+
+    /// Writes the state of a daemon.
+    pub fn write_daemon_state(&self, daemon: &str, state: &DaemonState) -> Result<(), String> {
+        let json = serde_json::to_string(state)
+            .map_err(|e| format!("Could not serialize {} state: {}", daemon, e))?;
+        fs::write(self.daemons_dir.join(format!("{}.json", daemon)), json)
+            .map_err(|e| format!("Could not write {} state file: {}", daemon, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_api_key_is_encrypted() {
+        let dir = crate::test_util::temp_dir("cache");
+        let cache = Cache::in_dir(&dir);
+        let chat = ChatState { api_key: Some("chat-api-key".to_string()), ..ChatState::default() };
+        cache.write_chat_state(42, &chat).unwrap();
+
+        let file = fs::read_to_string(dir.join("chats/42.json")).unwrap();
+        assert!(!file.contains("chat-api-key"), "{}", file);
+        let read = cache.read_chat_state(42).unwrap().unwrap();
+        assert_eq!(read.api_key.as_deref(), Some("chat-api-key"));
+        assert!(!format!("{:?}", read).contains("chat-api-key"));
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,970 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module handles the `config.toml` file holding per-command defaults.
+//!
+//! Each profile has its own `config.toml` in its configuration directory.
+//! Settings are resolved with the precedence flag > environment variable >
+//! config file > built-in default; flags are applied by the callers, this
+//! module takes care of the rest.
+
+use crate::cache::write_private;
+use crate::profile;
+use std::fs;
+use std::path::PathBuf;
+
+/// The kind of value a setting holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// A free-form string.
+    String,
+    /// An http(s) URL.
+    Url,
+    /// A free-form string that is never displayed, e.g. a password.
+    Secret,
+    /// A secret of 1-256 characters of A-Z, a-z, 0-9, _ and -, as Telegram
+    /// requires of webhook secrets.
+    Token,
+    /// One of a fixed set of strings.
+    Choice(&'static [&'static str]),
+    /// A non-negative integer.
+    Integer,
+    /// A positive integer, e.g. an interval.
+    PositiveInteger,
+    /// A boolean.
+    Bool,
+    /// A comma-separated list of notification event names, stored as a TOML
+    /// array.
+    EventList,
+    /// A comma-separated list of email addresses, stored as a TOML array.
+    EmailList,
+    /// A comma-separated list of Telegram user or chat ids, stored as a TOML
+    /// array.
+    TelegramIdList,
+}
+
+impl Kind {
+    /// Returns whether values of this kind are hidden when displayed.
+    pub fn is_secret(self) -> bool {
+        matches!(self, Kind::Secret | Kind::Token)
+    }
+}
+
+/// Describes a known setting.
+#[derive(Debug)]
+pub struct Setting {
+    /// The dotted key of the setting, e.g. `sessions.branch`.
+    pub key: &'static str,
+    /// The environment variable overriding the setting.
+    pub env: &'static str,
+    /// The built-in default, if any.
+    pub default: Option<&'static str>,
+    /// The kind of value the setting holds.
+    pub kind: Kind,
+    /// A short description of the setting.
+    pub description: &'static str,
+}
+
+impl Setting {
+    /// Returns a value of the setting as it may be displayed, with secrets
+    /// hidden.
+    pub fn display(&self, value: &str) -> String {
+        if self.kind.is_secret() && !value.is_empty() {
+            "********".to_string()
+        } else {
+            value.to_string()
+        }
+    }
+}
+
+/// The events the bot can notify about.
+pub const NOTIFICATION_EVENTS: &[&str] = &["plan", "question", "completed", "failed", "pr", "progress", "artifact"];
+
+/// The known settings.
+pub const SETTINGS: &[Setting] = &[
+    Setting {
+        key: "sessions.source",
+        env: "JULEZZ_SOURCE",
+        default: None,
+        kind: Kind::String,
+        description: "Default source for new sessions",
+    },
+    Setting {
+        key: "sessions.branch",
+        env: "JULEZZ_BRANCH",
+        default: Some("main"),
+        kind: Kind::String,
+        description: "Default starting branch for new sessions",
+    },
+    Setting {
+        key: "sessions.automation_mode",
+        env: "JULEZZ_AUTOMATION_MODE",
+        default: Some("auto-create-pr"),
+        kind: Kind::Choice(&["auto-create-pr", "none"]),
+        description: "Whether new sessions automatically create a pull request",
+    },
+    Setting {
+        key: "activities.count",
+        env: "JULEZZ_ACTIVITY_COUNT",
+        default: Some("5"),
+        kind: Kind::Integer,
+        description: "Number of activities shown by `activities list`",
+    },
+    Setting {
+        key: "output.format",
+        env: "JULEZZ_OUTPUT_FORMAT",
+        default: Some("text"),
+        kind: Kind::Choice(&["text", "json"]),
+        description: "Output format of listing commands",
+    },
+    Setting {
+        key: "output.color",
+        env: "JULEZZ_COLOR",
+        default: Some("auto"),
+        kind: Kind::Choice(&["auto", "always", "never"]),
+        description: "When to use colours",
+    },
+    Setting {
+        key: "output.pager",
+        env: "JULEZZ_PAGER",
+        default: None,
+        kind: Kind::String,
+        description: "Pager command for long output, e.g. `less -R`",
+    },
+    Setting {
+        key: "bot.poll_interval_seconds",
+        env: "JULEZZ_POLL_INTERVAL_SECONDS",
+        default: Some("30"),
+        kind: Kind::PositiveInteger,
+        description: "Interval at which the bot checks for new activities",
+    },
+    Setting {
+        key: "bot.max_poll_interval_seconds",
+        env: "JULEZZ_MAX_POLL_INTERVAL_SECONDS",
+        default: Some("600"),
+        kind: Kind::PositiveInteger,
+        description: "Longest interval at which the bot checks an idle session",
+    },
+    Setting {
+        key: "bot.poll_concurrency",
+        env: "JULEZZ_POLL_CONCURRENCY",
+        default: Some("4"),
+        kind: Kind::PositiveInteger,
+        description: "Number of sessions the bot fetches activities for in parallel",
+    },
+    Setting {
+        key: "bot.admins",
+        env: "JULEZZ_BOT_ADMINS",
+        default: None,
+        kind: Kind::TelegramIdList,
+        description: "Telegram user ids with the admin role",
+    },
+    Setting {
+        key: "bot.operators",
+        env: "JULEZZ_BOT_OPERATORS",
+        default: None,
+        kind: Kind::TelegramIdList,
+        description: "Telegram user ids with the operator role",
+    },
+    Setting {
+        key: "bot.viewers",
+        env: "JULEZZ_BOT_VIEWERS",
+        default: None,
+        kind: Kind::TelegramIdList,
+        description: "Telegram user ids with the viewer role",
+    },
+    Setting {
+        key: "bot.default_role",
+        env: "JULEZZ_BOT_DEFAULT_ROLE",
+        default: Some("none"),
+        kind: Kind::Choice(&["none", "viewer", "operator", "admin"]),
+        description: "Role of Telegram users not listed in any role",
+    },
+    Setting {
+        key: "bot.allowed_chats",
+        env: "JULEZZ_BOT_ALLOWED_CHATS",
+        default: None,
+        kind: Kind::TelegramIdList,
+        description: "Telegram chat ids the bot answers in (all chats if empty)",
+    },
+    Setting {
+        key: "bot.webhook_url",
+        env: "JULEZZ_WEBHOOK_URL",
+        default: None,
+        kind: Kind::Url,
+        description: "Public URL Telegram sends updates to (long polling if unset)",
+    },
+    Setting {
+        key: "bot.webhook_listen",
+        env: "JULEZZ_WEBHOOK_LISTEN",
+        default: Some("0.0.0.0:8443"),
+        kind: Kind::String,
+        description: "Address the webhook listener binds to",
+    },
+    Setting {
+        key: "bot.webhook_secret",
+        env: "JULEZZ_WEBHOOK_SECRET",
+        default: None,
+        kind: Kind::Token,
+        description: "Secret token Telegram sends with every update (generated if unset)",
+    },
+    Setting {
+        key: "bot.webhook_register",
+        env: "JULEZZ_WEBHOOK_REGISTER",
+        default: Some("true"),
+        kind: Kind::Bool,
+        description: "Register the webhook with Telegram on start (turn off to test locally)",
+    },
+    Setting {
+        key: "notifications.events",
+        env: "JULEZZ_NOTIFY",
+        default: Some("plan,question,completed,failed,pr,progress,artifact"),
+        kind: Kind::EventList,
+        description: "Events the bot and `julezz notify` report (plan, question, completed, failed, pr, progress, artifact)",
+    },
+    Setting {
+        key: "notify.slack_webhook_url",
+        env: "JULEZZ_SLACK_WEBHOOK_URL",
+        default: None,
+        kind: Kind::Url,
+        description: "Slack incoming webhook `julezz notify` posts events to",
+    },
+    Setting {
+        key: "notify.discord_webhook_url",
+        env: "JULEZZ_DISCORD_WEBHOOK_URL",
+        default: None,
+        kind: Kind::Url,
+        description: "Discord webhook `julezz notify` posts events to",
+    },
+    Setting {
+        key: "notify.webhook_url",
+        env: "JULEZZ_NOTIFY_WEBHOOK_URL",
+        default: None,
+        kind: Kind::Url,
+        description: "URL `julezz notify` posts events to as JSON",
+    },
+    Setting {
+        key: "notify.matrix_homeserver_url",
+        env: "JULEZZ_MATRIX_HOMESERVER_URL",
+        default: None,
+        kind: Kind::Url,
+        description: "Matrix homeserver `julezz notify` sends events through",
+    },
+    Setting {
+        key: "notify.matrix_room_id",
+        env: "JULEZZ_MATRIX_ROOM_ID",
+        default: None,
+        kind: Kind::String,
+        description: "Matrix room id `julezz notify` sends events to, e.g. `!abc:example.org`",
+    },
+    Setting {
+        key: "notify.matrix_access_token",
+        env: "JULEZZ_MATRIX_ACCESS_TOKEN",
+        default: None,
+        kind: Kind::Secret,
+        description: "Access token of the Matrix user sending events",
+    },
+    Setting {
+        key: "notify.smtp_host",
+        env: "JULEZZ_SMTP_HOST",
+        default: None,
+        kind: Kind::String,
+        description: "SMTP server `julezz notify` sends event emails through",
+    },
+    Setting {
+        key: "notify.smtp_port",
+        env: "JULEZZ_SMTP_PORT",
+        default: None,
+        kind: Kind::PositiveInteger,
+        description: "SMTP port (defaults to 587 with starttls, 465 with tls, 25 without)",
+    },
+    Setting {
+        key: "notify.smtp_tls",
+        env: "JULEZZ_SMTP_TLS",
+        default: Some("starttls"),
+        kind: Kind::Choice(&["starttls", "tls", "none"]),
+        description: "How the SMTP connection is encrypted",
+    },
+    Setting {
+        key: "notify.smtp_username",
+        env: "JULEZZ_SMTP_USERNAME",
+        default: None,
+        kind: Kind::String,
+        description: "SMTP user name (no authentication if unset)",
+    },
+    Setting {
+        key: "notify.smtp_password",
+        env: "JULEZZ_SMTP_PASSWORD",
+        default: None,
+        kind: Kind::Secret,
+        description: "SMTP password",
+    },
+    Setting {
+        key: "notify.smtp_from",
+        env: "JULEZZ_SMTP_FROM",
+        default: None,
+        kind: Kind::String,
+        description: "Sender address of event emails",
+    },
+    Setting {
+        key: "notify.smtp_to",
+        env: "JULEZZ_SMTP_TO",
+        default: None,
+        kind: Kind::EmailList,
+        description: "Recipients of event emails",
+    },
+    Setting {
+        key: "notify.telegram_chat_id",
+        env: "JULEZZ_NOTIFY_TELEGRAM_CHAT_ID",
+        default: None,
+        kind: Kind::String,
+        description: "Telegram chat `julezz notify` sends events to, with the TELOXIDE_TOKEN bot",
+    },
+    Setting {
+        key: "hooks.plan",
+        env: "JULEZZ_HOOK_PLAN",
+        default: None,
+        kind: Kind::String,
+        description: "Command run when a plan is generated",
+    },
+    Setting {
+        key: "hooks.question",
+        env: "JULEZZ_HOOK_QUESTION",
+        default: None,
+        kind: Kind::String,
+        description: "Command run when the agent sends a message",
+    },
+    Setting {
+        key: "hooks.completed",
+        env: "JULEZZ_HOOK_COMPLETED",
+        default: None,
+        kind: Kind::String,
+        description: "Command run when a session completes",
+    },
+    Setting {
+        key: "hooks.failed",
+        env: "JULEZZ_HOOK_FAILED",
+        default: None,
+        kind: Kind::String,
+        description: "Command run when a session fails",
+    },
+    Setting {
+        key: "hooks.pr",
+        env: "JULEZZ_HOOK_PR",
+        default: None,
+        kind: Kind::String,
+        description: "Command run when a pull request is opened",
+    },
+    Setting {
+        key: "hooks.progress",
+        env: "JULEZZ_HOOK_PROGRESS",
+        default: None,
+        kind: Kind::String,
+        description: "Command run when the agent reports progress",
+    },
+    Setting {
+        key: "hooks.artifact",
+        env: "JULEZZ_HOOK_ARTIFACT",
+        default: None,
+        kind: Kind::String,
+        description: "Command run when the agent produces artifacts, e.g. a change set",
+    },
+    Setting {
+        key: "hooks.timeout_seconds",
+        env: "JULEZZ_HOOK_TIMEOUT_SECONDS",
+        default: Some("60"),
+        kind: Kind::PositiveInteger,
+        description: "Time after which a hook is killed",
+    },
+    Setting {
+        key: "webhooks.max_attempts",
+        env: "JULEZZ_WEBHOOKS_MAX_ATTEMPTS",
+        default: Some("8"),
+        kind: Kind::PositiveInteger,
+        description: "Delivery attempts before `julezz serve webhooks` gives up on an event",
+    },
+    Setting {
+        key: "webhooks.timeout_seconds",
+        env: "JULEZZ_WEBHOOKS_TIMEOUT_SECONDS",
+        default: Some("10"),
+        kind: Kind::PositiveInteger,
+        description: "Time after which a webhook delivery fails",
+    },
+    Setting {
+        key: "gateway.listen",
+        env: "JULEZZ_GATEWAY_LISTEN",
+        default: Some("127.0.0.1:8765"),
+        kind: Kind::String,
+        description: "Address the `julezz serve` gateway binds to",
+    },
+    Setting {
+        key: "gateway.token",
+        env: "JULEZZ_GATEWAY_TOKEN",
+        default: None,
+        kind: Kind::Secret,
+        description: "Bearer token the gateway requires from its clients, if set",
+    },
+    Setting {
+        key: "gateway.cache_seconds",
+        env: "JULEZZ_GATEWAY_CACHE_SECONDS",
+        default: Some("10"),
+        kind: Kind::Integer,
+        description: "Time the gateway reuses the session list for",
+    },
+    Setting {
+        key: "gateway.concurrency",
+        env: "JULEZZ_GATEWAY_CONCURRENCY",
+        default: Some("4"),
+        kind: Kind::PositiveInteger,
+        description: "Maximum number of API requests the gateway makes at a time",
+    },
+    Setting {
+        key: "metrics.listen",
+        env: "JULEZZ_METRICS_LISTEN",
+        default: None,
+        kind: Kind::String,
+        description: "Address serving Prometheus metrics at /metrics for the bot and daemons, if set",
+    },
+    Setting {
+        key: "log.format",
+        env: "JULEZZ_LOG_FORMAT",
+        default: Some("text"),
+        kind: Kind::Choice(&["text", "json"]),
+        description: "Format of the logs written to stderr",
+    },
+];
+
+/// Where the effective value of a setting comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+    Env,
+    File,
+    Default,
+}
+
+/// The output format of listing commands, and of the logs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// When to use colours.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+/// Manages the `config.toml` file of a profile.
+pub struct Config {
+    /// The path to the config file.
+    path: PathBuf,
+    /// The parsed contents of the config file.
+    table: toml::Table,
+}
+
+impl Config {
+    /// Loads the config file of the current profile.
+    ///
+    /// A missing config file is treated as empty.
+    pub fn load() -> Result<Self, String> {
+        Self::load_from(profile::config_dir()?.join("config.toml"))
+    }
+
+    /// Loads the config file of the given profile.
+    pub fn load_profile(name: &str) -> Result<Self, String> {
+        Self::load_from(profile::dir_for(name)?.join("config.toml"))
+    }
+
+    pub(crate) fn load_from(path: PathBuf) -> Result<Self, String> {
+        let table = if path.exists() {
+            let data = fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read config file: {}", e))?;
+            data.parse::<toml::Table>()
+                .map_err(|e| format!("Could not parse config file {}: {}", path.display(), e))?
+        } else {
+            toml::Table::new()
+        };
+        let config = Self { path, table };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Returns the path to the config file.
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Writes the config file to disk.
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Could not create config directory: {}", e))?;
+        }
+        let data = toml::to_string_pretty(&self.table)
+            .map_err(|e| format!("Could not serialize config: {}", e))?;
+        // The file may hold secrets, e.g. `notify.smtp_password`.
+        write_private(&self.path, data.as_bytes()).map_err(|e| format!("Could not write config file: {}", e))
+    }
+
+    /// Returns the value of a setting as stored in the config file.
+    pub fn file_value(&self, key: &str) -> Option<String> {
+        let mut parts = key.split('.');
+        let section = self.table.get(parts.next()?)?.as_table()?;
+        match section.get(parts.next()?)? {
+            toml::Value::String(s) => Some(s.clone()),
+            toml::Value::Array(values) => Some(
+                values
+                    .iter()
+                    .map(|v| v.as_str().map(str::to_string).unwrap_or_else(|| v.to_string()))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            value => Some(value.to_string()),
+        }
+    }
+
+    /// Returns the effective value of a setting and where it comes from,
+    /// ignoring command-line flags.
+    pub fn resolve(&self, key: &str) -> Result<Option<(String, Origin)>, String> {
+        let setting = setting(key)?;
+        if let Ok(value) = std::env::var(setting.env) {
+            if !value.is_empty() {
+                check_value(setting, &value)?;
+                return Ok(Some((value, Origin::Env)));
+            }
+        }
+        if let Some(value) = self.file_value(key) {
+            return Ok(Some((value, Origin::File)));
+        }
+        Ok(setting.default.map(|value| (value.to_string(), Origin::Default)))
+    }
+
+    /// Sets a setting in the config file. The file is not saved.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let setting = setting(key)?;
+        let value = check_value(setting, value)?;
+        let (section, name) = key.split_once('.').ok_or("Invalid key")?;
+        let entry = self
+            .table
+            .entry(section.to_string())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        let table = entry
+            .as_table_mut()
+            .ok_or_else(|| format!("'{}' is not a table in the config file", section))?;
+        table.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// Removes a setting from the config file. The file is not saved.
+    pub fn unset(&mut self, key: &str) -> Result<bool, String> {
+        setting(key)?;
+        let (section, name) = key.split_once('.').ok_or("Invalid key")?;
+        Ok(self
+            .table
+            .get_mut(section)
+            .and_then(|s| s.as_table_mut())
+            .and_then(|s| s.remove(name))
+            .is_some())
+    }
+
+    /// Checks that every setting in the config file is known and valid.
+    fn validate(&self) -> Result<(), String> {
+        for (section, values) in &self.table {
+            let values = values
+                .as_table()
+                .ok_or_else(|| format!("Unknown config setting '{}'", section))?;
+            for name in values.keys() {
+                let key = format!("{}.{}", section, name);
+                let setting = setting(&key)?;
+                if let Some(value) = self.file_value(&key) {
+                    check_value(setting, &value)
+                        .map_err(|e| format!("Invalid value in {}: {}", self.path.display(), e))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn resolved(&self, key: &str) -> Option<String> {
+        match self.resolve(key) {
+            Ok(value) => value.map(|(value, _)| value),
+            Err(e) => {
+                tracing::warn!(key, error = %e, "Ignoring setting");
+                setting(key).ok().and_then(|s| s.default).map(str::to_string)
+            }
+        }
+    }
+
+    /// The default source for new sessions.
+    pub fn source(&self) -> Option<String> {
+        self.resolved("sessions.source")
+    }
+
+    /// The default starting branch for new sessions.
+    pub fn branch(&self) -> String {
+        self.resolved("sessions.branch").unwrap_or_else(|| "main".to_string())
+    }
+
+    /// Whether new sessions automatically create a pull request.
+    pub fn auto_pr(&self) -> bool {
+        self.resolved("sessions.automation_mode").as_deref() != Some("none")
+    }
+
+    /// The number of activities shown by `activities list`.
+    pub fn activity_count(&self) -> usize {
+        self.resolved("activities.count")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5)
+    }
+
+    /// The output format of listing commands.
+    pub fn output_format(&self) -> OutputFormat {
+        match self.resolved("output.format").as_deref() {
+            Some("json") => OutputFormat::Json,
+            _ => OutputFormat::Text,
+        }
+    }
+
+    /// When to use colours.
+    pub fn color(&self) -> ColorMode {
+        match self.resolved("output.color").as_deref() {
+            Some("always") => ColorMode::Always,
+            Some("never") => ColorMode::Never,
+            _ => ColorMode::Auto,
+        }
+    }
+
+    /// The pager command for long output, if any.
+    pub fn pager(&self) -> Option<String> {
+        self.resolved("output.pager").filter(|p| !p.is_empty())
+    }
+
+    /// The interval, in seconds, at which the bot checks for new activities.
+    pub fn poll_interval_seconds(&self) -> u64 {
+        self.resolved("bot.poll_interval_seconds")
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(30)
+    }
+
+    /// The longest interval, in seconds, at which the bot checks an idle
+    /// session.
+    pub fn max_poll_interval_seconds(&self) -> u64 {
+        self.resolved("bot.max_poll_interval_seconds")
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(600)
+    }
+
+    /// The number of sessions the bot fetches activities for in parallel.
+    pub fn poll_concurrency(&self) -> usize {
+        self.resolved("bot.poll_concurrency")
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(4)
+    }
+
+    /// The Telegram user ids listed in a bot role setting, e.g. `bot.admins`.
+    pub fn bot_users(&self, key: &str) -> Vec<u64> {
+        self.resolved(key)
+            .map(|v| split_list(&v).iter().filter_map(|id| id.parse().ok()).collect())
+            .unwrap_or_default()
+    }
+
+    /// The Telegram chat ids the bot answers in. Empty means every chat.
+    pub fn bot_allowed_chats(&self) -> Vec<i64> {
+        self.resolved("bot.allowed_chats")
+            .map(|v| split_list(&v).iter().filter_map(|id| id.parse().ok()).collect())
+            .unwrap_or_default()
+    }
+
+    /// The role of Telegram users not listed in any role, if any.
+    pub fn bot_default_role(&self) -> Option<String> {
+        self.resolved("bot.default_role").filter(|r| r != "none")
+    }
+
+    /// The public URL of the bot's webhook, if the bot should use one.
+    pub fn webhook_url(&self) -> Option<String> {
+        self.resolved("bot.webhook_url").filter(|u| !u.is_empty())
+    }
+
+    /// The address the webhook listener binds to.
+    pub fn webhook_listen(&self) -> String {
+        self.resolved("bot.webhook_listen")
+            .unwrap_or_else(|| "0.0.0.0:8443".to_string())
+    }
+
+    /// The secret token of the webhook, if set.
+    pub fn webhook_secret(&self) -> Option<String> {
+        self.resolved("bot.webhook_secret").filter(|s| !s.is_empty())
+    }
+
+    /// Whether the bot registers its webhook with Telegram on start.
+    pub fn webhook_register(&self) -> bool {
+        self.resolved("bot.webhook_register")
+            .map(|v| v == "true")
+            .unwrap_or(true)
+    }
+
+    /// The events the bot and `julezz notify` report.
+    pub fn notification_events(&self) -> Vec<String> {
+        self.resolved("notifications.events")
+            .map(|v| split_list(&v))
+            .unwrap_or_default()
+    }
+
+    /// The hook command of an event kind, if any.
+    pub fn hook(&self, kind: &str) -> Option<String> {
+        self.resolved(&format!("hooks.{}", kind)).filter(|c| !c.is_empty())
+    }
+
+    /// The time, in seconds, after which a hook is killed.
+    pub fn hook_timeout_seconds(&self) -> u64 {
+        self.resolved("hooks.timeout_seconds")
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(60)
+    }
+
+    /// The number of attempts to deliver an event to a webhook endpoint.
+    pub fn webhook_max_attempts(&self) -> u32 {
+        self.resolved("webhooks.max_attempts")
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(8)
+    }
+
+    /// The time, in seconds, after which a webhook delivery fails.
+    pub fn webhook_timeout_seconds(&self) -> u64 {
+        self.resolved("webhooks.timeout_seconds")
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(10)
+    }
+
+    /// The address the gateway binds to.
+    pub fn gateway_listen(&self) -> String {
+        self.resolved("gateway.listen")
+            .unwrap_or_else(|| "127.0.0.1:8765".to_string())
+    }
+
+    /// The bearer token the gateway requires, if set.
+    pub fn gateway_token(&self) -> Option<String> {
+        self.resolved("gateway.token").filter(|s| !s.is_empty())
+    }
+
+    /// The time, in seconds, during which the gateway reuses the session list.
+    pub fn gateway_cache_seconds(&self) -> u64 {
+        self.resolved("gateway.cache_seconds")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10)
+    }
+
+    /// The maximum number of API requests the gateway makes at a time.
+    pub fn gateway_concurrency(&self) -> usize {
+        self.resolved("gateway.concurrency")
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(4)
+    }
+
+    /// The address serving the metrics, if set.
+    pub fn metrics_listen(&self) -> Option<String> {
+        self.resolved("metrics.listen").filter(|s| !s.is_empty())
+    }
+
+    /// The format of the logs.
+    pub fn log_format(&self) -> OutputFormat {
+        match self.resolved("log.format").as_deref() {
+            Some("json") => OutputFormat::Json,
+            _ => OutputFormat::Text,
+        }
+    }
+}
+
+/// Looks up a known setting by key.
+pub fn setting(key: &str) -> Result<&'static Setting, String> {
+    SETTINGS
+        .iter()
+        .find(|s| s.key == key)
+        .ok_or_else(|| format!("Unknown config setting '{}'", key))
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Checks a value against the kind of a setting and converts it to TOML.
+fn check_value(setting: &Setting, value: &str) -> Result<toml::Value, String> {
+    match setting.kind {
+        Kind::Url => {
+            if value.starts_with("https://") || value.starts_with("http://") {
+                Ok(toml::Value::String(value.to_string()))
+            } else {
+                Err(format!("'{}' must be an http(s) URL, not '{}'", setting.key, value))
+            }
+        }
+        Kind::Token => {
+            let valid = (1..=256).contains(&value.len())
+                && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            if valid {
+                Ok(toml::Value::String(value.to_string()))
+            } else {
+                Err(format!(
+                    "'{}' must be 1-256 characters of A-Z, a-z, 0-9, _ and -",
+                    setting.key
+                ))
+            }
+        }
+        Kind::String | Kind::Secret => Ok(toml::Value::String(value.to_string())),
+        Kind::Choice(choices) => {
+            if choices.contains(&value) {
+                Ok(toml::Value::String(value.to_string()))
+            } else {
+                Err(format!(
+                    "'{}' must be one of {}, not '{}'",
+                    setting.key,
+                    choices.join(", "),
+                    value
+                ))
+            }
+        }
+        Kind::Integer => value
+            .parse::<u64>()
+            .ok()
+            .and_then(|v| i64::try_from(v).ok())
+            .map(toml::Value::Integer)
+            .ok_or_else(|| format!("'{}' must be a non-negative integer, not '{}'", setting.key, value)),
+        Kind::PositiveInteger => value
+            .parse::<u64>()
+            .ok()
+            .filter(|&v| v > 0)
+            .and_then(|v| i64::try_from(v).ok())
+            .map(toml::Value::Integer)
+            .ok_or_else(|| format!("'{}' must be a positive integer, not '{}'", setting.key, value)),
+        Kind::Bool => value
+            .parse::<bool>()
+            .map(toml::Value::Boolean)
+            .map_err(|_| format!("'{}' must be true or false, not '{}'", setting.key, value)),
+        Kind::EventList => list_value(value, |item| {
+            if NOTIFICATION_EVENTS.contains(&item) {
+                Ok(())
+            } else {
+                Err(format!("Unknown notification event '{}'. Use {}.", item, NOTIFICATION_EVENTS.join(", ")))
+            }
+        }),
+        Kind::EmailList => list_value(value, |item| {
+            if item.contains('@') {
+                Ok(())
+            } else {
+                Err(format!("'{}' must be a list of email addresses, not '{}'", setting.key, item))
+            }
+        }),
+        Kind::TelegramIdList => list_value(value, |item| {
+            item.parse::<i64>()
+                .map(|_| ())
+                .map_err(|_| format!("'{}' must be a list of Telegram ids, not '{}'", setting.key, item))
+        }),
+    }
+}
+
+/// Splits the value of a list setting into a TOML array, checking each item.
+fn list_value(value: &str, check: impl Fn(&str) -> Result<(), String>) -> Result<toml::Value, String> {
+    let items = split_list(value);
+    for item in &items {
+        check(item)?;
+    }
+    Ok(toml::Value::Array(items.into_iter().map(toml::Value::String).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_config() -> Config {
+        Config {
+            path: std::env::temp_dir().join("julezz-test-config.toml"),
+            table: toml::Table::new(),
+        }
+    }
+
+    #[test]
+    fn test_set_validates_values() {
+        let mut config = empty_config();
+        assert!(config.set("output.format", "yaml").is_err());
+        assert!(config.set("activities.count", "-1").is_err());
+        assert!(config.set("notifications.events", "plan,bogus").is_err());
+        assert!(config.set("nope.key", "1").is_err());
+        assert!(config.set("bot.admins", "12345,@someone").is_err());
+        assert!(config.set("bot.webhook_url", "bot.example.com").is_err());
+        assert!(config.set("bot.webhook_secret", "not secret!").is_err());
+        assert!(config.set("notify.slack_webhook_url", "hooks.slack.com").is_err());
+        assert!(config.set("notify.smtp_to", "ops@example.com,ops").is_err());
+        assert!(config.set("bot.allowed_chats", "-100123,team").is_err());
+        assert!(config.set("bot.poll_interval_seconds", "0").is_err());
+
+        config.set("activities.count", "12").unwrap();
+        config.set("notifications.events", "plan, question").unwrap();
+        // Only Telegram's webhook secret is restricted to a token's characters.
+        config.set("notify.smtp_password", "not secret!").unwrap();
+        assert_eq!(config.file_value("activities.count").as_deref(), Some("12"));
+        assert_eq!(config.activity_count(), 12);
+        assert_eq!(config.file_value("notifications.events").as_deref(), Some("plan,question"));
+
+        // An interval of 0 written to the file by hand falls back to the default.
+        config.table.insert("bot".to_string(), toml::toml! { poll_interval_seconds = 0 }.into());
+        assert_eq!(config.poll_interval_seconds(), 30);
+    }
+
+    #[test]
+    fn test_file_values_round_trip_through_toml() {
+        let mut config = empty_config();
+        config.set("sessions.branch", "develop").unwrap();
+        config.set("sessions.automation_mode", "none").unwrap();
+        let data = toml::to_string_pretty(&config.table).unwrap();
+
+        let table = data.parse::<toml::Table>().unwrap();
+        let config = Config { path: config.path, table };
+        config.validate().unwrap();
+        assert_eq!(config.file_value("sessions.branch").as_deref(), Some("develop"));
+        assert!(!config.auto_pr());
+    }
+
+    #[test]
+    fn test_secrets_are_hidden_and_saved_privately() {
+        assert_eq!(setting("notify.smtp_password").unwrap().display("hunter2"), "********");
+        assert_eq!(setting("bot.webhook_secret").unwrap().display("abc"), "********");
+        assert_eq!(setting("gateway.token").unwrap().display(""), "");
+        assert_eq!(setting("sessions.branch").unwrap().display("main"), "main");
+
+        let path = std::env::temp_dir().join(format!("julezz-config-private-{}.toml", std::process::id()));
+        fs::write(&path, "").unwrap();
+        let mut config = Config::load_from(path.clone()).unwrap();
+        config.set("gateway.token", "secret").unwrap();
+        config.save().unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,455 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module handles the resolution and secure storage of API keys.
+//!
+//! When no API key is given explicitly, the key is looked up through a chain
+//! of providers, in order:
+//!
+//! 1. the environment variable referenced by the current profile,
+//! 2. the profile's credential helper, a command speaking a protocol modelled
+//!    on git's credential helpers,
+//! 3. the encrypted credential file written by `julezz auth login`.
+//!
+//! The credential file is encrypted with ChaCha20-Poly1305, using a key
+//! derived with Argon2id from either a passphrase or a user-provided key file.
+
+use crate::cache::write_private;
+use crate::profile::{self, Profile};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The name of the encrypted credential file within a profile's directory.
+const CREDENTIALS_FILE: &str = "credentials.enc";
+
+/// Describes how the key protecting the credential file is obtained.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum Protection {
+    /// The key is derived from a passphrase, read from `JULEZZ_PASSPHRASE`
+    /// or prompted for.
+    Passphrase,
+    /// The key is derived from the contents of a key file.
+    KeyFile { path: PathBuf },
+}
+
+/// Represents the on-disk format of the encrypted credential file.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EncryptedCredentials {
+    protection: Protection,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Identifies the provider an API key was resolved from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeySource {
+    /// The environment variable referenced by the profile.
+    ProfileEnv(String),
+    /// The profile's credential helper.
+    Helper(String),
+    /// The encrypted credential file.
+    CredentialFile,
+}
+
+/// Resolves the API key for the current profile through the provider chain.
+///
+/// # Returns
+///
+/// A `Result` containing the API key and where it came from, `None` if no
+/// provider has a key, or an error string if a provider failed (e.g. the
+/// passphrase is wrong).
+pub fn resolve_api_key() -> Result<Option<(String, KeySource)>, String> {
+    resolve_with(&profile::load_current()?, &credentials_path()?)
+}
+
+/// Resolves the API key of a profile whose credential file is at `path`.
+fn resolve_with(profile: &Profile, path: &Path) -> Result<Option<(String, KeySource)>, String> {
+    if let Some(key) = profile.api_key() {
+        let var = profile.api_key_env.clone().unwrap_or_default();
+        return Ok(Some((key, KeySource::ProfileEnv(var))));
+    }
+
+    if let Some(helper) = &profile.credential_helper {
+        if let Some(key) = helper_get(helper)? {
+            return Ok(Some((key, KeySource::Helper(helper.clone()))));
+        }
+    }
+
+    if path.exists() {
+        return load_encrypted(path).map(|key| Some((key, KeySource::CredentialFile)));
+    }
+
+    Ok(None)
+}
+
+/// Encrypts and stores an API key in the current profile's credential file.
+pub fn store_encrypted(api_key: &str, protection: Protection) -> Result<(), String> {
+    let secret = protection_secret(&protection, true)?;
+    write_encrypted(&credentials_path()?, api_key, protection, &secret)
+}
+
+/// Encrypts an API key with the given secret and writes it to `path`.
+fn write_encrypted(path: &Path, api_key: &str, protection: Protection, secret: &[u8]) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(&encrypt(api_key, protection, secret)?)
+        .map_err(|e| format!("Could not serialize credentials: {}", e))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Could not create config directory: {}", e))?;
+    }
+    write_private(path, json.as_bytes())
+        .map_err(|e| format!("Could not write credential file: {}", e))
+}
+
+fn encrypt(api_key: &str, protection: Protection, secret: &[u8]) -> Result<EncryptedCredentials, String> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let cipher = ChaCha20Poly1305::new(&derive_key(secret, &salt)?);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, api_key.as_bytes())
+        .map_err(|_| "Could not encrypt credentials".to_string())?;
+    Ok(EncryptedCredentials {
+        protection,
+        salt: BASE64.encode(salt),
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    })
+}
+
+/// Removes the current profile's credential file.
+///
+/// # Returns
+///
+/// A `Result` containing whether a credential file existed.
+pub fn remove_encrypted() -> Result<bool, String> {
+    let path = credentials_path()?;
+    if !path.exists() {
+        return Ok(false);
+    }
+    fs::remove_file(&path).map_err(|e| format!("Could not remove credential file: {}", e))?;
+    Ok(true)
+}
+
+/// Asks a credential helper to store an API key.
+pub fn helper_store(helper: &str, api_key: &str) -> Result<(), String> {
+    run_helper(helper, "store", Some(api_key)).map(|_| ())
+}
+
+/// Asks a credential helper to forget the API key.
+pub fn helper_erase(helper: &str) -> Result<(), String> {
+    run_helper(helper, "erase", None).map(|_| ())
+}
+
+/// Asks a credential helper for the API key.
+///
+/// The helper prints `key=value` lines; the key is read from `api_key`, or
+/// from `password` so that generic git credential helpers can be reused.
+fn helper_get(helper: &str) -> Result<Option<String>, String> {
+    let output = run_helper(helper, "get", None)?;
+    let mut password = None;
+    for line in output.lines() {
+        if let Some((key, value)) = line.split_once('=') {
+            match key {
+                "api_key" => return Ok(Some(value.to_string())),
+                "password" => password = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+    Ok(password.filter(|p| !p.is_empty()))
+}
+
+/// Runs a credential helper with the given action.
+///
+/// Like git, the helper is run through the shell with the action appended as
+/// its last argument, and receives a description of the credential on stdin
+/// as `key=value` lines terminated by a blank line.
+fn run_helper(helper: &str, action: &str, api_key: Option<&str>) -> Result<String, String> {
+    let mut input = format!(
+        "protocol=https\nhost=jules.googleapis.com\nusername={}\n",
+        profile::current()
+    );
+    if let Some(api_key) = api_key {
+        input.push_str(&format!("password={}\napi_key={}\n", api_key, api_key));
+    }
+    input.push('\n');
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} {}", helper, action))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| format!("Failed to run credential helper: {}", e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // Like git, helpers that exit without reading their input are fine.
+        match stdin.write_all(input.as_bytes()) {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+                return Err(format!("Failed to write to credential helper: {}", e));
+            }
+            _ => {}
+        }
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to run credential helper: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Credential helper '{}' failed on '{}' ({})",
+            helper, action, output.status
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Returns the path of the current profile's credential file.
+fn credentials_path() -> Result<PathBuf, String> {
+    Ok(profile::config_dir()?.join(CREDENTIALS_FILE))
+}
+
+fn load_encrypted(path: &Path) -> Result<String, String> {
+    let data =
+        fs::read_to_string(path).map_err(|e| format!("Could not read credential file: {}", e))?;
+    let credentials: EncryptedCredentials = serde_json::from_str(&data)
+        .map_err(|e| format!("Could not parse credential file: {}", e))?;
+    let secret = protection_secret(&credentials.protection, false)?;
+    decrypt(&credentials, &secret)
+}
+
+fn decrypt(credentials: &EncryptedCredentials, secret: &[u8]) -> Result<String, String> {
+    let salt = BASE64
+        .decode(&credentials.salt)
+        .map_err(|e| format!("Invalid credential file: {}", e))?;
+    let nonce = BASE64
+        .decode(&credentials.nonce)
+        .map_err(|e| format!("Invalid credential file: {}", e))?;
+    let ciphertext = BASE64
+        .decode(&credentials.ciphertext)
+        .map_err(|e| format!("Invalid credential file: {}", e))?;
+    if nonce.len() != 12 {
+        return Err("Invalid credential file: bad nonce length".to_string());
+    }
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(secret, &salt)?);
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| match credentials.protection {
+            Protection::Passphrase => "Could not decrypt credentials: wrong passphrase".to_string(),
+            Protection::KeyFile { .. } => "Could not decrypt credentials: wrong key file".to_string(),
+        })?;
+    String::from_utf8(plaintext).map_err(|_| "Invalid credential file: key is not UTF-8".to_string())
+}
+
+/// Obtains the secret protecting the credential file.
+///
+/// For passphrases, `JULEZZ_PASSPHRASE` is used when set, otherwise the user
+/// is prompted (twice when `confirm` is set). Without a terminal to prompt
+/// on, e.g. in the bot or the daemons, this fails instead of blocking.
+fn protection_secret(protection: &Protection, confirm: bool) -> Result<Vec<u8>, String> {
+    match protection {
+        Protection::Passphrase => {
+            if let Ok(passphrase) = std::env::var("JULEZZ_PASSPHRASE") {
+                if passphrase.is_empty() {
+                    return Err("JULEZZ_PASSPHRASE must not be empty.".to_string());
+                }
+                return Ok(passphrase.into_bytes());
+            }
+            if !std::io::stdin().is_terminal() {
+                return Err(
+                    "The credential file is protected by a passphrase, but there is no terminal to ask for it. Set JULEZZ_PASSPHRASE or use a key file."
+                        .to_string(),
+                );
+            }
+            let passphrase = rpassword::prompt_password("Credential passphrase: ")
+                .map_err(|e| format!("Could not read passphrase: {}", e))?;
+            if confirm {
+                let again = rpassword::prompt_password("Confirm passphrase: ")
+                    .map_err(|e| format!("Could not read passphrase: {}", e))?;
+                if again != passphrase {
+                    return Err("Passphrases do not match.".to_string());
+                }
+            }
+            if passphrase.is_empty() {
+                return Err("The passphrase must not be empty.".to_string());
+            }
+            Ok(passphrase.into_bytes())
+        }
+        Protection::KeyFile { path } => {
+            let secret = fs::read(path)
+                .map_err(|e| format!("Could not read key file '{}': {}", path.display(), e))?;
+            if secret.len() < 16 {
+                return Err(format!(
+                    "Key file '{}' is too short; use at least 16 bytes of random data.",
+                    path.display()
+                ));
+            }
+            Ok(secret)
+        }
+    }
+}
+
+fn derive_key(secret: &[u8], salt: &[u8]) -> Result<Key, String> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(secret, salt, &mut key)
+        .map_err(|e| format!("Could not derive encryption key: {}", e))?;
+    Ok(key)
+}
+
+/// The prefix of the values sealed by a `SecretBox`.
+const SEALED_PREFIX: &str = "sealed:";
+
+/// Encrypts the secrets kept in state files, such as the API key of each bot
+/// chat, with a random key stored in a private key file.
+pub struct SecretBox {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SecretBox {
+    /// Loads the key in `key_file`, creating it with a random key if needed.
+    pub fn load_or_create(key_file: &Path) -> Result<Self, String> {
+        let key = if key_file.exists() {
+            fs::read(key_file).map_err(|e| format!("Could not read key file '{}': {}", key_file.display(), e))?
+        } else {
+            let key = ChaCha20Poly1305::generate_key(&mut OsRng).to_vec();
+            if let Some(parent) = key_file.parent() {
+                fs::create_dir_all(parent).map_err(|e| format!("Could not create config directory: {}", e))?;
+            }
+            write_private(key_file, &key).map_err(|e| format!("Could not write key file: {}", e))?;
+            key
+        };
+        if key.len() != 32 {
+            return Err(format!("Key file '{}' is invalid.", key_file.display()));
+        }
+        Ok(Self { cipher: ChaCha20Poly1305::new(Key::from_slice(&key)) })
+    }
+
+    /// Encrypts a secret.
+    pub fn seal(&self, secret: &str) -> Result<String, String> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(&nonce, secret.as_bytes())
+                .map_err(|_| "Could not encrypt secret".to_string())?,
+        );
+        Ok(format!("{}{}", SEALED_PREFIX, BASE64.encode(sealed)))
+    }
+
+    /// Decrypts a secret sealed with `seal`.
+    pub fn open(&self, value: &str) -> Result<String, String> {
+        let sealed = value
+            .strip_prefix(SEALED_PREFIX)
+            .ok_or("Invalid sealed secret: the secret is not encrypted")?;
+        let sealed = BASE64.decode(sealed).map_err(|e| format!("Invalid sealed secret: {}", e))?;
+        if sealed.len() < 12 {
+            return Err("Invalid sealed secret".to_string());
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "Could not decrypt secret: wrong key file".to_string())?;
+        String::from_utf8(plaintext).map_err(|_| "Invalid sealed secret: not UTF-8".to_string())
+    }
+}
+
+/// Returns a short description of the current profile's credential setup.
+pub fn describe(profile: &Profile) -> Result<Vec<String>, String> {
+    let mut lines = Vec::new();
+    if let Some(var) = &profile.api_key_env {
+        lines.push(format!("environment variable: {}", var));
+    }
+    if let Some(helper) = &profile.credential_helper {
+        lines.push(format!("credential helper: {}", helper));
+    }
+    let path = credentials_path()?;
+    if path.exists() {
+        let data = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read credential file: {}", e))?;
+        let credentials: EncryptedCredentials = serde_json::from_str(&data)
+            .map_err(|e| format!("Could not parse credential file: {}", e))?;
+        let protection = match credentials.protection {
+            Protection::Passphrase => "passphrase".to_string(),
+            Protection::KeyFile { path } => format!("key file {}", path.display()),
+        };
+        lines.push(format!("encrypted credential file: {} ({})", path.display(), protection));
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption_round_trip() {
+        let credentials = encrypt("secret-key", Protection::Passphrase, b"correct horse").unwrap();
+        assert!(!credentials.ciphertext.contains("secret-key"));
+        assert_eq!(decrypt(&credentials, b"correct horse").unwrap(), "secret-key");
+        assert_eq!(
+            decrypt(&credentials, b"wrong horse").unwrap_err(),
+            "Could not decrypt credentials: wrong passphrase"
+        );
+
+        let key_file = std::env::temp_dir().join(format!("julezz-secret-box-{}", std::process::id()));
+        let secrets = SecretBox::load_or_create(&key_file).unwrap();
+        let sealed = secrets.seal("chat-key").unwrap();
+        assert!(!sealed.contains("chat-key"));
+        assert_eq!(SecretBox::load_or_create(&key_file).unwrap().open(&sealed).unwrap(), "chat-key");
+        assert!(secrets.open("plain-key").is_err());
+        fs::remove_file(key_file).unwrap();
+    }
+
+    #[test]
+    fn test_resolution_order() {
+        let dir = crate::test_util::temp_dir("credentials");
+        let key_file = dir.join("key");
+        fs::write(&key_file, [7u8; 32]).unwrap();
+        let path = dir.join(CREDENTIALS_FILE);
+        write_encrypted(&path, "file-key", Protection::KeyFile { path: key_file }, &[7u8; 32]).unwrap();
+
+        let var = format!("JULEZZ_TEST_API_KEY_{}", std::process::id());
+        std::env::set_var(&var, "env-key");
+        let mut profile = Profile {
+            api_key_env: Some(var.clone()),
+            credential_helper: Some("echo api_key=helper-key #".to_string()),
+            ..Profile::default()
+        };
+        let resolve = |profile: &Profile| resolve_with(profile, &path).unwrap().unwrap();
+
+        assert_eq!(resolve(&profile), ("env-key".to_string(), KeySource::ProfileEnv(var.clone())));
+        std::env::remove_var(&var);
+        assert_eq!(resolve(&profile).0, "helper-key");
+        profile.credential_helper = Some("true".to_string());
+        assert_eq!(resolve(&profile), ("file-key".to_string(), KeySource::CredentialFile));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(resolve_with(&profile, &path).unwrap(), None);
+    }
+}
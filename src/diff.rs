@@ -0,0 +1,179 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module summarises the unified diffs found in the change sets of
+//! session activities.
+
+use crate::api::Activity;
+
+/// Collects the patches of a session's code changes, in order.
+pub fn collect_patches(activities: &[Activity]) -> String {
+    activities
+        .iter()
+        .filter_map(|a| a.artifacts.as_ref())
+        .flatten()
+        .filter_map(|artifact| artifact.change_set.as_ref())
+        .filter_map(|change_set| change_set.git_patch.unidiff_patch.clone())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Represents the changes made to one file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileStat {
+    /// The path of the file, or its old path if it was deleted.
+    pub path: String,
+    /// The number of lines added.
+    pub added: usize,
+    /// The number of lines removed.
+    pub removed: usize,
+}
+
+/// Computes the lines added and removed per file in a unified diff.
+///
+/// Hunk headers are used to tell changed lines from file headers, so a
+/// removed line starting with `--` is not mistaken for a new file. Files
+/// appearing several times, e.g. in concatenated patches, are merged.
+pub fn diff_stats(patch: &str) -> Vec<FileStat> {
+    let mut stats: Vec<FileStat> = Vec::new();
+    let mut current: Option<usize> = None;
+    let mut has_hunks = false;
+    let (mut old_left, mut new_left) = (0usize, 0usize);
+
+    for line in patch.lines() {
+        if old_left > 0 || new_left > 0 {
+            match line.chars().next() {
+                Some('+') => {
+                    new_left = new_left.saturating_sub(1);
+                    if let Some(i) = current {
+                        stats[i].added += 1;
+                    }
+                }
+                Some('-') => {
+                    old_left = old_left.saturating_sub(1);
+                    if let Some(i) = current {
+                        stats[i].removed += 1;
+                    }
+                }
+                Some('\\') => {}
+                _ => {
+                    old_left = old_left.saturating_sub(1);
+                    new_left = new_left.saturating_sub(1);
+                }
+            }
+            continue;
+        }
+
+        if let Some(paths) = line.strip_prefix("diff --git ") {
+            let path = paths.rsplit_once(" b/").map(|(_, path)| path).unwrap_or(paths);
+            current = Some(file_index(&mut stats, path));
+            has_hunks = false;
+        } else if let Some(path) = line.strip_prefix("--- ") {
+            // Without a `diff --git` line, the old path starts a new file.
+            if current.is_none() || has_hunks {
+                current = Some(file_index(&mut stats, strip_prefix(path)));
+                has_hunks = false;
+            }
+        } else if let Some(path) = line.strip_prefix("+++ ") {
+            let path = strip_prefix(path);
+            if let (Some(i), false) = (current, path == "/dev/null") {
+                if stats[i].path != path {
+                    stats[i].path = path.to_string();
+                }
+            }
+        } else if let Some(header) = line.strip_prefix("@@ ") {
+            if let Some((old, new)) = parse_hunk_header(header) {
+                old_left = old;
+                new_left = new;
+                has_hunks = true;
+            }
+        }
+    }
+    stats
+}
+
+/// Returns the index of a file in the stats, adding it if needed.
+fn file_index(stats: &mut Vec<FileStat>, path: &str) -> usize {
+    match stats.iter().position(|stat| stat.path == path) {
+        Some(i) => i,
+        None => {
+            stats.push(FileStat {
+                path: path.to_string(),
+                ..FileStat::default()
+            });
+            stats.len() - 1
+        }
+    }
+}
+
+/// Strips the `a/` or `b/` prefix and any trailing timestamp from a path in
+/// a file header.
+fn strip_prefix(path: &str) -> &str {
+    let path = path.split('\t').next().unwrap_or(path);
+    path.strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path)
+}
+
+/// Parses the line counts of a hunk header such as `-1,5 +1,7 @@`.
+fn parse_hunk_header(header: &str) -> Option<(usize, usize)> {
+    let mut ranges = header.split_whitespace();
+    let old = ranges.next()?.strip_prefix('-')?;
+    let new = ranges.next()?.strip_prefix('+')?;
+    let count = |range: &str| match range.split_once(',') {
+        Some((_, count)) => count.parse().ok(),
+        None => Some(1),
+    };
+    Some((count(old)?, count(new)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_stats() {
+        let patch = "\
+diff --git a/src/lib.rs b/src/lib.rs
+index 1111111..2222222 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1,3 +1,4 @@
+ use std::fs;
+--- not a header
++pub mod diff;
++pub mod api;
+ pub mod cache;
+diff --git a/old.txt b/old.txt
+deleted file mode 100644
+--- a/old.txt
++++ /dev/null
+@@ -1 +0,0 @@
+-gone
+--- a/README.md
++++ b/README.md
+@@ -10,0 +11,2 @@
++More docs.
++
+";
+        assert_eq!(
+            diff_stats(patch),
+            [
+                FileStat { path: "src/lib.rs".to_string(), added: 2, removed: 1 },
+                FileStat { path: "old.txt".to_string(), added: 0, removed: 1 },
+                FileStat { path: "README.md".to_string(), added: 2, removed: 0 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,363 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module builds the scheduled digests sent by the bot.
+//!
+//! A digest summarises what happened in a chat's sessions since the previous
+//! digest: sessions created, completed and failed, pull requests opened and
+//! merged, plans waiting for approval and stale sessions. It is computed from
+//! the cached sessions and activities only, which the bot's activity poller
+//! keeps up to date.
+
+use crate::api::Activity;
+use crate::cache::CachedSession;
+use crate::poll::TERMINAL_STATES;
+use chrono::{DateTime, Duration, Local, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The default number of hours without activity after which a session is
+/// reported as stale.
+pub const DEFAULT_STALE_HOURS: u64 = 24;
+
+/// The largest number of hours without activity a digest accepts.
+pub const MAX_STALE_HOURS: u64 = 24 * 365;
+
+fn default_stale_hours() -> u64 {
+    DEFAULT_STALE_HOURS
+}
+
+/// Represents a pull request opened or merged, as seen by the bot.
+///
+/// Pull requests have no activity of their own, so the bot records these
+/// events for the next digest.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PullRequestEvent {
+    /// The session the pull request belongs to.
+    pub session_id: String,
+    /// The URL of the pull request.
+    pub url: String,
+    /// Whether the pull request was merged, rather than opened.
+    pub merged: bool,
+    /// When the event was seen, in RFC 3339 format.
+    pub time: String,
+}
+
+/// Returns how long a session may go without activity before it is stale.
+pub fn stale_after(stale_hours: u64) -> Result<Duration, String> {
+    let invalid = || format!("Invalid number of hours '{}'. Use 1 to {}.", stale_hours, MAX_STALE_HOURS);
+    if !(1..=MAX_STALE_HOURS).contains(&stale_hours) {
+        return Err(invalid());
+    }
+    Duration::try_hours(stale_hours as i64).ok_or_else(invalid)
+}
+
+/// Represents the digest schedule of a chat.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DigestSchedule {
+    /// The time of day the digest is sent, as `HH:MM` in the bot's local
+    /// time.
+    pub time: String,
+    /// The number of hours without activity after which a session is stale.
+    #[serde(default = "default_stale_hours")]
+    pub stale_hours: u64,
+    /// When the last digest was sent, in RFC 3339 format.
+    pub last_sent: Option<String>,
+    /// The pull request events since the last digest.
+    #[serde(default)]
+    pub pull_requests: Vec<PullRequestEvent>,
+}
+
+impl DigestSchedule {
+    /// Creates a new `DigestSchedule`, checking the time of day and the
+    /// number of hours.
+    pub fn new(time: &str, stale_hours: u64) -> Result<Self, String> {
+        NaiveTime::parse_from_str(time, "%H:%M")
+            .map_err(|_| format!("Invalid time '{}'. Use HH:MM, e.g. 09:00.", time))?;
+        stale_after(stale_hours)?;
+        Ok(Self {
+            time: time.to_string(),
+            stale_hours,
+            last_sent: None,
+            pull_requests: Vec::new(),
+        })
+    }
+
+    /// Returns whether the digest is due: today's time has passed and no
+    /// digest has been sent since.
+    pub fn is_due(&self, now: DateTime<Local>) -> bool {
+        let time = match NaiveTime::parse_from_str(&self.time, "%H:%M") {
+            Ok(time) => time,
+            Err(_) => return false,
+        };
+        let scheduled = match Local.from_local_datetime(&now.date_naive().and_time(time)).earliest() {
+            Some(scheduled) => scheduled,
+            None => return false,
+        };
+        if now < scheduled {
+            return false;
+        }
+        match self.last_sent.as_deref().and_then(|t| DateTime::parse_from_rfc3339(t).ok()) {
+            Some(last_sent) => last_sent < scheduled,
+            None => true,
+        }
+    }
+
+    /// Records a pull request event for the next digest.
+    pub fn record_pull_request(&mut self, session_id: &str, url: &str, merged: bool, time: DateTime<Utc>) {
+        self.pull_requests.push(PullRequestEvent {
+            session_id: session_id.to_string(),
+            url: url.to_string(),
+            merged,
+            time: time.to_rfc3339(),
+        });
+    }
+}
+
+/// Represents a session mentioned in a digest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DigestEntry {
+    pub session_id: String,
+    pub title: String,
+    /// Extra information, e.g. a pull request URL.
+    pub detail: Option<String>,
+}
+
+/// Represents a digest of a chat's sessions over a period.
+#[derive(Debug, Clone, Default)]
+pub struct Digest {
+    pub created: Vec<DigestEntry>,
+    pub completed: Vec<DigestEntry>,
+    pub failed: Vec<DigestEntry>,
+    pub pull_requests_opened: Vec<DigestEntry>,
+    pub pull_requests_merged: Vec<DigestEntry>,
+    /// Sessions whose latest plan waits for approval, whenever it was made.
+    pub awaiting_approval: Vec<DigestEntry>,
+    /// Active sessions without recent activity.
+    pub stale: Vec<DigestEntry>,
+}
+
+impl Digest {
+    /// Builds the digest of the period from `from` to `to`.
+    ///
+    /// # Arguments
+    ///
+    /// * `activities` - The cached activities of each session, by session ID.
+    /// * `stale_after` - How long an active session may go without activity
+    ///   before it is reported as stale.
+    pub fn build(
+        sessions: &[CachedSession],
+        activities: &HashMap<String, Vec<Activity>>,
+        pull_requests: &[PullRequestEvent],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        stale_after: Duration,
+    ) -> Self {
+        let in_period = |time: Option<DateTime<Utc>>| time.is_some_and(|time| time > from && time <= to);
+        let no_activities = Vec::new();
+        let mut digest = Digest::default();
+
+        for session in sessions {
+            let entry = |detail: Option<String>| DigestEntry {
+                session_id: session.id.clone(),
+                title: session.title.clone(),
+                detail,
+            };
+            let mut session_activities: Vec<&Activity> =
+                activities.get(&session.id).unwrap_or(&no_activities).iter().collect();
+            session_activities.sort_by_key(|a| a.created_at());
+            let state = session.state.as_deref().unwrap_or_default();
+            let terminal = TERMINAL_STATES.contains(&state);
+
+            let created = parse_time(session.create_time.as_deref())
+                .or_else(|| session_activities.first().and_then(|a| a.created_at()));
+            if in_period(created) {
+                digest.created.push(entry(None));
+            }
+
+            // Sessions whose last activities are not cached yet are reported
+            // from their state.
+            let completed = session_activities
+                .iter()
+                .any(|a| a.session_completed.is_some() && in_period(a.created_at()))
+                || (state == "COMPLETED" && in_period(parse_time(session.update_time.as_deref())));
+            if completed {
+                digest.completed.push(entry(None));
+            }
+            let failure = session_activities
+                .iter()
+                .find(|a| a.session_failed.is_some() && in_period(a.created_at()));
+            if let Some(failure) = failure {
+                let reason = failure.session_failed.as_ref().and_then(|f| f.reason.clone());
+                digest.failed.push(entry(reason));
+            } else if state == "FAILED" && in_period(parse_time(session.update_time.as_deref())) {
+                digest.failed.push(entry(None));
+            }
+
+            let plan_waiting = session_activities
+                .iter()
+                .rposition(|a| a.plan_generated.is_some())
+                .is_some_and(|i| !session_activities[i + 1..].iter().any(|a| a.plan_approved.is_some()));
+            if !terminal && (state == "AWAITING_PLAN_APPROVAL" || plan_waiting) {
+                digest.awaiting_approval.push(entry(None));
+            }
+
+            let last_activity = session_activities
+                .last()
+                .and_then(|a| a.created_at())
+                .or_else(|| parse_time(session.update_time.as_deref()));
+            if let Some(last_activity) = last_activity {
+                let idle = to - last_activity;
+                if !terminal && idle >= stale_after {
+                    digest.stale.push(entry(Some(format!("no activity for {}h", idle.num_hours()))));
+                }
+            }
+        }
+
+        for event in pull_requests.iter().filter(|e| in_period(parse_time(Some(&e.time)))) {
+            let title = sessions
+                .iter()
+                .find(|s| s.id == event.session_id)
+                .map(|s| s.title.clone())
+                .unwrap_or_default();
+            let entry = DigestEntry {
+                session_id: event.session_id.clone(),
+                title,
+                detail: Some(event.url.clone()),
+            };
+            if event.merged {
+                digest.pull_requests_merged.push(entry);
+            } else {
+                digest.pull_requests_opened.push(entry);
+            }
+        }
+        digest
+    }
+
+    /// Returns whether nothing is worth reporting.
+    pub fn is_empty(&self) -> bool {
+        self.sections().iter().all(|(_, entries)| entries.is_empty())
+    }
+
+    fn sections(&self) -> [(&'static str, &Vec<DigestEntry>); 7] {
+        [
+            ("Created", &self.created),
+            ("Completed", &self.completed),
+            ("Failed", &self.failed),
+            ("Pull requests opened", &self.pull_requests_opened),
+            ("Pull requests merged", &self.pull_requests_merged),
+            ("Plans waiting for approval", &self.awaiting_approval),
+            ("Stale", &self.stale),
+        ]
+    }
+
+    /// Formats the digest as plain text.
+    pub fn format(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> String {
+        let format_time = |time: DateTime<Utc>| time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string();
+        let mut text = format!("Digest from {} to {}:\n", format_time(from), format_time(to));
+        if self.is_empty() {
+            text.push_str("\nNothing happened in your sessions.\n");
+            return text;
+        }
+        for (title, entries) in self.sections() {
+            if entries.is_empty() {
+                continue;
+            }
+            text.push_str(&format!("\n{} ({}):\n", title, entries.len()));
+            for entry in entries {
+                text.push_str(&format!("- {}: {}", entry.session_id, entry.title));
+                if let Some(detail) = &entry.detail {
+                    text.push_str(&format!(" ({})", detail));
+                }
+                text.push('\n');
+            }
+        }
+        text
+    }
+}
+
+fn parse_time(time: Option<&str>) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time?).ok().map(|time| time.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, activity};
+
+    fn session(id: &str, state: &str, create_time: &str) -> CachedSession {
+        CachedSession {
+            create_time: Some(create_time.to_string()),
+            ..CachedSession::from(&test_util::session(id, state))
+        }
+    }
+
+    #[test]
+    fn test_digest_covers_the_period() {
+        let from = parse_time(Some("2024-05-01T09:00:00Z")).unwrap();
+        let to = parse_time(Some("2024-05-02T09:00:00Z")).unwrap();
+        let sessions = vec![
+            session("new", "COMPLETED", "2024-05-01T10:00:00Z"),
+            session("plan", "AWAITING_PLAN_APPROVAL", "2024-04-30T10:00:00Z"),
+            session("idle", "IN_PROGRESS", "2024-04-29T10:00:00Z"),
+            CachedSession {
+                update_time: Some("2024-05-01T15:00:00Z".to_string()),
+                ..session("uncached", "COMPLETED", "2024-04-28T10:00:00Z")
+            },
+        ];
+        let mut activities = HashMap::new();
+        activities.insert("new".to_string(), vec![
+            activity("a", "2024-05-01T11:00:00Z", serde_json::json!({"sessionCompleted": {}})),
+        ]);
+        activities.insert("idle".to_string(), vec![
+            activity("b", "2024-04-30T08:00:00Z", serde_json::json!({"progressUpdated": {"title": "Working"}})),
+        ]);
+        let pull_requests = vec![PullRequestEvent {
+            session_id: "new".to_string(),
+            url: "https://github.com/o/r/pull/1".to_string(),
+            merged: true,
+            time: "2024-05-01T12:00:00Z".to_string(),
+        }];
+
+        let digest = Digest::build(&sessions, &activities, &pull_requests, from, to, Duration::hours(24));
+        let ids = |entries: &[DigestEntry]| entries.iter().map(|e| e.session_id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&digest.created), ["new"]);
+        assert_eq!(ids(&digest.completed), ["new", "uncached"]);
+        assert_eq!(ids(&digest.pull_requests_merged), ["new"]);
+        assert_eq!(ids(&digest.awaiting_approval), ["plan"]);
+        assert_eq!(ids(&digest.stale), ["idle"]);
+        assert!(digest.failed.is_empty());
+        assert!(digest.format(from, to).contains("Stale (1):\n- idle: Session idle (no activity for 49h)"));
+    }
+
+    #[test]
+    fn test_schedule_is_due_once_a_day() {
+        assert!(DigestSchedule::new("09:00", 0).is_err());
+        assert!(DigestSchedule::new("09:00", 9999999999999999).is_err());
+        assert_eq!(stale_after(MAX_STALE_HOURS), Ok(Duration::hours(24 * 365)));
+
+        let mut schedule = DigestSchedule::new("09:00", DEFAULT_STALE_HOURS).unwrap();
+        let before = Local.with_ymd_and_hms(2024, 5, 1, 8, 59, 0).unwrap();
+        let after = Local.with_ymd_and_hms(2024, 5, 1, 9, 1, 0).unwrap();
+        assert!(!schedule.is_due(before));
+        assert!(schedule.is_due(after));
+
+        schedule.last_sent = Some(after.with_timezone(&Utc).to_rfc3339());
+        assert!(!schedule.is_due(after + Duration::hours(3)));
+        assert!(schedule.is_due(after + Duration::days(1)));
+        assert!(DigestSchedule::new("9am", 24).is_err());
+    }
+}
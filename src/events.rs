@@ -0,0 +1,430 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module detects the events of sessions from their activities.
+//!
+//! An event is something worth telling a person about: a plan waiting for
+//! approval, a question from the agent, a completed or failed session, a
+//! pull request, a progress update or new artifacts. Events are detected by
+//! comparing a session's activities with a `NotificationCursor`, the last
+//! activity already reported, so that every consumer (the bot, the notify
+//! daemon) reports each event once.
+
+use crate::api::{Activity, Session};
+use crate::cache::NotificationCursor;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The kind of an event.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    /// A plan was generated and waits for approval.
+    Plan,
+    /// The agent sent a message, usually a question.
+    Question,
+    /// The session completed.
+    Completed,
+    /// The session failed.
+    Failed,
+    /// A pull request was opened for the session.
+    #[serde(rename = "pr")]
+    PullRequest,
+    /// The agent reported progress.
+    Progress,
+    /// The agent produced artifacts, e.g. a change set or a bash output.
+    Artifact,
+}
+
+impl EventKind {
+    /// Returns the kind of event an activity corresponds to, if any.
+    ///
+    /// Only the agent's own activities are events, apart from the end of a
+    /// session.
+    pub fn of(activity: &Activity) -> Option<Self> {
+        if activity.session_completed.is_some() {
+            Some(Self::Completed)
+        } else if activity.session_failed.is_some() {
+            Some(Self::Failed)
+        } else if activity.originator != "agent" {
+            None
+        } else if activity.agent_messaged.is_some() {
+            Some(Self::Question)
+        } else if activity.plan_generated.is_some() {
+            Some(Self::Plan)
+        } else if activity.progress_updated.is_some() {
+            Some(Self::Progress)
+        } else if activity.artifacts.is_some() {
+            Some(Self::Artifact)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the name of the kind, as used in `notifications.events`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Plan => "plan",
+            Self::Question => "question",
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+            Self::PullRequest => "pr",
+            Self::Progress => "progress",
+            Self::Artifact => "artifact",
+        }
+    }
+}
+
+/// Represents an event of a session.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    /// The kind of the event.
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    /// The ID of the session.
+    pub session_id: String,
+    /// The title of the session.
+    pub session_title: String,
+    /// The ID of the activity the event comes from. Pull requests are not
+    /// activities, so their events have none.
+    pub activity_id: Option<String>,
+    /// When the event happened, in RFC 3339 format.
+    pub time: String,
+    /// The text of the event: the agent's message, the failure reason, the
+    /// progress title, the pull request URL, or the kinds of artifacts
+    /// (`changeSet`, `bashOutput`) separated by commas.
+    pub detail: Option<String>,
+    /// The pull request URL of the session, if any.
+    pub pull_request_url: Option<String>,
+}
+
+impl Event {
+    /// Creates the event of an activity, if it is one.
+    pub fn from_activity(activity: &Activity, session: &Session) -> Option<Self> {
+        let kind = EventKind::of(activity)?;
+        let detail = match kind {
+            EventKind::Question => activity.agent_messaged.as_ref().map(|m| m.agent_message.clone()),
+            EventKind::Failed => activity.session_failed.as_ref().and_then(|f| f.reason.clone()),
+            EventKind::Progress => activity.progress_updated.as_ref().and_then(|p| p.title.clone()),
+            EventKind::Artifact => {
+                let mut kinds = Vec::new();
+                for artifact in activity.artifacts.iter().flatten() {
+                    if artifact.change_set.is_some() && !kinds.contains(&"changeSet") {
+                        kinds.push("changeSet");
+                    }
+                    if artifact.bash_output.is_some() && !kinds.contains(&"bashOutput") {
+                        kinds.push("bashOutput");
+                    }
+                }
+                Some(kinds.join(",")).filter(|kinds| !kinds.is_empty())
+            }
+            _ => None,
+        };
+        Some(Self {
+            kind,
+            session_id: session.id.clone(),
+            session_title: session.title.clone(),
+            activity_id: Some(activity.id.clone()),
+            time: activity.create_time.clone(),
+            detail,
+            pull_request_url: session.pull_request_url.clone(),
+        })
+    }
+
+    /// Creates the event of a pull request opened for a session.
+    pub fn pull_request(session: &Session, url: &str, time: DateTime<Utc>) -> Self {
+        Self {
+            kind: EventKind::PullRequest,
+            session_id: session.id.clone(),
+            session_title: session.title.clone(),
+            activity_id: None,
+            time: time.to_rfc3339(),
+            detail: Some(url.to_string()),
+            pull_request_url: Some(url.to_string()),
+        }
+    }
+
+    /// Describes the event as plain text.
+    pub fn summary(&self) -> String {
+        let session = format!("{} ({})", self.session_title, self.session_id);
+        let detail = self.detail.as_deref();
+        match self.kind {
+            EventKind::Plan => format!("Plan generated for session {}.", session),
+            EventKind::Question => {
+                format!("New message in session {}:\n{}", session, detail.unwrap_or_default())
+            }
+            EventKind::Completed => format!("Session {} completed.", session),
+            EventKind::Failed => {
+                format!("Session {} failed:\n{}", session, detail.unwrap_or("No reason given"))
+            }
+            EventKind::PullRequest => {
+                format!("Pull request opened for session {}:\n{}", session, detail.unwrap_or_default())
+            }
+            EventKind::Progress => {
+                format!("Progress update for session {}:\n{}", session, detail.unwrap_or("No title"))
+            }
+            EventKind::Artifact => format!("New artifacts generated for session {}.", session),
+        }
+    }
+}
+
+/// Sorts activities by creation time.
+pub fn sort_activities(activities: &mut [Activity]) {
+    activities.sort_by_key(|a| a.created_at());
+}
+
+/// Returns the activities that are newer than a cursor.
+///
+/// Without a cursor, only activities created after `last_checked` are new,
+/// so that the history of existing sessions is not announced. The activities
+/// must be sorted.
+pub fn new_activities<'a>(
+    activities: &'a [Activity],
+    cursor: Option<&NotificationCursor>,
+    last_checked: Option<DateTime<Utc>>,
+) -> &'a [Activity] {
+    let since = match cursor {
+        Some(cursor) => match activities.iter().position(|a| a.id == cursor.activity_id) {
+            Some(index) => return &activities[index + 1..],
+            None => parse_time(&cursor.create_time),
+        },
+        None if last_checked.is_some() => last_checked,
+        None => return &[],
+    };
+    let index = activities
+        .iter()
+        .position(|a| a.created_at() > since)
+        .unwrap_or(activities.len());
+    &activities[index..]
+}
+
+/// Represents what changed in a session since its cursor.
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// The new events, in order.
+    pub events: Vec<Event>,
+    /// The number of new activities, including those that are not events.
+    pub new_activities: usize,
+    /// The cursor to keep for the session, if it has activities.
+    pub cursor: Option<NotificationCursor>,
+}
+
+/// Detects the events of a session since its cursor.
+///
+/// A pull request is not an activity, so it is detected by comparing the
+/// session's pull request URL with the one in the cursor; sessions seen for
+/// the first time do not report the pull request they already have.
+pub fn detect(
+    session: &Session,
+    activities: &mut [Activity],
+    cursor: Option<&NotificationCursor>,
+    last_checked: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Changes {
+    sort_activities(activities);
+    let new = new_activities(activities, cursor, last_checked);
+    let mut events: Vec<Event> = new
+        .iter()
+        .filter_map(|activity| Event::from_activity(activity, session))
+        .collect();
+
+    if let (Some(cursor), Some(url)) = (cursor, &session.pull_request_url) {
+        if cursor.pull_request_url.as_ref() != Some(url) {
+            events.push(Event::pull_request(session, url, now));
+        }
+    }
+
+    Changes {
+        events,
+        new_activities: new.len(),
+        cursor: activities.last().map(|last| NotificationCursor {
+            activity_id: last.id.clone(),
+            create_time: last.create_time.clone(),
+            pull_request_url: session.pull_request_url.clone(),
+        }),
+    }
+}
+
+/// Returns the cursor to keep when an event could not be delivered, so that
+/// it and the events after it are detected again by the next check.
+///
+/// The activities must be sorted, as `detect` leaves them, and `cursor` and
+/// `last_checked` must be the ones `detect` was given.
+pub fn cursor_before(
+    session: &Session,
+    activities: &[Activity],
+    failed: &Event,
+    cursor: Option<&NotificationCursor>,
+    last_checked: Option<DateTime<Utc>>,
+) -> Option<NotificationCursor> {
+    // Sessions seen for the first time do not report their pull request.
+    let pull_request_url = match cursor {
+        Some(cursor) => cursor.pull_request_url.clone(),
+        None => session.pull_request_url.clone(),
+    };
+    let at = |activity: &Activity| NotificationCursor {
+        activity_id: activity.id.clone(),
+        create_time: activity.create_time.clone(),
+        pull_request_url: pull_request_url.clone(),
+    };
+    let index = match &failed.activity_id {
+        Some(id) => activities.iter().position(|a| &a.id == id)?,
+        // The pull request is reported after every activity.
+        None => return activities.last().map(at),
+    };
+    if index > 0 {
+        return Some(at(&activities[index - 1]));
+    }
+    match cursor {
+        Some(cursor) => Some(cursor.clone()),
+        None => cursor_since(session, last_checked),
+    }
+}
+
+/// Returns the cursor to keep for a session that has none after a check,
+/// e.g. because its activities could not be fetched, so that the activities
+/// it got since `last_checked` are still detected once the time of the last
+/// check moves on.
+pub fn cursor_since(session: &Session, last_checked: Option<DateTime<Utc>>) -> Option<NotificationCursor> {
+    // A cursor on no activity falls back to its time.
+    last_checked.map(|time| NotificationCursor {
+        activity_id: String::new(),
+        create_time: time.to_rfc3339(),
+        pull_request_url: session.pull_request_url.clone(),
+    })
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn activity(id: &str, create_time: &str) -> Activity {
+        test_util::activity(id, create_time, serde_json::json!({"planGenerated": {"plan": {"id": id, "steps": []}}}))
+    }
+
+    fn session(pull_request_url: Option<&str>) -> Session {
+        Session {
+            pull_request_url: pull_request_url.map(str::to_string),
+            ..test_util::session("1", "IN_PROGRESS")
+        }
+    }
+
+    #[test]
+    fn test_new_activities_follow_the_cursor() {
+        let mut activities = vec![
+            activity("c", "2024-05-01T10:00:02Z"),
+            activity("a", "2024-05-01T10:00:00.5Z"),
+            activity("b", "2024-05-01T10:00:01.25Z"),
+        ];
+        sort_activities(&mut activities);
+        let ids = |new: &[Activity]| new.iter().map(|a| a.id.clone()).collect::<Vec<_>>();
+
+        let cursor = NotificationCursor {
+            activity_id: "a".to_string(),
+            create_time: "2024-05-01T10:00:00.5Z".to_string(),
+            pull_request_url: None,
+        };
+        assert_eq!(ids(new_activities(&activities, Some(&cursor), None)), ["b", "c"]);
+
+        // A cursor pointing to an activity that is gone falls back to its time.
+        let cursor = NotificationCursor {
+            activity_id: "gone".to_string(),
+            create_time: "2024-05-01T10:00:01Z".to_string(),
+            pull_request_url: None,
+        };
+        assert_eq!(ids(new_activities(&activities, Some(&cursor), None)), ["b", "c"]);
+
+        // Without a cursor, only activities since the last check are new.
+        assert!(new_activities(&activities, None, None).is_empty());
+        let last_checked = parse_time("2024-05-01T10:00:01.5Z");
+        assert_eq!(ids(new_activities(&activities, None, last_checked)), ["c"]);
+    }
+
+    #[test]
+    fn test_detect_reports_typed_events_and_pull_requests() {
+        let now = Utc::now();
+        let mut activities = vec![activity("b", "2024-05-01T10:00:01Z"), activity("a", "2024-05-01T10:00:00Z")];
+        let cursor = NotificationCursor {
+            activity_id: "a".to_string(),
+            create_time: "2024-05-01T10:00:00Z".to_string(),
+            pull_request_url: None,
+        };
+
+        let url = "https://github.com/o/r/pull/1";
+        let changes = detect(&session(Some(url)), &mut activities, Some(&cursor), None, now);
+        let kinds: Vec<EventKind> = changes.events.iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, [EventKind::Plan, EventKind::PullRequest]);
+        assert_eq!(changes.new_activities, 1);
+        let cursor = changes.cursor.unwrap();
+        assert_eq!(cursor.activity_id, "b");
+        assert_eq!(cursor.pull_request_url.as_deref(), Some(url));
+
+        // Nothing new once the cursor has moved.
+        let changes = detect(&session(Some(url)), &mut activities, Some(&cursor), None, now);
+        assert!(changes.events.is_empty());
+
+        let json = serde_json::to_value(Event::pull_request(&session(None), url, now)).unwrap();
+        assert_eq!(json["type"], "pr");
+        assert_eq!(json["sessionId"], "1");
+    }
+
+    #[test]
+    fn test_cursor_before_an_undelivered_event() {
+        let now = Utc::now();
+        let url = "https://github.com/o/r/pull/1";
+        let mut activities = vec![
+            activity("a", "2024-05-01T10:00:00Z"),
+            activity("b", "2024-05-01T10:00:01Z"),
+            activity("c", "2024-05-01T10:00:02Z"),
+        ];
+        let cursor = NotificationCursor {
+            activity_id: "a".to_string(),
+            create_time: "2024-05-01T10:00:00Z".to_string(),
+            pull_request_url: None,
+        };
+        let changes = detect(&session(Some(url)), &mut activities, Some(&cursor), None, now);
+        assert_eq!(changes.events.len(), 3);
+
+        // When "c" fails, "b" was delivered.
+        let kept = cursor_before(&session(Some(url)), &activities, &changes.events[1], Some(&cursor), None).unwrap();
+        assert_eq!(kept.activity_id, "b");
+        assert_eq!(kept.pull_request_url, None);
+        let changes = detect(&session(Some(url)), &mut activities, Some(&kept), None, now);
+        let kinds: Vec<EventKind> = changes.events.iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, [EventKind::Plan, EventKind::PullRequest]);
+
+        // When the pull request fails, it is reported again.
+        let kept = cursor_before(&session(Some(url)), &activities, &changes.events[1], Some(&cursor), None).unwrap();
+        assert_eq!(kept.activity_id, "c");
+        let changes = detect(&session(Some(url)), &mut activities, Some(&kept), None, now);
+        assert_eq!(changes.events.len(), 1);
+        assert_eq!(changes.events[0].kind, EventKind::PullRequest);
+
+        // Without a cursor, the first event is found again from the last check.
+        let last_checked = parse_time("2024-05-01T10:00:01.5Z");
+        let changes = detect(&session(Some(url)), &mut activities, None, last_checked, now);
+        let kept = cursor_before(&session(Some(url)), &activities, &changes.events[0], None, last_checked).unwrap();
+        let changes = detect(&session(Some(url)), &mut activities, Some(&kept), None, now);
+        assert_eq!(changes.events.len(), 1);
+        assert_eq!(changes.events[0].activity_id.as_deref(), Some("c"));
+    }
+}
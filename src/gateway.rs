@@ -0,0 +1,610 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements `julezz serve`, a local HTTP/JSON gateway to the
+//! Jules API.
+//!
+//! Scripts and editor plugins talk to the gateway instead of the API, so a
+//! single process owns the credentials, the session and alias caches and the
+//! request rate. Sessions are identified like on the command line: by ID, by
+//! index in the session list or by `@alias`. The gateway reuses the session
+//! list for `gateway.cache_seconds`, makes at most `gateway.concurrency` API
+//! requests at a time, and requires `gateway.token` as a bearer token when it
+//! is set. Without a token, the gateway only listens on loopback addresses and
+//! only answers requests for a loopback host, so that web pages cannot reach
+//! it through DNS rebinding.
+//!
+//! Routes:
+//!
+//! - `GET /sessions`, `POST /sessions`, `GET /sessions/{session}`
+//! - `GET /sessions/{session}/activities`, and
+//!   `GET /sessions/{session}/activities/stream` as Server-Sent Events
+//! - `POST /sessions/{session}/messages`, `POST /sessions/{session}/approve`
+//! - `GET /aliases`, `GET /resolve/{session}`
+
+use crate::api::{Activity, JulesClient, JulesError, Session};
+use crate::cache::{Cache, CachedSession};
+use crate::config::Config;
+use crate::events::sort_activities;
+use crate::poll::is_terminal;
+use crate::resolve::resolve_session_identifier_with_aliases;
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{self, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+use tokio::sync::{Mutex, Semaphore};
+
+/// Represents an error answered by the gateway, as `{"error": "..."}`.
+#[derive(Debug)]
+pub struct GatewayError(StatusCode, String);
+
+impl IntoResponse for GatewayError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<JulesError> for GatewayError {
+    fn from(e: JulesError) -> Self {
+        GatewayError(StatusCode::BAD_GATEWAY, e.to_string())
+    }
+}
+
+/// The body of `POST /sessions`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateSession {
+    /// The title and prompt of the session.
+    pub title: String,
+    /// The source, defaulting to `sessions.source`.
+    pub source: Option<String>,
+    /// The starting branch, defaulting to `sessions.branch`.
+    pub branch: Option<String>,
+    /// Whether to create a pull request, defaulting to
+    /// `sessions.automation_mode`.
+    pub auto_pr: Option<bool>,
+    /// An alias for the session, e.g. `@fix`.
+    pub alias: Option<String>,
+}
+
+/// The body of `POST /sessions/{session}/messages`.
+#[derive(Deserialize, Debug)]
+pub struct SendMessage {
+    /// The message.
+    pub prompt: String,
+}
+
+/// The answer of `GET /resolve/{session}`.
+#[derive(Serialize, Debug)]
+pub struct Resolved {
+    /// The ID of the session.
+    pub id: String,
+    /// The index of the session in the session list, starting at 1.
+    pub index: usize,
+}
+
+/// The state shared by the gateway's handlers.
+pub struct Gateway {
+    client: Arc<JulesClient>,
+    cache: Cache,
+    source: Option<String>,
+    branch: String,
+    auto_pr: bool,
+    token: Option<String>,
+    /// The interval at which activity streams check their session.
+    poll_interval: Duration,
+    cache_duration: Duration,
+    /// Limits the number of API requests made at a time.
+    permits: Semaphore,
+    /// The last session list, and when it was fetched.
+    sessions: Mutex<Option<(Instant, Vec<Session>)>>,
+}
+
+impl Gateway {
+    /// Creates a new `Gateway`, using the session defaults and the poll
+    /// interval of the config.
+    pub fn new(client: Arc<JulesClient>, cache: Cache, config: &Config) -> Self {
+        Self {
+            client,
+            cache,
+            source: config.source(),
+            branch: config.branch(),
+            auto_pr: config.auto_pr(),
+            token: config.gateway_token(),
+            poll_interval: Duration::from_secs(config.poll_interval_seconds()),
+            cache_duration: Duration::from_secs(config.gateway_cache_seconds()),
+            permits: Semaphore::new(config.gateway_concurrency()),
+            sessions: Mutex::new(None),
+        }
+    }
+
+    /// Returns the session list, fetching it if the last one is too old.
+    ///
+    /// A fetched list is also written to the session cache, so that session
+    /// indexes match those of the command line.
+    async fn sessions(&self) -> Result<Vec<Session>, GatewayError> {
+        let mut sessions = self.sessions.lock().await;
+        if let Some((fetched, list)) = sessions.as_ref() {
+            if fetched.elapsed() < self.cache_duration {
+                return Ok(list.clone());
+            }
+        }
+        let list = {
+            let _permit = self.permits.acquire().await;
+            self.client.list_sessions().await?
+        };
+        let cached: Vec<CachedSession> = list.iter().map(CachedSession::from).collect();
+        if let Err(e) = self.cache.write_sessions(&cached) {
+            tracing::warn!(error = %e, "Failed to cache sessions");
+        }
+        *sessions = Some((Instant::now(), list.clone()));
+        Ok(list)
+    }
+
+    /// Resolves a session ID, index or alias to a session ID and index.
+    async fn resolve(&self, identifier: &str) -> Result<(String, usize), GatewayError> {
+        let sessions = self.sessions().await?;
+        let aliases = self.cache.read_aliases().map_err(internal)?;
+        resolve_session_identifier_with_aliases(identifier, &sessions, &aliases)
+            .map_err(|e| GatewayError(StatusCode::NOT_FOUND, e))
+    }
+
+    async fn activities(&self, session_id: &str) -> Result<Vec<Activity>, JulesError> {
+        let _permit = self.permits.acquire().await;
+        let mut activities = self.client.fetch_activities(session_id).await?;
+        sort_activities(&mut activities);
+        Ok(activities)
+    }
+}
+
+/// Builds the gateway's routes.
+pub fn router(gateway: Arc<Gateway>) -> Router {
+    Router::new()
+        .route("/sessions", get(list_sessions).post(create_session))
+        .route("/sessions/:session", get(get_session))
+        .route("/sessions/:session/activities", get(list_activities))
+        .route("/sessions/:session/activities/stream", get(stream_activities))
+        .route("/sessions/:session/messages", post(send_message))
+        .route("/sessions/:session/approve", post(approve_plan))
+        .route("/aliases", get(list_aliases))
+        .route("/resolve/:session", get(resolve))
+        .layer(middleware::from_fn_with_state(gateway.clone(), authorize))
+        .with_state(gateway)
+}
+
+/// Serves the gateway until the process is stopped.
+///
+/// Listening on an address other than a loopback one requires
+/// `gateway.token`.
+pub async fn serve(gateway: Arc<Gateway>, listen: &str) -> Result<(), String> {
+    let address: SocketAddr = listen
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid address, e.g. 127.0.0.1:8765", listen))?;
+    if !address.ip().is_loopback() && gateway.token.is_none() {
+        return Err(format!(
+            "Refusing to listen on {} without a token. Set gateway.token or listen on a loopback address.",
+            address
+        ));
+    }
+    let server = axum::Server::try_bind(&address)
+        .map_err(|e| format!("Could not listen on {}: {}", address, e))?;
+    tracing::info!(%address, "Gateway listening");
+    server
+        .serve(router(gateway).into_make_service())
+        .await
+        .map_err(|e| format!("Gateway failed: {}", e))
+}
+
+/// Rejects requests without the bearer token when one is configured, and
+/// requests for a host other than a loopback one otherwise.
+async fn authorize<B>(
+    State(gateway): State<Arc<Gateway>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let headers = request.headers();
+    match &gateway.token {
+        Some(token) => {
+            let given = headers
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .unwrap_or_default();
+            if !bool::from(given.as_bytes().ct_eq(token.as_bytes())) {
+                return GatewayError(StatusCode::UNAUTHORIZED, "Missing or invalid bearer token".to_string())
+                    .into_response();
+            }
+        }
+        None => {
+            let host = headers.get(header::HOST).and_then(|value| value.to_str().ok());
+            if !host.is_some_and(is_loopback_host) {
+                return GatewayError(StatusCode::FORBIDDEN, "Host must be a loopback address".to_string())
+                    .into_response();
+            }
+        }
+    }
+    next.run(request).await
+}
+
+/// Checks whether the value of a `Host` header names a loopback address,
+/// e.g. `localhost:8765` or `[::1]:8765`.
+fn is_loopback_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    name.eq_ignore_ascii_case("localhost") || name.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+async fn list_sessions(State(gateway): State<Arc<Gateway>>) -> Result<Json<Vec<Session>>, GatewayError> {
+    Ok(Json(gateway.sessions().await?))
+}
+
+async fn create_session(
+    State(gateway): State<Arc<Gateway>>,
+    Json(request): Json<CreateSession>,
+) -> Result<(StatusCode, Json<Session>), GatewayError> {
+    let source = request.source.or_else(|| gateway.source.clone()).ok_or_else(|| {
+        bad_request("No source given and no default source is configured (sessions.source).")
+    })?;
+    if let Some(alias) = &request.alias {
+        if !alias.starts_with('@') {
+            return Err(bad_request("Alias must start with '@'."));
+        }
+    }
+    let branch = request.branch.unwrap_or_else(|| gateway.branch.clone());
+    let auto_pr = request.auto_pr.unwrap_or(gateway.auto_pr);
+
+    let session = {
+        let _permit = gateway.permits.acquire().await;
+        gateway
+            .client
+            .create_session(&source, &request.title, auto_pr, &branch)
+            .await?
+    };
+    // The next listing includes the new session.
+    *gateway.sessions.lock().await = None;
+    if let Some(alias) = request.alias {
+        let mut aliases = gateway.cache.read_aliases().map_err(internal)?;
+        aliases.insert(alias, session.id.clone());
+        gateway.cache.write_aliases(&aliases).map_err(internal)?;
+    }
+    Ok((StatusCode::CREATED, Json(session)))
+}
+
+async fn get_session(
+    State(gateway): State<Arc<Gateway>>,
+    Path(identifier): Path<String>,
+) -> Result<Json<Session>, GatewayError> {
+    let (session_id, _) = gateway.resolve(&identifier).await?;
+    let _permit = gateway.permits.acquire().await;
+    Ok(Json(gateway.client.get_session(&session_id).await?))
+}
+
+async fn list_activities(
+    State(gateway): State<Arc<Gateway>>,
+    Path(identifier): Path<String>,
+) -> Result<Json<Vec<Activity>>, GatewayError> {
+    let (session_id, _) = gateway.resolve(&identifier).await?;
+    Ok(Json(gateway.activities(&session_id).await?))
+}
+
+async fn send_message(
+    State(gateway): State<Arc<Gateway>>,
+    Path(identifier): Path<String>,
+    Json(request): Json<SendMessage>,
+) -> Result<StatusCode, GatewayError> {
+    if request.prompt.trim().is_empty() {
+        return Err(bad_request("The message is empty."));
+    }
+    let (session_id, _) = gateway.resolve(&identifier).await?;
+    let _permit = gateway.permits.acquire().await;
+    gateway.client.send_message(&session_id, &request.prompt).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn approve_plan(
+    State(gateway): State<Arc<Gateway>>,
+    Path(identifier): Path<String>,
+) -> Result<StatusCode, GatewayError> {
+    let (session_id, _) = gateway.resolve(&identifier).await?;
+    let _permit = gateway.permits.acquire().await;
+    gateway.client.approve_plan(&session_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_aliases(State(gateway): State<Arc<Gateway>>) -> Result<impl IntoResponse, GatewayError> {
+    Ok(Json(gateway.cache.read_aliases().map_err(internal)?))
+}
+
+async fn resolve(
+    State(gateway): State<Arc<Gateway>>,
+    Path(identifier): Path<String>,
+) -> Result<Json<Resolved>, GatewayError> {
+    let (id, index) = gateway.resolve(&identifier).await?;
+    Ok(Json(Resolved { id, index }))
+}
+
+/// Streams the activities of a session as Server-Sent Events.
+///
+/// Every activity is sent as an `activity` event whose ID is the activity
+/// ID, starting after the `Last-Event-ID` header when a client reconnects.
+/// The stream ends with an `end` event holding the session once it reaches a
+/// terminal state.
+async fn stream_activities(
+    State(gateway): State<Arc<Gateway>>,
+    Path(identifier): Path<String>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, GatewayError> {
+    let (session_id, _) = gateway.resolve(&identifier).await?;
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let stream = ActivityStream {
+        gateway,
+        session_id,
+        last_id,
+        pending: VecDeque::new(),
+        started: false,
+        finished: false,
+    };
+    Ok(Sse::new(futures_util::stream::unfold(stream, ActivityStream::next)).keep_alive(KeepAlive::default()))
+}
+
+/// The state of an activity stream.
+struct ActivityStream {
+    gateway: Arc<Gateway>,
+    session_id: String,
+    /// The last activity sent.
+    last_id: Option<String>,
+    /// The events to send before checking the session again.
+    pending: VecDeque<sse::Event>,
+    started: bool,
+    finished: bool,
+}
+
+impl ActivityStream {
+    async fn next(mut self) -> Option<(Result<sse::Event, Infallible>, Self)> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some((Ok(event), self));
+            }
+            if self.finished {
+                return None;
+            }
+            if self.started {
+                tokio::time::sleep(self.gateway.poll_interval).await;
+            }
+            self.started = true;
+            if let Err(e) = self.check().await {
+                // Errors are retried at the next check, and reported to the
+                // client in the meantime.
+                self.pending.push_back(sse::Event::default().event("error").data(e.to_string()));
+            }
+        }
+    }
+
+    /// Queues the new activities of the session, and the end of the stream
+    /// if the session is over.
+    async fn check(&mut self) -> Result<(), JulesError> {
+        let session = {
+            let _permit = self.gateway.permits.acquire().await;
+            self.gateway.client.get_session(&self.session_id).await?
+        };
+        let activities = self.gateway.activities(&self.session_id).await?;
+        let start = self
+            .last_id
+            .as_ref()
+            .and_then(|last| activities.iter().position(|activity| &activity.id == last))
+            .map_or(0, |index| index + 1);
+        for activity in &activities[start..] {
+            let event = sse::Event::default()
+                .event("activity")
+                .id(activity.id.clone())
+                .json_data(activity)
+                .map_err(|e| JulesError::ApiError(format!("Could not serialize activity: {}", e)))?;
+            self.pending.push_back(event);
+            self.last_id = Some(activity.id.clone());
+        }
+        if is_terminal(&session) {
+            let event = sse::Event::default()
+                .event("end")
+                .json_data(&session)
+                .map_err(|e| JulesError::ApiError(format!("Could not serialize session: {}", e)))?;
+            self.pending.push_back(event);
+            self.finished = true;
+        }
+        Ok(())
+    }
+}
+
+fn bad_request(message: &str) -> GatewayError {
+    GatewayError(StatusCode::BAD_REQUEST, message.to_string())
+}
+
+fn internal(message: String) -> GatewayError {
+    GatewayError(StatusCode::INTERNAL_SERVER_ERROR, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use axum::http::{Method, Uri};
+    use serde_json::{json, Value};
+    use std::fs;
+
+    /// A request received by the API stand-in: method, path and body.
+    type Recorded = Arc<std::sync::Mutex<Vec<(String, String, String)>>>;
+
+    fn session(id: &str, state: &str) -> Value {
+        serde_json::to_value(test_util::session(id, state)).unwrap()
+    }
+
+    /// Starts a stand-in for the Jules API with the given sessions, each
+    /// having the given activities.
+    fn jules_stand_in(sessions: Vec<Value>, activities: Value) -> (String, Recorded) {
+        let recorded: Recorded = Arc::default();
+        let requests = recorded.clone();
+        let app = Router::new().fallback(move |method: Method, uri: Uri, body: String| {
+            let (requests, sessions, activities) = (requests.clone(), sessions.clone(), activities.clone());
+            async move {
+                let path = uri.path().to_string();
+                requests.lock().unwrap().push((method.to_string(), path.clone(), body));
+                let id = path.trim_start_matches("/sessions").trim_start_matches('/');
+                let answer = match (method, id) {
+                    (Method::GET, "") => json!({ "sessions": sessions }),
+                    (Method::POST, "") => session("3", "QUEUED"),
+                    (Method::GET, id) if id.ends_with("/activities") => json!({ "activities": activities }),
+                    (Method::GET, id) => sessions.iter().find(|s| s["id"] == id).cloned().unwrap_or_default(),
+                    _ => json!({}),
+                };
+                Json(answer)
+            }
+        });
+        (test_util::serve(app), recorded)
+    }
+
+    /// Starts a gateway in front of the API stand-in, with its files in a
+    /// temporary directory.
+    fn gateway(name: &str, api: &str, config: &str) -> (std::path::PathBuf, String) {
+        let dir = test_util::temp_dir(&format!("gateway-{}", name));
+        fs::write(dir.join("config.toml"), config).unwrap();
+        let config = Config::load_from(dir.join("config.toml")).unwrap();
+        let cache = Cache::in_dir(&dir);
+        cache.write_aliases(&[("@fix".to_string(), "2".to_string())].into()).unwrap();
+        let client = JulesClient::new(Some("key".to_string()))
+            .unwrap()
+            .with_base_url(api)
+            .with_activities_dir(&dir);
+        let gateway = Arc::new(Gateway::new(Arc::new(client), cache, &config));
+        (dir, test_util::serve(router(gateway)))
+    }
+
+    #[tokio::test]
+    async fn test_gateway_resolves_sessions_and_forwards_requests() {
+        let (api, recorded) = jules_stand_in(vec![session("1", "IN_PROGRESS"), session("2", "AWAITING_PLAN_APPROVAL")], json!([]));
+        let config = "[gateway]\ntoken = \"t0ken\"\n\n[sessions]\nsource = \"sources/github/o/r\"\n";
+        let (dir, base) = gateway("requests", &api, config);
+        let http = reqwest::Client::new();
+        let get = |path: &str| http.get(format!("{}{}", base, path)).bearer_auth("t0ken").send();
+        let post = |path: &str, body: Value| http.post(format!("{}{}", base, path)).bearer_auth("t0ken").json(&body).send();
+
+        let response = http.get(format!("{}/sessions", base)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let resolved: Value = get("/resolve/@fix").await.unwrap().json().await.unwrap();
+        assert_eq!(resolved, json!({"id": "2", "index": 2}));
+        assert_eq!(get("/resolve/@gone").await.unwrap().status(), StatusCode::NOT_FOUND);
+        let fetched: Value = get("/sessions/1").await.unwrap().json().await.unwrap();
+        assert_eq!(fetched["title"], "Session 1");
+
+        let response = post("/sessions/@fix/messages", json!({"prompt": "Use tabs"})).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(post("/sessions/2/approve", json!({})).await.unwrap().status(), StatusCode::NO_CONTENT);
+        let response = post("/sessions", json!({"title": "Add docs", "alias": "@docs"})).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let aliases: Value = get("/aliases").await.unwrap().json().await.unwrap();
+        assert_eq!(aliases["@docs"], "3");
+
+        let requests = recorded.lock().unwrap().clone();
+        let paths: Vec<String> = requests.iter().map(|(method, path, _)| format!("{} {}", method, path)).collect();
+        // The session list is fetched once and reused.
+        assert_eq!(
+            paths,
+            [
+                "GET /sessions",
+                "GET /sessions/1",
+                "POST /sessions/2:sendMessage",
+                "POST /sessions/2:approvePlan",
+                "POST /sessions",
+            ]
+        );
+        assert_eq!(serde_json::from_str::<Value>(&requests[2].2).unwrap()["prompt"], "Use tabs");
+        let created: Value = serde_json::from_str(&requests[4].2).unwrap();
+        assert_eq!(created["sourceContext"]["source"], "sources/github/o/r");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_gateway_without_token_stays_local() {
+        let (api, _) = jules_stand_in(vec![session("1", "IN_PROGRESS")], json!([]));
+        let (dir, base) = gateway("local", &api, "");
+        let http = reqwest::Client::new();
+        let response = http.get(format!("{}/sessions", base)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // A page of another site, resolving to the loopback address.
+        let response = http
+            .get(format!("{}/sessions", base))
+            .header(header::HOST, "attacker.example:8765")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        assert!(is_loopback_host("localhost:8765") && is_loopback_host("127.0.0.1") && is_loopback_host("[::1]:80"));
+        assert!(!is_loopback_host("localhost.attacker.example") && !is_loopback_host("192.168.1.2:8765"));
+
+        let config = Config::load_from(dir.join("config.toml")).unwrap();
+        let client = JulesClient::new(Some("key".to_string())).unwrap();
+        let gateway = Arc::new(Gateway::new(Arc::new(client), Cache::in_dir(&dir), &config));
+        let error = serve(gateway, "0.0.0.0:0").await.unwrap_err();
+        assert!(error.contains("gateway.token"), "{}", error);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_activity_stream_resumes_and_ends_with_the_session() {
+        let id = "1";
+        let activity = |activity_id: &str| {
+            json!({
+                "name": format!("sessions/{}/activities/{}", id, activity_id),
+                "id": activity_id,
+                "createTime": "2024-05-01T10:00:00Z",
+                "originator": "agent",
+                "progressUpdated": {"title": format!("Step {}", activity_id)},
+            })
+        };
+        let (api, _) = jules_stand_in(vec![session(id, "COMPLETED")], json!([activity("a"), activity("b")]));
+        let (dir, base) = gateway("stream", &api, "");
+
+        let body = reqwest::Client::new()
+            .get(format!("{}/sessions/{}/activities/stream", base, id))
+            .header("Last-Event-ID", "a")
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let events: Vec<&str> = body.lines().filter_map(|line| line.strip_prefix("event:")).collect();
+        assert_eq!(events, ["activity", "end"], "{}", body);
+        assert!(body.contains("id:b") && body.contains("Step b"), "{}", body);
+        assert!(!body.contains("Step a"), "{}", body);
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module runs user-defined commands when sessions change.
+//!
+//! Hooks are set in the `hooks` section of the config, one shell command per
+//! event kind, e.g. `hooks.artifact = "make test"`. A hook receives the event
+//! as JSON on stdin and in `JULEZZ_*` environment variables, and is killed
+//! when it runs longer than `hooks.timeout_seconds`. A failing hook is
+//! reported to the caller and does not stop it.
+
+use crate::config::{Config, NOTIFICATION_EVENTS};
+use crate::events::Event;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// The commands to run for each kind of event.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    /// The command of each event kind, by kind name.
+    commands: Vec<(String, String)>,
+    timeout: Duration,
+}
+
+impl Hooks {
+    /// Reads the hooks from the config.
+    pub fn from_config(config: &Config) -> Self {
+        let commands = NOTIFICATION_EVENTS
+            .iter()
+            .filter_map(|kind| {
+                let command = config.hook(kind)?;
+                Some((kind.to_string(), command))
+            })
+            .collect();
+        Self {
+            commands,
+            timeout: Duration::from_secs(config.hook_timeout_seconds()),
+        }
+    }
+
+    /// Returns whether no hook is set.
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Runs the hook of an event, if it has one.
+    ///
+    /// # Returns
+    ///
+    /// An error string if the hook could not be run, failed or timed out.
+    pub async fn run(&self, event: &Event) -> Result<(), String> {
+        match self.commands.iter().find(|(kind, _)| kind == event.kind.name()) {
+            Some((_, command)) => run_hook(command, event, self.timeout).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns the environment variables describing an event to a hook.
+pub fn event_env(event: &Event) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("JULEZZ_EVENT_TYPE", event.kind.name().to_string()),
+        ("JULEZZ_EVENT_TIME", event.time.clone()),
+        ("JULEZZ_SESSION_ID", event.session_id.clone()),
+        ("JULEZZ_SESSION_TITLE", event.session_title.clone()),
+    ];
+    if let Some(activity_id) = &event.activity_id {
+        env.push(("JULEZZ_ACTIVITY_ID", activity_id.clone()));
+    }
+    if let Some(detail) = &event.detail {
+        env.push(("JULEZZ_EVENT_DETAIL", detail.clone()));
+    }
+    if let Some(url) = &event.pull_request_url {
+        env.push(("JULEZZ_PULL_REQUEST_URL", url.clone()));
+    }
+    env
+}
+
+/// Runs a hook command through the shell, passing it an event.
+async fn run_hook(command: &str, event: &Event, timeout: Duration) -> Result<(), String> {
+    let json = serde_json::to_string(event).map_err(|e| format!("Could not serialize event: {}", e))?;
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(event_env(event))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to run hook '{}': {}", command, e))?;
+
+    // The input is written while the hook runs, so that a hook that does not
+    // read it cannot outlive the timeout.
+    let stdin = child.stdin.take();
+    let write = async move {
+        if let Some(mut stdin) = stdin {
+            // A hook that does not read its input is not an error.
+            let _ = stdin.write_all(json.as_bytes()).await;
+        }
+    };
+    let run = async move { tokio::join!(write, child.wait_with_output()).1 };
+    let output = match tokio::time::timeout(timeout, run).await {
+        Ok(output) => output.map_err(|e| format!("Failed to run hook '{}': {}", command, e))?,
+        Err(_) => return Err(format!("Hook '{}' timed out after {}s", command, timeout.as_secs())),
+    };
+    if !output.status.success() {
+        return Err(format!(
+            "Hook '{}' failed ({}): {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+    use crate::test_util;
+
+    fn hooks(kind: &str, command: &str, timeout: Duration) -> Hooks {
+        Hooks {
+            commands: vec![(kind.to_string(), command.to_string())],
+            timeout,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks_receive_the_event() {
+        let output = std::env::temp_dir().join(format!("julezz-hook-test-{}", std::process::id()));
+        let command = format!(
+            "cat > {0} && echo \"$JULEZZ_EVENT_TYPE $JULEZZ_SESSION_ID $JULEZZ_EVENT_DETAIL\" >> {0}",
+            output.display()
+        );
+        let event = Event {
+            detail: Some("changeSet".to_string()),
+            ..test_util::event(EventKind::Artifact)
+        };
+        hooks("artifact", &command, Duration::from_secs(10)).run(&event).await.unwrap();
+
+        let written = std::fs::read_to_string(&output).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        let (json, env) = written.split_at(written.find('}').unwrap() + 1);
+        let sent: Event = serde_json::from_str(json).unwrap();
+        assert_eq!(sent, event);
+        assert_eq!(env.trim(), "artifact 42 changeSet");
+
+        // Other kinds of events do not run the hook.
+        hooks("plan", "exit 1", Duration::from_secs(10)).run(&event).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failing_and_slow_hooks_are_errors() {
+        let error = hooks("artifact", "echo broken >&2; exit 3", Duration::from_secs(10))
+            .run(&test_util::event(EventKind::Artifact))
+            .await
+            .unwrap_err();
+        assert!(error.contains("broken"), "{}", error);
+
+        let error = hooks("artifact", "sleep 5", Duration::from_millis(100))
+            .run(&test_util::event(EventKind::Artifact))
+            .await
+            .unwrap_err();
+        assert!(error.contains("timed out"), "{}", error);
+
+        // An input larger than a pipe's buffer, which the hook never reads.
+        // It is in the environment too, whose variables are limited to 128 KiB.
+        let event = Event {
+            detail: Some("x".repeat(100_000)),
+            ..test_util::event(EventKind::Artifact)
+        };
+        let started = std::time::Instant::now();
+        let error = hooks("artifact", "sleep 5", Duration::from_millis(100)).run(&event).await.unwrap_err();
+        assert!(error.contains("timed out"), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
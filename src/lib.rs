@@ -1,3 +1,23 @@
+pub mod access;
 pub mod api;
 pub mod cache;
+pub mod config;
+pub mod credentials;
+pub mod diff;
+pub mod digest;
+pub mod events;
+pub mod gateway;
+pub mod hooks;
+pub mod logging;
+pub mod mcp;
+pub mod metrics;
+pub mod notify;
+pub mod poll;
+pub mod preferences;
+pub mod profile;
 pub mod resolve;
+pub mod trash;
+pub mod webhooks;
+
+#[cfg(test)]
+mod test_util;
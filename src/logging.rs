@@ -0,0 +1,115 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module sets up the logs of the CLI, the bot and the daemons.
+//!
+//! Logs are `tracing` events, written to stderr as text or as JSON lines.
+//! Records of the `log` crate are forwarded to them. Each request to the
+//! Jules API is a span of its own, and the headers and bodies of requests are
+//! logged under the `julezz::http` target, with credentials redacted.
+
+use crate::config::OutputFormat;
+use reqwest::header::HeaderMap;
+use tracing_subscriber::EnvFilter;
+
+/// The target of the logs holding HTTP headers and bodies.
+pub const HTTP_TARGET: &str = "julezz::http";
+
+/// The headers whose values are never logged.
+const SENSITIVE_HEADERS: &[&str] = &["authorization", "x-goog-api-key", "cookie", "set-cookie"];
+
+/// Returns the log filter directives.
+///
+/// # Arguments
+///
+/// * `verbosity` - The number of `-v` flags. When zero, `RUST_LOG` is used,
+///   or only errors are logged.
+/// * `trace_http` - Whether to log the headers and bodies of requests. They
+///   are not logged otherwise, whatever the verbosity.
+/// * `rust_log` - The value of `RUST_LOG`.
+pub fn directives(verbosity: u8, trace_http: bool, rust_log: Option<&str>) -> String {
+    let mut directives = match (verbosity, rust_log.map(str::trim).filter(|s| !s.is_empty())) {
+        (0, Some(rust_log)) => rust_log.to_string(),
+        (0, None) => "error".to_string(),
+        (1, _) => "warn,julezz=info".to_string(),
+        (2, _) => "info,julezz=debug".to_string(),
+        _ => "debug,julezz=trace".to_string(),
+    };
+    if trace_http {
+        directives.push_str(&format!(",{}=trace", HTTP_TARGET));
+    } else if verbosity >= 3 {
+        directives.push_str(&format!(",{}=off", HTTP_TARGET));
+    }
+    directives
+}
+
+/// Installs the global logger. Does nothing if one is already installed.
+pub fn init(verbosity: u8, trace_http: bool, format: OutputFormat) {
+    let rust_log = std::env::var("RUST_LOG").ok();
+    let filter = EnvFilter::try_new(directives(verbosity, trace_http, rust_log.as_deref()))
+        .unwrap_or_else(|e| {
+            eprintln!("Ignoring invalid RUST_LOG: {}", e);
+            EnvFilter::new(directives(verbosity, trace_http, None))
+        });
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    let _ = match format {
+        OutputFormat::Json => builder.json().try_init(),
+        OutputFormat::Text => builder.try_init(),
+    };
+}
+
+/// Formats headers for the logs, with the values of credentials redacted.
+pub fn redact_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                "[redacted]"
+            } else {
+                value.to_str().unwrap_or("[binary]")
+            };
+            format!("{}: {}", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directives() {
+        assert_eq!(directives(0, false, None), "error");
+        assert_eq!(directives(0, false, Some("julezz=debug")), "julezz=debug");
+        // Flags take precedence over RUST_LOG.
+        assert_eq!(directives(2, false, Some("off")), "info,julezz=debug");
+        assert_eq!(directives(5, false, None), "debug,julezz=trace,julezz::http=off");
+        assert_eq!(directives(3, true, None), "debug,julezz=trace,julezz::http=trace");
+        assert_eq!(directives(0, true, Some(" ")), "error,julezz::http=trace");
+    }
+
+    #[test]
+    fn test_redact_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-goog-api-key", "secret-key".parse().unwrap());
+        headers.insert("authorization", "Bearer secret-token".parse().unwrap());
+        headers.insert("content-type", "application/json".parse().unwrap());
+
+        let redacted = redact_headers(&headers);
+        assert!(!redacted.contains("secret"), "{}", redacted);
+        assert!(redacted.contains("x-goog-api-key: [redacted]"));
+        assert!(redacted.contains("content-type: application/json"));
+    }
+}
@@ -14,14 +14,27 @@
 
 //! A command-line interface for the Jules API.
 
-use clap::{CommandFactory, Parser};
+use clap::{CommandFactory, FromArgMatches, Parser};
 use colored::Colorize;
 use julezz::api::{handle_error, JulesClient, Session};
-use std::io;
+use std::io::{self, IsTerminal, Write};
+use std::process::{Command, Stdio};
+use tracing::Instrument;
 
 mod bot;
 use julezz::cache::{Cache, CachedSession};
+use julezz::config::{ColorMode, Config, Origin, OutputFormat};
+use julezz::events::{detect, new_activities, sort_activities, Event, EventKind};
+use julezz::gateway::{self, Gateway};
+use julezz::hooks::Hooks;
+use julezz::logging;
+use julezz::mcp::McpServer;
+use julezz::metrics;
+use julezz::notify::{Notifier, Sink};
+use julezz::poll::is_terminal;
 use julezz::resolve::{resolve_session_identifier, resolve_session_identifier_and_index};
+use julezz::trash::Trash;
+use julezz::webhooks::{self, DeliveryRecord, WebhookServer, WebhookStore};
 
 fn get_sessions_from_cache() -> Result<Vec<Session>, String> {
     let cache = Cache::new()?;
@@ -35,6 +48,8 @@ fn get_sessions_from_cache() -> Result<Vec<Session>, String> {
             title: s.title,
             source_context: s.source_context,
             pull_request_url: s.pull_request_url,
+            create_time: None,
+            update_time: None,
         })
         .collect();
     Ok(api_sessions)
@@ -48,6 +63,18 @@ struct Args {
     #[arg(short, long, env = "JULES_API_KEY")]
     api_key: Option<String>,
 
+    /// The profile to use
+    #[arg(long, global = true, env = "JULEZZ_PROFILE")]
+    profile: Option<String>,
+
+    /// Log more details to stderr (-v, -vv or -vvv)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Log the headers and bodies of Jules API requests, with credentials redacted
+    #[arg(long, global = true)]
+    trace_http: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -69,11 +96,52 @@ enum Commands {
         #[command(subcommand)]
         command: ActivitiesCommands,
     },
+    /// Manage the configuration file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Manage stored credentials
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommands,
+    },
+    /// Manage profiles
+    Profile {
+        #[command(subcommand)]
+        command: ProfileCommands,
+    },
+    /// Manage locally trashed sessions
+    Trash {
+        #[command(subcommand)]
+        command: TrashCommands,
+    },
     /// Start the Telegram bot
     Bot {
         #[command(subcommand)]
         command: BotCommands,
     },
+    /// Send session events to Slack, Discord, Matrix, email, webhooks or Telegram
+    Notify {
+        #[command(subcommand)]
+        command: NotifyCommands,
+    },
+    /// Manage the endpoints receiving session events over webhooks
+    Webhooks {
+        #[command(subcommand)]
+        command: WebhooksCommands,
+    },
+    /// Run a local HTTP/JSON gateway to the Jules API, or another server
+    #[command(args_conflicts_with_subcommands = true)]
+    Serve {
+        /// The address the gateway listens on (defaults to gateway.listen)
+        #[arg(long)]
+        listen: Option<String>,
+        #[command(subcommand)]
+        command: Option<ServeCommands>,
+    },
+    /// Serve Jules to other coding assistants over the Model Context Protocol (stdio)
+    Mcp,
     /// Generate shell completions
     Completions {
         /// The shell to generate completions for
@@ -116,16 +184,16 @@ enum SessionsCommands {
     List,
     /// Create a new session
     Create {
-        /// The source to use for the session
+        /// The source to use for the session (defaults to sessions.source)
         #[arg(short, long)]
-        source: String,
+        source: Option<String>,
         /// The title of the session
         #[arg(last = true)]
         title: String,
-        /// The branch to use for the session
-        #[arg(short, long, default_value = "main")]
-        branch: String,
-        /// Disable automatically creating a pull request
+        /// The branch to use for the session (defaults to sessions.branch, or main)
+        #[arg(short, long)]
+        branch: Option<String>,
+        /// Disable automatically creating a pull request (overrides sessions.automation_mode)
         #[arg(long)]
         no_auto_pr: bool,
         /// Alias to create for the new session
@@ -172,13 +240,13 @@ enum ActivitiesCommands {
     List {
         /// The index of the session
         index: String,
-        /// Number of last messages to show
-        #[arg(short, long, default_value_t = 5)]
-        n: usize,
+        /// Number of last messages to show (defaults to activities.count, or 5)
+        #[arg(short, long)]
+        n: Option<usize>,
         /// Re-fetch messages before listing
         #[arg(short, long)]
         r: bool,
-        /// Show raw JSON output
+        /// Show raw JSON output (also enabled by output.format = "json")
         #[arg(long)]
         raw: bool,
     },
@@ -189,6 +257,117 @@ enum ActivitiesCommands {
         /// The ID of the activity to get
         id: String,
     },
+    /// Watch a session by index, printing new activities and running hooks
+    Watch {
+        /// The index of the session
+        index: String,
+        /// Seconds between checks (defaults to bot.poll_interval_seconds, or 30)
+        #[arg(short, long)]
+        interval: Option<u64>,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ConfigCommands {
+    /// Show the effective value of a setting
+    Get {
+        /// The setting, e.g. sessions.branch
+        key: String,
+    },
+    /// Set a setting in the config file
+    Set {
+        /// The setting, e.g. sessions.branch
+        key: String,
+        /// The new value (comma-separated for lists)
+        value: String,
+    },
+    /// Remove a setting from the config file
+    Unset {
+        /// The setting, e.g. sessions.branch
+        key: String,
+    },
+    /// List all settings with their effective values
+    List,
+    /// Open the config file in your editor
+    Edit,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum AuthCommands {
+    /// Store an API key for the current profile
+    ///
+    /// By default the key is stored in an encrypted credential file protected
+    /// by a passphrase (read from JULEZZ_PASSPHRASE or prompted for).
+    Login {
+        /// Protect the credential file with this key file instead of a passphrase
+        #[arg(long, conflicts_with = "helper")]
+        key_file: Option<std::path::PathBuf>,
+        /// Hand the key to this credential helper command instead of storing it
+        #[arg(long)]
+        helper: Option<String>,
+    },
+    /// Forget the stored API key of the current profile
+    Logout,
+    /// Show where the API key of the current profile comes from
+    Status,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ProfileCommands {
+    /// List profiles
+    List,
+    /// Add a profile, or update its settings
+    Add {
+        /// The name of the profile
+        name: String,
+        /// The environment variable holding the profile's API key
+        #[arg(long)]
+        api_key_env: Option<String>,
+        /// How the profile authenticates (api-key, service-account or application-default)
+        #[arg(long)]
+        auth: Option<julezz::profile::AuthMethod>,
+        /// The service account credentials JSON file
+        #[arg(long)]
+        credentials_file: Option<std::path::PathBuf>,
+        /// The base URL of the Jules API
+        #[arg(long)]
+        base_url: Option<String>,
+        /// The default source for new sessions (sets sessions.source in the profile's config)
+        #[arg(long)]
+        source: Option<String>,
+        /// The default branch for new sessions (sets sessions.branch in the profile's config)
+        #[arg(long)]
+        branch: Option<String>,
+    },
+    /// Make a profile the active one
+    Use {
+        /// The name of the profile
+        name: String,
+    },
+    /// Remove a profile along with its cache and aliases
+    Remove {
+        /// The name of the profile
+        name: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum TrashCommands {
+    /// List deleted sessions kept in the trash
+    List,
+    /// Restore the local metadata of a deleted session
+    Restore {
+        /// The ID of the trashed session
+        id: String,
+    },
+    /// Permanently remove sessions from the trash
+    Purge {
+        /// The ID of the trashed session to purge (defaults to expired sessions)
+        id: Option<String>,
+        /// Purge every session in the trash
+        #[arg(long, conflicts_with = "id")]
+        all: bool,
+    },
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -197,11 +376,134 @@ enum BotCommands {
     Start,
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum NotifyCommands {
+    /// Start the notify daemon
+    Start,
+    /// Send a test event to every configured sink
+    Test,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum WebhooksCommands {
+    /// Register an endpoint
+    Add {
+        /// The URL to POST events to
+        url: String,
+        /// The events to deliver, separated by commas (defaults to all)
+        #[arg(long, value_delimiter = ',')]
+        events: Vec<String>,
+        /// The secret to sign payloads with (defaults to a random one)
+        #[arg(long)]
+        secret: Option<String>,
+    },
+    /// List the registered endpoints
+    List,
+    /// Remove an endpoint
+    Remove {
+        /// The ID of the endpoint
+        id: String,
+    },
+    /// Show the latest delivery attempts
+    Log {
+        /// The number of attempts to show
+        #[arg(short, long, default_value_t = 20)]
+        number: usize,
+        /// Only show the attempts of this endpoint
+        #[arg(long)]
+        endpoint: Option<String>,
+    },
+    /// Send a logged delivery again
+    Replay {
+        /// The ID of the delivery
+        delivery_id: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ServeCommands {
+    /// Deliver session events to the registered webhook endpoints
+    Webhooks,
+}
+
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    // Settings in .env apply to the arguments, the config and the sinks.
+    dotenv::dotenv().ok();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    if let Err(e) = julezz::profile::set_current(args.profile.clone()) {
+        eprintln!("{} {}", "Error:".red(), e);
+        return;
+    }
 
-    let client = match JulesClient::new(args.api_key) {
+    // An invalid config file is reported by the command itself.
+    let log_format = Config::load().map(|config| config.log_format()).unwrap_or(OutputFormat::Text);
+    logging::init(args.verbose, args.trace_http, log_format);
+    let span = tracing::info_span!("command", command = %command_name(&matches));
+    run(args).instrument(span).await;
+}
+
+/// Names the command being run, e.g. `sessions list`, for the logs.
+fn command_name(matches: &clap::ArgMatches) -> String {
+    let mut names = Vec::new();
+    let mut matches = matches;
+    while let Some((name, subcommand)) = matches.subcommand() {
+        names.push(name);
+        matches = subcommand;
+    }
+    names.join(" ")
+}
+
+async fn run(args: Args) {
+    // Profile management does not need an API client.
+    if let Commands::Profile { command } = args.command {
+        if let Err(e) = manage_profiles(command) {
+            eprintln!("{} {}", "Error:".red(), e);
+        }
+        return;
+    }
+
+    let profile = match julezz::profile::load_current() {
+        Ok(profile) => profile,
+        Err(e) => {
+            eprintln!("{} {}", "Error:".red(), e);
+            return;
+        }
+    };
+
+    // The config file can be repaired with `config edit` even when invalid.
+    if let Commands::Config { command } = args.command {
+        if let Err(e) = manage_config(command) {
+            eprintln!("{} {}", "Error:".red(), e);
+        }
+        return;
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{} {}", "Error:".red(), e);
+            eprintln!("Use `julezz config edit` to fix your config file.");
+            return;
+        }
+    };
+    match config.color() {
+        ColorMode::Always => colored::control::set_override(true),
+        ColorMode::Never => colored::control::set_override(false),
+        ColorMode::Auto => {}
+    }
+
+    // Credential management must work before a key is available.
+    if let Commands::Auth { command } = args.command {
+        if let Err(e) = manage_auth(command, profile) {
+            eprintln!("{} {}", "Error:".red(), e);
+        }
+        return;
+    }
+
+    let client = match JulesClient::for_profile(args.api_key, &profile) {
         Ok(client) => client,
         Err(e) => {
             handle_error(e);
@@ -244,11 +546,15 @@ async fn main() {
             SessionsCommands::List => {
                 match client.list_sessions().await {
                     Ok(sessions_list) => {
-                        println!("{}", "Jules Sessions".bold().underline());
-                        if sessions_list.is_empty() {
-                            println!("No sessions found.");
+                        if config.output_format() == OutputFormat::Json {
+                            if let Err(e) = manage_sessions_cache(&sessions_list, OutputFormat::Json) {
+                                eprintln!("{} {}", "Error:".red(), e);
+                            }
                         } else {
-                            if let Err(e) = manage_sessions_cache(&sessions_list) {
+                            println!("{}", "Jules Sessions".bold().underline());
+                            if sessions_list.is_empty() {
+                                println!("No sessions found.");
+                            } else if let Err(e) = manage_sessions_cache(&sessions_list, OutputFormat::Text) {
                                 eprintln!("{} {}", "Error:".red(), e);
                             }
                         }
@@ -259,7 +565,13 @@ async fn main() {
                 }
             }
             SessionsCommands::Create { source, title, branch, no_auto_pr, alias } => {
-                match client.create_session(&source, &title, !no_auto_pr, &branch).await {
+                let Some(source) = source.or_else(|| config.source()) else {
+                    eprintln!("{} No source given and no default source is configured (sessions.source).", "Error:".red());
+                    return;
+                };
+                let branch = branch.unwrap_or_else(|| config.branch());
+                let auto_pr = !no_auto_pr && config.auto_pr();
+                match client.create_session(&source, &title, auto_pr, &branch).await {
                     Ok(session) => {
                         println!("Session created:");
                         println!("- {}: {} ({})", session.id, session.name, session.state.clone().unwrap_or_default());
@@ -313,11 +625,11 @@ async fn main() {
                                             println!("Pull request merged successfully!");
                                         }
                                     } else {
-                                        eprintln!("{} {}", "Error:".red(), "No pull request URL found for this session.");
+                                        eprintln!("{} No pull request URL found for this session.", "Error:".red());
                                     }
                                 } else {
                                     // This case should ideally not be reached if resolve_session_identifier works correctly
-                                    eprintln!("{} {}", "Error:".red(), "Session not found after resolving identifier.");
+                                    eprintln!("{} Session not found after resolving identifier.", "Error:".red());
                                 }
                             }
                             Err(e) => {
@@ -353,11 +665,11 @@ async fn main() {
                 match get_sessions_from_cache() {
                     Ok(sessions) => {
                         match resolve_session_identifier_and_index(&index, &sessions) {
-                            Ok((session_id, session_index)) => {
+                            Ok((session_id, _)) => {
                                 match client.delete_session(&session_id).await {
                                     Ok(_) => {
                                         println!("Session {} deleted.", session_id);
-                                        if let Err(e) = remove_session_from_cache(session_index).and_then(|_| update_aliases_after_deletion(&session_id)) {
+                                        if let Err(e) = move_session_to_trash(&session_id) {
                                             eprintln!("{} {}", "Error updating local state:".red(), e);
                                             eprintln!("{}", "Your local state may be out of sync with the server.".yellow());
                                         }
@@ -407,7 +719,7 @@ async fn main() {
                                 let session = cache.read_sessions().unwrap().remove(session_index - 1);
                                 match client.fetch_activities(&session_id).await {
                                     Ok(activities) => {
-                                        print_activities(&activities, activities.len(), &session);
+                                        with_pager(&config, |out| print_activities(out, &activities, activities.len(), &session));
                                     }
                                     Err(e) => {
                                         handle_error(e);
@@ -439,14 +751,15 @@ async fn main() {
 
                                 match activities_result {
                                     Ok(activities) => {
-                                        if raw {
+                                        if raw || config.output_format() == OutputFormat::Json {
                                             if let Ok(json) = serde_json::to_string_pretty(&activities) {
                                                 println!("{}", json);
                                             } else {
-                                                eprintln!("{} {}", "Error:".red(), "Could not serialize activities to JSON");
+                                                eprintln!("{} Could not serialize activities to JSON", "Error:".red());
                                             }
                                         } else {
-                                            print_activities(&activities, n, &session);
+                                            let n = n.unwrap_or_else(|| config.activity_count());
+                                            with_pager(&config, |out| print_activities(out, &activities, n, &session));
                                         }
                                     }
                                     Err(e) => {
@@ -489,12 +802,53 @@ async fn main() {
                     }
                 }
             }
+            ActivitiesCommands::Watch { index, interval } => {
+                if let Err(e) = watch_activities(&client, &config, &index, interval).await {
+                    eprintln!("{} {}", "Error:".red(), e);
+                }
+            }
         },
+        Commands::Profile { .. } | Commands::Auth { .. } | Commands::Config { .. } => {
+            unreachable!("profile, auth and config commands are handled before the client is created")
+        }
+        Commands::Trash { command } => {
+            if let Err(e) = manage_trash(command) {
+                eprintln!("{} {}", "Error:".red(), e);
+            }
+        }
         Commands::Bot { command } => match command {
             BotCommands::Start => {
                 bot::start_bot().await;
             }
         },
+        Commands::Notify { command } => {
+            if let Err(e) = manage_notify(command, client, &config).await {
+                eprintln!("{} {}", "Error:".red(), e);
+            }
+        }
+        Commands::Webhooks { command } => {
+            if let Err(e) = manage_webhooks(command, &config).await {
+                eprintln!("{} {}", "Error:".red(), e);
+            }
+        }
+        Commands::Serve { listen, command } => {
+            if let Err(e) = serve(listen, command, client, &config).await {
+                eprintln!("{} {}", "Error:".red(), e);
+            }
+        }
+        Commands::Mcp => {
+            let cache = match Cache::new() {
+                Ok(cache) => cache,
+                Err(e) => {
+                    eprintln!("{} {}", "Error:".red(), e);
+                    return;
+                }
+            };
+            let server = McpServer::new(std::sync::Arc::new(client), cache, &config);
+            if let Err(e) = server.run().await {
+                eprintln!("{} {}", "Error:".red(), e);
+            }
+        }
         Commands::Completions { shell } => {
             let mut cmd = Args::command();
             let name = cmd.get_name().to_string();
@@ -518,20 +872,22 @@ async fn main() {
 /// This function ensures that the local cache is up-to-date with the server.
 /// It removes any sessions from the cache that are no longer on the server,
 /// and adds any new sessions from the server to the cache.
-fn manage_sessions_cache(sessions_list: &[julezz::api::Session]) -> Result<(), String> {
+fn manage_sessions_cache(sessions_list: &[julezz::api::Session], format: OutputFormat) -> Result<(), String> {
     let cache = Cache::new()?;
     let cached_sessions: Vec<CachedSession> = sessions_list
         .iter()
-        .map(|session| CachedSession {
-            id: session.id.clone(),
-            title: session.title.clone(),
-            source_context: session.source_context.clone(),
-            pull_request_url: session.pull_request_url.clone(),
-        })
+        .map(CachedSession::from)
         .collect();
 
     cache.write_sessions(&cached_sessions)?;
 
+    if format == OutputFormat::Json {
+        let json = serde_json::to_string_pretty(sessions_list)
+            .map_err(|e| format!("Could not serialize sessions to JSON: {}", e))?;
+        println!("{}", json);
+        return Ok(());
+    }
+
     let session_states: std::collections::HashMap<_, _> = sessions_list
         .iter()
         .map(|s| (s.id.as_str(), s.state.as_deref().unwrap_or("UNKNOWN")))
@@ -571,23 +927,500 @@ fn manage_sessions_cache(sessions_list: &[julezz::api::Session]) -> Result<(), S
     Ok(())
 }
 
-/// Removes a session from the local cache by its 1-based index.
-fn remove_session_from_cache(index: usize) -> Result<(), String> {
+/// Moves the local state of a deleted session into the trash.
+///
+/// This removes the session from the sessions cache and its aliases, keeping
+/// them along with the cached activities so they can be restored later.
+fn move_session_to_trash(session_id: &str) -> Result<(), String> {
     let cache = Cache::new()?;
-    let mut sessions = cache.read_sessions()?;
-    if index > 0 && index <= sessions.len() {
-        sessions.remove(index - 1);
-        cache.write_sessions(&sessions)?;
+    let trash = Trash::new()?;
+    trash.trash_session(&cache, session_id)?;
+    println!(
+        "Local metadata moved to trash (kept for {} days). Use `julezz trash restore {}` to undo.",
+        trash.retention_days(),
+        session_id
+    );
+    Ok(())
+}
+
+/// Manages the config file of the current profile.
+fn manage_config(command: ConfigCommands) -> Result<(), String> {
+    match command {
+        ConfigCommands::Edit => {
+            // The file is opened even when invalid, so that it can be fixed.
+            let path = julezz::profile::config_dir()?.join("config.toml");
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| format!("Could not create config directory: {}", e))?;
+            }
+            let editor = std::env::var("VISUAL")
+                .or_else(|_| std::env::var("EDITOR"))
+                .unwrap_or_else(|_| "vi".to_string());
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(format!("{} \"$1\"", editor))
+                .arg("sh")
+                .arg(&path)
+                .status()
+                .map_err(|e| format!("Could not run editor '{}': {}", editor, e))?;
+            if !status.success() {
+                return Err(format!("Editor '{}' exited with {}", editor, status));
+            }
+            Config::load()?;
+            println!("Config file {} is valid.", path.display());
+            return Ok(());
+        }
+        ConfigCommands::Get { key } => {
+            let config = Config::load()?;
+            match config.resolve(&key)? {
+                Some((value, _)) => println!("{}", value),
+                None => return Err(format!("'{}' is not set.", key)),
+            }
+        }
+        ConfigCommands::Set { key, value } => {
+            let mut config = Config::load()?;
+            config.set(&key, &value)?;
+            config.save()?;
+            let setting = julezz::config::setting(&key)?;
+            println!("{} = {}", key, setting.display(&value));
+            if std::env::var(setting.env).is_ok() {
+                eprintln!(
+                    "{} {} is set and overrides this value.",
+                    "Warning:".yellow(),
+                    setting.env
+                );
+            }
+        }
+        ConfigCommands::Unset { key } => {
+            let mut config = Config::load()?;
+            if config.unset(&key)? {
+                config.save()?;
+                println!("{} removed.", key);
+            } else {
+                println!("{} is not set in the config file.", key);
+            }
+        }
+        ConfigCommands::List => {
+            let config = Config::load()?;
+            println!("{} {}", "Config file:".dimmed(), config.path().display());
+            for setting in julezz::config::SETTINGS {
+                let (value, origin) = match config.resolve(setting.key)? {
+                    Some((value, Origin::Env)) => (value, format!("env {}", setting.env)),
+                    Some((value, Origin::File)) => (value, "config".to_string()),
+                    Some((value, Origin::Default)) => (value, "default".to_string()),
+                    None => ("".to_string(), "unset".to_string()),
+                };
+                println!(
+                    "{} = {} {}",
+                    setting.key.bold(),
+                    setting.display(&value),
+                    format!("({})", origin).dimmed()
+                );
+            }
+        }
     }
     Ok(())
 }
 
-/// Removes any aliases that point to a deleted session.
-fn update_aliases_after_deletion(deleted_session_id: &str) -> Result<(), String> {
+/// Runs `write` with the configured pager as output, or with stdout when no
+/// pager is configured or stdout is not a terminal.
+fn with_pager(config: &Config, write: impl FnOnce(&mut dyn Write) -> io::Result<()>) {
+    if let Some(pager) = config.pager().filter(|_| io::stdout().is_terminal()) {
+        match Command::new("sh").arg("-c").arg(&pager).stdin(Stdio::piped()).spawn() {
+            Ok(mut child) => {
+                if let Some(mut stdin) = child.stdin.take() {
+                    // The pager may exit before reading everything.
+                    let _ = write(&mut stdin);
+                }
+                let _ = child.wait();
+                return;
+            }
+            Err(e) => {
+                eprintln!("{} Could not run pager '{}': {}", "Warning:".yellow(), pager, e);
+            }
+        }
+    }
+    if let Err(e) = write(&mut io::stdout().lock()) {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("{} {}", "Error:".red(), e);
+        }
+    }
+}
+
+/// Manages the stored credentials of the current profile.
+fn manage_auth(command: AuthCommands, mut profile: julezz::profile::Profile) -> Result<(), String> {
+    use julezz::credentials::{self, KeySource, Protection};
+
+    let profile_name = julezz::profile::current();
+    match command {
+        AuthCommands::Login { key_file, helper } => {
+            let api_key = rpassword::prompt_password("Jules API key: ")
+                .map_err(|e| format!("Could not read API key: {}", e))?;
+            let api_key = api_key.trim();
+            if api_key.is_empty() {
+                return Err("The API key must not be empty.".to_string());
+            }
+
+            if let Some(helper) = helper {
+                credentials::helper_store(&helper, api_key)?;
+                profile.credential_helper = Some(helper.clone());
+                julezz::profile::save(&profile_name, &profile)?;
+                println!("API key handed to credential helper '{}'.", helper);
+            } else {
+                let protection = match key_file {
+                    Some(path) => Protection::KeyFile {
+                        path: std::fs::canonicalize(&path)
+                            .map_err(|e| format!("Could not find key file '{}': {}", path.display(), e))?,
+                    },
+                    None => Protection::Passphrase,
+                };
+                credentials::store_encrypted(api_key, protection)?;
+                println!("API key stored in the encrypted credential file of profile '{}'.", profile_name);
+            }
+        }
+        AuthCommands::Logout => {
+            let mut forgotten = credentials::remove_encrypted()?;
+            if let Some(helper) = profile.credential_helper.take() {
+                credentials::helper_erase(&helper)?;
+                julezz::profile::save(&profile_name, &profile)?;
+                forgotten = true;
+            }
+            if forgotten {
+                println!("Stored credentials of profile '{}' removed.", profile_name);
+            } else {
+                println!("No stored credentials for profile '{}'.", profile_name);
+            }
+        }
+        AuthCommands::Status => {
+            println!("Profile: {}", profile_name.bold());
+            if std::env::var("JULES_API_KEY").is_ok() {
+                println!("  {}", "JULES_API_KEY is set and takes precedence.".yellow());
+            }
+            for line in credentials::describe(&profile)? {
+                println!("  configured: {}", line);
+            }
+            match credentials::resolve_api_key()? {
+                Some((_, source)) => {
+                    let source = match source {
+                        KeySource::ProfileEnv(var) => format!("environment variable {}", var),
+                        KeySource::Helper(helper) => format!("credential helper '{}'", helper),
+                        KeySource::CredentialFile => "encrypted credential file".to_string(),
+                    };
+                    println!("  {} API key resolved from {}.", "OK".green(), source);
+                }
+                None => println!("  {} No API key available.", "Missing".red()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Manages profiles.
+///
+/// This function handles the listing, creation, selection and removal of
+/// profiles.
+fn manage_profiles(command: ProfileCommands) -> Result<(), String> {
+    match command {
+        ProfileCommands::List => {
+            let current = julezz::profile::current();
+            for name in julezz::profile::list()? {
+                if name == current {
+                    println!("* {}", name.green());
+                } else {
+                    println!("  {}", name);
+                }
+            }
+        }
+        ProfileCommands::Add { name, api_key_env, auth, credentials_file, base_url, source, branch } => {
+            let mut profile = julezz::profile::load(&name).unwrap_or_default();
+            if api_key_env.is_some() {
+                profile.api_key_env = api_key_env;
+            }
+            if auth.is_some() {
+                profile.auth_method = auth;
+            }
+            if let Some(path) = credentials_file {
+                let path = std::fs::canonicalize(&path)
+                    .map_err(|e| format!("Could not find credentials file '{}': {}", path.display(), e))?;
+                profile.credentials_file = Some(path);
+            }
+            if base_url.is_some() {
+                profile.base_url = base_url;
+            }
+            julezz::profile::save(&name, &profile)?;
+            if source.is_some() || branch.is_some() {
+                let mut config = Config::load_profile(&name)?;
+                if let Some(source) = source {
+                    config.set("sessions.source", &source)?;
+                }
+                if let Some(branch) = branch {
+                    config.set("sessions.branch", &branch)?;
+                }
+                config.save()?;
+            }
+            println!("Profile '{}' saved.", name);
+        }
+        ProfileCommands::Use { name } => {
+            julezz::profile::set_active(&name)?;
+            println!("Now using profile '{}'.", name);
+        }
+        ProfileCommands::Remove { name } => {
+            julezz::profile::remove(&name)?;
+            println!("Profile '{}' removed.", name);
+        }
+    }
+    Ok(())
+}
+
+/// Manages the local trash of deleted sessions.
+fn manage_trash(command: TrashCommands) -> Result<(), String> {
     let cache = Cache::new()?;
-    let mut aliases = cache.read_aliases()?;
-    aliases.retain(|_, session_id| session_id != deleted_session_id);
-    cache.write_aliases(&aliases)
+    let trash = Trash::new()?;
+
+    match command {
+        TrashCommands::List => {
+            trash.purge_expired()?;
+            let entries = trash.list()?;
+            if entries.is_empty() {
+                println!("Trash is empty.");
+                return Ok(());
+            }
+            println!("{}", "Trashed Sessions".bold().underline());
+            for entry in entries {
+                let alias_str = if entry.aliases.is_empty() {
+                    "".yellow()
+                } else {
+                    format!(" ({}) ", entry.aliases.join(", ")).yellow()
+                };
+                println!("\n{}{}: {}", entry.session.id.bold(), alias_str, entry.session.title);
+                println!(
+                    "  {}: {} day(s) ago, purged in {} day(s)",
+                    "Deleted".dimmed(),
+                    entry.age_days(),
+                    trash.retention_days().saturating_sub(entry.age_days())
+                );
+            }
+        }
+        TrashCommands::Restore { id } => {
+            let (entry, conflicts) = trash.restore(&cache, &id)?;
+            println!("Restored local metadata for session {} ({}).", entry.session.id, entry.session.title);
+            for alias in conflicts {
+                eprintln!(
+                    "{} Alias '{}' is now used by another session and was not restored.",
+                    "Warning:".yellow(),
+                    alias
+                );
+            }
+        }
+        TrashCommands::Purge { id, all } => {
+            if let Some(id) = id {
+                trash.purge(&id)?;
+                println!("Session {} purged from trash.", id);
+            } else if all {
+                let purged = trash.purge_all()?;
+                println!("{} session(s) purged from trash.", purged);
+            } else {
+                let purged = trash.purge_expired()?;
+                println!("{} expired session(s) purged from trash.", purged);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the new activities of a session as they arrive and runs the hooks
+/// of their events, until the session completes, fails or is cancelled.
+async fn watch_activities(
+    client: &JulesClient,
+    config: &Config,
+    index: &str,
+    interval: Option<u64>,
+) -> Result<(), String> {
+    let sessions = get_sessions_from_cache()?;
+    let (session_id, session_index) = resolve_session_identifier_and_index(index, &sessions)?;
+    let cached = Cache::new()?.read_sessions()?.remove(session_index - 1);
+    let hooks = Hooks::from_config(config);
+    let interval = std::time::Duration::from_secs(interval.unwrap_or_else(|| config.poll_interval_seconds()).max(1));
+
+    println!("Watching session {} ({}). Press Ctrl-C to stop.\n", cached.id.bold(), cached.title);
+    let mut cursor = None;
+    // Only activities from now on are shown, like `tail -f`.
+    let since = Some(chrono::Utc::now());
+    loop {
+        let fetched = match client.get_session(&session_id).await {
+            Ok(session) => client.fetch_activities(&session_id).await.map(|activities| (session, activities)),
+            Err(e) => Err(e),
+        };
+        match fetched {
+            Ok((session, mut activities)) => {
+                sort_activities(&mut activities);
+                for activity in new_activities(&activities, cursor.as_ref(), since) {
+                    print_activity(&mut io::stdout(), activity, &cached).map_err(|e| e.to_string())?;
+                }
+                let changes = detect(&session, &mut activities, cursor.as_ref(), since, chrono::Utc::now());
+                for event in &changes.events {
+                    if event.kind == EventKind::PullRequest {
+                        println!("{} {}\n", "Pull Request".blue(), event.detail.as_deref().unwrap_or_default());
+                    }
+                    if let Err(e) = hooks.run(event).await {
+                        eprintln!("{} {}", "Hook failed:".red(), e);
+                    }
+                }
+                if changes.cursor.is_some() {
+                    cursor = changes.cursor;
+                }
+                if is_terminal(&session) {
+                    println!("Session {} is {}.", session.id, session.state.unwrap_or_default().to_lowercase());
+                    return Ok(());
+                }
+            }
+            // Errors are retried at the next check, e.g. after a network hiccup.
+            Err(e) => handle_error(e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Runs the notify daemon, or sends a test event to its sinks.
+async fn manage_notify(command: NotifyCommands, client: JulesClient, config: &Config) -> Result<(), String> {
+    let sinks = Sink::from_config(config)?;
+    if sinks.is_empty() {
+        return Err("No notification sinks are configured. Set e.g. notify.slack_webhook_url with `julezz config set`.".to_string());
+    }
+    let notifier = Notifier::new(std::sync::Arc::new(client), sinks, config);
+
+    match command {
+        NotifyCommands::Start => {
+            let names: Vec<&str> = notifier.sinks().iter().map(Sink::name).collect();
+            tracing::info!(sinks = %names.join(", "), "Starting notify daemon");
+            metrics::spawn_server(config);
+            notifier.run(Cache::new()?).await;
+        }
+        NotifyCommands::Test => {
+            let event = Event {
+                kind: EventKind::Completed,
+                session_id: "test".to_string(),
+                session_title: "Test notification from julezz".to_string(),
+                activity_id: None,
+                time: chrono::Utc::now().to_rfc3339(),
+                detail: None,
+                pull_request_url: None,
+            };
+            for (sink, result) in notifier.deliver(&event).await {
+                match result {
+                    Ok(()) => println!("{}: {}", sink, "sent".green()),
+                    Err(e) => println!("{}: {} {}", sink, "failed:".red(), e),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Manages the webhook endpoints and their deliveries.
+async fn manage_webhooks(command: WebhooksCommands, config: &Config) -> Result<(), String> {
+    let store = WebhookStore::new()?;
+
+    match command {
+        WebhooksCommands::Add { url, events, secret } => {
+            let endpoint = store.add_endpoint(&url, events, secret)?;
+            println!("Added webhook endpoint {} for {}.", endpoint.id.bold(), endpoint.url);
+            println!("Payloads are signed with the secret: {}", endpoint.secret);
+        }
+        WebhooksCommands::List => {
+            let endpoints = store.endpoints()?;
+            if endpoints.is_empty() {
+                println!("No webhook endpoints. Add one with `julezz webhooks add <url>`.");
+                return Ok(());
+            }
+            println!("{}", "Webhook Endpoints".bold().underline());
+            for endpoint in endpoints {
+                let events = if endpoint.events.is_empty() {
+                    "all".to_string()
+                } else {
+                    endpoint.events.join(", ")
+                };
+                println!("\n{}: {}", endpoint.id.bold(), endpoint.url);
+                println!("  {}: {}", "Events".dimmed(), events);
+            }
+        }
+        WebhooksCommands::Remove { id } => {
+            let endpoint = store.remove_endpoint(&id)?;
+            println!("Removed webhook endpoint {} ({}).", endpoint.id, endpoint.url);
+        }
+        WebhooksCommands::Log { number, endpoint } => {
+            let mut records = store.deliveries()?;
+            if let Some(endpoint) = &endpoint {
+                records.retain(|record| &record.endpoint_id == endpoint);
+            }
+            if records.is_empty() {
+                println!("No deliveries yet.");
+                return Ok(());
+            }
+            for record in records.iter().rev().take(number).rev() {
+                print_delivery(record);
+            }
+        }
+        WebhooksCommands::Replay { delivery_id } => {
+            let record = webhooks::replay(&store, &delivery_id, config).await?;
+            print_delivery(&record);
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints an attempt of the delivery log on one line.
+fn print_delivery(record: &DeliveryRecord) {
+    let time = chrono::DateTime::from_timestamp(record.time as i64, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default();
+    let result = match &record.error {
+        None => record.status.map(|status| status.to_string()).unwrap_or_default().green(),
+        Some(error) => error.red(),
+    };
+    println!(
+        "{} {} #{} {} {} session {} -> {}: {}",
+        time.dimmed(),
+        record.delivery_id.bold(),
+        record.attempt,
+        record.endpoint_id,
+        record.event.kind.name(),
+        record.event.session_id,
+        record.url,
+        result
+    );
+}
+
+/// Runs the gateway, or the server given as a subcommand.
+async fn serve(
+    listen: Option<String>,
+    command: Option<ServeCommands>,
+    client: JulesClient,
+    config: &Config,
+) -> Result<(), String> {
+    match command {
+        None => {
+            let listen = listen.unwrap_or_else(|| config.gateway_listen());
+            metrics::spawn_server(config);
+            let gateway = Gateway::new(std::sync::Arc::new(client), Cache::new()?, config);
+            gateway::serve(std::sync::Arc::new(gateway), &listen).await?;
+        }
+        Some(ServeCommands::Webhooks) => {
+            let store = WebhookStore::new()?;
+            if store.endpoints()?.is_empty() {
+                return Err("No webhook endpoints are registered. Add one with `julezz webhooks add <url>`.".to_string());
+            }
+            tracing::info!("Starting webhook server");
+            metrics::spawn_server(config);
+            WebhookServer::new(std::sync::Arc::new(client), store, config)
+                .run(Cache::new()?)
+                .await;
+        }
+    }
+    Ok(())
 }
 
 /// Generates a Carapace spec for shell completions.
@@ -664,12 +1497,7 @@ fn add_alias_for_new_session(
 ) -> Result<(), String> {
     let cache = Cache::new()?;
     let mut sessions = cache.read_sessions()?;
-    sessions.push(CachedSession {
-        id: session.id.clone(),
-        title: session.title.clone(),
-        source_context: session.source_context.clone(),
-        pull_request_url: session.pull_request_url.clone(),
-    });
+    sessions.push(CachedSession::from(session));
     cache.write_sessions(&sessions)?;
 
     let mut aliases = cache.read_aliases()?;
@@ -729,9 +1557,84 @@ fn manage_aliases(
     Ok(())
 }
 
+fn print_activities(
+    out: &mut dyn Write,
+    activities: &[julezz::api::Activity],
+    n: usize,
+    session: &CachedSession,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "{}\n",
+        format!("Activities for session {}", session.id)
+            .bold()
+            .underline()
+    )?;
+    let mut activities = activities.to_vec();
+    activities.sort_by(|a, b| a.create_time.cmp(&b.create_time));
+    let activities_to_show = activities.iter().rev().take(n).rev();
 
+    for activity in activities_to_show {
+        print_activity(out, activity, session)?;
+    }
+    Ok(())
+}
 
+fn print_activity(out: &mut dyn Write, activity: &julezz::api::Activity, session: &CachedSession) -> io::Result<()> {
+    let originator = match activity.originator.as_str() {
+        "agent" => activity.originator.cyan(),
+        "user" => activity.originator.green(),
+        _ => activity.originator.dimmed(),
+    };
+    writeln!(out, "[{}] {}", activity.create_time.dimmed(), originator)?;
 
+    if let Some(agent_messaged) = &activity.agent_messaged {
+        if !agent_messaged.agent_message.is_empty() {
+            writeln!(out, "  {}", agent_messaged.agent_message)?;
+        }
+    } else if let Some(user_messaged) = &activity.user_messaged {
+        writeln!(out, "  {}", user_messaged.user_message)?;
+    } else if let Some(plan_generated) = &activity.plan_generated {
+        writeln!(out, "  {}", "Plan Generated".yellow())?;
+        for step in &plan_generated.plan.steps {
+            writeln!(out, "    - {}", step.title)?;
+        }
+    } else if activity.plan_approved.is_some() {
+        writeln!(out, "  {}", "Plan Approved".yellow())?;
+    } else if activity.session_completed.is_some() {
+        writeln!(out, "  {}", "Session Completed".blue())?;
+    } else if let Some(progress) = &activity.progress_updated {
+        if let Some(title) = &progress.title {
+            writeln!(out, "  {}", title.dimmed())?;
+        }
+        if let Some(description) = &progress.description {
+            writeln!(out, "    {}", description.dimmed())?;
+        }
+    } else if let Some(artifacts) = &activity.artifacts {
+        for artifact in artifacts {
+            if let Some(bash_output) = &artifact.bash_output {
+                writeln!(out, "  {}", format!("$ {}", bash_output.command).blue())?;
+                writeln!(out, "    {}", bash_output.output)?;
+            }
+            if let Some(change_set) = &artifact.change_set {
+                let branch = session
+                    .source_context
+                    .as_ref()
+                    .and_then(|sc| sc.github_repo_context.as_ref())
+                    .map(|ghc| ghc.starting_branch.as_str())
+                    .unwrap_or("unknown branch");
+                writeln!(out, "  {} on {}", "Code Change".blue(), branch.yellow())?;
+                if let Some(patch) = &change_set.git_patch.unidiff_patch {
+                    writeln!(out, "{}", patch)?;
+                }
+            }
+        }
+    } else if let Some(title) = &activity.title {
+        writeln!(out, "  {}", title.dimmed())?;
+    }
+
+    writeln!(out)
+}
 
 #[cfg(test)]
 mod tests {
@@ -739,7 +1642,7 @@ mod tests {
 
     #[test]
     fn test_cli_args() {
-        let args = Args::parse_from(&[
+        let args = Args::parse_from([
             "julezz",
             "--api-key",
             "test-key",
@@ -749,72 +1652,20 @@ mod tests {
         assert_eq!(args.api_key, Some("test-key".to_string()));
         assert!(matches!(args.command, Commands::Sources { .. }));
     }
-}
-
-fn print_activities(activities: &[julezz::api::Activity], n: usize, session: &CachedSession) {
-    println!(
-        "{}\n",
-        format!("Activities for session {}", session.id)
-            .bold()
-            .underline()
-    );
-    let mut activities = activities.to_vec();
-    activities.sort_by(|a, b| a.create_time.cmp(&b.create_time));
-    let activities_to_show = activities.iter().rev().take(n).rev();
 
-    for activity in activities_to_show {
-        let originator = match activity.originator.as_str() {
-            "agent" => activity.originator.cyan(),
-            "user" => activity.originator.green(),
-            _ => activity.originator.dimmed(),
-        };
-        println!("[{}] {}", activity.create_time.dimmed(), originator);
-
-        if let Some(agent_messaged) = &activity.agent_messaged {
-            if !agent_messaged.agent_message.is_empty() {
-                println!("  {}", agent_messaged.agent_message);
-            }
-        } else if let Some(user_messaged) = &activity.user_messaged {
-            println!("  {}", user_messaged.user_message);
-        } else if let Some(plan_generated) = &activity.plan_generated {
-            println!("  {}", "Plan Generated".yellow());
-            for step in &plan_generated.plan.steps {
-                println!("    - {}", step.title);
-            }
-        } else if activity.plan_approved.is_some() {
-            println!("  {}", "Plan Approved".yellow());
-        } else if activity.session_completed.is_some() {
-            println!("  {}", "Session Completed".blue());
-        } else if let Some(progress) = &activity.progress_updated {
-            if let Some(title) = &progress.title {
-                println!("  {}", title.dimmed());
-            }
-            if let Some(description) = &progress.description {
-                println!("    {}", description.dimmed());
-            }
-        } else if let Some(artifacts) = &activity.artifacts {
-            for artifact in artifacts {
-                if let Some(bash_output) = &artifact.bash_output {
-                    println!("  {}", format!("$ {}", bash_output.command).blue());
-                    println!("    {}", bash_output.output);
-                }
-                if let Some(change_set) = &artifact.change_set {
-                    let branch = session
-                        .source_context
-                        .as_ref()
-                        .and_then(|sc| sc.github_repo_context.as_ref())
-                        .map(|ghc| ghc.starting_branch.as_str())
-                        .unwrap_or("unknown branch");
-                    println!("  {} on {}", "Code Change".blue(), branch.yellow());
-                    if let Some(patch) = &change_set.git_patch.unidiff_patch {
-                        println!("{}", patch);
-                    }
-                }
-            }
-        } else if let Some(title) = &activity.title {
-            println!("  {}", title.dimmed());
-        }
+    #[test]
+    fn test_profile_flag_is_global() {
+        let args = Args::parse_from(["julezz", "sessions", "list", "--profile", "work"]);
+        assert_eq!(args.profile, Some("work".to_string()));
+    }
 
-        println!();
+    #[test]
+    fn test_logging_flags() {
+        Args::command().debug_assert();
+        let matches = Args::command().get_matches_from(["julezz", "sessions", "list", "-vv", "--trace-http"]);
+        let args = Args::from_arg_matches(&matches).unwrap();
+        assert_eq!(args.verbose, 2);
+        assert!(args.trace_http);
+        assert_eq!(command_name(&matches), "sessions list");
     }
 }
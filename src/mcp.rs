@@ -0,0 +1,596 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements `julezz mcp`, a Model Context Protocol server, so
+//! that other coding assistants can delegate tasks to Jules.
+//!
+//! The server speaks JSON-RPC over stdio, one message per line, and logs to
+//! stderr. It offers the `create_session`, `list_sessions`, `get_activities`,
+//! `send_message`, `approve_plan` and `get_diff` tools, and exposes the
+//! transcript and patch of each session as the resources
+//! `julezz://sessions/{id}/transcript` and `julezz://sessions/{id}/patch`.
+//! Sessions are identified like on the command line: by ID, by index or by
+//! `@alias`.
+
+use crate::api::{Activity, JulesClient, Session};
+use crate::cache::{Cache, CachedSession};
+use crate::config::Config;
+use crate::diff::{collect_patches, diff_stats};
+use crate::events::sort_activities;
+use crate::resolve::resolve_session_identifier_with_aliases;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+/// The protocol versions the server speaks, newest first.
+const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+/// The JSON-RPC error codes used by the server.
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// The scheme of the resource URIs.
+const RESOURCE_PREFIX: &str = "julezz://sessions/";
+
+/// Serves the Jules API as MCP tools and resources.
+pub struct McpServer {
+    client: Arc<JulesClient>,
+    cache: Cache,
+    source: Option<String>,
+    branch: String,
+    auto_pr: bool,
+}
+
+impl McpServer {
+    /// Creates a new `McpServer`, using the session defaults of the config.
+    pub fn new(client: Arc<JulesClient>, cache: Cache, config: &Config) -> Self {
+        Self {
+            client,
+            cache,
+            source: config.source(),
+            branch: config.branch(),
+            auto_pr: config.auto_pr(),
+        }
+    }
+
+    /// Answers the messages read from stdin until it is closed.
+    pub async fn run(self) -> Result<(), String> {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        let mut stdout = tokio::io::stdout();
+        while let Some(line) = lines
+            .next_line()
+            .await
+            .map_err(|e| format!("Could not read from stdin: {}", e))?
+        {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Value>(&line) {
+                Ok(message) => self.handle(message).await,
+                Err(e) => Some(error(Value::Null, PARSE_ERROR, &format!("Invalid JSON: {}", e))),
+            };
+            if let Some(response) = response {
+                let mut line = response.to_string();
+                line.push('\n');
+                stdout
+                    .write_all(line.as_bytes())
+                    .await
+                    .map_err(|e| format!("Could not write to stdout: {}", e))?;
+                stdout.flush().await.map_err(|e| format!("Could not write to stdout: {}", e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a JSON-RPC message.
+    ///
+    /// # Returns
+    ///
+    /// The response to a request, or `None` for a notification.
+    pub async fn handle(&self, message: Value) -> Option<Value> {
+        let id = message.get("id").cloned()?;
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+        tracing::debug!(%id, method, "MCP request");
+
+        let result = match method {
+            "initialize" => Ok(initialize(&params)),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": tools() })),
+            "tools/call" => self.call_tool(&params).await,
+            "resources/list" => self.list_resources().await,
+            "resources/templates/list" => Ok(json!({ "resourceTemplates": resource_templates() })),
+            "resources/read" => self.read_resource(&params).await,
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method '{}'", method))),
+        };
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => error(id, code, &message),
+        })
+    }
+
+    /// Runs a tool.
+    ///
+    /// Failures of the tool itself, e.g. an unknown session, are results
+    /// flagged with `isError`, so that the model can see and fix them.
+    async fn call_tool(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let name = params["name"].as_str().unwrap_or_default();
+        let arguments = &params["arguments"];
+        let result = match name {
+            "create_session" => self.create_session(arguments).await,
+            "list_sessions" => self.list_sessions().await,
+            "get_activities" => self.get_activities(arguments).await,
+            "send_message" => self.send_message(arguments).await,
+            "approve_plan" => self.approve_plan(arguments).await,
+            "get_diff" => self.get_diff(arguments).await,
+            _ => return Err((INVALID_PARAMS, format!("Unknown tool '{}'", name))),
+        };
+        let (text, is_error) = match result {
+            Ok(text) => (text, false),
+            Err(e) => (e, true),
+        };
+        Ok(json!({ "content": [{ "type": "text", "text": text }], "isError": is_error }))
+    }
+
+    async fn create_session(&self, arguments: &Value) -> Result<String, String> {
+        let title = required(arguments, "title")?;
+        let source = optional(arguments, "source")
+            .or_else(|| self.source.clone())
+            .ok_or("No source given and no default source is configured (sessions.source).")?;
+        let branch = optional(arguments, "branch").unwrap_or_else(|| self.branch.clone());
+        let auto_pr = arguments["auto_pr"].as_bool().unwrap_or(self.auto_pr);
+        let alias = optional(arguments, "alias");
+        if alias.as_ref().is_some_and(|alias| !alias.starts_with('@')) {
+            return Err("Alias must start with '@'.".to_string());
+        }
+
+        let session = self
+            .client
+            .create_session(&source, &title, auto_pr, &branch)
+            .await
+            .map_err(|e| e.to_string())?;
+        // The API decides where the new session goes, so the cache is refreshed
+        // rather than appended to.
+        if let Err(e) = self.sessions().await {
+            tracing::warn!(error = %e, "Failed to refresh the session cache");
+        }
+        if let Some(alias) = &alias {
+            let mut aliases = self.cache.read_aliases()?;
+            aliases.insert(alias.clone(), session.id.clone());
+            self.cache.write_aliases(&aliases)?;
+        }
+        Ok(format!(
+            "Created session {}{}: {} ({})",
+            session.id,
+            alias.map(|alias| format!(" ({})", alias)).unwrap_or_default(),
+            session.title,
+            session.state.unwrap_or_default()
+        ))
+    }
+
+    async fn list_sessions(&self) -> Result<String, String> {
+        let sessions = self.sessions().await?;
+        if sessions.is_empty() {
+            return Ok("No sessions found.".to_string());
+        }
+        let aliases = self.cache.read_aliases()?;
+        let mut text = String::new();
+        for (index, session) in sessions.iter().enumerate() {
+            let mut names: Vec<&String> = aliases
+                .iter()
+                .filter(|(_, id)| **id == session.id)
+                .map(|(alias, _)| alias)
+                .collect();
+            names.sort();
+            let names = names.iter().map(|alias| format!(" {}", alias)).collect::<String>();
+            text.push_str(&format!(
+                "{}. {}{} [{}] {}\n",
+                index + 1,
+                session.id,
+                names,
+                session.state.as_deref().unwrap_or("UNKNOWN"),
+                session.title
+            ));
+        }
+        Ok(text)
+    }
+
+    async fn get_activities(&self, arguments: &Value) -> Result<String, String> {
+        let (session, activities) = self.session_with_activities(&required(arguments, "session")?).await?;
+        let count = arguments["count"].as_u64().map_or(activities.len(), |count| count as usize);
+        Ok(transcript(&session, &activities[activities.len().saturating_sub(count)..]))
+    }
+
+    async fn send_message(&self, arguments: &Value) -> Result<String, String> {
+        let session_id = self.resolve(&required(arguments, "session")?).await?;
+        let message = required(arguments, "message")?;
+        self.client.send_message(&session_id, &message).await.map_err(|e| e.to_string())?;
+        Ok(format!("Message sent to session {}.", session_id))
+    }
+
+    async fn approve_plan(&self, arguments: &Value) -> Result<String, String> {
+        let session_id = self.resolve(&required(arguments, "session")?).await?;
+        self.client.approve_plan(&session_id).await.map_err(|e| e.to_string())?;
+        Ok(format!("Plan approved for session {}.", session_id))
+    }
+
+    async fn get_diff(&self, arguments: &Value) -> Result<String, String> {
+        let (session, activities) = self.session_with_activities(&required(arguments, "session")?).await?;
+        let patch = collect_patches(&activities);
+        if patch.is_empty() {
+            return Ok(format!("Session {} has no code changes.", session.id));
+        }
+        let stats = diff_stats(&patch);
+        let mut text = format!("Changes in session {}: {} file(s)\n", session.id, stats.len());
+        for stat in &stats {
+            text.push_str(&format!("+{} -{}  {}\n", stat.added, stat.removed, stat.path));
+        }
+        text.push('\n');
+        text.push_str(&patch);
+        Ok(text)
+    }
+
+    async fn list_resources(&self) -> Result<Value, (i64, String)> {
+        let sessions = self.sessions().await.map_err(|e| (INVALID_PARAMS, e))?;
+        let mut resources = Vec::new();
+        for session in &sessions {
+            resources.push(json!({
+                "uri": format!("{}{}/transcript", RESOURCE_PREFIX, session.id),
+                "name": format!("{} transcript", session.title),
+                "mimeType": "text/markdown",
+            }));
+            resources.push(json!({
+                "uri": format!("{}{}/patch", RESOURCE_PREFIX, session.id),
+                "name": format!("{} patch", session.title),
+                "mimeType": "text/x-diff",
+            }));
+        }
+        Ok(json!({ "resources": resources }))
+    }
+
+    async fn read_resource(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let uri = params["uri"].as_str().unwrap_or_default();
+        let not_found = || (INVALID_PARAMS, format!("Unknown resource '{}'", uri));
+        let (identifier, kind) = uri
+            .strip_prefix(RESOURCE_PREFIX)
+            .and_then(|rest| rest.rsplit_once('/'))
+            .ok_or_else(not_found)?;
+        let (session, activities) = self
+            .session_with_activities(identifier)
+            .await
+            .map_err(|e| (INVALID_PARAMS, e))?;
+        let (mime_type, text) = match kind {
+            "transcript" => ("text/markdown", transcript(&session, &activities)),
+            "patch" => ("text/x-diff", collect_patches(&activities)),
+            _ => return Err(not_found()),
+        };
+        Ok(json!({ "contents": [{ "uri": uri, "mimeType": mime_type, "text": text }] }))
+    }
+
+    /// Lists the sessions, and updates the session cache with them so that
+    /// indexes match those of the command line.
+    async fn sessions(&self) -> Result<Vec<Session>, String> {
+        let sessions = self.client.list_sessions().await.map_err(|e| e.to_string())?;
+        let cached: Vec<CachedSession> = sessions.iter().map(CachedSession::from).collect();
+        self.cache.write_sessions(&cached)?;
+        Ok(sessions)
+    }
+
+    /// Resolves a session ID, index or alias to a session ID.
+    async fn resolve(&self, identifier: &str) -> Result<String, String> {
+        let sessions = self.sessions().await?;
+        let aliases = self.cache.read_aliases()?;
+        resolve_session_identifier_with_aliases(identifier, &sessions, &aliases).map(|(id, _)| id)
+    }
+
+    async fn session_with_activities(&self, identifier: &str) -> Result<(Session, Vec<Activity>), String> {
+        let session_id = self.resolve(identifier).await?;
+        let session = self.client.get_session(&session_id).await.map_err(|e| e.to_string())?;
+        let mut activities = self.client.fetch_activities(&session_id).await.map_err(|e| e.to_string())?;
+        sort_activities(&mut activities);
+        Ok((session, activities))
+    }
+}
+
+/// Answers `initialize` with the client's protocol version when the server
+/// speaks it, and the newest one otherwise.
+fn initialize(params: &Value) -> Value {
+    let requested = params["protocolVersion"].as_str().unwrap_or_default();
+    let version = PROTOCOL_VERSIONS
+        .iter()
+        .find(|version| **version == requested)
+        .unwrap_or(&PROTOCOL_VERSIONS[0]);
+    json!({
+        "protocolVersion": version,
+        "capabilities": { "tools": {}, "resources": {} },
+        "serverInfo": { "name": "julezz", "version": env!("CARGO_PKG_VERSION") },
+        "instructions": "Delegate coding tasks to Jules. Sessions are identified by ID, by index in list_sessions or by @alias.",
+    })
+}
+
+/// Describes the tools and their arguments.
+fn tools() -> Value {
+    let session = json!({ "type": "string", "description": "The session ID, index or @alias" });
+    json!([
+        {
+            "name": "create_session",
+            "description": "Start a Jules session working on a task in a repository",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "title": { "type": "string", "description": "The task, used as the prompt and title" },
+                    "source": { "type": "string", "description": "The source, e.g. sources/github/owner/repo (defaults to sessions.source)" },
+                    "branch": { "type": "string", "description": "The starting branch (defaults to sessions.branch)" },
+                    "auto_pr": { "type": "boolean", "description": "Whether to open a pull request automatically" },
+                    "alias": { "type": "string", "description": "An alias for the session, starting with @" },
+                },
+                "required": ["title"],
+            },
+        },
+        {
+            "name": "list_sessions",
+            "description": "List the Jules sessions with their index, ID, aliases, state and title",
+            "inputSchema": { "type": "object", "properties": {} },
+        },
+        {
+            "name": "get_activities",
+            "description": "Get the transcript of a session: messages, plans, progress, commands and code changes",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "session": session,
+                    "count": { "type": "integer", "description": "Only return the last activities" },
+                },
+                "required": ["session"],
+            },
+        },
+        {
+            "name": "send_message",
+            "description": "Send a message to a session, e.g. to answer the agent's question",
+            "inputSchema": {
+                "type": "object",
+                "properties": { "session": session, "message": { "type": "string" } },
+                "required": ["session", "message"],
+            },
+        },
+        {
+            "name": "approve_plan",
+            "description": "Approve the plan of a session waiting for approval",
+            "inputSchema": {
+                "type": "object",
+                "properties": { "session": session },
+                "required": ["session"],
+            },
+        },
+        {
+            "name": "get_diff",
+            "description": "Get the code changes of a session as a unified diff, with per-file stats",
+            "inputSchema": {
+                "type": "object",
+                "properties": { "session": session },
+                "required": ["session"],
+            },
+        },
+    ])
+}
+
+fn resource_templates() -> Value {
+    json!([
+        {
+            "uriTemplate": format!("{}{{session}}/transcript", RESOURCE_PREFIX),
+            "name": "Session transcript",
+            "description": "The activities of a session, by session ID, index or @alias",
+            "mimeType": "text/markdown",
+        },
+        {
+            "uriTemplate": format!("{}{{session}}/patch", RESOURCE_PREFIX),
+            "name": "Session patch",
+            "description": "The code changes of a session as a unified diff",
+            "mimeType": "text/x-diff",
+        },
+    ])
+}
+
+/// Formats the activities of a session as Markdown.
+pub fn transcript(session: &Session, activities: &[Activity]) -> String {
+    let mut text = format!(
+        "# {}\n\nSession {} ({})\n",
+        session.title,
+        session.id,
+        session.state.as_deref().unwrap_or("UNKNOWN")
+    );
+    if let Some(url) = &session.pull_request_url {
+        text.push_str(&format!("Pull request: {}\n", url));
+    }
+    for activity in activities {
+        text.push_str(&format!("\n## {} ({})\n\n", activity.originator, activity.create_time));
+        if let Some(message) = &activity.agent_messaged {
+            text.push_str(&format!("{}\n", message.agent_message));
+        } else if let Some(message) = &activity.user_messaged {
+            text.push_str(&format!("{}\n", message.user_message));
+        } else if let Some(plan) = &activity.plan_generated {
+            text.push_str("Plan generated:\n");
+            for (i, step) in plan.plan.steps.iter().enumerate() {
+                text.push_str(&format!("{}. {}\n", i + 1, step.title));
+            }
+        } else if activity.plan_approved.is_some() {
+            text.push_str("Plan approved.\n");
+        } else if activity.session_completed.is_some() {
+            text.push_str("Session completed.\n");
+        } else if let Some(failed) = &activity.session_failed {
+            text.push_str(&format!("Session failed: {}\n", failed.reason.as_deref().unwrap_or("no reason given")));
+        } else if let Some(progress) = &activity.progress_updated {
+            for line in [&progress.title, &progress.description].into_iter().flatten() {
+                text.push_str(&format!("{}\n", line));
+            }
+        } else if let Some(artifacts) = &activity.artifacts {
+            for artifact in artifacts {
+                if let Some(bash) = &artifact.bash_output {
+                    text.push_str(&format!("```console\n$ {}\n{}\n```\n", bash.command, bash.output));
+                }
+                if let Some(patch) = artifact.change_set.as_ref().and_then(|c| c.git_patch.unidiff_patch.as_ref()) {
+                    text.push_str(&format!("```diff\n{}\n```\n", patch.trim_end()));
+                }
+            }
+        } else if let Some(title) = &activity.title {
+            text.push_str(&format!("{}\n", title));
+        }
+    }
+    text
+}
+
+fn required(arguments: &Value, name: &str) -> Result<String, String> {
+    optional(arguments, name).ok_or_else(|| format!("Missing argument '{}'", name))
+}
+
+fn optional(arguments: &Value, name: &str) -> Option<String> {
+    arguments[name]
+        .as_str()
+        .map(str::to_string)
+        .filter(|value| !value.trim().is_empty())
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, serve};
+    use axum::http::{Method, Uri};
+    use std::fs;
+    use std::sync::Mutex;
+
+    /// Creates a server in front of the given API, with its files in a
+    /// temporary directory.
+    fn server(name: &str, api: &str) -> (std::path::PathBuf, McpServer) {
+        let dir = test_util::temp_dir(&format!("mcp-{}", name));
+        let config = Config::load_from(dir.join("config.toml")).unwrap();
+        let cache = Cache::in_dir(&dir);
+        let client = JulesClient::new(Some("key".to_string()))
+            .unwrap()
+            .with_base_url(api)
+            .with_activities_dir(&dir);
+        (dir.clone(), McpServer::new(Arc::new(client), cache, &config))
+    }
+
+    async fn request(server: &McpServer, method: &str, params: Value) -> Value {
+        server
+            .handle(json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_protocol_negotiation_and_errors() {
+        let (dir, server) = server("protocol", "http://127.0.0.1:9");
+
+        let response = request(&server, "initialize", json!({ "protocolVersion": "2024-11-05" })).await;
+        assert_eq!(response["result"]["protocolVersion"], "2024-11-05");
+        assert_eq!(response["result"]["serverInfo"]["name"], "julezz");
+        let response = request(&server, "initialize", json!({ "protocolVersion": "1999-01-01" })).await;
+        assert_eq!(response["result"]["protocolVersion"], PROTOCOL_VERSIONS[0]);
+
+        let notification = json!({ "jsonrpc": "2.0", "method": "notifications/initialized" });
+        assert_eq!(server.handle(notification).await, None);
+
+        let response = request(&server, "tools/list", Value::Null).await;
+        let names: Vec<&str> = response["result"]["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tool| tool["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            ["create_session", "list_sessions", "get_activities", "send_message", "approve_plan", "get_diff"]
+        );
+
+        let response = request(&server, "sessions/delete", Value::Null).await;
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+        let response = request(&server, "tools/call", json!({ "name": "delete_session" })).await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        // Tool failures are results the model can read.
+        let response = request(&server, "tools/call", json!({ "name": "send_message", "arguments": {} })).await;
+        assert_eq!(response["result"]["isError"], true);
+        assert_eq!(response["result"]["content"][0]["text"], "Missing argument 'session'");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tools_and_resources_use_the_api() {
+        let id = format!("mcp-{}", std::process::id());
+        let session = json!(Session { title: "Fix the parser".to_string(), ..test_util::session(&id, "COMPLETED") });
+        let activities = json!([
+            {
+                "name": "a", "id": "a", "createTime": "2024-05-01T10:00:00Z", "originator": "agent",
+                "agentMessaged": { "agentMessage": "Which parser?" },
+            },
+            {
+                "name": "b", "id": "b", "createTime": "2024-05-01T10:01:00Z", "originator": "agent",
+                "artifacts": [{ "changeSet": { "source": "s", "gitPatch": {
+                    "baseCommitId": "c",
+                    "unidiffPatch": "--- a/src/parser.rs\n+++ b/src/parser.rs\n@@ -1 +1 @@\n-old\n+new\n",
+                } } }],
+            },
+        ]);
+        let recorded: Arc<Mutex<Vec<String>>> = Arc::default();
+        let requests = recorded.clone();
+        let app = axum::Router::new().fallback(move |method: Method, uri: Uri, body: String| {
+            let (requests, session, activities) = (requests.clone(), session.clone(), activities.clone());
+            async move {
+                requests.lock().unwrap().push(format!("{} {} {}", method, uri.path(), body));
+                let answer = match uri.path() {
+                    "/sessions" => json!({ "sessions": [session] }),
+                    path if path.ends_with("/activities") => json!({ "activities": activities }),
+                    _ if method == Method::GET => session,
+                    _ => json!({}),
+                };
+                axum::Json(answer)
+            }
+        });
+        let (dir, server) = server("tools", &serve(app));
+        server.cache.write_aliases(&[("@parser".to_string(), id.clone())].into()).unwrap();
+        let call = |name: &'static str, arguments: Value| {
+            let server = &server;
+            async move {
+                let response = request(server, "tools/call", json!({ "name": name, "arguments": arguments })).await;
+                assert_eq!(response["result"]["isError"], false, "{}", response);
+                response["result"]["content"][0]["text"].as_str().unwrap().to_string()
+            }
+        };
+
+        let list = call("list_sessions", json!({})).await;
+        assert_eq!(list, format!("1. {} @parser [COMPLETED] Fix the parser\n", id));
+        call("send_message", json!({ "session": "@parser", "message": "The JSON one" })).await;
+        let diff = call("get_diff", json!({ "session": "1" })).await;
+        assert!(diff.contains("+1 -1  src/parser.rs") && diff.ends_with("+new\n"), "{}", diff);
+
+        let uri = format!("julezz://sessions/{}/transcript", id);
+        let response = request(&server, "resources/read", json!({ "uri": uri })).await;
+        let transcript = response["result"]["contents"][0]["text"].as_str().unwrap().to_string();
+        let response = request(&server, "resources/read", json!({ "uri": "julezz://sessions/1/log" })).await;
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(transcript.starts_with("# Fix the parser\n"), "{}", transcript);
+        assert!(transcript.contains("Which parser?") && transcript.contains("```diff\n--- a/src/parser.rs"));
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        let sent = recorded.lock().unwrap().iter().any(|request| {
+            request.starts_with(&format!("POST /sessions/{}:sendMessage", id)) && request.contains("The JSON one")
+        });
+        assert!(sent);
+    }
+}
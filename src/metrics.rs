@@ -0,0 +1,219 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module collects Prometheus metrics about the process.
+//!
+//! Metrics are recorded in a registry shared by the whole process: API
+//! requests and retries by `JulesClient`, poll loops and session states by
+//! the bot and the daemons, notifications by the sinks and Telegram errors by
+//! the bot. When `metrics.listen` is set, the bot and the daemons serve them
+//! at `/metrics` in the Prometheus text format.
+
+use crate::config::Config;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// The collectors of the process.
+struct Metrics {
+    registry: Registry,
+    api_requests: HistogramVec,
+    api_retries: IntCounterVec,
+    poll_duration: Histogram,
+    sessions: IntGaugeVec,
+    notifications: IntCounterVec,
+    telegram_errors: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let api_requests = HistogramVec::new(
+            HistogramOpts::new(
+                "julezz_api_request_duration_seconds",
+                "Duration of Jules API requests, by endpoint and HTTP status",
+            ),
+            &["endpoint", "status"],
+        )
+        .unwrap();
+        let api_retries = IntCounterVec::new(
+            Opts::new("julezz_api_retries_total", "Retried Jules API requests, by endpoint"),
+            &["endpoint"],
+        )
+        .unwrap();
+        let poll_duration = Histogram::with_opts(
+            HistogramOpts::new("julezz_poll_loop_duration_seconds", "Duration of the activity poll loop")
+                .buckets(vec![0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]),
+        )
+        .unwrap();
+        let sessions = IntGaugeVec::new(
+            Opts::new("julezz_sessions", "Sessions seen by the last poll, by state"),
+            &["state"],
+        )
+        .unwrap();
+        let notifications = IntCounterVec::new(
+            Opts::new("julezz_notifications_total", "Notifications, by channel and result (sent or failed)"),
+            &["channel", "result"],
+        )
+        .unwrap();
+        let telegram_errors = IntCounterVec::new(
+            Opts::new("julezz_telegram_errors_total", "Errors of the Telegram Bot API, by kind"),
+            &["kind"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(api_requests.clone())).unwrap();
+        registry.register(Box::new(api_retries.clone())).unwrap();
+        registry.register(Box::new(poll_duration.clone())).unwrap();
+        registry.register(Box::new(sessions.clone())).unwrap();
+        registry.register(Box::new(notifications.clone())).unwrap();
+        registry.register(Box::new(telegram_errors.clone())).unwrap();
+        Self {
+            registry,
+            api_requests,
+            api_retries,
+            poll_duration,
+            sessions,
+            notifications,
+            telegram_errors,
+        }
+    }
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Records a request to the Jules API. The status is the HTTP status code,
+/// or `error` if no response was received.
+pub fn record_api_request(endpoint: &str, status: &str, duration: Duration) {
+    metrics()
+        .api_requests
+        .with_label_values(&[endpoint, status])
+        .observe(duration.as_secs_f64());
+}
+
+/// Records the retry of a request to the Jules API.
+pub fn record_api_retry(endpoint: &str) {
+    metrics().api_retries.with_label_values(&[endpoint]).inc();
+}
+
+/// Records the duration of a poll loop.
+pub fn record_poll(duration: Duration) {
+    metrics().poll_duration.observe(duration.as_secs_f64());
+}
+
+/// Sets the number of sessions by state, from the states of the sessions
+/// seen by a poll. States that are gone are reset to zero.
+pub fn set_sessions<'a>(states: impl IntoIterator<Item = Option<&'a str>>) {
+    let mut counts: HashMap<&str, i64> = HashMap::new();
+    for state in states {
+        *counts.entry(state.unwrap_or("UNKNOWN")).or_default() += 1;
+    }
+    let gauge = &metrics().sessions;
+    gauge.reset();
+    for (state, count) in counts {
+        gauge.with_label_values(&[state]).set(count);
+    }
+}
+
+/// Records a notification sent to a channel, e.g. `telegram` or `slack`.
+pub fn record_notification(channel: &str, sent: bool) {
+    let result = if sent { "sent" } else { "failed" };
+    metrics().notifications.with_label_values(&[channel, result]).inc();
+}
+
+/// Records an error of the Telegram Bot API, e.g. `network` or `api`.
+pub fn record_telegram_error(kind: &str) {
+    metrics().telegram_errors.with_label_values(&[kind]).inc();
+}
+
+/// Renders the metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&metrics().registry.gather(), &mut buffer) {
+        tracing::error!(error = %e, "Failed to encode metrics");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Serves the metrics at `/metrics` until the process is stopped.
+pub async fn serve(listen: &str) -> Result<(), String> {
+    let address: SocketAddr = listen
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid address, e.g. 127.0.0.1:9464", listen))?;
+    let server = axum::Server::try_bind(&address)
+        .map_err(|e| format!("Could not listen on {}: {}", address, e))?;
+    tracing::info!(%address, "Serving metrics at /metrics");
+    server
+        .serve(router().into_make_service())
+        .await
+        .map_err(|e| format!("Metrics server failed: {}", e))
+}
+
+fn router() -> axum::Router {
+    axum::Router::new().route(
+        "/metrics",
+        axum::routing::get(|| async { ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], render()) }),
+    )
+}
+
+/// Starts serving the metrics in the background if `metrics.listen` is set.
+pub fn spawn_server(config: &Config) {
+    if let Some(listen) = config.metrics_listen() {
+        tokio::spawn(async move {
+            if let Err(e) = serve(&listen).await {
+                tracing::error!(error = %e, "Metrics server stopped");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_are_rendered() {
+        record_api_request("sessions.test", "503", Duration::from_millis(20));
+        record_api_request("sessions.test", "200", Duration::from_millis(40));
+        record_api_retry("sessions.test");
+        record_notification("test-sink", false);
+        record_telegram_error("test-network");
+        set_sessions([Some("TEST_RUNNING"), Some("TEST_RUNNING"), None]);
+
+        let text = render();
+        assert!(text.contains(r#"julezz_api_request_duration_seconds_count{endpoint="sessions.test",status="200"} 1"#));
+        assert!(text.contains(r#"julezz_api_retries_total{endpoint="sessions.test"} 1"#));
+        assert!(text.contains(r#"julezz_notifications_total{channel="test-sink",result="failed"} 1"#));
+        assert!(text.contains(r#"julezz_telegram_errors_total{kind="test-network"} 1"#));
+        assert!(text.contains(r#"julezz_sessions{state="TEST_RUNNING"} 2"#), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_are_served() {
+        record_poll(Duration::from_millis(300));
+        let base = crate::test_util::serve(router());
+
+        let response = reqwest::get(format!("{}/metrics", base)).await.unwrap();
+        assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/plain"));
+        let text = response.text().await.unwrap();
+        assert!(text.contains("julezz_poll_loop_duration_seconds_count"), "{}", text);
+    }
+}
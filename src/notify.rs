@@ -0,0 +1,560 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module delivers session events to notification sinks.
+//!
+//! `julezz notify start` runs a `Notifier`: it polls the sessions of the
+//! current profile like the bot does, detects their events with the `events`
+//! module and sends each event to every configured `Sink`. The last activity
+//! notified for each session is saved in the `Cache`, with the events a sink
+//! failed to receive, so restarting the daemon neither repeats nor loses
+//! events.
+
+use crate::api::JulesClient;
+use crate::cache::{Cache, PendingEvent};
+use crate::config::Config;
+use crate::events::Event;
+use crate::metrics;
+use crate::poll::EventPoller;
+use chrono::Utc;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The base URL of the Telegram Bot API.
+const TELEGRAM_API_URL: &str = "https://api.telegram.org";
+
+/// How long a sink may take to answer a request.
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of checks an event is sent to a failing sink before it is
+/// dropped.
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Numbers Matrix transactions, which must be unique per access token.
+static MATRIX_TRANSACTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// How the connection to an SMTP server is encrypted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmtpTls {
+    /// A plain connection upgraded with STARTTLS.
+    StartTls,
+    /// A TLS connection from the start.
+    Tls,
+    /// No encryption, e.g. for a local relay.
+    None,
+}
+
+impl SmtpTls {
+    fn default_port(self) -> u16 {
+        match self {
+            SmtpTls::StartTls => 587,
+            SmtpTls::Tls => 465,
+            SmtpTls::None => 25,
+        }
+    }
+}
+
+/// The settings of the email sink.
+#[derive(Debug, Clone)]
+pub struct EmailSettings {
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    /// The user name to authenticate with, if the server requires it.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Represents a destination for events.
+#[derive(Debug, Clone)]
+pub enum Sink {
+    /// A Slack incoming webhook.
+    Slack { webhook_url: String },
+    /// A Discord webhook.
+    Discord { webhook_url: String },
+    /// Any HTTP endpoint, which receives the event as JSON.
+    Webhook { url: String },
+    /// A Matrix room, written to with the client-server API.
+    Matrix {
+        homeserver_url: String,
+        room_id: String,
+        access_token: String,
+    },
+    /// Email sent over SMTP.
+    Email(EmailSettings),
+    /// A Telegram chat, written to by a bot.
+    Telegram {
+        api_url: String,
+        token: String,
+        chat_id: String,
+    },
+}
+
+impl Sink {
+    /// Reads the sinks configured in the `notify` section of the config.
+    pub fn from_config(config: &Config) -> Result<Vec<Self>, String> {
+        let mut sinks = Vec::new();
+        if let Some(webhook_url) = value(config, "notify.slack_webhook_url")? {
+            sinks.push(Sink::Slack { webhook_url });
+        }
+        if let Some(webhook_url) = value(config, "notify.discord_webhook_url")? {
+            sinks.push(Sink::Discord { webhook_url });
+        }
+        if let Some(url) = value(config, "notify.webhook_url")? {
+            sinks.push(Sink::Webhook { url });
+        }
+        if let Some(homeserver_url) = value(config, "notify.matrix_homeserver_url")? {
+            sinks.push(Sink::Matrix {
+                homeserver_url,
+                room_id: required(config, "notify.matrix_room_id", "the Matrix sink")?,
+                access_token: required(config, "notify.matrix_access_token", "the Matrix sink")?,
+            });
+        }
+        if let Some(host) = value(config, "notify.smtp_host")? {
+            let tls = match value(config, "notify.smtp_tls")?.as_deref() {
+                Some("tls") => SmtpTls::Tls,
+                Some("none") => SmtpTls::None,
+                _ => SmtpTls::StartTls,
+            };
+            let port = match value(config, "notify.smtp_port")? {
+                Some(port) => port
+                    .parse()
+                    .map_err(|_| format!("'notify.smtp_port' must be a port number, not '{}'", port))?,
+                None => tls.default_port(),
+            };
+            let to = required(config, "notify.smtp_to", "the email sink")?
+                .split(',')
+                .map(|to| to.trim().to_string())
+                .filter(|to| !to.is_empty())
+                .collect();
+            sinks.push(Sink::Email(EmailSettings {
+                host,
+                port,
+                tls,
+                username: value(config, "notify.smtp_username")?,
+                password: value(config, "notify.smtp_password")?,
+                from: required(config, "notify.smtp_from", "the email sink")?,
+                to,
+            }));
+        }
+        if let Some(chat_id) = value(config, "notify.telegram_chat_id")? {
+            let token = std::env::var("TELOXIDE_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty())
+                .ok_or("The Telegram sink needs the bot token in TELOXIDE_TOKEN.")?;
+            sinks.push(Sink::Telegram {
+                api_url: TELEGRAM_API_URL.to_string(),
+                token,
+                chat_id,
+            });
+        }
+        Ok(sinks)
+    }
+
+    /// Returns the name of the sink, for logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sink::Slack { .. } => "slack",
+            Sink::Discord { .. } => "discord",
+            Sink::Webhook { .. } => "webhook",
+            Sink::Matrix { .. } => "matrix",
+            Sink::Email(_) => "email",
+            Sink::Telegram { .. } => "telegram",
+        }
+    }
+
+    /// Sends an event to the sink.
+    pub async fn send(&self, http: &reqwest::Client, event: &Event) -> Result<(), String> {
+        match self {
+            Sink::Slack { webhook_url } => {
+                let body = serde_json::json!({ "text": event.summary() });
+                check_response(http.post(webhook_url).json(&body).send().await).await
+            }
+            Sink::Discord { webhook_url } => {
+                let body = serde_json::json!({ "content": event.summary() });
+                check_response(http.post(webhook_url).json(&body).send().await).await
+            }
+            Sink::Webhook { url } => check_response(http.post(url).json(event).send().await).await,
+            Sink::Matrix { homeserver_url, room_id, access_token } => {
+                let transaction = format!(
+                    "julezz-{}-{}",
+                    Utc::now().timestamp_millis(),
+                    MATRIX_TRANSACTIONS.fetch_add(1, Ordering::Relaxed)
+                );
+                let mut url = reqwest::Url::parse(homeserver_url)
+                    .map_err(|e| format!("Invalid Matrix homeserver URL: {}", e))?;
+                url.path_segments_mut()
+                    .map_err(|_| "Invalid Matrix homeserver URL".to_string())?
+                    .pop_if_empty()
+                    .extend(["_matrix", "client", "v3", "rooms", room_id, "send", "m.room.message", &transaction]);
+                let body = serde_json::json!({ "msgtype": "m.text", "body": event.summary() });
+                check_response(http.put(url).bearer_auth(access_token).json(&body).send().await).await
+            }
+            Sink::Email(settings) => send_email(settings, event).await,
+            Sink::Telegram { api_url, token, chat_id } => {
+                let url = format!("{}/bot{}/sendMessage", api_url.trim_end_matches('/'), token);
+                let body = serde_json::json!({ "chat_id": chat_id, "text": event.summary() });
+                check_response(http.post(url).json(&body).send().await).await
+            }
+        }
+    }
+}
+
+/// Returns the effective value of a setting, treating empty values as unset.
+fn value(config: &Config, key: &str) -> Result<Option<String>, String> {
+    Ok(config.resolve(key)?.map(|(value, _)| value).filter(|value| !value.is_empty()))
+}
+
+fn required(config: &Config, key: &str, sink: &str) -> Result<String, String> {
+    value(config, key)?.ok_or_else(|| format!("'{}' must be set for {}.", key, sink))
+}
+
+/// Turns a failed request or an unsuccessful status into an error.
+async fn check_response(response: Result<reqwest::Response, reqwest::Error>) -> Result<(), String> {
+    let response = response.map_err(|e| format!("Request error: {}", e))?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        Err(format!("{} - {}", status, text))
+    }
+}
+
+/// Sends an event by email, with the first line of its summary as subject.
+async fn send_email(settings: &EmailSettings, event: &Event) -> Result<(), String> {
+    let summary = event.summary();
+    let subject = summary.lines().next().unwrap_or_default().trim_end_matches(':');
+    let from: Mailbox = settings
+        .from
+        .parse()
+        .map_err(|e| format!("Invalid sender address '{}': {}", settings.from, e))?;
+    let mut builder = Message::builder().from(from).subject(format!("[julezz] {}", subject));
+    for to in &settings.to {
+        let to: Mailbox = to.parse().map_err(|e| format!("Invalid recipient address '{}': {}", to, e))?;
+        builder = builder.to(to);
+    }
+    let message = builder
+        .body(summary.clone())
+        .map_err(|e| format!("Could not build email: {}", e))?;
+
+    let transport = match settings.tls {
+        SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.host)
+            .map_err(|e| format!("Could not set up SMTP: {}", e))?,
+        SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&settings.host)
+            .map_err(|e| format!("Could not set up SMTP: {}", e))?,
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.host),
+    };
+    let transport = match (&settings.username, &settings.password) {
+        (Some(username), Some(password)) => {
+            transport.credentials(Credentials::new(username.clone(), password.clone()))
+        }
+        _ => transport,
+    };
+    transport
+        .port(settings.port)
+        .build()
+        .send(message)
+        .await
+        .map_err(|e| format!("Could not send email: {}", e))?;
+    Ok(())
+}
+
+/// Detects the events of a profile's sessions and delivers them to sinks.
+pub struct Notifier {
+    sinks: Vec<Sink>,
+    /// The names of the event kinds to deliver.
+    events: Vec<String>,
+    poller: EventPoller,
+    http: reqwest::Client,
+}
+
+impl Notifier {
+    /// Creates a new `Notifier`, using the bot's poll settings and
+    /// `notifications.events`.
+    pub fn new(client: Arc<JulesClient>, sinks: Vec<Sink>, config: &Config) -> Self {
+        Self {
+            sinks,
+            events: config.notification_events(),
+            poller: EventPoller::new(client, config),
+            http: reqwest::Client::builder().timeout(SEND_TIMEOUT).build().unwrap_or_default(),
+        }
+    }
+
+    /// Returns the configured sinks.
+    pub fn sinks(&self) -> &[Sink] {
+        &self.sinks
+    }
+
+    /// Sends an event to every sink.
+    ///
+    /// # Returns
+    ///
+    /// The result of each sink, by sink name.
+    pub async fn deliver(&self, event: &Event) -> Vec<(&'static str, Result<(), String>)> {
+        let mut results = Vec::new();
+        for sink in &self.sinks {
+            let result = sink.send(&self.http, event).await;
+            metrics::record_notification(sink.name(), result.is_ok());
+            results.push((sink.name(), result));
+        }
+        results
+    }
+
+    /// Sends queued events to their sinks.
+    ///
+    /// An event a sink fails to receive is sent again at the next check,
+    /// until `MAX_ATTEMPTS` is reached. Events for sinks that are no longer
+    /// configured are dropped.
+    ///
+    /// # Returns
+    ///
+    /// The events to send again.
+    pub async fn flush(&self, queue: Vec<PendingEvent>) -> Vec<PendingEvent> {
+        let mut remaining = Vec::new();
+        for mut pending in queue {
+            let Some(sink) = self.sinks.iter().find(|sink| sink.name() == pending.sink) else {
+                continue;
+            };
+            pending.attempts += 1;
+            let result = sink.send(&self.http, &pending.event).await;
+            metrics::record_notification(sink.name(), result.is_ok());
+            if let Err(e) = result {
+                if pending.attempts < MAX_ATTEMPTS {
+                    tracing::warn!(
+                        sink = sink.name(),
+                        session_id = %pending.event.session_id,
+                        event = pending.event.kind.name(),
+                        attempt = pending.attempts,
+                        error = %e,
+                        "Failed to send event"
+                    );
+                    remaining.push(pending);
+                } else {
+                    tracing::error!(
+                        sink = sink.name(),
+                        session_id = %pending.event.session_id,
+                        event = pending.event.kind.name(),
+                        attempts = pending.attempts,
+                        error = %e,
+                        "Giving up on event"
+                    );
+                }
+            }
+        }
+        remaining
+    }
+
+    /// Checks the sessions at the poll interval and delivers their events,
+    /// forever.
+    ///
+    /// New events are queued for every sink with those that failed before,
+    /// and the queue is saved with the cursors, so a crash repeats events
+    /// rather than losing them and a failing sink receives them later.
+    pub async fn run(mut self, cache: Cache) {
+        let mut interval = tokio::time::interval(self.poller.interval());
+        loop {
+            interval.tick().await;
+
+            let mut state = match cache.read_daemon_state("notifier") {
+                Ok(state) => state,
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to read notifier state");
+                    continue;
+                }
+            };
+            let events = match self.poller.check(&mut state).await {
+                Ok(events) => events,
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to check sessions");
+                    Vec::new()
+                }
+            };
+            let mut queue = std::mem::take(&mut state.pending);
+            let wanted = events.iter().filter(|event| self.events.iter().any(|name| name == event.kind.name()));
+            for event in wanted {
+                queue.extend(self.sinks.iter().map(|sink| PendingEvent {
+                    sink: sink.name().to_string(),
+                    event: event.clone(),
+                    attempts: 0,
+                }));
+            }
+            state.pending = self.flush(queue).await;
+            if let Err(e) = cache.write_daemon_state("notifier", &state) {
+                tracing::error!(error = %e, "Failed to save notifier state");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+    use crate::test_util::{self, serve};
+    use axum::http::{HeaderMap, Method, Uri};
+    use std::sync::Mutex;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    /// A request received by the HTTP stand-in: method, path, authorization
+    /// header and body.
+    type Recorded = Arc<Mutex<Vec<(String, String, Option<String>, String)>>>;
+
+    /// Starts an HTTP server recording every request it receives.
+    fn http_stand_in() -> (String, Recorded) {
+        let recorded: Recorded = Arc::default();
+        let requests = recorded.clone();
+        let app = axum::Router::new().fallback(move |method: Method, uri: Uri, headers: HeaderMap, body: String| {
+            let requests = requests.clone();
+            async move {
+                let authorization = headers
+                    .get("authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+                requests.lock().unwrap().push((method.to_string(), uri.path().to_string(), authorization, body));
+                "{}"
+            }
+        });
+        (serve(app), recorded)
+    }
+
+    /// Starts an SMTP server accepting one connection, and returns its port
+    /// and the transcript of what the client sent.
+    async fn smtp_stand_in() -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            let mut transcript = String::new();
+            let mut in_data = false;
+            while let Ok(Some(line)) = lines.next_line().await {
+                transcript.push_str(&line);
+                transcript.push('\n');
+                let reply: &[u8] = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 OK\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250 localhost\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                } else if line == "QUIT" {
+                    b"221 Bye\r\n"
+                } else {
+                    b"250 OK\r\n"
+                };
+                if write.write_all(reply).await.is_err() {
+                    break;
+                }
+            }
+            transcript
+        });
+        (port, handle)
+    }
+
+    #[tokio::test]
+    async fn test_http_sinks_post_to_their_endpoints() {
+        let (base, recorded) = http_stand_in();
+        let sinks = [
+            Sink::Slack { webhook_url: format!("{}/slack", base) },
+            Sink::Discord { webhook_url: format!("{}/discord", base) },
+            Sink::Webhook { url: format!("{}/hook", base) },
+            Sink::Matrix {
+                homeserver_url: base.clone(),
+                room_id: "!room:example.org".to_string(),
+                access_token: "matrix-token".to_string(),
+            },
+            Sink::Telegram {
+                api_url: base.clone(),
+                token: "123:abc".to_string(),
+                chat_id: "-100".to_string(),
+            },
+        ];
+        let http = reqwest::Client::new();
+        for sink in &sinks {
+            sink.send(&http, &test_util::event(EventKind::Completed)).await.unwrap();
+        }
+
+        let requests = recorded.lock().unwrap().clone();
+        assert_eq!(requests.len(), 5);
+        let body = |i: usize| serde_json::from_str::<serde_json::Value>(&requests[i].3).unwrap();
+        let summary = "Session Fix the parser (42) completed.";
+        assert_eq!((requests[0].1.as_str(), body(0)["text"].as_str()), ("/slack", Some(summary)));
+        assert_eq!((requests[1].1.as_str(), body(1)["content"].as_str()), ("/discord", Some(summary)));
+        assert_eq!((requests[2].1.as_str(), body(2)["type"].as_str()), ("/hook", Some("completed")));
+        assert_eq!(requests[3].0, "PUT");
+        assert!(requests[3].1.starts_with("/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/julezz-"));
+        assert_eq!(requests[3].2.as_deref(), Some("Bearer matrix-token"));
+        assert_eq!(body(3)["body"].as_str(), Some(summary));
+        assert_eq!((requests[4].1.as_str(), body(4)["chat_id"].as_str()), ("/bot123:abc/sendMessage", Some("-100")));
+    }
+
+    #[tokio::test]
+    async fn test_failed_events_stay_queued() {
+        let (base, recorded) = http_stand_in();
+        let sinks = vec![
+            Sink::Webhook { url: format!("{}/hook", base) },
+            // Nothing listens on port 1.
+            Sink::Slack { webhook_url: "http://127.0.0.1:1/slack".to_string() },
+        ];
+        let client = Arc::new(JulesClient::with_auth(crate::api::Auth::ApiKey("key".to_string())));
+        let config = Config::load_from(std::env::temp_dir().join("julezz-notify-test-none.toml")).unwrap();
+        let notifier = Notifier::new(client, sinks, &config);
+        let pending = |sink: &str, attempts: u32| PendingEvent {
+            sink: sink.to_string(),
+            event: test_util::event(EventKind::Completed),
+            attempts,
+        };
+
+        let queue = vec![pending("webhook", 0), pending("slack", 0), pending("discord", 0)];
+        let remaining = notifier.flush(queue).await;
+        assert_eq!(remaining, [pending("slack", 1)]);
+        assert_eq!(recorded.lock().unwrap().len(), 1);
+
+        // The last attempt drops the event.
+        assert!(notifier.flush(vec![pending("slack", MAX_ATTEMPTS - 1)]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_email_sink_speaks_smtp() {
+        let (port, transcript) = smtp_stand_in().await;
+        let sink = Sink::Email(EmailSettings {
+            host: "127.0.0.1".to_string(),
+            port,
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            from: "julezz@example.com".to_string(),
+            to: vec!["ops@example.com".to_string()],
+        });
+        sink.send(&reqwest::Client::new(), &test_util::event(EventKind::Completed)).await.unwrap();
+
+        let transcript = transcript.await.unwrap();
+        assert!(transcript.contains("MAIL FROM:<julezz@example.com>"), "{}", transcript);
+        assert!(transcript.contains("RCPT TO:<ops@example.com>"), "{}", transcript);
+        assert!(transcript.contains("Subject: [julezz] Session Fix the parser (42) completed."), "{}", transcript);
+    }
+}
@@ -0,0 +1,397 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module decides which sessions to refetch when polling for activities.
+//!
+//! Listing sessions is a single request, while fetching the activities of
+//! every session is one request per session (or more). A `Poller` remembers
+//! the state and update time of each session and only refetches sessions that
+//! changed or are due: terminal sessions are skipped once seen, and the
+//! interval of each session adapts, short while it is producing activities and
+//! growing while it is idle.
+
+use crate::api::{Activity, JulesClient, JulesError, Session};
+use crate::cache::DaemonState;
+use crate::config::Config;
+use crate::events::{cursor_since, detect, Event};
+use crate::metrics;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// The states in which a session no longer produces activities.
+pub const TERMINAL_STATES: &[&str] = &["COMPLETED", "FAILED", "CANCELLED"];
+
+/// Returns whether a session is in a terminal state.
+pub fn is_terminal(session: &Session) -> bool {
+    session
+        .state
+        .as_deref()
+        .is_some_and(|state| TERMINAL_STATES.contains(&state))
+}
+
+/// Represents statistics about polling.
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PollStats {
+    /// The number of completed poll ticks.
+    pub ticks: u64,
+    /// The number of sessions seen in the last tick.
+    pub sessions: usize,
+    /// The number of activity fetches.
+    pub fetched: u64,
+    /// The number of times a terminal session was skipped.
+    pub skipped_terminal: u64,
+    /// The number of times an idle session was skipped.
+    pub skipped_idle: u64,
+    /// The number of failed activity fetches.
+    pub errors: u64,
+    /// The number of new activities found.
+    pub new_activities: u64,
+    /// The duration of the last tick, in milliseconds.
+    pub last_tick_millis: u64,
+}
+
+/// Tracks what was last seen of a session.
+#[derive(Debug, Clone)]
+struct Tracker {
+    state: Option<String>,
+    update_time: Option<String>,
+    interval: Duration,
+    next_due: Instant,
+}
+
+/// Decides which sessions to refetch on each poll tick.
+#[derive(Debug, Clone)]
+pub struct Poller {
+    min_interval: Duration,
+    max_interval: Duration,
+    trackers: HashMap<String, Tracker>,
+    stats: PollStats,
+}
+
+impl Poller {
+    /// Creates a new `Poller`.
+    ///
+    /// Busy sessions are refetched every `min_interval`, which should be the
+    /// tick interval. The interval of an idle session doubles after every
+    /// fetch without new activities, up to `max_interval`.
+    pub fn new(min_interval: Duration, max_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            max_interval: max_interval.max(min_interval),
+            trackers: HashMap::new(),
+            stats: PollStats::default(),
+        }
+    }
+
+    /// Selects the sessions whose activities should be fetched in this tick.
+    ///
+    /// A session is due when it has not been fetched yet, when its state or
+    /// update time changed, or when its interval has elapsed. Terminal
+    /// sessions are never due again unless they change.
+    pub fn due<'a>(&mut self, sessions: &'a [Session]) -> Vec<&'a Session> {
+        let now = Instant::now();
+        self.trackers
+            .retain(|id, _| sessions.iter().any(|session| &session.id == id));
+        self.stats.sessions = sessions.len();
+
+        let mut due = Vec::new();
+        for session in sessions {
+            match self.trackers.get(&session.id) {
+                None => due.push(session),
+                Some(tracker) => {
+                    let changed = tracker.state != session.state
+                        || (session.update_time.is_some() && tracker.update_time != session.update_time);
+                    if changed {
+                        due.push(session);
+                    } else if is_terminal(session) {
+                        self.stats.skipped_terminal += 1;
+                    } else if now >= tracker.next_due {
+                        due.push(session);
+                    } else {
+                        self.stats.skipped_idle += 1;
+                    }
+                }
+            }
+        }
+        due
+    }
+
+    /// Records a successful fetch of a session's activities.
+    pub fn record(&mut self, session: &Session, new_activities: usize) {
+        let interval = match self.trackers.get(&session.id) {
+            Some(tracker) if new_activities == 0 => (tracker.interval * 2).min(self.max_interval),
+            _ => self.min_interval,
+        };
+        self.trackers.insert(
+            session.id.clone(),
+            Tracker {
+                state: session.state.clone(),
+                update_time: session.update_time.clone(),
+                interval,
+                next_due: Instant::now() + interval,
+            },
+        );
+        self.stats.fetched += 1;
+        self.stats.new_activities += new_activities as u64;
+    }
+
+    /// Records a failed fetch. The session is retried on the next tick.
+    pub fn record_error(&mut self) {
+        self.stats.errors += 1;
+    }
+
+    /// Records the end of a tick.
+    pub fn finish_tick(&mut self, duration: Duration) {
+        self.stats.ticks += 1;
+        self.stats.last_tick_millis = duration.as_millis() as u64;
+    }
+
+    /// Returns the polling statistics.
+    pub fn stats(&self) -> &PollStats {
+        &self.stats
+    }
+}
+
+/// Fetches the activities of several sessions, at most `concurrency` at a
+/// time.
+///
+/// # Returns
+///
+/// The result for each session, in the order of `session_ids`.
+pub async fn fetch_all(
+    client: Arc<JulesClient>,
+    session_ids: Vec<String>,
+    concurrency: usize,
+) -> Vec<(String, Result<Vec<Activity>, JulesError>)> {
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let handles: Vec<_> = session_ids
+        .into_iter()
+        .map(|session_id| {
+            let client = client.clone();
+            let semaphore = semaphore.clone();
+            tokio::spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let result = client.fetch_activities(&session_id).await;
+                (session_id, result)
+            })
+        })
+        .collect();
+
+    let mut results = Vec::new();
+    for handle in handles {
+        match handle.await {
+            Ok(result) => results.push(result),
+            Err(e) => tracing::error!(error = %e, "Activity fetch task failed"),
+        }
+    }
+    results
+}
+
+/// Detects the events of a profile's sessions, for the daemons reporting
+/// them (`julezz notify`, `julezz serve webhooks`).
+pub struct EventPoller {
+    client: Arc<JulesClient>,
+    interval: Duration,
+    concurrency: usize,
+    poller: Poller,
+}
+
+impl EventPoller {
+    /// Creates a new `EventPoller`, using the bot's poll settings.
+    pub fn new(client: Arc<JulesClient>, config: &Config) -> Self {
+        let interval = Duration::from_secs(config.poll_interval_seconds());
+        let max_interval = Duration::from_secs(config.max_poll_interval_seconds());
+        Self {
+            client,
+            interval,
+            concurrency: config.poll_concurrency(),
+            poller: Poller::new(interval, max_interval),
+        }
+    }
+
+    /// Returns the interval at which to check the sessions.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Detects the events of the sessions since the cursors in `state`, and
+    /// moves the cursors past them.
+    ///
+    /// Only the sessions the `Poller` considers due are fetched. When the
+    /// daemon runs for the first time, the existing history is skipped.
+    /// Sessions that are not fetched keep their cursor, or get one at the
+    /// previous check if they have none.
+    pub async fn check(&mut self, state: &mut DaemonState) -> Result<Vec<Event>, JulesError> {
+        let started = Instant::now();
+        let sessions = self.client.list_sessions().await?;
+        let checked_at = Utc::now();
+        let last_checked = state
+            .last_checked
+            .as_deref()
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.with_timezone(&Utc));
+
+        state.cursors.retain(|id, _| sessions.iter().any(|session| &session.id == id));
+        let due_ids = self.poller.due(&sessions).iter().map(|session| session.id.clone()).collect();
+
+        let mut events = Vec::new();
+        for (session_id, result) in fetch_all(self.client.clone(), due_ids, self.concurrency).await {
+            let session = match sessions.iter().find(|session| session.id == session_id) {
+                Some(session) => session,
+                None => continue,
+            };
+            let mut activities = match result {
+                Ok(activities) => activities,
+                Err(e) => {
+                    tracing::error!(session_id = %session.id, error = %e, "Failed to fetch activities");
+                    self.poller.record_error();
+                    continue;
+                }
+            };
+            let changes = detect(session, &mut activities, state.cursors.get(&session.id), last_checked, checked_at);
+            self.poller.record(session, changes.new_activities);
+            if let Some(cursor) = changes.cursor {
+                state.cursors.insert(session.id.clone(), cursor);
+            }
+            events.extend(changes.events);
+        }
+        // Sessions left without a cursor, e.g. because their activities could
+        // not be fetched, are detected since the previous check next time.
+        for session in &sessions {
+            if !state.cursors.contains_key(&session.id) {
+                state.cursors.extend(cursor_since(session, last_checked).map(|cursor| (session.id.clone(), cursor)));
+            }
+        }
+
+        self.poller.finish_tick(started.elapsed());
+        metrics::record_poll(started.elapsed());
+        metrics::set_sessions(sessions.iter().map(|session| session.state.as_deref()));
+        state.last_checked = Some(checked_at.to_rfc3339());
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, serve};
+    use axum::http::{StatusCode, Uri};
+    use serde_json::json;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn session(id: &str, state: &str, update_time: Option<&str>) -> Session {
+        Session {
+            update_time: update_time.map(str::to_string),
+            ..test_util::session(id, state)
+        }
+    }
+
+    fn ids(sessions: Vec<&Session>) -> Vec<String> {
+        sessions.into_iter().map(|s| s.id.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_activities_missed_by_a_failed_fetch_are_reported() {
+        let now = Utc::now();
+        let activity = json!({
+            "name": "sessions/1/activities/a",
+            "id": "a",
+            "createTime": (now - chrono::Duration::minutes(30)).to_rfc3339(),
+            "originator": "agent",
+            "planGenerated": {"plan": {"id": "a", "steps": []}},
+        });
+        // The first fetch of the activities fails.
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let count = fetches.clone();
+        let app = axum::Router::new().fallback(move |uri: Uri| {
+            let (count, activity) = (count.clone(), activity.clone());
+            async move {
+                if uri.path() == "/sessions" {
+                    let session = test_util::session("1", "IN_PROGRESS");
+                    return (StatusCode::OK, axum::Json(json!({ "sessions": [session] })));
+                }
+                if count.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return (StatusCode::FORBIDDEN, axum::Json(json!({})));
+                }
+                (StatusCode::OK, axum::Json(json!({ "activities": [activity] })))
+            }
+        });
+        let base = serve(app);
+
+        let dir = test_util::temp_dir("poll");
+        let config = Config::load_from(dir.join("config.toml")).unwrap();
+        let client = JulesClient::new(Some("key".to_string()))
+            .unwrap()
+            .with_base_url(&base)
+            .with_activities_dir(&dir);
+        let mut poller = EventPoller::new(Arc::new(client), &config);
+        let mut state = DaemonState {
+            last_checked: Some((now - chrono::Duration::hours(1)).to_rfc3339()),
+            ..Default::default()
+        };
+
+        assert!(poller.check(&mut state).await.unwrap().is_empty());
+        let events = poller.check(&mut state).await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].activity_id.as_deref(), Some("a"));
+        assert_eq!(state.cursors["1"].activity_id, "a");
+    }
+
+    #[test]
+    fn test_skips_terminal_and_unchanged_sessions() {
+        let mut poller = Poller::new(Duration::from_secs(60), Duration::from_secs(600));
+        let sessions = vec![
+            session("done", "COMPLETED", Some("t1")),
+            session("busy", "IN_PROGRESS", Some("t1")),
+        ];
+
+        assert_eq!(ids(poller.due(&sessions)), ["done", "busy"]);
+        poller.record(&sessions[0], 3);
+        poller.record(&sessions[1], 1);
+
+        // Nothing changed and the interval has not elapsed.
+        assert!(poller.due(&sessions).is_empty());
+        assert_eq!(poller.stats().skipped_terminal, 1);
+        assert_eq!(poller.stats().skipped_idle, 1);
+
+        // A changed update time makes a session due immediately.
+        let sessions = vec![
+            session("done", "COMPLETED", Some("t1")),
+            session("busy", "IN_PROGRESS", Some("t2")),
+        ];
+        assert_eq!(ids(poller.due(&sessions)), ["busy"]);
+    }
+
+    #[test]
+    fn test_idle_sessions_back_off() {
+        let mut poller = Poller::new(Duration::from_secs(30), Duration::from_secs(100));
+        let busy = session("busy", "IN_PROGRESS", None);
+
+        poller.record(&busy, 0);
+        assert_eq!(poller.trackers["busy"].interval, Duration::from_secs(30));
+        poller.record(&busy, 0);
+        assert_eq!(poller.trackers["busy"].interval, Duration::from_secs(60));
+        poller.record(&busy, 0);
+        assert_eq!(poller.trackers["busy"].interval, Duration::from_secs(100));
+        poller.record(&busy, 2);
+        assert_eq!(poller.trackers["busy"].interval, Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module handles the notification preferences of a bot chat.
+//!
+//! A chat can choose the events it is notified about, mute notifications for
+//! a while, watch specific sessions and set quiet hours. Events and muting can
+//! also be set per session, overriding the chat's preferences.
+
+use crate::config::NOTIFICATION_EVENTS;
+use chrono::{DateTime, Duration, Local, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The events that are still notified during quiet hours, as they mean a
+/// session is waiting for the user.
+pub const URGENT_EVENTS: &[&str] = &["question", "plan"];
+
+/// Represents a period during which notifications are muted.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Mute {
+    /// When the mute ends, in RFC 3339 format, or `None` to mute until
+    /// unmuted.
+    pub until: Option<String>,
+}
+
+impl Mute {
+    /// Returns whether the mute is in effect at the given time.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        match &self.until {
+            Some(until) => DateTime::parse_from_rfc3339(until)
+                .map(|until| now < until)
+                .unwrap_or(false),
+            None => true,
+        }
+    }
+}
+
+/// Represents the notification preferences for a session.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionPreferences {
+    /// The events notified for the session, overriding the chat's events.
+    pub events: Option<Vec<String>>,
+    /// The mute of the session, if any.
+    pub mute: Option<Mute>,
+    /// Whether the session is watched.
+    #[serde(default)]
+    pub watched: bool,
+}
+
+/// Represents the daily period during which only urgent events are notified,
+/// in the local time of the bot.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuietHours {
+    /// The start of the quiet hours, as `HH:MM`.
+    pub start: String,
+    /// The end of the quiet hours, as `HH:MM`.
+    pub end: String,
+}
+
+impl QuietHours {
+    /// Parses quiet hours in the `HH:MM-HH:MM` format.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid quiet hours '{}'. Use HH:MM-HH:MM, e.g. 22:00-07:00.", value);
+        let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+        let (start, end) = (start.trim(), end.trim());
+        NaiveTime::parse_from_str(start, "%H:%M").map_err(|_| invalid())?;
+        NaiveTime::parse_from_str(end, "%H:%M").map_err(|_| invalid())?;
+        Ok(Self {
+            start: start.to_string(),
+            end: end.to_string(),
+        })
+    }
+
+    /// Returns whether a time of day falls within the quiet hours, which may
+    /// span midnight.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        let (start, end) = match (
+            NaiveTime::parse_from_str(&self.start, "%H:%M"),
+            NaiveTime::parse_from_str(&self.end, "%H:%M"),
+        ) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return false,
+        };
+        if start <= end {
+            start <= time && time < end
+        } else {
+            time >= start || time < end
+        }
+    }
+}
+
+/// Represents the notification preferences of a chat.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationPreferences {
+    /// The events notified, or `None` for the `notifications.events` setting.
+    pub events: Option<Vec<String>>,
+    /// The mute of the whole chat, if any.
+    pub mute: Option<Mute>,
+    /// The quiet hours, if any.
+    pub quiet_hours: Option<QuietHours>,
+    /// The preferences of individual sessions, by session id.
+    #[serde(default)]
+    pub sessions: HashMap<String, SessionPreferences>,
+}
+
+impl NotificationPreferences {
+    /// Returns the preferences of a session, creating them if needed.
+    pub fn session_mut(&mut self, session_id: &str) -> &mut SessionPreferences {
+        self.sessions.entry(session_id.to_string()).or_default()
+    }
+
+    /// Returns the IDs of the watched sessions.
+    pub fn watched(&self) -> Vec<String> {
+        let mut watched: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|(_, prefs)| prefs.watched)
+            .map(|(id, _)| id.clone())
+            .collect();
+        watched.sort();
+        watched
+    }
+
+    /// Returns the events notified for a session.
+    pub fn events_for(&self, session_id: &str, default_events: &[String]) -> Vec<String> {
+        self.sessions
+            .get(session_id)
+            .and_then(|prefs| prefs.events.clone())
+            .or_else(|| self.events.clone())
+            .unwrap_or_else(|| default_events.to_vec())
+    }
+
+    /// Decides whether an event of a session should be notified.
+    ///
+    /// Muting the chat or the session silences everything. When some sessions
+    /// are watched, only those are notified. During quiet hours, only urgent
+    /// events are notified.
+    pub fn allows(
+        &self,
+        session_id: &str,
+        event: &str,
+        default_events: &[String],
+        now: DateTime<Local>,
+    ) -> bool {
+        let now_utc = now.with_timezone(&Utc);
+        if self.mute.as_ref().is_some_and(|mute| mute.is_active(now_utc)) {
+            return false;
+        }
+        let session = self.sessions.get(session_id);
+        if session
+            .and_then(|prefs| prefs.mute.as_ref())
+            .is_some_and(|mute| mute.is_active(now_utc))
+        {
+            return false;
+        }
+        if self.sessions.values().any(|prefs| prefs.watched) && !session.is_some_and(|prefs| prefs.watched) {
+            return false;
+        }
+        if !self.events_for(session_id, default_events).iter().any(|e| e == event) {
+            return false;
+        }
+        if let Some(quiet_hours) = &self.quiet_hours {
+            if quiet_hours.contains(now.time()) && !URGENT_EVENTS.contains(&event) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Parses a comma-separated list of notification events.
+pub fn parse_events(value: &str) -> Result<Vec<String>, String> {
+    let events: Vec<String> = value
+        .split(',')
+        .map(|e| e.trim().to_string())
+        .filter(|e| !e.is_empty())
+        .collect();
+    if events.is_empty() {
+        return Err(format!("No events given. Use {}.", NOTIFICATION_EVENTS.join(", ")));
+    }
+    if let Some(unknown) = events.iter().find(|e| !NOTIFICATION_EVENTS.contains(&e.as_str())) {
+        return Err(format!(
+            "Unknown notification event '{}'. Use {}.",
+            unknown,
+            NOTIFICATION_EVENTS.join(", ")
+        ));
+    }
+    Ok(events)
+}
+
+/// Parses a duration such as `30m`, `2h` or `1d`.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration '{}'. Use e.g. 30m, 2h or 1d.", value);
+    let unit = value.chars().last().ok_or_else(invalid)?;
+    let amount: i64 = value[..value.len() - unit.len_utf8()].parse().map_err(|_| invalid())?;
+    if amount <= 0 {
+        return Err(invalid());
+    }
+    let duration = match unit {
+        's' => Duration::try_seconds(amount),
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        'w' => Duration::try_weeks(amount),
+        _ => return Err(invalid()),
+    };
+    duration.ok_or_else(|| format!("Duration '{}' is too long.", value))
+}
+
+/// Returns when a mute of the given duration, starting now, ends.
+pub fn mute_until(now: DateTime<Utc>, duration: Duration) -> Result<DateTime<Utc>, String> {
+    now.checked_add_signed(duration)
+        .ok_or_else(|| "Duration is too long.".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_allows_respects_mute_watch_events_and_quiet_hours() {
+        let defaults: Vec<String> = NOTIFICATION_EVENTS.iter().map(|e| e.to_string()).collect();
+        let noon = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let night = Local.with_ymd_and_hms(2024, 5, 1, 23, 0, 0).unwrap();
+
+        let mut prefs = NotificationPreferences::default();
+        assert!(prefs.allows("a", "progress", &defaults, noon));
+
+        prefs.events = Some(vec!["question".to_string()]);
+        assert!(!prefs.allows("a", "progress", &defaults, noon));
+        prefs.session_mut("a").events = Some(vec!["progress".to_string()]);
+        assert!(prefs.allows("a", "progress", &defaults, noon));
+        assert!(!prefs.allows("b", "progress", &defaults, noon));
+
+        prefs.quiet_hours = Some(QuietHours::parse("22:00-07:00").unwrap());
+        assert!(!prefs.allows("a", "progress", &defaults, night));
+        assert!(prefs.allows("b", "question", &defaults, night));
+
+        prefs.session_mut("b").watched = true;
+        assert!(!prefs.allows("a", "progress", &defaults, noon));
+        assert!(prefs.allows("b", "question", &defaults, noon));
+
+        let until = (noon + Duration::hours(1)).with_timezone(&Utc).to_rfc3339();
+        prefs.session_mut("b").mute = Some(Mute { until: Some(until) });
+        assert!(!prefs.allows("b", "question", &defaults, noon));
+        assert!(prefs.allows("b", "question", &defaults, noon + Duration::hours(2)));
+
+        prefs.mute = Some(Mute { until: None });
+        assert!(!prefs.allows("b", "question", &defaults, noon + Duration::hours(2)));
+    }
+
+    #[test]
+    fn test_parse_duration_and_events() {
+        assert_eq!(parse_duration("30m"), Ok(Duration::minutes(30)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::hours(2)));
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("0m").is_err());
+        assert!(parse_duration("5y").is_err());
+        assert!(parse_duration("999999999999d").is_err());
+        assert!(parse_duration("9999999999999999w").is_err());
+
+        let now = Utc::now();
+        assert_eq!(mute_until(now, Duration::hours(2)), Ok(now + Duration::hours(2)));
+        let long = parse_duration("100000000d").unwrap();
+        assert!(mute_until(now, long).is_err());
+        assert_eq!(parse_events("plan, pr").unwrap(), ["plan", "pr"]);
+        assert!(parse_events("plan,bogus").is_err());
+        assert!(QuietHours::parse("25:00-07:00").is_err());
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module handles named profiles.
+//!
+//! A profile bundles an identity (which API key to use and which API base URL
+//! to talk to) with its own session cache, aliases, activity store and
+//! `config.toml`. The
+//! `default` profile uses the top-level `julezz` configuration and cache
+//! directories, so existing installations keep working unchanged. Named
+//! profiles live under a `profiles/<name>` subdirectory of both.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Once, OnceLock};
+
+/// The name of the profile used when none is selected.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// The profile selected for this process with `set_current`.
+static CURRENT_PROFILE: OnceLock<String> = OnceLock::new();
+
+/// Represents how a profile authenticates with the Jules API.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    /// An API key, resolved through the credential provider chain.
+    #[default]
+    ApiKey,
+    /// OAuth tokens obtained with a service account key file.
+    ServiceAccount,
+    /// OAuth tokens obtained with the application-default credentials.
+    ApplicationDefault,
+}
+
+impl FromStr for AuthMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "api-key" => Ok(AuthMethod::ApiKey),
+            "service-account" => Ok(AuthMethod::ServiceAccount),
+            "application-default" => Ok(AuthMethod::ApplicationDefault),
+            _ => Err(format!(
+                "Unknown authentication method '{}'. Use api-key, service-account or application-default.",
+                s
+            )),
+        }
+    }
+}
+
+/// Represents the settings stored for a profile.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    /// The name of the environment variable holding the profile's API key.
+    pub api_key_env: Option<String>,
+    /// The credential helper command used to look up the profile's API key.
+    pub credential_helper: Option<String>,
+    /// How the profile authenticates with the Jules API.
+    pub auth_method: Option<AuthMethod>,
+    /// The credentials JSON file used for service account authentication.
+    pub credentials_file: Option<PathBuf>,
+    /// The base URL of the Jules API.
+    pub base_url: Option<String>,
+}
+
+impl Profile {
+    /// Resolves the API key referenced by the profile, if any.
+    pub fn api_key(&self) -> Option<String> {
+        self.api_key_env
+            .as_ref()
+            .and_then(|var| std::env::var(var).ok())
+            .filter(|key| !key.is_empty())
+    }
+}
+
+/// Selects the profile to use for the rest of the process.
+///
+/// This should be called once, before any cache is created. Passing `None`
+/// keeps the default resolution (`JULEZZ_PROFILE`, then the profile chosen
+/// with `julezz profile use`, then `default`).
+pub fn set_current(name: Option<String>) -> Result<(), String> {
+    if let Some(name) = name {
+        validate_name(&name)?;
+        CURRENT_PROFILE
+            .set(name)
+            .map_err(|_| "The current profile has already been set".to_string())?;
+    }
+    Ok(())
+}
+
+/// Returns the name of the profile in use.
+///
+/// An invalid name in `JULEZZ_PROFILE` or in the active profile file is
+/// ignored, with a warning, in favour of the default profile.
+pub fn current() -> String {
+    if let Some(name) = CURRENT_PROFILE.get() {
+        return name.clone();
+    }
+    let selected = std::env::var("JULEZZ_PROFILE")
+        .ok()
+        .filter(|name| !name.is_empty())
+        .or_else(read_active);
+    valid_or_default(selected)
+}
+
+/// Returns the given profile name if it is valid, or the default profile.
+fn valid_or_default(name: Option<String>) -> String {
+    static WARNED: Once = Once::new();
+
+    match name {
+        Some(name) => match validate_name(&name) {
+            Ok(()) => name,
+            Err(e) => {
+                // The profile is read before logging is set up; warn once it is.
+                if tracing::dispatcher::has_been_set() {
+                    WARNED.call_once(|| {
+                        tracing::warn!(profile = %name, error = %e, "Invalid profile name, using the default profile");
+                    });
+                }
+                DEFAULT_PROFILE.to_string()
+            }
+        },
+        None => DEFAULT_PROFILE.to_string(),
+    }
+}
+
+/// Returns the configuration directory of the profile in use.
+pub fn config_dir() -> Result<PathBuf, String> {
+    profile_config_dir(&current())
+}
+
+/// Returns the activity cache directory of the profile in use.
+pub fn cache_dir() -> Result<PathBuf, String> {
+    profile_cache_dir(&current())
+}
+
+/// Returns the configuration directory of the given profile.
+pub fn dir_for(name: &str) -> Result<PathBuf, String> {
+    validate_name(name)?;
+    profile_config_dir(name)
+}
+
+/// Loads the settings of the profile in use.
+pub fn load_current() -> Result<Profile, String> {
+    load(&current())
+}
+
+/// Loads the settings of a profile.
+///
+/// The `default` profile may have no settings file, in which case empty
+/// settings are returned. Any other profile must have been added first.
+pub fn load(name: &str) -> Result<Profile, String> {
+    validate_name(name)?;
+    let profile_file = profile_config_dir(name)?.join("profile.json");
+    if !profile_file.exists() {
+        if name == DEFAULT_PROFILE {
+            return Ok(Profile::default());
+        }
+        return Err(format!(
+            "Profile '{}' not found. Use `julezz profile add {}` to create it.",
+            name, name
+        ));
+    }
+    let data = fs::read_to_string(&profile_file)
+        .map_err(|e| format!("Could not read profile file: {}", e))?;
+    serde_json::from_str(&data).map_err(|e| format!("Could not parse profile file: {}", e))
+}
+
+/// Saves the settings of a profile, creating its directories if needed.
+pub fn save(name: &str, profile: &Profile) -> Result<(), String> {
+    validate_name(name)?;
+    let dir = profile_config_dir(name)?;
+    fs::create_dir_all(&dir).map_err(|e| format!("Could not create profile directory: {}", e))?;
+    let json = serde_json::to_string_pretty(profile)
+        .map_err(|e| format!("Could not serialize profile: {}", e))?;
+    fs::write(dir.join("profile.json"), json)
+        .map_err(|e| format!("Could not write profile file: {}", e))
+}
+
+/// Lists the names of all known profiles, including `default`.
+pub fn list() -> Result<Vec<String>, String> {
+    let mut names = vec![DEFAULT_PROFILE.to_string()];
+    let profiles_dir = base_config_dir()?.join("profiles");
+    if profiles_dir.exists() {
+        let entries = fs::read_dir(&profiles_dir)
+            .map_err(|e| format!("Could not read profiles directory: {}", e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Could not read profiles directory: {}", e))?;
+            if entry.path().join("profile.json").exists() {
+                names.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+    }
+    names[1..].sort();
+    Ok(names)
+}
+
+/// Removes a profile along with its session cache, aliases and activities.
+pub fn remove(name: &str) -> Result<(), String> {
+    validate_name(name)?;
+    if name == DEFAULT_PROFILE {
+        return Err("The default profile cannot be removed.".to_string());
+    }
+    let config_dir = profile_config_dir(name)?;
+    if !config_dir.join("profile.json").exists() {
+        return Err(format!("Profile '{}' not found.", name));
+    }
+    fs::remove_dir_all(&config_dir)
+        .map_err(|e| format!("Could not remove profile directory: {}", e))?;
+    let cache_dir = profile_cache_dir(name)?;
+    if cache_dir.exists() {
+        fs::remove_dir_all(&cache_dir)
+            .map_err(|e| format!("Could not remove profile cache directory: {}", e))?;
+    }
+    if read_active().as_deref() == Some(name) {
+        set_active(DEFAULT_PROFILE)?;
+    }
+    Ok(())
+}
+
+/// Makes a profile the active one for subsequent runs.
+pub fn set_active(name: &str) -> Result<(), String> {
+    validate_name(name)?;
+    if name != DEFAULT_PROFILE && !profile_config_dir(name)?.join("profile.json").exists() {
+        return Err(format!("Profile '{}' not found.", name));
+    }
+    let dir = base_config_dir()?;
+    fs::create_dir_all(&dir).map_err(|e| format!("Could not create config directory: {}", e))?;
+    fs::write(dir.join("active_profile"), name)
+        .map_err(|e| format!("Could not write active profile file: {}", e))
+}
+
+/// Reads the profile chosen with `julezz profile use`, if any.
+fn read_active() -> Option<String> {
+    let path = base_config_dir().ok()?.join("active_profile");
+    fs::read_to_string(path)
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "Invalid profile name '{}'. Use letters, digits, '-' and '_' only.",
+            name
+        ));
+    }
+    Ok(())
+}
+
+fn base_config_dir() -> Result<PathBuf, String> {
+    let config_dir = dirs::config_dir().ok_or("Could not find config directory")?;
+    Ok(config_dir.join("julezz"))
+}
+
+fn profile_config_dir(name: &str) -> Result<PathBuf, String> {
+    let base = base_config_dir()?;
+    if name == DEFAULT_PROFILE {
+        Ok(base)
+    } else {
+        Ok(base.join("profiles").join(name))
+    }
+}
+
+fn profile_cache_dir(name: &str) -> Result<PathBuf, String> {
+    let base = dirs::cache_dir()
+        .ok_or("Could not determine cache directory")?
+        .join("julezz");
+    if name == DEFAULT_PROFILE {
+        Ok(base)
+    } else {
+        Ok(base.join("profiles").join(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_names_are_rejected() {
+        for name in ["../..", "..", "a/b", "", "work space"] {
+            assert!(load(name).is_err(), "{}", name);
+            assert!(remove(name).unwrap_err().starts_with("Invalid profile name"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_invalid_selection_falls_back_to_default() {
+        assert_eq!(valid_or_default(Some("work".to_string())), "work");
+        assert_eq!(valid_or_default(Some("../../etc".to_string())), DEFAULT_PROFILE);
+        assert_eq!(valid_or_default(None), DEFAULT_PROFILE);
+    }
+}
@@ -1,7 +1,7 @@
 // src/resolve.rs
 
 use crate::api::Session;
-use crate::cache::Cache;
+use crate::cache::{Aliases, Cache};
 
 pub fn resolve_session_identifier(
     identifier: &str,
@@ -13,14 +13,26 @@ pub fn resolve_session_identifier(
 pub fn resolve_session_identifier_and_index(
     identifier: &str,
     sessions: &[Session],
+) -> Result<(String, usize), String> {
+    if identifier.starts_with('@') && !sessions.is_empty() {
+        let aliases = Cache::new()?.read_aliases()?;
+        return resolve_session_identifier_with_aliases(identifier, sessions, &aliases);
+    }
+    resolve_session_identifier_with_aliases(identifier, sessions, &Aliases::new())
+}
+
+/// Resolves a session identifier using the given aliases instead of the
+/// aliases from the cache, e.g. the aliases of a bot chat.
+pub fn resolve_session_identifier_with_aliases(
+    identifier: &str,
+    sessions: &[Session],
+    aliases: &Aliases,
 ) -> Result<(String, usize), String> {
     if sessions.is_empty() {
         return Err("No sessions found.".to_string());
     }
 
     if identifier.starts_with('@') {
-        let cache = Cache::new()?;
-        let aliases = cache.read_aliases()?;
         let session_id = aliases
             .get(identifier)
             .ok_or_else(|| format!("Alias '{}' not found.", identifier))?;
@@ -0,0 +1,83 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module holds the fixtures shared by the tests of several modules.
+
+use crate::api::{Activity, Session};
+use crate::events::{Event, EventKind};
+use std::path::PathBuf;
+
+/// Returns a session with the given ID and state, titled "Session <id>".
+pub(crate) fn session(id: &str, state: &str) -> Session {
+    Session {
+        name: format!("sessions/{}", id),
+        id: id.to_string(),
+        state: Some(state.to_string()),
+        title: format!("Session {}", id),
+        source_context: None,
+        pull_request_url: None,
+        create_time: None,
+        update_time: None,
+    }
+}
+
+/// Returns an agent activity of session 1 with the given ID and time.
+///
+/// `kind` holds the field telling what happened, e.g.
+/// `json!({"sessionCompleted": {}})`.
+pub(crate) fn activity(id: &str, time: &str, kind: serde_json::Value) -> Activity {
+    let mut value = serde_json::json!({
+        "name": format!("sessions/1/activities/{}", id),
+        "id": id,
+        "createTime": time,
+        "originator": "agent",
+    });
+    value.as_object_mut().unwrap().extend(kind.as_object().unwrap().clone());
+    serde_json::from_value(value).unwrap()
+}
+
+/// Returns an empty directory for a test, named after the test and the
+/// process so that concurrent runs do not share it.
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("julezz-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Returns an event of session 42, "Fix the parser".
+pub(crate) fn event(kind: EventKind) -> Event {
+    Event {
+        kind,
+        session_id: "42".to_string(),
+        session_title: "Fix the parser".to_string(),
+        activity_id: Some("a".to_string()),
+        time: "2024-05-01T10:00:00Z".to_string(),
+        detail: None,
+        pull_request_url: None,
+    }
+}
+
+/// Serves an app on a free local port in the background.
+///
+/// # Returns
+///
+/// The base URL of the server, e.g. `http://127.0.0.1:41234`.
+pub(crate) fn serve(app: axum::Router) -> String {
+    let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = tcp.local_addr().unwrap();
+    tcp.set_nonblocking(true).unwrap();
+    tokio::spawn(axum::Server::from_tcp(tcp).unwrap().serve(app.into_make_service()));
+    format!("http://{}", address)
+}
@@ -0,0 +1,444 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module handles the local trash for deleted sessions.
+//!
+//! When a session is deleted, its cached metadata, aliases and activity
+//! history are moved into the trash instead of being discarded. Trashed
+//! entries are kept for a retention period, after which they are purged.
+
+use crate::cache::{Aliases, Cache, CachedSession};
+use crate::profile;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The default number of days a trashed session is kept before being purged.
+pub const DEFAULT_RETENTION_DAYS: u64 = 30;
+
+/// Represents a deleted session that is stored in the trash.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrashedSession {
+    /// The cached metadata of the session at the time of deletion.
+    pub session: CachedSession,
+    /// The aliases that pointed to the session.
+    pub aliases: Vec<String>,
+    /// The time of deletion, in seconds since the Unix epoch.
+    #[serde(rename = "deletedAt")]
+    pub deleted_at: u64,
+}
+
+impl TrashedSession {
+    /// Returns the number of whole days since the session was deleted.
+    pub fn age_days(&self) -> u64 {
+        now().saturating_sub(self.deleted_at) / 86400
+    }
+}
+
+/// Manages the local trash for deleted sessions.
+///
+/// Each trashed session lives in its own directory, holding a `session.json`
+/// file with its metadata and an `activities` directory with its cached
+/// activity history.
+pub struct Trash {
+    /// The path to the trash directory.
+    trash_dir: PathBuf,
+    /// The directory holding the cached activities of each session.
+    activities_root: PathBuf,
+    /// The number of days trashed sessions are kept.
+    retention_days: u64,
+}
+
+impl Trash {
+    /// Creates a new `Trash` instance for the current profile.
+    ///
+    /// The retention period defaults to `DEFAULT_RETENTION_DAYS` and can be
+    /// overridden with the `JULEZZ_TRASH_RETENTION_DAYS` environment variable,
+    /// which must be at least 1.
+    pub fn new() -> Result<Self, String> {
+        Self::at(profile::config_dir()?.join("trash"))
+    }
+
+    /// Creates the `Trash` of a bot chat, which keeps the chat's own
+    /// sessions and aliases apart from those of the command line.
+    pub fn for_chat(chat_id: i64) -> Result<Self, String> {
+        Self::at(profile::config_dir()?.join("trash").join("chats").join(chat_id.to_string()))
+    }
+
+    fn at(trash_dir: PathBuf) -> Result<Self, String> {
+        fs::create_dir_all(&trash_dir)
+            .map_err(|e| format!("Could not create trash directory: {}", e))?;
+
+        let retention_days = match std::env::var("JULEZZ_TRASH_RETENTION_DAYS") {
+            // 0 would purge every session as soon as it is trashed.
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|&days| days > 0)
+                .ok_or("JULEZZ_TRASH_RETENTION_DAYS must be a positive integer")?,
+            Err(_) => DEFAULT_RETENTION_DAYS,
+        };
+
+        Ok(Self::in_dir(&trash_dir, &profile::cache_dir()?, retention_days))
+    }
+
+    /// Creates a `Trash` in the given directory, for the sessions whose
+    /// activities are cached in `activities_root`.
+    pub(crate) fn in_dir(trash_dir: &Path, activities_root: &Path, retention_days: u64) -> Self {
+        Self {
+            trash_dir: trash_dir.to_path_buf(),
+            activities_root: activities_root.to_path_buf(),
+            retention_days,
+        }
+    }
+
+    /// Returns the directory of a session's trash entry.
+    fn entry_dir(&self, session_id: &str) -> Result<PathBuf, String> {
+        validate_session_id(session_id)?;
+        Ok(self.trash_dir.join(session_id))
+    }
+
+    /// Returns the number of days trashed sessions are kept.
+    pub fn retention_days(&self) -> u64 {
+        self.retention_days
+    }
+
+    /// Moves a deleted session's local state into the trash.
+    ///
+    /// This removes the session from the sessions cache, removes any aliases
+    /// pointing to it and moves its cached activities into the trash. If the
+    /// session is not in the sessions cache, only a minimal entry is recorded.
+    pub fn trash_session(&self, cache: &Cache, session_id: &str) -> Result<TrashedSession, String> {
+        let mut sessions = cache.read_sessions()?;
+        let mut aliases = cache.read_aliases()?;
+        let entry = self.trash(&mut sessions, &mut aliases, session_id)?;
+        cache.write_sessions(&sessions)?;
+        cache.write_aliases(&aliases)?;
+        Ok(entry)
+    }
+
+    /// Moves a deleted session's local state into the trash, removing it
+    /// from the given sessions and aliases, e.g. those of a bot chat.
+    pub fn trash(
+        &self,
+        sessions: &mut Vec<CachedSession>,
+        aliases: &mut Aliases,
+        session_id: &str,
+    ) -> Result<TrashedSession, String> {
+        let entry_dir = self.entry_dir(session_id)?;
+        let session = match sessions.iter().position(|s| s.id == session_id) {
+            Some(index) => sessions.remove(index),
+            None => CachedSession {
+                id: session_id.to_string(),
+                title: String::new(),
+                source_context: None,
+                pull_request_url: None,
+                state: None,
+                create_time: None,
+                update_time: None,
+            },
+        };
+
+        let mut session_aliases: Vec<String> = aliases
+            .iter()
+            .filter(|(_, id)| id.as_str() == session_id)
+            .map(|(alias, _)| alias.clone())
+            .collect();
+        session_aliases.sort();
+        aliases.retain(|_, id| id != session_id);
+
+        let entry = TrashedSession {
+            session,
+            aliases: session_aliases,
+            deleted_at: now(),
+        };
+
+        if entry_dir.exists() {
+            fs::remove_dir_all(&entry_dir)
+                .map_err(|e| format!("Could not remove old trash entry: {}", e))?;
+        }
+        fs::create_dir_all(&entry_dir)
+            .map_err(|e| format!("Could not create trash entry: {}", e))?;
+        write_entry(&entry_dir, &entry)?;
+
+        let activities_dir = self.activities_root.join(session_id);
+        if activities_dir.exists() {
+            move_dir(&activities_dir, &entry_dir.join("activities"))?;
+        }
+
+        self.purge_expired()?;
+        Ok(entry)
+    }
+
+    /// Lists the sessions in the trash, most recently deleted first.
+    ///
+    /// Entries that cannot be read are skipped with a warning, so that one
+    /// corrupt entry does not hide the others or block purging them.
+    pub fn list(&self) -> Result<Vec<TrashedSession>, String> {
+        let mut entries = Vec::new();
+        let dir_entries = fs::read_dir(&self.trash_dir)
+            .map_err(|e| format!("Could not read trash directory: {}", e))?;
+        for dir_entry in dir_entries {
+            let path = match dir_entry {
+                Ok(dir_entry) => dir_entry.path(),
+                Err(e) => {
+                    tracing::warn!(error = %e, "Skipping unreadable trash entry");
+                    continue;
+                }
+            };
+            if !path.join("session.json").exists() {
+                continue;
+            }
+            match read_entry(&path) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => tracing::warn!(path = %path.display(), error = %e, "Skipping unreadable trash entry"),
+            }
+        }
+        entries.sort_by_key(|e| std::cmp::Reverse(e.deleted_at));
+        Ok(entries)
+    }
+
+    /// Restores a trashed session's local state.
+    ///
+    /// The session is added back to the sessions cache, its aliases are
+    /// re-created (unless they have been reassigned in the meantime) and its
+    /// activities are moved back into the activity cache. Only local metadata
+    /// is restored; the session itself cannot be recovered on the server.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the restored entry and the aliases that could not
+    /// be restored because they are now in use.
+    pub fn restore(&self, cache: &Cache, session_id: &str) -> Result<(TrashedSession, Vec<String>), String> {
+        let mut sessions = cache.read_sessions()?;
+        let mut aliases = cache.read_aliases()?;
+        let restored = self.restore_into(&mut sessions, &mut aliases, session_id)?;
+        cache.write_sessions(&sessions)?;
+        cache.write_aliases(&aliases)?;
+        Ok(restored)
+    }
+
+    /// Restores a trashed session's local state into the given sessions and
+    /// aliases, e.g. those of a bot chat. See `restore`.
+    pub fn restore_into(
+        &self,
+        sessions: &mut Vec<CachedSession>,
+        aliases: &mut Aliases,
+        session_id: &str,
+    ) -> Result<(TrashedSession, Vec<String>), String> {
+        let entry_dir = self.entry_dir(session_id)?;
+        if !entry_dir.join("session.json").exists() {
+            return Err(format!("Session '{}' not found in trash.", session_id));
+        }
+        let entry = read_entry(&entry_dir)?;
+
+        if !sessions.iter().any(|s| s.id == session_id) {
+            sessions.push(entry.session.clone());
+        }
+
+        let mut conflicts = Vec::new();
+        for alias in &entry.aliases {
+            match aliases.get(alias) {
+                Some(id) if id != session_id => conflicts.push(alias.clone()),
+                _ => {
+                    aliases.insert(alias.clone(), session_id.to_string());
+                }
+            }
+        }
+
+        let trashed_activities = entry_dir.join("activities");
+        if trashed_activities.exists() {
+            let activities_dir = self.activities_root.join(session_id);
+            if activities_dir.exists() {
+                fs::remove_dir_all(&activities_dir)
+                    .map_err(|e| format!("Could not replace activity cache: {}", e))?;
+            }
+            move_dir(&trashed_activities, &activities_dir)?;
+        }
+
+        fs::remove_dir_all(&entry_dir)
+            .map_err(|e| format!("Could not remove trash entry: {}", e))?;
+
+        Ok((entry, conflicts))
+    }
+
+    /// Permanently removes a session from the trash.
+    pub fn purge(&self, session_id: &str) -> Result<(), String> {
+        let entry_dir = self.entry_dir(session_id)?;
+        if !entry_dir.join("session.json").exists() {
+            return Err(format!("Session '{}' not found in trash.", session_id));
+        }
+        fs::remove_dir_all(&entry_dir).map_err(|e| format!("Could not remove trash entry: {}", e))
+    }
+
+    /// Permanently removes every session from the trash.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of purged sessions.
+    pub fn purge_all(&self) -> Result<usize, String> {
+        let entries = self.list()?;
+        for entry in &entries {
+            self.purge(&entry.session.id)?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Permanently removes the sessions whose retention period has expired.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of purged sessions.
+    pub fn purge_expired(&self) -> Result<usize, String> {
+        let mut purged = 0;
+        for entry in self.list()? {
+            if entry.age_days() >= self.retention_days {
+                self.purge(&entry.session.id)?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+}
+
+/// Checks that a session ID names a single directory, so that it cannot
+/// escape the trash directory.
+fn validate_session_id(session_id: &str) -> Result<(), String> {
+    if session_id.is_empty()
+        || session_id == "."
+        || session_id.contains("..")
+        || session_id.contains(['/', '\\'])
+    {
+        return Err(format!("'{}' is not a valid session ID.", session_id));
+    }
+    Ok(())
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn read_entry(entry_dir: &Path) -> Result<TrashedSession, String> {
+    let data = fs::read_to_string(entry_dir.join("session.json"))
+        .map_err(|e| format!("Could not read trash entry: {}", e))?;
+    serde_json::from_str(&data).map_err(|e| format!("Could not parse trash entry: {}", e))
+}
+
+fn write_entry(entry_dir: &Path, entry: &TrashedSession) -> Result<(), String> {
+    let json = serde_json::to_string(entry)
+        .map_err(|e| format!("Could not serialize trash entry: {}", e))?;
+    fs::write(entry_dir.join("session.json"), json)
+        .map_err(|e| format!("Could not write trash entry: {}", e))
+}
+
+/// Moves a directory, falling back to a recursive copy when the source and
+/// destination live on different filesystems.
+fn move_dir(from: &Path, to: &Path) -> Result<(), String> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Could not create directory: {}", e))?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_dir(from, to)?;
+    fs::remove_dir_all(from).map_err(|e| format!("Could not remove directory: {}", e))
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), String> {
+    fs::create_dir_all(to).map_err(|e| format!("Could not create directory: {}", e))?;
+    let entries = fs::read_dir(from).map_err(|e| format!("Could not read directory: {}", e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Could not read directory: {}", e))?;
+        let path = entry.path();
+        let target = to.join(entry.file_name());
+        if path.is_dir() {
+            copy_dir(&path, &target)?;
+        } else {
+            fs::copy(&path, &target).map_err(|e| format!("Could not copy file: {}", e))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a trash, a cache and an activity cache in a temporary directory.
+    fn setup(name: &str, retention_days: u64) -> (PathBuf, Trash, Cache) {
+        let dir = crate::test_util::temp_dir(&format!("trash-{}", name));
+        fs::create_dir_all(dir.join("trash")).unwrap();
+        let trash = Trash::in_dir(&dir.join("trash"), &dir.join("activities"), retention_days);
+        let cache = Cache::in_dir(&dir);
+        let session: CachedSession = serde_json::from_value(serde_json::json!({"id": "123", "title": "Fix it"})).unwrap();
+        cache.write_sessions(&[session]).unwrap();
+        cache.write_aliases(&[("fix".to_string(), "123".to_string())].into_iter().collect()).unwrap();
+        fs::create_dir_all(dir.join("activities/123")).unwrap();
+        fs::write(dir.join("activities/123/messages.json"), "[]").unwrap();
+        (dir, trash, cache)
+    }
+
+    #[test]
+    fn test_trash_and_restore() {
+        let (dir, trash, cache) = setup("restore", 30);
+        let entry = trash.trash_session(&cache, "123").unwrap();
+        assert_eq!(entry.aliases, vec!["fix"]);
+        assert!(cache.read_sessions().unwrap().is_empty());
+        assert!(cache.read_aliases().unwrap().is_empty());
+        assert!(!dir.join("activities/123").exists());
+        assert_eq!(trash.list().unwrap().len(), 1);
+
+        // A corrupt entry does not hide the others.
+        fs::create_dir_all(dir.join("trash/456")).unwrap();
+        fs::write(dir.join("trash/456/session.json"), "{").unwrap();
+        assert_eq!(trash.list().unwrap().len(), 1);
+
+        let (restored, conflicts) = trash.restore(&cache, "123").unwrap();
+        assert_eq!(restored.session.title, "Fix it");
+        assert!(conflicts.is_empty());
+        assert_eq!(cache.read_aliases().unwrap()["fix"], "123");
+        assert!(dir.join("activities/123/messages.json").exists());
+        assert!(trash.list().unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_purge_and_expiry() {
+        let (dir, trash, cache) = setup("purge", 30);
+        trash.trash_session(&cache, "123").unwrap();
+        for id in ["../123", "..", "a/b", "a\\b", ""] {
+            assert!(trash.restore(&cache, id).is_err(), "{}", id);
+            assert!(trash.purge(id).is_err(), "{}", id);
+        }
+        assert_eq!(trash.purge_expired().unwrap(), 0);
+        trash.purge("123").unwrap();
+        assert!(trash.purge("123").is_err());
+        fs::remove_dir_all(dir).unwrap();
+
+        // Entries older than the retention period are purged.
+        let (dir, expiring, cache) = setup("expiry", 1);
+        let mut entry = expiring.trash_session(&cache, "123").unwrap();
+        entry.deleted_at -= 2 * 86400;
+        write_entry(&dir.join("trash/123"), &entry).unwrap();
+        assert_eq!(expiring.list().unwrap()[0].age_days(), 2);
+        assert_eq!(expiring.purge_expired().unwrap(), 1);
+        assert!(expiring.list().unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
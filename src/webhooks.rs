@@ -0,0 +1,716 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module delivers the events of sessions to other systems over
+//! webhooks.
+//!
+//! Endpoints are registered with `julezz webhooks add`, each with a shared
+//! secret. `julezz serve webhooks` polls the sessions like the bot and POSTs
+//! each event to the endpoints subscribed to its kind, as JSON signed with
+//! HMAC-SHA256 in the `X-Julezz-Signature` header. Failed deliveries wait in
+//! a queue and are retried with exponential backoff, every attempt is
+//! appended to a delivery log, rotated when it grows large, and a logged
+//! delivery can be replayed.
+
+use crate::api::JulesClient;
+use crate::cache::{write_private, Cache};
+use crate::config::{Config, NOTIFICATION_EVENTS};
+use crate::events::Event;
+use crate::metrics;
+use crate::poll::EventPoller;
+use crate::profile;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The header holding the signature of a delivery.
+pub const SIGNATURE_HEADER: &str = "X-Julezz-Signature";
+/// The header holding the kind of the delivered event.
+pub const EVENT_HEADER: &str = "X-Julezz-Event";
+/// The header holding the ID of a delivery.
+pub const DELIVERY_HEADER: &str = "X-Julezz-Delivery";
+
+/// The delay before the first retry of a failed delivery. It doubles after
+/// each attempt, up to `MAX_RETRY_DELAY`.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// The size beyond which the delivery log is rotated. The previous log is
+/// kept, so replays can use up to twice this size of history.
+const MAX_LOG_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Represents an endpoint receiving events.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Endpoint {
+    /// The ID of the endpoint.
+    pub id: String,
+    /// The URL events are POSTed to.
+    pub url: String,
+    /// The secret the payloads are signed with.
+    pub secret: String,
+    /// The names of the event kinds to deliver. Empty means all of them.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// When the endpoint was added, in seconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+impl Endpoint {
+    /// Returns whether the endpoint is subscribed to an event.
+    pub fn wants(&self, event: &Event) -> bool {
+        self.events.is_empty() || self.events.iter().any(|name| name == event.kind.name())
+    }
+}
+
+/// Represents the JSON body POSTed to an endpoint.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Payload {
+    /// The ID of the delivery, the same across its attempts.
+    pub delivery_id: String,
+    /// The attempt, starting at 1.
+    pub attempt: u32,
+    /// The event.
+    pub event: Event,
+}
+
+/// Represents a delivery waiting in the retry queue.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Delivery {
+    /// The ID of the delivery.
+    pub id: String,
+    /// The ID of the endpoint to deliver to.
+    pub endpoint_id: String,
+    /// The event to deliver.
+    pub event: Event,
+    /// The number of attempts made so far.
+    pub attempts: u32,
+    /// When to make the next attempt, in seconds since the Unix epoch.
+    pub next_attempt: u64,
+}
+
+/// Represents an attempt in the delivery log.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryRecord {
+    /// The ID of the delivery.
+    pub delivery_id: String,
+    /// The ID of the endpoint.
+    pub endpoint_id: String,
+    /// The URL the event was POSTed to.
+    pub url: String,
+    /// The attempt, starting at 1.
+    pub attempt: u32,
+    /// When the attempt was made, in seconds since the Unix epoch.
+    pub time: u64,
+    /// The HTTP status of the response, if one was received.
+    pub status: Option<u16>,
+    /// Why the attempt failed, if it did.
+    pub error: Option<String>,
+    /// The event.
+    pub event: Event,
+}
+
+impl DeliveryRecord {
+    /// Returns whether the attempt succeeded.
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Stores the endpoints, the retry queue and the delivery log of the current
+/// profile.
+pub struct WebhookStore {
+    /// The path to the endpoints file. It holds the secrets, so it is only
+    /// readable by the user.
+    endpoints_file: PathBuf,
+    /// The path to the retry queue file.
+    queue_file: PathBuf,
+    /// The path to the delivery log, one JSON record per line.
+    log_file: PathBuf,
+    /// The path to the previous delivery log.
+    rotated_log_file: PathBuf,
+    /// The size beyond which the delivery log is rotated.
+    max_log_bytes: u64,
+}
+
+impl WebhookStore {
+    /// Creates a new `WebhookStore` instance for the current profile.
+    pub fn new() -> Result<Self, String> {
+        let julezz_dir = profile::config_dir()?;
+        fs::create_dir_all(&julezz_dir)
+            .map_err(|e| format!("Could not create config directory: {}", e))?;
+        Ok(Self::in_dir(&julezz_dir))
+    }
+
+    fn in_dir(dir: &Path) -> Self {
+        Self {
+            endpoints_file: dir.join("webhook_endpoints.json"),
+            queue_file: dir.join("webhook_queue.json"),
+            log_file: dir.join("webhook_deliveries.log"),
+            rotated_log_file: dir.join("webhook_deliveries.log.1"),
+            max_log_bytes: MAX_LOG_BYTES,
+        }
+    }
+
+    /// Reads the registered endpoints.
+    pub fn endpoints(&self) -> Result<Vec<Endpoint>, String> {
+        if !self.endpoints_file.exists() {
+            return Ok(Vec::new());
+        }
+        let data = fs::read_to_string(&self.endpoints_file)
+            .map_err(|e| format!("Could not read webhook endpoints file: {}", e))?;
+        serde_json::from_str(&data).map_err(|e| format!("Could not parse webhook endpoints file: {}", e))
+    }
+
+    fn write_endpoints(&self, endpoints: &[Endpoint]) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(endpoints)
+            .map_err(|e| format!("Could not serialize webhook endpoints: {}", e))?;
+        write_private(&self.endpoints_file, json.as_bytes())
+            .map_err(|e| format!("Could not write webhook endpoints file: {}", e))
+    }
+
+    /// Registers an endpoint.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL events are POSTed to.
+    /// * `events` - The names of the event kinds to deliver, or none for all.
+    /// * `secret` - The secret to sign payloads with. A random one is
+    ///   generated if it is not given.
+    pub fn add_endpoint(&self, url: &str, events: Vec<String>, secret: Option<String>) -> Result<Endpoint, String> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("'{}' is not an http(s) URL", url));
+        }
+        if let Some(unknown) = events.iter().find(|name| !NOTIFICATION_EVENTS.contains(&name.as_str())) {
+            return Err(format!(
+                "Unknown event '{}'; expected one of: {}",
+                unknown,
+                NOTIFICATION_EVENTS.join(", ")
+            ));
+        }
+        let mut endpoints = self.endpoints()?;
+        let endpoint = Endpoint {
+            id: random_hex(4),
+            url: url.to_string(),
+            secret: secret.unwrap_or_else(|| random_hex(32)),
+            events,
+            created_at: now(),
+        };
+        endpoints.push(endpoint.clone());
+        self.write_endpoints(&endpoints)?;
+        Ok(endpoint)
+    }
+
+    /// Removes an endpoint, and the deliveries queued for it.
+    pub fn remove_endpoint(&self, id: &str) -> Result<Endpoint, String> {
+        let mut endpoints = self.endpoints()?;
+        let index = endpoints
+            .iter()
+            .position(|endpoint| endpoint.id == id)
+            .ok_or_else(|| format!("No webhook endpoint with ID '{}'", id))?;
+        let endpoint = endpoints.remove(index);
+        self.write_endpoints(&endpoints)?;
+
+        let mut queue = self.queue()?;
+        queue.retain(|delivery| delivery.endpoint_id != id);
+        self.write_queue(&queue)?;
+        Ok(endpoint)
+    }
+
+    /// Reads the retry queue.
+    pub fn queue(&self) -> Result<Vec<Delivery>, String> {
+        if !self.queue_file.exists() {
+            return Ok(Vec::new());
+        }
+        let data = fs::read_to_string(&self.queue_file)
+            .map_err(|e| format!("Could not read webhook queue file: {}", e))?;
+        serde_json::from_str(&data).map_err(|e| format!("Could not parse webhook queue file: {}", e))
+    }
+
+    /// Writes the retry queue.
+    pub fn write_queue(&self, queue: &[Delivery]) -> Result<(), String> {
+        let json = serde_json::to_string(queue)
+            .map_err(|e| format!("Could not serialize webhook queue: {}", e))?;
+        fs::write(&self.queue_file, json).map_err(|e| format!("Could not write webhook queue file: {}", e))
+    }
+
+    /// Appends an attempt to the delivery log, rotating it first if it is
+    /// too large.
+    pub fn record(&self, record: &DeliveryRecord) -> Result<(), String> {
+        let line = serde_json::to_string(record)
+            .map_err(|e| format!("Could not serialize delivery record: {}", e))?;
+        if fs::metadata(&self.log_file).is_ok_and(|metadata| metadata.len() >= self.max_log_bytes) {
+            fs::rename(&self.log_file, &self.rotated_log_file)
+                .map_err(|e| format!("Could not rotate delivery log: {}", e))?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log_file)
+            .map_err(|e| format!("Could not open delivery log: {}", e))?;
+        writeln!(file, "{}", line).map_err(|e| format!("Could not write delivery log: {}", e))
+    }
+
+    /// Reads the delivery log and the previous one, oldest attempt first.
+    /// Lines that cannot be parsed are skipped.
+    pub fn deliveries(&self) -> Result<Vec<DeliveryRecord>, String> {
+        let mut deliveries = Vec::new();
+        for file in [&self.rotated_log_file, &self.log_file] {
+            if !file.exists() {
+                continue;
+            }
+            let data = fs::read_to_string(file).map_err(|e| format!("Could not read delivery log: {}", e))?;
+            deliveries.extend(data.lines().filter_map(|line| serde_json::from_str::<DeliveryRecord>(line).ok()));
+        }
+        Ok(deliveries)
+    }
+}
+
+/// Signs a payload with a secret.
+///
+/// # Returns
+///
+/// The value of the `X-Julezz-Signature` header: `sha256=` followed by the
+/// hex-encoded HMAC-SHA256 of the body.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Checks the signature of a payload, in constant time, as a receiver would.
+pub fn verify(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Some(signature) = signature.strip_prefix("sha256=").and_then(|s| hex::decode(s).ok()) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// POSTs an event to an endpoint.
+///
+/// # Returns
+///
+/// The record of the attempt, whether it succeeded or not. Any response
+/// other than a 2xx status is a failure.
+pub async fn send(
+    http: &reqwest::Client,
+    endpoint: &Endpoint,
+    delivery_id: &str,
+    attempt: u32,
+    event: &Event,
+    timeout: Duration,
+) -> DeliveryRecord {
+    let mut record = DeliveryRecord {
+        delivery_id: delivery_id.to_string(),
+        endpoint_id: endpoint.id.clone(),
+        url: endpoint.url.clone(),
+        attempt,
+        time: now(),
+        status: None,
+        error: None,
+        event: event.clone(),
+    };
+    let payload = Payload {
+        delivery_id: delivery_id.to_string(),
+        attempt,
+        event: event.clone(),
+    };
+    let body = match serde_json::to_vec(&payload) {
+        Ok(body) => body,
+        Err(e) => {
+            record.error = Some(format!("Could not serialize payload: {}", e));
+            return record;
+        }
+    };
+    let result = http
+        .post(&endpoint.url)
+        .timeout(timeout)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(reqwest::header::USER_AGENT, "julezz")
+        .header(SIGNATURE_HEADER, sign(&endpoint.secret, &body))
+        .header(EVENT_HEADER, event.kind.name())
+        .header(DELIVERY_HEADER, delivery_id)
+        .body(body)
+        .send()
+        .await;
+    match result {
+        Ok(response) => {
+            let status = response.status();
+            record.status = Some(status.as_u16());
+            if !status.is_success() {
+                record.error = Some(format!("Endpoint answered {}", status));
+            }
+        }
+        Err(e) => record.error = Some(format!("Could not reach endpoint: {}", e)),
+    }
+    record
+}
+
+/// Sends a logged delivery again to its endpoint, as a new attempt.
+///
+/// The endpoint's current URL and secret are used, so a delivery can be
+/// replayed after the endpoint was fixed.
+pub async fn replay(store: &WebhookStore, delivery_id: &str, config: &Config) -> Result<DeliveryRecord, String> {
+    let deliveries = store.deliveries()?;
+    let last = deliveries
+        .iter()
+        .rev()
+        .find(|record| record.delivery_id == delivery_id)
+        .ok_or_else(|| format!("No delivery with ID '{}' in the log", delivery_id))?;
+    let endpoint = store
+        .endpoints()?
+        .into_iter()
+        .find(|endpoint| endpoint.id == last.endpoint_id)
+        .ok_or_else(|| format!("Webhook endpoint '{}' was removed", last.endpoint_id))?;
+
+    let timeout = Duration::from_secs(config.webhook_timeout_seconds());
+    let record = send(&reqwest::Client::new(), &endpoint, delivery_id, last.attempt + 1, &last.event, timeout).await;
+    store.record(&record)?;
+    Ok(record)
+}
+
+/// Detects the events of a profile's sessions and delivers them to the
+/// registered endpoints.
+pub struct WebhookServer {
+    poller: EventPoller,
+    store: WebhookStore,
+    http: reqwest::Client,
+    max_attempts: u32,
+    timeout: Duration,
+}
+
+impl WebhookServer {
+    /// Creates a new `WebhookServer`, using the bot's poll settings.
+    pub fn new(client: Arc<JulesClient>, store: WebhookStore, config: &Config) -> Self {
+        Self {
+            poller: EventPoller::new(client, config),
+            store,
+            http: reqwest::Client::new(),
+            max_attempts: config.webhook_max_attempts().max(1),
+            timeout: Duration::from_secs(config.webhook_timeout_seconds()),
+        }
+    }
+
+    /// Queues the deliveries of events to the endpoints subscribed to them.
+    pub fn enqueue(&self, events: &[Event]) -> Result<(), String> {
+        let endpoints = self.store.endpoints()?;
+        let mut queue = self.store.queue()?;
+        for event in events {
+            for endpoint in endpoints.iter().filter(|endpoint| endpoint.wants(event)) {
+                queue.push(Delivery {
+                    id: random_hex(8),
+                    endpoint_id: endpoint.id.clone(),
+                    event: event.clone(),
+                    attempts: 0,
+                    next_attempt: 0,
+                });
+            }
+        }
+        self.store.write_queue(&queue)
+    }
+
+    /// Makes the attempts that are due in the queue.
+    ///
+    /// The endpoints are sent to concurrently, each in the order of its
+    /// deliveries. A failed delivery is retried later, until
+    /// `webhooks.max_attempts` is reached. Deliveries to removed endpoints are
+    /// dropped.
+    ///
+    /// # Returns
+    ///
+    /// The records of the attempts made.
+    pub async fn flush(&self) -> Result<Vec<DeliveryRecord>, String> {
+        let endpoints = self.store.endpoints()?;
+        let mut by_endpoint: Vec<(&Endpoint, Vec<Delivery>)> = Vec::new();
+        for delivery in self.store.queue()? {
+            let Some(endpoint) = endpoints.iter().find(|endpoint| endpoint.id == delivery.endpoint_id) else {
+                continue;
+            };
+            match by_endpoint.iter_mut().find(|(e, _)| e.id == endpoint.id) {
+                Some((_, deliveries)) => deliveries.push(delivery),
+                None => by_endpoint.push((endpoint, vec![delivery])),
+            }
+        }
+
+        let flushed = join_all(
+            by_endpoint
+                .into_iter()
+                .map(|(endpoint, deliveries)| self.flush_endpoint(endpoint, deliveries)),
+        )
+        .await;
+        let mut records = Vec::new();
+        let mut remaining = Vec::new();
+        for (endpoint_records, endpoint_remaining) in flushed {
+            records.extend(endpoint_records);
+            remaining.extend(endpoint_remaining);
+        }
+        // The queue is written first so a failing log cannot resend deliveries.
+        self.store.write_queue(&remaining)?;
+        for record in &records {
+            if let Err(e) = self.store.record(record) {
+                tracing::warn!(delivery = %record.delivery_id, error = %e, "Could not log webhook delivery");
+            }
+            metrics::record_notification("webhooks", record.succeeded());
+        }
+        Ok(records)
+    }
+
+    /// Makes the attempts that are due for an endpoint.
+    ///
+    /// Once an attempt fails, the endpoint's other deliveries wait for the
+    /// next flush, so an endpoint that is down costs one timeout per flush.
+    ///
+    /// # Returns
+    ///
+    /// The records of the attempts made, and the deliveries to keep queued.
+    async fn flush_endpoint(&self, endpoint: &Endpoint, deliveries: Vec<Delivery>) -> (Vec<DeliveryRecord>, Vec<Delivery>) {
+        let mut records = Vec::new();
+        let mut remaining = Vec::new();
+        let mut failed = false;
+        for mut delivery in deliveries {
+            if failed || delivery.next_attempt > now() {
+                remaining.push(delivery);
+                continue;
+            }
+
+            delivery.attempts += 1;
+            let record = send(&self.http, endpoint, &delivery.id, delivery.attempts, &delivery.event, self.timeout).await;
+            if let Some(error) = &record.error {
+                failed = true;
+                if delivery.attempts < self.max_attempts {
+                    delivery.next_attempt = now() + retry_delay(delivery.attempts).as_secs();
+                    remaining.push(delivery);
+                } else {
+                    tracing::error!(
+                        delivery_id = %delivery.id,
+                        url = %endpoint.url,
+                        attempts = delivery.attempts,
+                        %error,
+                        "Giving up on delivery"
+                    );
+                }
+            }
+            records.push(record);
+        }
+        (records, remaining)
+    }
+
+    /// Checks the sessions at the poll interval and delivers their events,
+    /// forever.
+    ///
+    /// The deliveries are queued before the cursors are saved, so a crash
+    /// repeats events rather than losing them.
+    pub async fn run(mut self, cache: Cache) {
+        let mut interval = tokio::time::interval(self.poller.interval());
+        loop {
+            interval.tick().await;
+
+            match cache.read_daemon_state("webhook_server") {
+                Ok(mut state) => match self.poller.check(&mut state).await {
+                    Ok(events) => {
+                        if let Err(e) = self.enqueue(&events) {
+                            tracing::error!(error = %e, "Failed to queue webhook deliveries");
+                        } else if let Err(e) = cache.write_daemon_state("webhook_server", &state) {
+                            tracing::error!(error = %e, "Failed to save webhook server state");
+                        }
+                    }
+                    Err(e) => tracing::error!(error = %e, "Failed to check sessions"),
+                },
+                Err(e) => tracing::error!(error = %e, "Failed to read webhook server state"),
+            }
+
+            match self.flush().await {
+                Ok(records) => {
+                    for record in records.iter().filter(|record| !record.succeeded()) {
+                        tracing::warn!(
+                            delivery_id = %record.delivery_id,
+                            url = %record.url,
+                            attempt = record.attempt,
+                            error = record.error.as_deref().unwrap_or_default(),
+                            "Delivery failed"
+                        );
+                    }
+                }
+                Err(e) => tracing::error!(error = %e, "Failed to deliver webhooks"),
+            }
+        }
+    }
+}
+
+/// Returns the delay before retrying a delivery after a failed attempt.
+fn retry_delay(attempts: u32) -> Duration {
+    RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY)
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut buffer = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buffer);
+    hex::encode(buffer)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+    use crate::test_util::{self, event, serve};
+    use axum::http::{HeaderMap, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    fn store(name: &str) -> (PathBuf, WebhookStore) {
+        let dir = test_util::temp_dir(&format!("webhooks-{}", name));
+        let store = WebhookStore::in_dir(&dir);
+        (dir, store)
+    }
+
+    #[test]
+    fn test_endpoints_and_signatures() {
+        let (dir, store) = store("endpoints");
+        let all = store.add_endpoint("https://example.com/all", Vec::new(), None).unwrap();
+        let plans = store
+            .add_endpoint("https://example.com/plans", vec!["plan".to_string()], Some("s3cret".to_string()))
+            .unwrap();
+        assert!(store.add_endpoint("ftp://example.com", Vec::new(), None).is_err());
+        assert!(store.add_endpoint("https://example.com", vec!["merged".to_string()], None).is_err());
+        assert_eq!(all.secret.len(), 64);
+        assert!(all.wants(&event(EventKind::Failed)));
+        assert!(plans.wants(&event(EventKind::Plan)) && !plans.wants(&event(EventKind::Failed)));
+
+        store.remove_endpoint(&all.id).unwrap();
+        assert_eq!(store.endpoints().unwrap(), [plans]);
+        assert!(store.remove_endpoint(&all.id).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let signature = sign("s3cret", b"{}");
+        assert!(verify("s3cret", b"{}", &signature));
+        assert!(!verify("other", b"{}", &signature));
+        assert!(!verify("s3cret", b"{ }", &signature));
+        assert_eq!(retry_delay(1), RETRY_DELAY);
+        assert_eq!(retry_delay(3), RETRY_DELAY * 4);
+        assert_eq!(retry_delay(30), MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_failed_deliveries_are_retried_and_replayed() {
+        // An endpoint failing its first request, and recording the valid
+        // signed payloads it receives.
+        let received: Arc<Mutex<Vec<Payload>>> = Arc::default();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (payloads, count) = (received.clone(), requests.clone());
+        let app = axum::Router::new().fallback(move |headers: HeaderMap, body: String| {
+            let (payloads, count) = (payloads.clone(), count.clone());
+            async move {
+                if count.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return StatusCode::SERVICE_UNAVAILABLE;
+                }
+                let signature = headers[SIGNATURE_HEADER].to_str().unwrap();
+                assert!(verify("s3cret", body.as_bytes(), signature));
+                payloads.lock().unwrap().push(serde_json::from_str(&body).unwrap());
+                StatusCode::OK
+            }
+        });
+        let base = serve(app);
+
+        let (dir, store) = store("deliveries");
+        let endpoint = store
+            .add_endpoint(&format!("{}/hook", base), vec!["plan".to_string()], Some("s3cret".to_string()))
+            .unwrap();
+        let config = Config::load_from(dir.join("config.toml")).unwrap();
+        let client = JulesClient::new(Some("key".to_string())).unwrap();
+        let server = WebhookServer::new(Arc::new(client), store, &config);
+
+        server.enqueue(&[event(EventKind::Plan), event(EventKind::Progress)]).unwrap();
+        let records = server.flush().await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].status, Some(503));
+        let queue = server.store.queue().unwrap();
+        assert_eq!(queue[0].attempts, 1);
+        assert!(queue[0].next_attempt > now());
+
+        // The retry is not due yet.
+        assert!(server.flush().await.unwrap().is_empty());
+        let mut queue = server.store.queue().unwrap();
+        queue[0].next_attempt = 0;
+        server.store.write_queue(&queue).unwrap();
+        let records = server.flush().await.unwrap();
+        assert!(records[0].succeeded());
+        assert!(server.store.queue().unwrap().is_empty());
+
+        let delivery_id = records[0].delivery_id.clone();
+        let record = replay(&server.store, &delivery_id, &config).await.unwrap();
+        assert!(record.succeeded());
+        assert_eq!(record.attempt, 3);
+        assert_eq!(server.store.deliveries().unwrap().len(), 3);
+
+        let payloads = received.lock().unwrap().clone();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[1].delivery_id, delivery_id);
+        assert_eq!(payloads[1].event, event(EventKind::Plan));
+        assert_eq!(endpoint.id, server.store.deliveries().unwrap()[0].endpoint_id);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failing_endpoint_does_not_hold_up_others_and_log_rotates() {
+        let down = serve(axum::Router::new().fallback(|| async { StatusCode::SERVICE_UNAVAILABLE }));
+        let up = serve(axum::Router::new().fallback(|| async { StatusCode::OK }));
+
+        let (dir, mut store) = store("endpoints-down");
+        store.max_log_bytes = 1;
+        let down = store.add_endpoint(&down, Vec::new(), None).unwrap();
+        let up = store.add_endpoint(&up, Vec::new(), None).unwrap();
+        let config = Config::load_from(dir.join("config.toml")).unwrap();
+        let client = JulesClient::new(Some("key".to_string())).unwrap();
+        let server = WebhookServer::new(Arc::new(client), store, &config);
+
+        // Once its first delivery fails, the endpoint that is down is not
+        // tried again until the next flush.
+        server.enqueue(&[event(EventKind::Plan), event(EventKind::Failed)]).unwrap();
+        let records = server.flush().await.unwrap();
+        let attempts = |id: &str| records.iter().filter(|record| record.endpoint_id == id).count();
+        assert_eq!((attempts(&down.id), attempts(&up.id)), (1, 2));
+        assert!(records.iter().filter(|record| record.endpoint_id == up.id).all(DeliveryRecord::succeeded));
+        let queue = server.store.queue().unwrap();
+        assert_eq!(queue.len(), 2);
+        assert!(queue.iter().all(|delivery| delivery.endpoint_id == down.id));
+        assert_eq!((queue[0].attempts, queue[1].attempts), (1, 0));
+
+        // Every record rotates the log, and only the previous log is kept.
+        assert!(server.store.rotated_log_file.exists());
+        assert_eq!(server.store.deliveries().unwrap().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}